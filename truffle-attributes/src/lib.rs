@@ -1,6 +1,9 @@
 use proc_macro::TokenStream;
 use quote::{format_ident, quote};
-use syn::{parse::Parse, parse_macro_input, Expr, ExprPath, ItemFn, Token};
+use syn::{
+    parse::Parse, parse_macro_input, Data, DeriveInput, Expr, ExprPath, Fields, ItemFn, Token,
+    Visibility,
+};
 
 struct RegisterFnInput {
     engine: Expr,
@@ -40,6 +43,15 @@ pub fn register_fn(input: TokenStream) -> TokenStream {
         ident_segment.ident = format_ident!("register_{}", ident_segment.ident);
         path
     };
+    let fun_is_fallible = {
+        let mut path = fun.path.clone();
+        let ident_segment = path
+            .segments
+            .last_mut()
+            .expect("path should always have at least one segment");
+        ident_segment.ident = format_ident!("{}_is_fallible", ident_segment.ident);
+        path
+    };
     let fn_location = {
         let mut path = fun.path.clone();
         let ident_segment = path
@@ -53,7 +65,7 @@ pub fn register_fn(input: TokenStream) -> TokenStream {
     #[cfg(feature = "lsp")]
     {
         quote! {
-            if #fun_is_async() {
+            if #fun_is_async() || #fun_is_fallible() {
                 #engine.with(#register_fun(#name))
             } else {
                 #engine.register_fn(#name, #fun, Some(#fn_location()))
@@ -69,8 +81,8 @@ pub fn register_fn(input: TokenStream) -> TokenStream {
     #[cfg(not(feature = "lsp"))]
     {
         quote! {
-            if #fun_is_async() {
-                #engine.with(#register_fun())
+            if #fun_is_async() || #fun_is_fallible() {
+                #engine.with(#register_fun(#name))
             } else {
                 #engine.register_fn(#name, #fun, Some(#fn_location()))
             };
@@ -79,16 +91,124 @@ pub fn register_fn(input: TokenStream) -> TokenStream {
     }
 }
 
+/// Register getters and setters for the public fields of a struct, so scripts can read them with
+/// `value.field` and assign them with `value.field = ...`
+///
+/// Fields marked with `#[truffle(skip)]` are left out.
+#[proc_macro_derive(Fields, attributes(truffle))]
+pub fn derive_fields(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    derive_fields_impl(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn derive_fields_impl(input: DeriveInput) -> Result<proc_macro2::TokenStream, syn::Error> {
+    let name = &input.ident;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "Fields can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "Fields can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut registrations = vec![];
+    for field in fields {
+        if !matches!(field.vis, Visibility::Public(_)) {
+            continue;
+        }
+
+        let mut skip = false;
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("truffle"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("skip") {
+                    skip = true;
+                    Ok(())
+                } else {
+                    Err(meta.error("unsupported truffle attribute"))
+                }
+            })?;
+        }
+        if skip {
+            continue;
+        }
+
+        let ident = field
+            .ident
+            .as_ref()
+            .expect("named fields should have an identifier");
+        let field_name = ident.to_string();
+        let ty = &field.ty;
+        registrations.push(quote! {
+            engine.register_field::<#name, #ty>(
+                #field_name,
+                |this: &#name| this.#ident.clone(),
+                |this: &mut #name, value: #ty| this.#ident = value,
+            );
+        });
+    }
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::truffle::Fields for #name #ty_generics #where_clause {
+            fn register_fields(engine: &mut ::truffle::Engine) {
+                #(#registrations)*
+            }
+        }
+    })
+}
+
+/// Generate what `register_fn!` needs to register a function
+///
+/// A function returning `Result<T, E>` gives scripts a `Result` value, like `engine.register_fn`
+/// does. With `#[export(fallible)]`, scripts see a function returning `T` instead, and an `Err`
+/// stops the script, like `engine.register_fallible_fn`.
 #[proc_macro_attribute]
-pub fn export(_attr: TokenStream, item: TokenStream) -> TokenStream {
-    foo(item).unwrap().into()
+pub fn export(attr: TokenStream, item: TokenStream) -> TokenStream {
+    foo(attr, item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
 }
 
-fn foo(item: TokenStream) -> Result<proc_macro2::TokenStream, syn::Error> {
+fn foo(attr: TokenStream, item: TokenStream) -> Result<proc_macro2::TokenStream, syn::Error> {
+    let mut fallible = false;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("fallible") {
+            fallible = true;
+            Ok(())
+        } else {
+            Err(meta.error("unsupported export attribute"))
+        }
+    });
+    syn::parse::Parser::parse(parser, attr)?;
+
     let input = syn::parse::<ItemFn>(item.clone())?;
+    if fallible && generate::fallible_ok_type(&input.sig.output).is_none() {
+        return Err(syn::Error::new_spanned(
+            &input.sig,
+            "#[export(fallible)] needs a function returning a `Result`",
+        ));
+    }
+
     let output = if input.sig.asyncness.is_some() {
-        let register_fn = generate::register_fn(input.clone())?;
+        let register_fn = generate::register_fn(input.clone(), fallible)?;
         let fn_is_async = generate::fn_is_async(input.clone())?;
+        let fn_is_fallible = generate::fn_is_fallible(input.clone(), fallible)?;
         let fn_location = generate::fn_location(input.clone())?;
 
         quote! {
@@ -96,17 +216,24 @@ fn foo(item: TokenStream) -> Result<proc_macro2::TokenStream, syn::Error> {
 
             #register_fn
             #fn_is_async
+            #fn_is_fallible
             #fn_location
         }
     } else {
-        let register_fn = generate::register_fn_stub(input.clone()).expect("stub should generate");
+        let register_fn = if fallible {
+            generate::register_fallible_fn(input.clone())?
+        } else {
+            generate::register_fn_stub(input.clone()).expect("stub should generate")
+        };
         let fn_is_async = generate::fn_is_async(input.clone())?;
+        let fn_is_fallible = generate::fn_is_fallible(input.clone(), fallible)?;
         let fn_location = generate::fn_location(input.clone())?;
         quote! {
             #input
 
             #register_fn
             #fn_is_async
+            #fn_is_fallible
             #fn_location
         }
     };
@@ -117,17 +244,37 @@ mod generate {
     use proc_macro2::TokenStream;
     use quote::{format_ident, quote};
     use syn::{
-        parse_quote,
-        punctuated::Punctuated,
-        token::{Comma, Mut},
-        FnArg, ItemFn, Pat, ReturnType,
+        parse_quote, punctuated::Punctuated, token::Comma, FnArg, GenericArgument, ItemFn, Pat,
+        PathArguments, ReturnType, Type,
     };
 
-    pub fn register_fn(input: ItemFn) -> Result<TokenStream, syn::Error> {
+    /// The `T` of a function returning `Result<T, E>`, if it returns a `Result`
+    pub fn fallible_ok_type(output: &ReturnType) -> Option<&Type> {
+        let ReturnType::Type(_, ty) = output else {
+            return None;
+        };
+        let Type::Path(path) = &**ty else {
+            return None;
+        };
+        let segment = path.path.segments.last()?;
+        if segment.ident != "Result" {
+            return None;
+        }
+        let PathArguments::AngleBracketed(args) = &segment.arguments else {
+            return None;
+        };
+
+        match args.args.first()? {
+            GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        }
+    }
+
+    pub fn register_fn(input: ItemFn, fallible: bool) -> Result<TokenStream, syn::Error> {
         let wrapped_fn_name = input.sig.ident.to_string();
         let register_fn_name = format_ident!("register_{wrapped_fn_name}");
-        let wrapped_fn = wrapped_fn(input.clone())?;
-        let registration_closure = registration_closure(input)?;
+        let wrapped_fn = wrapped_fn(input.clone(), fallible)?;
+        let registration_closure = registration_closure(input, fallible)?;
 
         Ok(quote! {
             fn #register_fn_name(name: &'static str) -> impl Fn(&mut truffle::Engine) {
@@ -179,6 +326,59 @@ mod generate {
         })
     }
 
+    pub fn register_fallible_fn(input: ItemFn) -> Result<TokenStream, syn::Error> {
+        let wrapped_fn_name = input.sig.ident.clone();
+        let fn_location = format_ident!("{wrapped_fn_name}_location");
+        let generics = input.sig.generics.clone();
+        let (_, ty_generics, _) = generics.split_for_impl();
+        let turbofish = ty_generics.as_turbofish();
+
+        let mut register_fn = input;
+        register_fn.sig.ident = format_ident!("register_{wrapped_fn_name}");
+        register_fn.sig.output = syn::parse_str("-> impl Fn(&mut truffle::Engine)")
+            .expect("this should parse as a return type");
+        let mut inputs: Punctuated<FnArg, Comma> = Default::default();
+        let arg = syn::parse_str("name: &'static str").expect("should parse as arguments");
+        inputs.push(arg);
+        register_fn.sig.inputs = inputs;
+        register_fn.block = parse_quote! {
+            {
+                move |engine: &mut truffle::Engine| {
+                    use truffle::FallibleFnRegister;
+
+                    engine.register_fallible_fn(
+                        name,
+                        #wrapped_fn_name #turbofish,
+                        Some(#fn_location #turbofish()),
+                    )
+                }
+            }
+        };
+
+        Ok(quote! {
+            #register_fn
+        })
+    }
+
+    pub fn fn_is_fallible(input: ItemFn, is_fallible: bool) -> Result<TokenStream, syn::Error> {
+        let wrapped_fn_name = input.sig.ident.to_string();
+        let mut fn_is_fallible = input;
+        fn_is_fallible.sig.asyncness = None;
+        fn_is_fallible.sig.ident = format_ident!("{wrapped_fn_name}_is_fallible");
+        fn_is_fallible.sig.output =
+            syn::parse_str("-> bool").expect("this should parse as a return type");
+        fn_is_fallible.sig.inputs = Default::default();
+        fn_is_fallible.block = parse_quote! {
+            {
+                #is_fallible
+            }
+        };
+
+        Ok(quote! {
+            #fn_is_fallible
+        })
+    }
+
     pub fn fn_is_async(input: ItemFn) -> Result<TokenStream, syn::Error> {
         let wrapped_fn_name = input.sig.ident.to_string();
         let is_async = input.sig.asyncness.is_some();
@@ -199,39 +399,65 @@ mod generate {
         })
     }
 
-    fn wrapped_fn(input: ItemFn) -> Result<TokenStream, syn::Error> {
+    fn wrapped_fn(input: ItemFn, fallible: bool) -> Result<TokenStream, syn::Error> {
         let wrapped_fn_name = input.sig.ident;
-        let wrapped_params = input.sig.inputs.iter().map(|arg| {
-            let mut arg = arg.clone();
-            match &mut arg {
-                FnArg::Receiver(_receiver) => todo!(),
-                FnArg::Typed(pattype) => {
-                    match &mut *pattype.pat {
-                        Pat::Ident(patident) => patident.mutability = Some(Mut::default()),
-                        _ => todo!(),
-                    }
 
-                    pattype.ty = Box::new(
-                        syn::parse_str("&'a mut ::truffle::Value")
-                            .expect("input should be a valid rust type"),
-                    );
-                }
-            }
-            arg
-        });
+        let idents: Vec<_> = input
+            .sig
+            .inputs
+            .iter()
+            .map(|arg| match arg {
+                FnArg::Receiver(_) => todo!(),
+                FnArg::Typed(pattype) => match &*pattype.pat {
+                    Pat::Ident(patident) => &patident.ident,
+                    _ => todo!(),
+                },
+            })
+            .collect();
+
+        let is_reference: Vec<_> = input
+            .sig
+            .inputs
+            .iter()
+            .map(|arg| match arg {
+                FnArg::Receiver(_) => todo!(),
+                FnArg::Typed(pattype) => match &*pattype.ty {
+                    syn::Type::Reference(_) => true,
+                    syn::Type::Path(_) => false,
+                    _ => todo!(),
+                },
+            })
+            .collect();
+
+        // Arguments taken by value are cloned before any are borrowed, following an argument
+        // that's the same value as an earlier one back to it
+        let cloned_args = idents
+            .iter()
+            .zip(input.sig.inputs.iter())
+            .enumerate()
+            .filter(|(idx, _)| !is_reference[*idx])
+            .map(|(idx, (ident, arg))| {
+                let ty = match arg {
+                    FnArg::Receiver(_) => todo!(),
+                    FnArg::Typed(pattype) => &pattype.ty,
+                };
+                quote! { let #ident = ::truffle::arg_ref::<#ty>(args, #idx)?.clone(); }
+            });
 
-        let idents = input.sig.inputs.iter().map(|arg| match arg {
-            FnArg::Receiver(_) => todo!(),
-            FnArg::Typed(pattype) => match &*pattype.pat {
-                Pat::Ident(patident) => &patident.ident,
-                _ => todo!(),
-            },
+        let borrowed_patterns = idents.iter().enumerate().map(|(idx, ident)| {
+            if is_reference[idx] {
+                quote! { #ident }
+            } else {
+                quote! { _ }
+            }
         });
 
-        let converted_args = idents
-            .clone()
+        let borrowed_args = idents
+            .iter()
             .zip(input.sig.inputs.iter())
-            .map(|(ident, arg)| {
+            .enumerate()
+            .filter(|(idx, _)| is_reference[*idx])
+            .map(|(_, (ident, arg))| {
                 let ty = match arg {
                     FnArg::Receiver(_) => todo!(),
                     FnArg::Typed(pattype) => &pattype.ty,
@@ -239,35 +465,47 @@ mod generate {
                 quote! { let #ident = #ident.downcast_mut::<#ty>().expect("downcast type should match the actual type"); }
             });
 
-        let idents =
-            idents
-                .into_iter()
-                .zip(input.sig.inputs.iter())
-                .map(|(ident, arg)| match arg {
-                    FnArg::Receiver(_) => todo!(),
-                    FnArg::Typed(pattype) => match &*pattype.ty {
-                        syn::Type::Reference(_) => quote! { #ident },
-                        syn::Type::Path(_) => quote! { #ident.clone() },
-                        _ => todo!(),
-                    },
-                });
+        let call_args = idents.iter();
+        let num_args = idents.len();
+
+        let output = if fallible {
+            quote! {
+                match #wrapped_fn_name(#(#call_args),*).await {
+                    Ok(value) => Ok(Box::new(value) as ::truffle::Value),
+                    Err(error) => Err(error.to_string()),
+                }
+            }
+        } else {
+            quote! {
+                Ok(Box::new(#wrapped_fn_name(#(#call_args),*).await) as ::truffle::Value)
+            }
+        };
 
         Ok(quote! {
             fn wrapped_fn<'a>(
-                #(#wrapped_params),*
+                args: &'a mut [::truffle::Value],
             ) -> futures::future::BoxFuture<'a, Result<::truffle::Value, String>> {
                 async move {
+                    if args.len() != #num_args {
+                        return Err(format!("unexpected number of arguments: {}", args.len()));
+                    }
                     #(
-                    #converted_args
+                    #cloned_args
                     )*
-                    Ok(Box::new(#wrapped_fn_name(#(#idents),*).await) as ::truffle::Value)
+                    let [#(#borrowed_patterns),*] = args else {
+                        unreachable!("the number of arguments was checked above")
+                    };
+                    #(
+                    #borrowed_args
+                    )*
+                    #output
                 }
                 .boxed()
             }
         })
     }
 
-    fn registration_closure(input: ItemFn) -> Result<TokenStream, syn::Error> {
+    fn registration_closure(input: ItemFn, fallible: bool) -> Result<TokenStream, syn::Error> {
         let wrapped_fn_name = input.sig.ident.to_string();
         let fn_location = format_ident!("{wrapped_fn_name}_location");
 
@@ -280,21 +518,13 @@ mod generate {
             quote! { if let Some(id) = engine.get_type::<#ty>() { id } else { engine.register_type::<#ty>() } }
         });
 
-        let ret_type = match input.sig.output {
-            ReturnType::Default => syn::parse_str("()")?,
-            ReturnType::Type(_, ty) => ty,
+        let ret_type = match (fallible_ok_type(&input.sig.output), &input.sig.output) {
+            (Some(ty), _) if fallible => Box::new(ty.clone()),
+            (_, ReturnType::Default) => syn::parse_str("()")?,
+            (_, ReturnType::Type(_, ty)) => ty.clone(),
         };
 
-        let wrapper = match input.sig.inputs.len() {
-            0 => quote! { Function::ExternalAsyncFn0(wrapped_fn) },
-            1 => quote! { Function::ExternalAsyncFn1(wrapped_fn) },
-            2 => quote! { Function::ExternalAsyncFn2(wrapped_fn) },
-            3 => quote! { Function::ExternalAsyncFn3(wrapped_fn) },
-            4 => quote! { Function::ExternalAsyncFn4(wrapped_fn) },
-            _ => unimplemented!(
-                "only async functions with up to 4 arguments are currently supported"
-            ),
-        };
+        let wrapper = quote! { Function::ExternalAsyncFn(wrapped_fn) };
 
         Ok(quote! {
             |engine: &mut Engine| {
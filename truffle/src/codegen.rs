@@ -1,9 +1,14 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use crate::{
-    parser::{AstNode, NodeId, Span},
+    lexer::unescape,
+    parser::{AstNode, NodeId, Span, Variant},
     typechecker::{
-        ExternalFunctionId, TypeChecker, TypeId, BOOL_TYPE, I64_TYPE, STRING_TYPE, UNIT_TYPE,
+        Capture, ExternalFunctionId, FunctionId, TypeChecker, TypeId, BOOL_TYPE, I64_TYPE,
+        STRING_TYPE, UNIT_TYPE,
     },
     F64_TYPE,
 };
@@ -14,13 +19,33 @@ pub struct InstructionId(pub usize);
 #[derive(Clone, Copy, Debug)]
 pub struct RegisterId(pub usize);
 
-#[allow(clippy::box_collection)]
-#[derive(Clone, Copy)]
-pub union RegisterValue {
-    pub f64: f64,
-    pub i64: i64,
-    pub bool: bool,
-    pub ptr: *const Box<String>,
+/// The contents of a register
+///
+/// Numbers and bools are stored inline. Strings are shared between the registers they're moved
+/// into, and are never changed in place. Every other value, like a struct, an array or a value of
+/// a registered type, lives in a shared slot, so that changes made through one register are seen
+/// through the others. Values are freed once the last register holding them lets go.
+#[derive(Clone, Debug, Default)]
+pub enum RegisterValue {
+    #[default]
+    Unit,
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+    String(Arc<String>),
+    Object(Arc<Mutex<crate::Value>>),
+}
+
+impl RegisterValue {
+    /// The value a register of the given type starts out with, before anything is stored in it
+    pub fn zero(ty: TypeId) -> RegisterValue {
+        match ty {
+            I64_TYPE => RegisterValue::I64(0),
+            F64_TYPE => RegisterValue::F64(0.0),
+            BOOL_TYPE => RegisterValue::Bool(false),
+            _ => RegisterValue::Unit,
+        }
+    }
 }
 
 pub struct Value {
@@ -31,36 +56,35 @@ pub struct Value {
 impl Value {
     pub fn new_i64(val: i64) -> Value {
         Value {
-            val: RegisterValue { i64: val },
+            val: RegisterValue::I64(val),
             ty: I64_TYPE,
         }
     }
 
     pub fn new_f64(val: f64) -> Value {
         Value {
-            val: RegisterValue { f64: val },
+            val: RegisterValue::F64(val),
             ty: F64_TYPE,
         }
     }
 
     pub fn new_bool(val: bool) -> Value {
         Value {
-            val: RegisterValue { bool: val },
+            val: RegisterValue::Bool(val),
             ty: BOOL_TYPE,
         }
     }
 
     pub fn new_string(val: String) -> Value {
-        let thin_string = Box::new(val);
-        let ptr = Box::into_raw(thin_string) as _;
-        let val = RegisterValue { ptr };
-        let ty = STRING_TYPE;
-        Value { val, ty }
+        Value {
+            val: RegisterValue::String(Arc::new(val)),
+            ty: STRING_TYPE,
+        }
     }
 }
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Instruction {
     IADD {
         lhs: RegisterId,
@@ -82,6 +106,43 @@ pub enum Instruction {
         rhs: RegisterId,
         target: RegisterId,
     },
+    IMOD {
+        lhs: RegisterId,
+        rhs: RegisterId,
+        target: RegisterId,
+    },
+    IPOW {
+        lhs: RegisterId,
+        rhs: RegisterId,
+        target: RegisterId,
+    },
+
+    // Integer bitwise operations
+    IAND {
+        lhs: RegisterId,
+        rhs: RegisterId,
+        target: RegisterId,
+    },
+    IOR {
+        lhs: RegisterId,
+        rhs: RegisterId,
+        target: RegisterId,
+    },
+    IXOR {
+        lhs: RegisterId,
+        rhs: RegisterId,
+        target: RegisterId,
+    },
+    ISHL {
+        lhs: RegisterId,
+        rhs: RegisterId,
+        target: RegisterId,
+    },
+    ISHR {
+        lhs: RegisterId,
+        rhs: RegisterId,
+        target: RegisterId,
+    },
 
     // Integer comparisons (e.g., ILT = Integer + LessThan)
     ILT {
@@ -126,6 +187,11 @@ pub enum Instruction {
         rhs: RegisterId,
         target: RegisterId,
     },
+    FPOW {
+        lhs: RegisterId,
+        rhs: RegisterId,
+        target: RegisterId,
+    },
 
     // float comparisons (e.g., ILT = Integer + LessThan)
     FLT {
@@ -149,6 +215,94 @@ pub enum Instruction {
         target: RegisterId,
     },
 
+    // String comparisons (e.g., SLT = String + LessThan)
+    SLT {
+        lhs: RegisterId,
+        rhs: RegisterId,
+        target: RegisterId,
+    },
+    SLTE {
+        lhs: RegisterId,
+        rhs: RegisterId,
+        target: RegisterId,
+    },
+    SGT {
+        lhs: RegisterId,
+        rhs: RegisterId,
+        target: RegisterId,
+    },
+    SGTE {
+        lhs: RegisterId,
+        rhs: RegisterId,
+        target: RegisterId,
+    },
+
+    // Unary operators
+    INEG {
+        source: RegisterId,
+        target: RegisterId,
+    },
+    FNEG {
+        source: RegisterId,
+        target: RegisterId,
+    },
+    NOT {
+        source: RegisterId,
+        target: RegisterId,
+    },
+
+    // Strings
+    SCONCAT {
+        lhs: RegisterId,
+        rhs: RegisterId,
+        target: RegisterId,
+    },
+    // Conversions between numbers (e.g., ITOF = Integer + To Float)
+    ITOF {
+        source: RegisterId,
+        target: RegisterId,
+    },
+    FTOI {
+        source: RegisterId,
+        target: RegisterId,
+    },
+
+    // Conversions to strings, for interpolation (e.g., ITOS = Integer + To String)
+    ITOS {
+        source: RegisterId,
+        target: RegisterId,
+    },
+    FTOS {
+        source: RegisterId,
+        target: RegisterId,
+    },
+    BTOS {
+        source: RegisterId,
+        target: RegisterId,
+    },
+
+    // Equality of each builtin type (e.g., SEQ = String + Equal)
+    IEQ {
+        lhs: RegisterId,
+        rhs: RegisterId,
+        target: RegisterId,
+    },
+    FEQ {
+        lhs: RegisterId,
+        rhs: RegisterId,
+        target: RegisterId,
+    },
+    BEQ {
+        lhs: RegisterId,
+        rhs: RegisterId,
+        target: RegisterId,
+    },
+    SEQ {
+        lhs: RegisterId,
+        rhs: RegisterId,
+        target: RegisterId,
+    },
+
     MOV {
         target: RegisterId,
         source: RegisterId,
@@ -168,9 +322,71 @@ pub enum Instruction {
         target: RegisterId,
     },
 
+    CALL {
+        head: FunctionId,
+        args: Vec<RegisterId>,
+        target: RegisterId,
+    },
+
+    // Globals, for functions other than the script body, which have them in its registers
+    LOADGLOBAL {
+        global: usize,
+        target: RegisterId,
+    },
+    STOREGLOBAL {
+        global: usize,
+        source: RegisterId,
+    },
+
+    // Closures, which copy the values of their captures when they're created
+    NEWCLOSURE {
+        function: FunctionId,
+        captures: Vec<RegisterId>,
+        target: RegisterId,
+    },
+    CALLCLOSURE {
+        closure: RegisterId,
+        args: Vec<RegisterId>,
+        target: RegisterId,
+    },
+
+    // Arrays
+    NEWARRAY {
+        element_type: TypeId,
+        items: Vec<RegisterId>,
+        target: RegisterId,
+    },
+    INDEX {
+        array: RegisterId,
+        index: RegisterId,
+        target: RegisterId,
+    },
+    ARRAYLEN {
+        array: RegisterId,
+        element_type: TypeId,
+        target: RegisterId,
+    },
+
+    // Structs
+    NEWSTRUCT {
+        fields: Vec<RegisterId>,
+        target: RegisterId,
+    },
+    GETFIELD {
+        object: RegisterId,
+        field: usize,
+        target: RegisterId,
+    },
+    SETFIELD {
+        object: RegisterId,
+        field: usize,
+        value: RegisterId,
+    },
+
     RET,
 }
 
+#[derive(Clone)]
 pub struct FunctionCodegen {
     pub instructions: Vec<Instruction>,
     // Map InstructionId to NodeId
@@ -178,6 +394,9 @@ pub struct FunctionCodegen {
     pub register_values: Vec<RegisterValue>,
     pub register_types: Vec<TypeId>,
 
+    // Parameters live in the registers right after the return register
+    pub num_params: usize,
+
     // TODO: we may want a different permanent home, but this should work for now
     pub spans: Vec<Span>,
 }
@@ -202,8 +421,7 @@ impl FunctionCodegen {
     }
 
     pub fn new_register(&mut self, ty: TypeId) -> RegisterId {
-        // For now just add in an used value that'll be later replaced
-        self.register_values.push(RegisterValue { i64: 0 });
+        self.register_values.push(RegisterValue::zero(ty));
         self.register_types.push(ty);
 
         RegisterId(self.register_values.len() - 1)
@@ -298,6 +516,96 @@ impl FunctionCodegen {
         }
     }
 
+    pub fn rem(&mut self, node_id: NodeId, lhs: RegisterId, rhs: RegisterId) -> RegisterId {
+        if self.register_types[lhs.0] == I64_TYPE {
+            let target = self.new_register(I64_TYPE);
+
+            self.add_instruction(node_id, Instruction::IMOD { lhs, rhs, target });
+
+            target
+        } else {
+            panic!("unsupport rem operation")
+        }
+    }
+
+    pub fn pow(&mut self, node_id: NodeId, lhs: RegisterId, rhs: RegisterId) -> RegisterId {
+        if self.register_types[lhs.0] == F64_TYPE {
+            let target = self.new_register(F64_TYPE);
+
+            self.add_instruction(node_id, Instruction::FPOW { lhs, rhs, target });
+
+            target
+        } else if self.register_types[lhs.0] == I64_TYPE {
+            let target = self.new_register(I64_TYPE);
+
+            self.add_instruction(node_id, Instruction::IPOW { lhs, rhs, target });
+
+            target
+        } else {
+            panic!("unsupport pow operation")
+        }
+    }
+
+    pub fn bit_and(&mut self, node_id: NodeId, lhs: RegisterId, rhs: RegisterId) -> RegisterId {
+        if self.register_types[lhs.0] == I64_TYPE {
+            let target = self.new_register(I64_TYPE);
+
+            self.add_instruction(node_id, Instruction::IAND { lhs, rhs, target });
+
+            target
+        } else {
+            panic!("unsupport bit_and operation")
+        }
+    }
+
+    pub fn bit_or(&mut self, node_id: NodeId, lhs: RegisterId, rhs: RegisterId) -> RegisterId {
+        if self.register_types[lhs.0] == I64_TYPE {
+            let target = self.new_register(I64_TYPE);
+
+            self.add_instruction(node_id, Instruction::IOR { lhs, rhs, target });
+
+            target
+        } else {
+            panic!("unsupport bit_or operation")
+        }
+    }
+
+    pub fn bit_xor(&mut self, node_id: NodeId, lhs: RegisterId, rhs: RegisterId) -> RegisterId {
+        if self.register_types[lhs.0] == I64_TYPE {
+            let target = self.new_register(I64_TYPE);
+
+            self.add_instruction(node_id, Instruction::IXOR { lhs, rhs, target });
+
+            target
+        } else {
+            panic!("unsupport bit_xor operation")
+        }
+    }
+
+    pub fn shl(&mut self, node_id: NodeId, lhs: RegisterId, rhs: RegisterId) -> RegisterId {
+        if self.register_types[lhs.0] == I64_TYPE {
+            let target = self.new_register(I64_TYPE);
+
+            self.add_instruction(node_id, Instruction::ISHL { lhs, rhs, target });
+
+            target
+        } else {
+            panic!("unsupport shl operation")
+        }
+    }
+
+    pub fn shr(&mut self, node_id: NodeId, lhs: RegisterId, rhs: RegisterId) -> RegisterId {
+        if self.register_types[lhs.0] == I64_TYPE {
+            let target = self.new_register(I64_TYPE);
+
+            self.add_instruction(node_id, Instruction::ISHR { lhs, rhs, target });
+
+            target
+        } else {
+            panic!("unsupport shr operation")
+        }
+    }
+
     pub fn lt(&mut self, node_id: NodeId, lhs: RegisterId, rhs: RegisterId) -> RegisterId {
         let target = self.new_register(BOOL_TYPE);
 
@@ -308,6 +616,10 @@ impl FunctionCodegen {
         } else if self.register_types[lhs.0] == I64_TYPE {
             self.add_instruction(node_id, Instruction::ILT { lhs, rhs, target });
 
+            target
+        } else if self.register_types[lhs.0] == STRING_TYPE {
+            self.add_instruction(node_id, Instruction::SLT { lhs, rhs, target });
+
             target
         } else {
             panic!("unsupport lt operation")
@@ -324,6 +636,10 @@ impl FunctionCodegen {
         } else if self.register_types[lhs.0] == I64_TYPE {
             self.add_instruction(node_id, Instruction::ILTE { lhs, rhs, target });
 
+            target
+        } else if self.register_types[lhs.0] == STRING_TYPE {
+            self.add_instruction(node_id, Instruction::SLTE { lhs, rhs, target });
+
             target
         } else {
             panic!("unsupport lte operation")
@@ -340,6 +656,10 @@ impl FunctionCodegen {
         } else if self.register_types[lhs.0] == I64_TYPE {
             self.add_instruction(node_id, Instruction::IGT { lhs, rhs, target });
 
+            target
+        } else if self.register_types[lhs.0] == STRING_TYPE {
+            self.add_instruction(node_id, Instruction::SGT { lhs, rhs, target });
+
             target
         } else {
             panic!("unsupport gt operation")
@@ -356,12 +676,105 @@ impl FunctionCodegen {
         } else if self.register_types[lhs.0] == I64_TYPE {
             self.add_instruction(node_id, Instruction::IGTE { lhs, rhs, target });
 
+            target
+        } else if self.register_types[lhs.0] == STRING_TYPE {
+            self.add_instruction(node_id, Instruction::SGTE { lhs, rhs, target });
+
             target
         } else {
             panic!("unsupport gte operation")
         }
     }
 
+    pub fn neg(&mut self, node_id: NodeId, source: RegisterId) -> RegisterId {
+        if self.register_types[source.0] == F64_TYPE {
+            let target = self.new_register(F64_TYPE);
+
+            self.add_instruction(node_id, Instruction::FNEG { source, target });
+
+            target
+        } else if self.register_types[source.0] == I64_TYPE {
+            let target = self.new_register(I64_TYPE);
+
+            self.add_instruction(node_id, Instruction::INEG { source, target });
+
+            target
+        } else {
+            panic!("unsupport neg operation")
+        }
+    }
+
+    pub fn not(&mut self, node_id: NodeId, source: RegisterId) -> RegisterId {
+        let target = self.new_register(BOOL_TYPE);
+
+        self.add_instruction(node_id, Instruction::NOT { source, target });
+
+        target
+    }
+
+    pub fn concat(&mut self, node_id: NodeId, lhs: RegisterId, rhs: RegisterId) -> RegisterId {
+        let target = self.new_register(STRING_TYPE);
+
+        self.add_instruction(node_id, Instruction::SCONCAT { lhs, rhs, target });
+
+        target
+    }
+
+    /// Convert a number to the given number type, or give back the register itself if it already
+    /// has that type
+    pub fn cast(&mut self, node_id: NodeId, source: RegisterId, type_id: TypeId) -> RegisterId {
+        let source_type = self.register_types[source.0];
+        if source_type == type_id {
+            return source;
+        }
+
+        let target = self.new_register(type_id);
+
+        let instruction = match (source_type, type_id) {
+            (I64_TYPE, F64_TYPE) => Instruction::ITOF { source, target },
+            (F64_TYPE, I64_TYPE) => Instruction::FTOI { source, target },
+            _ => panic!("unsupport cast operation"),
+        };
+        self.add_instruction(node_id, instruction);
+
+        target
+    }
+
+    /// Convert a value to a string, or give back the register itself if it already is one
+    pub fn to_string(&mut self, node_id: NodeId, source: RegisterId) -> RegisterId {
+        let source_type = self.register_types[source.0];
+        if source_type == STRING_TYPE {
+            return source;
+        }
+
+        let target = self.new_register(STRING_TYPE);
+
+        let instruction = match source_type {
+            I64_TYPE => Instruction::ITOS { source, target },
+            F64_TYPE => Instruction::FTOS { source, target },
+            BOOL_TYPE => Instruction::BTOS { source, target },
+            _ => panic!("unsupport to_string operation"),
+        };
+        self.add_instruction(node_id, instruction);
+
+        target
+    }
+
+    pub fn eq(&mut self, node_id: NodeId, lhs: RegisterId, rhs: RegisterId) -> RegisterId {
+        let target = self.new_register(BOOL_TYPE);
+
+        let instruction = match self.register_types[lhs.0] {
+            I64_TYPE => Instruction::IEQ { lhs, rhs, target },
+            F64_TYPE => Instruction::FEQ { lhs, rhs, target },
+            BOOL_TYPE => Instruction::BEQ { lhs, rhs, target },
+            STRING_TYPE => Instruction::SEQ { lhs, rhs, target },
+            _ => panic!("unsupport eq operation"),
+        };
+        self.add_instruction(node_id, instruction);
+
+        target
+    }
+
     pub fn mov(&mut self, node_id: NodeId, target: RegisterId, source: RegisterId) -> RegisterId {
         if target.0 == source.0 {
             source
@@ -410,12 +823,183 @@ impl FunctionCodegen {
         self.add_instruction(node_id, Instruction::EXTERNALCALL { head, args, target });
     }
 
-    pub fn next_position(&self) -> usize {
-        self.instructions.len()
-    }
-
-    pub fn offset_instruction_addresses(&mut self, offset_amount: usize) {
-        // This moves all our jump addresses by some offset to allow for linking with other
+    pub fn call(
+        &mut self,
+        node_id: NodeId,
+        head: FunctionId,
+        args: Vec<RegisterId>,
+        target: RegisterId,
+    ) {
+        self.add_instruction(node_id, Instruction::CALL { head, args, target });
+    }
+
+    pub fn load_global(&mut self, node_id: NodeId, global: usize, ty: TypeId) -> RegisterId {
+        let target = self.new_register(ty);
+
+        self.add_instruction(node_id, Instruction::LOADGLOBAL { global, target });
+
+        target
+    }
+
+    pub fn store_global(&mut self, node_id: NodeId, global: usize, source: RegisterId) {
+        self.add_instruction(node_id, Instruction::STOREGLOBAL { global, source });
+    }
+
+    pub fn new_closure(
+        &mut self,
+        node_id: NodeId,
+        closure_type: TypeId,
+        function: FunctionId,
+        captures: Vec<RegisterId>,
+    ) -> RegisterId {
+        let target = self.new_register(closure_type);
+
+        self.add_instruction(
+            node_id,
+            Instruction::NEWCLOSURE {
+                function,
+                captures,
+                target,
+            },
+        );
+
+        target
+    }
+
+    pub fn call_closure(
+        &mut self,
+        node_id: NodeId,
+        closure: RegisterId,
+        args: Vec<RegisterId>,
+        target: RegisterId,
+    ) {
+        self.add_instruction(
+            node_id,
+            Instruction::CALLCLOSURE {
+                closure,
+                args,
+                target,
+            },
+        );
+    }
+
+    pub fn new_array(
+        &mut self,
+        node_id: NodeId,
+        array_type: TypeId,
+        element_type: TypeId,
+        items: Vec<RegisterId>,
+    ) -> RegisterId {
+        let target = self.new_register(array_type);
+
+        self.add_instruction(
+            node_id,
+            Instruction::NEWARRAY {
+                element_type,
+                items,
+                target,
+            },
+        );
+
+        target
+    }
+
+    pub fn index(
+        &mut self,
+        node_id: NodeId,
+        array: RegisterId,
+        index: RegisterId,
+        target: RegisterId,
+    ) -> RegisterId {
+        self.add_instruction(
+            node_id,
+            Instruction::INDEX {
+                array,
+                index,
+                target,
+            },
+        );
+
+        target
+    }
+
+    pub fn array_len(
+        &mut self,
+        node_id: NodeId,
+        array: RegisterId,
+        element_type: TypeId,
+    ) -> RegisterId {
+        let target = self.new_register(I64_TYPE);
+
+        self.add_instruction(
+            node_id,
+            Instruction::ARRAYLEN {
+                array,
+                element_type,
+                target,
+            },
+        );
+
+        target
+    }
+
+    pub fn new_struct(
+        &mut self,
+        node_id: NodeId,
+        struct_type: TypeId,
+        fields: Vec<RegisterId>,
+    ) -> RegisterId {
+        let target = self.new_register(struct_type);
+
+        self.add_instruction(node_id, Instruction::NEWSTRUCT { fields, target });
+
+        target
+    }
+
+    pub fn get_field(
+        &mut self,
+        node_id: NodeId,
+        object: RegisterId,
+        field: usize,
+        field_type: TypeId,
+    ) -> RegisterId {
+        let target = self.new_register(field_type);
+
+        self.add_instruction(
+            node_id,
+            Instruction::GETFIELD {
+                object,
+                field,
+                target,
+            },
+        );
+
+        target
+    }
+
+    pub fn set_field(
+        &mut self,
+        node_id: NodeId,
+        object: RegisterId,
+        field: usize,
+        value: RegisterId,
+    ) {
+        self.add_instruction(
+            node_id,
+            Instruction::SETFIELD {
+                object,
+                field,
+                value,
+            },
+        );
+    }
+
+    pub fn next_position(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn offset_instruction_addresses(&mut self, offset_amount: usize) {
+        // This moves all our jump addresses by some offset to allow for linking with other
         // codegen'd functions (think: single vector with all instructions after codegen
         // is complete and before evaluation begins)
 
@@ -436,8 +1020,19 @@ impl FunctionCodegen {
     }
 }
 
+/// The `break`s and `continue`s of a loop, whose jumps are patched once the loop is translated
+#[derive(Default)]
+struct LoopJumps {
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
 pub struct Translater<'permanent> {
     var_lookup: HashMap<NodeId, RegisterId>,
+    // The register of each global, by its index, in the function being translated
+    global_lookup: HashMap<usize, RegisterId>,
+    // The loops around the code being translated, innermost last
+    loops: Vec<LoopJumps>,
     pub typechecker: TypeChecker<'permanent>,
 }
 
@@ -445,18 +1040,28 @@ impl<'permanent> Translater<'permanent> {
     pub fn new(typechecker: TypeChecker<'permanent>) -> Self {
         Translater {
             var_lookup: HashMap::new(),
+            global_lookup: HashMap::new(),
+            loops: vec![],
             typechecker,
         }
     }
 
-    pub fn translate(&mut self) -> FunctionCodegen {
+    /// Translate the script body and all of its local functions, indexed by `FunctionId`
+    pub fn translate(&mut self) -> Vec<FunctionCodegen> {
         let mut builder = FunctionCodegen {
             instructions: vec![],
             source_map: vec![],
-            register_values: vec![RegisterValue { i64: 0 }],
+            register_values: vec![RegisterValue::Unit],
             register_types: vec![TypeId(0)],
+            num_params: 0,
             spans: self.typechecker.parse_results.spans.clone(),
         };
+        // Globals are loaded into the registers right after the return register before the
+        // script runs
+        for (idx, global) in self.typechecker.globals.iter().enumerate() {
+            let register_id = builder.new_register(global.type_id);
+            self.global_lookup.insert(idx, register_id);
+        }
         if !self.typechecker.parse_results.ast_nodes.is_empty() {
             let last = self.typechecker.parse_results.ast_nodes.len() - 1;
             let result = self.translate_node(&mut builder, NodeId(last));
@@ -471,14 +1076,140 @@ impl<'permanent> Translater<'permanent> {
 
         // FIXME: for now assume a RET at the end, though this should be inferred earlier in compilation
         builder.ret(NodeId(0)); // we used a dummy NodeId as this should be an infallible call
+
+        let mut output = vec![builder];
+        for idx in 0..self.typechecker.local_functions.len() {
+            output.push(self.translate_local_function(idx));
+        }
+
+        output
+    }
+
+    /// Translate a snippet that continues the main function of a session, along with the local
+    /// functions it defined
+    ///
+    /// The main function already has registers of the given types, which the snippet's code is
+    /// added on top of. Local functions before `first_function` were translated by earlier
+    /// snippets.
+    pub fn translate_snippet(
+        &mut self,
+        block: NodeId,
+        register_types: &[TypeId],
+        first_function: usize,
+    ) -> Vec<FunctionCodegen> {
+        let mut builder = FunctionCodegen {
+            instructions: vec![],
+            source_map: vec![],
+            register_values: register_types
+                .iter()
+                .copied()
+                .map(RegisterValue::zero)
+                .collect(),
+            register_types: register_types.to_vec(),
+            num_params: 0,
+            spans: self.typechecker.parse_results.spans.clone(),
+        };
+
+        for idx in 0..self.typechecker.globals.len() {
+            self.global_lookup.insert(idx, RegisterId(idx + 1));
+        }
+
+        let result = self.translate_node(&mut builder, block);
+        builder.register_types[0] = self.typechecker.node_types[block.0];
+        builder.mov(block, RegisterId(0), result);
+        builder.ret(block);
+
+        let mut output = vec![builder];
+        for idx in first_function..self.typechecker.local_functions.len() {
+            output.push(self.translate_local_function(idx));
+        }
+
+        output
+    }
+
+    pub fn translate_local_function(&mut self, idx: usize) -> FunctionCodegen {
+        let local_function = &self.typechecker.local_functions[idx];
+        let params: Vec<_> = local_function
+            .params
+            .iter()
+            .copied()
+            .zip(local_function.param_types.iter().copied())
+            .collect();
+        let captures: Vec<_> = local_function
+            .captures
+            .iter()
+            .map(|capture| (*capture, self.typechecker.capture_type(*capture)))
+            .collect();
+        let ret = local_function.ret;
+        let block = local_function.block;
+
+        let mut builder = FunctionCodegen {
+            instructions: vec![],
+            source_map: vec![],
+            register_values: vec![RegisterValue::Unit],
+            register_types: vec![ret],
+            num_params: params.len(),
+            spans: self.typechecker.parse_results.spans.clone(),
+        };
+
+        for (param, type_id) in params {
+            let register_id = builder.new_register(type_id);
+            self.var_lookup.insert(param, register_id);
+        }
+
+        // A closure's captures are copied into the registers after its parameters when it's
+        // called. The variables still live in their own registers outside of the closure. Any
+        // other global is loaded from the script body when it's used.
+        let outer_globals = std::mem::take(&mut self.global_lookup);
+        let mut outer_registers = vec![];
+        for (capture, type_id) in &captures {
+            let register_id = builder.new_register(*type_id);
+            outer_registers.push(self.set_capture_register(*capture, Some(register_id)));
+        }
+
+        let result = self.translate_node(&mut builder, block);
+        if ret != UNIT_TYPE {
+            builder.mov(block, RegisterId(0), result);
+        }
+        builder.ret(block);
+
+        for ((capture, _), register_id) in captures.iter().zip(outer_registers) {
+            self.set_capture_register(*capture, register_id);
+        }
+        self.global_lookup = outer_globals;
+
         builder
     }
 
+    /// Point a captured variable or global at a register, or at none, returning the register it
+    /// was in before
+    fn set_capture_register(
+        &mut self,
+        capture: Capture,
+        register_id: Option<RegisterId>,
+    ) -> Option<RegisterId> {
+        match (capture, register_id) {
+            (Capture::Variable(variable), Some(register_id)) => {
+                self.var_lookup.insert(variable, register_id)
+            }
+            (Capture::Variable(variable), None) => self.var_lookup.remove(&variable),
+            (Capture::Global(idx), Some(register_id)) => {
+                self.global_lookup.insert(idx, register_id)
+            }
+            (Capture::Global(idx), None) => self.global_lookup.remove(&idx),
+        }
+    }
+
     pub fn translate_node(&mut self, builder: &mut FunctionCodegen, node_id: NodeId) -> RegisterId {
-        match &self.typechecker.parse_results.ast_nodes[node_id.0] {
+        let output = match &self.typechecker.parse_results.ast_nodes[node_id.0] {
             AstNode::Int => self.translate_int(builder, node_id),
             AstNode::Float => self.translate_float(builder, node_id),
             AstNode::BinaryOp { lhs, op, rhs } => self.translate_binop(builder, *lhs, *op, *rhs),
+            AstNode::UnaryOp { op, operand } => self.translate_unary_op(builder, *op, *operand),
+            AstNode::Cast { value, .. } => {
+                let value = self.translate_node(builder, *value);
+                builder.cast(node_id, value, self.typechecker.node_types[node_id.0])
+            }
             AstNode::Block(nodes) => {
                 // FIXME: clone to get around ownership issue
                 let nodes = nodes.clone();
@@ -492,8 +1223,17 @@ impl<'permanent> Translater<'permanent> {
                 initializer,
                 ..
             } => self.translate_let(builder, *variable_name, *initializer),
-            AstNode::Variable => self.translate_variable(node_id),
-            AstNode::Statement(node_id) => self.translate_node(builder, *node_id),
+            AstNode::Variable => self.translate_variable(builder, node_id),
+            AstNode::Statement(inner) => {
+                let output = self.translate_node(builder, *inner);
+
+                // The value of an expression followed by `;` is thrown away
+                if self.typechecker.node_types[node_id.0] == UNIT_TYPE {
+                    builder.new_register(UNIT_TYPE)
+                } else {
+                    output
+                }
+            }
             AstNode::If {
                 condition,
                 then_block,
@@ -502,12 +1242,71 @@ impl<'permanent> Translater<'permanent> {
             AstNode::While { condition, block } => {
                 self.translate_while(builder, *condition, *block)
             }
+            AstNode::For {
+                variable,
+                range,
+                block,
+            } => self.translate_for(builder, *variable, *range, *block),
+            AstNode::IfLet {
+                pattern,
+                value,
+                then_block,
+                else_expression,
+            } => {
+                // An `if let` is a match with the else as its catch-all arm
+                let mut arms = vec![(Some(*pattern), *then_block)];
+                if let Some(else_expression) = else_expression {
+                    arms.push((None, *else_expression));
+                }
+
+                self.translate_match(builder, node_id, *value, &arms)
+            }
+            AstNode::Match { target, arms } => {
+                let arms: Vec<_> = arms
+                    .iter()
+                    .map(|(pattern, body)| (Some(*pattern), *body))
+                    .collect();
+
+                self.translate_match(builder, node_id, *target, &arms)
+            }
+            AstNode::Variant { variant, payload } => {
+                self.translate_variant(builder, *variant, *payload, node_id)
+            }
+            AstNode::Try(value) => self.translate_try(builder, *value, node_id),
+            AstNode::Return(value) => self.translate_return(builder, *value, node_id),
+            AstNode::Break | AstNode::Continue => self.translate_loop_jump(builder, node_id),
             AstNode::Call { head, args } => {
                 // FIXME: clone to get around ownership issue
                 self.translate_call(builder, *head, &args.clone(), node_id)
             }
             AstNode::String => self.translate_string(builder, node_id),
+            AstNode::Text => self.translate_text(builder, node_id),
+            AstNode::Interpolation(parts) => {
+                let parts = parts.clone();
+
+                self.translate_interpolation(builder, &parts, node_id)
+            }
+            AstNode::Array(items) => self.translate_array(builder, &items.clone(), node_id),
+            AstNode::Index { target, index } => {
+                self.translate_index(builder, *target, *index, node_id)
+            }
+            // Structs only exist in the typechecker, their values are built by literals
+            AstNode::Struct { .. } => builder.new_register(UNIT_TYPE),
+            AstNode::StructLiteral { fields, .. } => {
+                self.translate_struct_literal(builder, &fields.clone(), node_id)
+            }
+            AstNode::Field { target, .. } => self.translate_field(builder, *target, node_id),
+            // Local functions are translated separately, after the script body
+            AstNode::Fn { .. } => builder.new_register(UNIT_TYPE),
+            // Closure bodies are too, so only the closure value is built here
+            AstNode::Closure { .. } => self.translate_closure(builder, node_id),
             x => panic!("unsupported translation: {:?}", x),
+        };
+
+        if self.typechecker.promoted_nodes.contains(&node_id) {
+            builder.cast(node_id, output, F64_TYPE)
+        } else {
+            output
         }
     }
 
@@ -545,12 +1344,40 @@ impl<'permanent> Translater<'permanent> {
         let span = self.typechecker.parse_results.spans[node_id.0];
         let contents = self.typechecker.parse_results.contents_for_span(span);
 
-        let s = String::from_utf8(contents.to_owned())
-            .expect("internal error: string literal could not be parsed");
+        // Leave out the quotes
+        let s = unescape(&contents[1..contents.len() - 1]);
 
         builder.string_const(s)
     }
 
+    pub fn translate_text(&mut self, builder: &mut FunctionCodegen, node_id: NodeId) -> RegisterId {
+        let span = self.typechecker.parse_results.spans[node_id.0];
+        let contents = self.typechecker.parse_results.contents_for_span(span);
+
+        builder.string_const(unescape(contents))
+    }
+
+    /// Build an interpolated string by converting each part to a string and joining them
+    pub fn translate_interpolation(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        parts: &[NodeId],
+        node_id: NodeId,
+    ) -> RegisterId {
+        let mut output = None;
+        for part in parts {
+            let value = self.translate_node(builder, *part);
+            let value = builder.to_string(*part, value);
+
+            output = Some(match output {
+                Some(output) => builder.concat(node_id, output, value),
+                None => value,
+            });
+        }
+
+        output.unwrap_or_else(|| builder.string_const(String::new()))
+    }
+
     pub fn translate_binop(
         &mut self,
         builder: &mut FunctionCodegen,
@@ -558,7 +1385,24 @@ impl<'permanent> Translater<'permanent> {
         op: NodeId,
         rhs: NodeId,
     ) -> RegisterId {
-        match self.typechecker.parse_results.ast_nodes[op.0] {
+        let is_assignment = matches!(
+            self.typechecker.parse_results.ast_nodes[op.0],
+            AstNode::Assignment
+                | AstNode::AddAssignment
+                | AstNode::MinusAssignment
+                | AstNode::MultiplyAssignment
+                | AstNode::DivideAssignment
+        );
+        if is_assignment
+            && matches!(
+                self.typechecker.parse_results.ast_nodes[lhs.0],
+                AstNode::Field { .. }
+            )
+        {
+            return self.translate_field_assignment(builder, lhs, op, rhs);
+        }
+
+        let output = match self.typechecker.parse_results.ast_nodes[op.0] {
             AstNode::Plus => {
                 let lhs = self.translate_node(builder, lhs);
                 let rhs = self.translate_node(builder, rhs);
@@ -579,6 +1423,41 @@ impl<'permanent> Translater<'permanent> {
                 let rhs = self.translate_node(builder, rhs);
                 builder.div(op, lhs, rhs)
             }
+            AstNode::Modulo => {
+                let lhs = self.translate_node(builder, lhs);
+                let rhs = self.translate_node(builder, rhs);
+                builder.rem(op, lhs, rhs)
+            }
+            AstNode::Pow => {
+                let lhs = self.translate_node(builder, lhs);
+                let rhs = self.translate_node(builder, rhs);
+                builder.pow(op, lhs, rhs)
+            }
+            AstNode::BitAnd => {
+                let lhs = self.translate_node(builder, lhs);
+                let rhs = self.translate_node(builder, rhs);
+                builder.bit_and(op, lhs, rhs)
+            }
+            AstNode::BitOr => {
+                let lhs = self.translate_node(builder, lhs);
+                let rhs = self.translate_node(builder, rhs);
+                builder.bit_or(op, lhs, rhs)
+            }
+            AstNode::BitXor => {
+                let lhs = self.translate_node(builder, lhs);
+                let rhs = self.translate_node(builder, rhs);
+                builder.bit_xor(op, lhs, rhs)
+            }
+            AstNode::ShiftLeft => {
+                let lhs = self.translate_node(builder, lhs);
+                let rhs = self.translate_node(builder, rhs);
+                builder.shl(op, lhs, rhs)
+            }
+            AstNode::ShiftRight => {
+                let lhs = self.translate_node(builder, lhs);
+                let rhs = self.translate_node(builder, rhs);
+                builder.shr(op, lhs, rhs)
+            }
             AstNode::LessThan => {
                 let lhs = self.translate_node(builder, lhs);
                 let rhs = self.translate_node(builder, rhs);
@@ -599,6 +1478,22 @@ impl<'permanent> Translater<'permanent> {
                 let rhs = self.translate_node(builder, rhs);
                 builder.gte(op, lhs, rhs)
             }
+            AstNode::Append => {
+                let lhs = self.translate_node(builder, lhs);
+                let rhs = self.translate_node(builder, rhs);
+                builder.concat(op, lhs, rhs)
+            }
+            AstNode::Equal => {
+                let lhs = self.translate_node(builder, lhs);
+                let rhs = self.translate_node(builder, rhs);
+                self.translate_eq(builder, op, lhs, rhs)
+            }
+            AstNode::NotEqual => {
+                let lhs = self.translate_node(builder, lhs);
+                let rhs = self.translate_node(builder, rhs);
+                let equal = self.translate_eq(builder, op, lhs, rhs);
+                builder.not(op, equal)
+            }
             AstNode::Assignment => {
                 let lhs = self.translate_node(builder, lhs);
                 let rhs = self.translate_node(builder, rhs);
@@ -699,23 +1594,45 @@ impl<'permanent> Translater<'permanent> {
                 output
             }
             _ => panic!("unsupported operation"),
+        };
+
+        // A function works on its own copy of a global, which an assignment stores back
+        if is_assignment {
+            if let Some(global) = self.loaded_global(lhs) {
+                builder.store_global(op, global, output);
+            }
         }
+
+        output
     }
 
     pub fn translate_let(
         &mut self,
         builder: &mut FunctionCodegen,
         variable_name: NodeId,
-        initializer: NodeId,
+        initializer_node: NodeId,
     ) -> RegisterId {
-        let initializer = self.translate_node(builder, initializer);
+        let initializer = self.translate_node(builder, initializer_node);
 
-        self.var_lookup.insert(variable_name, initializer);
+        // The initializer may be a constant or another variable, which assigning to this one
+        // mustn't change, so the variable gets a register of its own
+        let register_id = builder.new_register(builder.register_types[initializer.0]);
+        builder.mov(initializer_node, register_id, initializer);
 
-        initializer
+        self.var_lookup.insert(variable_name, register_id);
+
+        register_id
     }
 
-    pub fn translate_variable(&mut self, variable_name: NodeId) -> RegisterId {
+    pub fn translate_variable(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        variable_name: NodeId,
+    ) -> RegisterId {
+        if let Some(idx) = self.typechecker.global_resolution.get(&variable_name) {
+            return self.global_register(builder, *idx, variable_name);
+        }
+
         let def_site = self
             .typechecker
             .variable_def_site
@@ -730,6 +1647,27 @@ impl<'permanent> Translater<'permanent> {
         *register_id
     }
 
+    /// The register holding a global. The script body and closures have their globals in
+    /// registers, while other functions load a copy of the global from the script body's.
+    fn global_register(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        global: usize,
+        node_id: NodeId,
+    ) -> RegisterId {
+        match self.global_lookup.get(&global) {
+            Some(register_id) => *register_id,
+            None => builder.load_global(node_id, global, self.typechecker.globals[global].type_id),
+        }
+    }
+
+    /// The global a variable refers to, if the function being translated loads its own copy of it
+    fn loaded_global(&self, variable_name: NodeId) -> Option<usize> {
+        let global = *self.typechecker.global_resolution.get(&variable_name)?;
+
+        (!self.global_lookup.contains_key(&global)).then_some(global)
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn translate_if(
         &mut self,
@@ -739,7 +1677,8 @@ impl<'permanent> Translater<'permanent> {
         then_block: NodeId,
         else_expression: Option<NodeId>,
     ) -> RegisterId {
-        let output = builder.new_register(self.typechecker.node_types[node_id.0]);
+        let output_type = self.typechecker.node_types[node_id.0];
+        let output = builder.new_register(output_type);
         let condition = self.translate_node(builder, condition);
 
         let brif_location = builder.next_position();
@@ -753,7 +1692,9 @@ impl<'permanent> Translater<'permanent> {
 
         let then_branch = InstructionId(builder.next_position());
         let then_output = self.translate_node(builder, then_block);
-        builder.mov(node_id, output, then_output);
+        if output_type != UNIT_TYPE {
+            builder.mov(node_id, output, then_output);
+        }
 
         let else_branch = if let Some(else_expression) = else_expression {
             // Create a jump with a temporary location we'll replace when we know the correct one
@@ -763,7 +1704,9 @@ impl<'permanent> Translater<'permanent> {
 
             let else_location = builder.next_position();
             let else_output = self.translate_node(builder, else_expression);
-            builder.mov(node_id, output, else_output);
+            if output_type != UNIT_TYPE {
+                builder.mov(node_id, output, else_output);
+            }
 
             let after_if = builder.next_position();
 
@@ -796,6 +1739,7 @@ impl<'permanent> Translater<'permanent> {
         builder.brif(block, output, condition, InstructionId(0), InstructionId(0));
 
         let block_begin = InstructionId(builder.next_position());
+        self.loops.push(LoopJumps::default());
         self.translate_node(builder, block);
         builder.jmp(block, InstructionId(top));
 
@@ -806,30 +1750,729 @@ impl<'permanent> Translater<'permanent> {
             then_branch: block_begin,
             else_branch: block_end,
         };
+        self.patch_loop_jumps(builder, InstructionId(top), block_end);
 
         output
     }
 
-    pub fn translate_block(
+    pub fn translate_return(
         &mut self,
         builder: &mut FunctionCodegen,
-        nodes: &[NodeId],
+        value: Option<NodeId>,
+        node_id: NodeId,
     ) -> RegisterId {
-        if nodes.is_empty() {
-            builder.new_register(UNIT_TYPE)
-        } else {
-            let mut idx = 0;
+        let output = match value {
+            Some(value) => self.translate_node(builder, value),
+            None => builder.new_register(UNIT_TYPE),
+        };
 
-            loop {
-                let output = self.translate_node(builder, nodes[idx]);
-                if idx == (nodes.len() - 1) {
-                    return output;
+        if self.typechecker.node_types[node_id.0] != UNIT_TYPE {
+            builder.mov(node_id, RegisterId(0), output);
+        }
+        builder.ret(node_id);
+
+        output
+    }
+
+    /// Run the body of the first arm whose pattern matches the target. Arms without a pattern
+    /// match anything.
+    pub fn translate_match(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        node_id: NodeId,
+        target: NodeId,
+        arms: &[(Option<NodeId>, NodeId)],
+    ) -> RegisterId {
+        let output_type = self.typechecker.node_types[node_id.0];
+        let output = builder.new_register(output_type);
+        let value = self.translate_node(builder, target);
+
+        let mut jumps_to_end = vec![];
+        for (pattern, body) in arms {
+            let mut failures = vec![];
+            if let Some(pattern) = pattern {
+                self.translate_pattern(builder, *pattern, value, &mut failures);
+            }
+
+            let body_output = self.translate_node(builder, *body);
+            if output_type != UNIT_TYPE {
+                builder.mov(node_id, output, body_output);
+            }
+            jumps_to_end.push(builder.next_position());
+            builder.jmp(node_id, InstructionId(0));
+
+            let next_arm = InstructionId(builder.next_position());
+            for location in failures {
+                if let Instruction::BRIF { else_branch, .. } = &mut builder.instructions[location] {
+                    *else_branch = next_arm;
+                }
+            }
+        }
+
+        let end = InstructionId(builder.next_position());
+        for location in jumps_to_end {
+            builder.instructions[location] = Instruction::JMP(end);
+        }
+
+        output
+    }
+
+    /// Check whether a value matches a pattern, binding the pattern's variables. Each check
+    /// branches away when it fails, to a place that isn't known yet, so the locations of those
+    /// branches are added to `failures` to be patched by the caller.
+    fn translate_pattern(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        pattern: NodeId,
+        value: RegisterId,
+        failures: &mut Vec<usize>,
+    ) {
+        if let AstNode::Variant { variant, payload } =
+            self.typechecker.parse_results.ast_nodes[pattern.0]
+        {
+            let enum_type = self
+                .typechecker
+                .enum_type(builder.register_types[value.0])
+                .expect("internal error: variant pattern on a value without variants");
+
+            let is_value = builder.new_register(BOOL_TYPE);
+            builder.external_call(pattern, enum_type.is_value, vec![value], is_value);
+            let matches = match variant {
+                Variant::Some | Variant::Ok => is_value,
+                Variant::None | Variant::Err => builder.not(pattern, is_value),
+            };
+            Self::branch_unless(builder, pattern, matches, failures);
+
+            if let Some(payload) = payload {
+                let (unwrap, payload_type) = if variant == Variant::Err {
+                    let unwrap_error = enum_type
+                        .unwrap_error
+                        .expect("internal error: Err pattern on a value without errors");
+                    (unwrap_error, enum_type.error)
+                } else {
+                    (enum_type.unwrap_value, enum_type.value)
+                };
+
+                let inner = builder.new_register(payload_type);
+                builder.external_call(pattern, unwrap, vec![value], inner);
+                self.translate_pattern(builder, payload, inner, failures);
+            }
+            return;
+        }
+
+        match self.typechecker.parse_results.ast_nodes[pattern.0] {
+            AstNode::Wildcard => {}
+            AstNode::Variable => {
+                self.var_lookup.insert(pattern, value);
+            }
+            _ => {
+                let literal = self.translate_node(builder, pattern);
+                let matches = self.translate_eq(builder, pattern, value, literal);
+                Self::branch_unless(builder, pattern, matches, failures);
+            }
+        }
+    }
+
+    /// Carry on if the condition holds, or otherwise branch to a place that's patched later
+    fn branch_unless(
+        builder: &mut FunctionCodegen,
+        node_id: NodeId,
+        condition: RegisterId,
+        failures: &mut Vec<usize>,
+    ) {
+        let location = builder.next_position();
+        builder.brif(
+            node_id,
+            condition,
+            condition,
+            InstructionId(location + 1),
+            InstructionId(0),
+        );
+        failures.push(location);
+    }
+
+    /// Build an `Option` or `Result` value with one of its variants
+    pub fn translate_variant(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        variant: Variant,
+        payload: Option<NodeId>,
+        node_id: NodeId,
+    ) -> RegisterId {
+        let type_id = self.typechecker.node_types[node_id.0];
+        let enum_type = self
+            .typechecker
+            .enum_type(type_id)
+            .expect("internal error: variant without an Option or Result type");
+
+        let wrap = match variant {
+            Variant::Some | Variant::Ok => enum_type.wrap_value,
+            Variant::None | Variant::Err => enum_type.wrap_error,
+        };
+        let args = match payload {
+            Some(payload) => vec![self.translate_node(builder, payload)],
+            None => vec![],
+        };
+
+        let output = builder.new_register(type_id);
+        builder.external_call(node_id, wrap, args, output);
+
+        output
+    }
+
+    /// Unpack the value of a `Some` or an `Ok`, or return early with the `None` or `Err`
+    pub fn translate_try(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        value: NodeId,
+        node_id: NodeId,
+    ) -> RegisterId {
+        let value = self.translate_node(builder, value);
+        let enum_type = self
+            .typechecker
+            .enum_type(builder.register_types[value.0])
+            .expect("internal error: `?` on a value without variants");
+
+        let is_value = builder.new_register(BOOL_TYPE);
+        builder.external_call(node_id, enum_type.is_value, vec![value], is_value);
+        let brif_location = builder.next_position();
+        builder.brif(
+            node_id,
+            is_value,
+            is_value,
+            InstructionId(0),
+            InstructionId(0),
+        );
+
+        // The early return builds a `None` or `Err` of the type being returned, which can hold a
+        // different value than the one we're unpacking
+        let return_location = InstructionId(builder.next_position());
+        let return_type = self.typechecker.try_return_types[&node_id];
+        let return_enum_type = self
+            .typechecker
+            .enum_type(return_type)
+            .expect("internal error: `?` returning a value without variants");
+
+        let early_return = builder.new_register(return_type);
+        let args = match enum_type.unwrap_error {
+            Some(unwrap_error) => {
+                let error = builder.new_register(enum_type.error);
+                builder.external_call(node_id, unwrap_error, vec![value], error);
+                vec![error]
+            }
+            None => vec![],
+        };
+        builder.external_call(node_id, return_enum_type.wrap_error, args, early_return);
+        builder.mov(node_id, RegisterId(0), early_return);
+        builder.ret(node_id);
+
+        let unwrap_location = InstructionId(builder.next_position());
+        let output = builder.new_register(enum_type.value);
+        builder.external_call(node_id, enum_type.unwrap_value, vec![value], output);
+
+        builder.instructions[brif_location] = Instruction::BRIF {
+            condition: is_value,
+            then_branch: unwrap_location,
+            else_branch: return_location,
+        };
+
+        output
+    }
+
+    /// Jump out of, or back around, the innermost loop. The target is filled in by
+    /// `patch_loop_jumps` once the loop is translated.
+    pub fn translate_loop_jump(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        node_id: NodeId,
+    ) -> RegisterId {
+        let is_break = matches!(
+            self.typechecker.parse_results.ast_nodes[node_id.0],
+            AstNode::Break
+        );
+        let loop_jumps = self
+            .loops
+            .last_mut()
+            .expect("internal error: break or continue outside of a loop");
+
+        let location = builder.next_position();
+        if is_break {
+            loop_jumps.breaks.push(location);
+        } else {
+            loop_jumps.continues.push(location);
+        }
+        builder.jmp(node_id, InstructionId(0));
+
+        builder.new_register(UNIT_TYPE)
+    }
+
+    /// Point the `break`s and `continue`s of the innermost loop at its end and at the place where
+    /// it goes around again
+    fn patch_loop_jumps(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        continue_target: InstructionId,
+        break_target: InstructionId,
+    ) {
+        let loop_jumps = self
+            .loops
+            .pop()
+            .expect("internal error: loop jumps went missing");
+
+        for location in loop_jumps.breaks {
+            builder.instructions[location] = Instruction::JMP(break_target);
+        }
+        for location in loop_jumps.continues {
+            builder.instructions[location] = Instruction::JMP(continue_target);
+        }
+    }
+
+    pub fn translate_array(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        items: &[NodeId],
+        node_id: NodeId,
+    ) -> RegisterId {
+        let array_type = self.typechecker.node_types[node_id.0];
+        let element_type = self
+            .typechecker
+            .element_type(array_type)
+            .expect("internal error: array literal without an array type");
+
+        let items = items
+            .iter()
+            .map(|item| self.translate_node(builder, *item))
+            .collect();
+
+        builder.new_array(node_id, array_type, element_type, items)
+    }
+
+    pub fn translate_index(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        target: NodeId,
+        index: NodeId,
+        node_id: NodeId,
+    ) -> RegisterId {
+        let array = self.translate_node(builder, target);
+        let index = self.translate_node(builder, index);
+        let output = builder.new_register(self.typechecker.node_types[node_id.0]);
+
+        builder.index(node_id, array, index, output)
+    }
+
+    pub fn translate_struct_literal(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        fields: &[(NodeId, NodeId)],
+        node_id: NodeId,
+    ) -> RegisterId {
+        let struct_type = self.typechecker.node_types[node_id.0];
+        let num_fields = self
+            .typechecker
+            .local_struct(struct_type)
+            .expect("internal error: struct literal without a struct type")
+            .fields
+            .len();
+
+        // Values are evaluated in the order they're written, but stored in the order the fields
+        // were declared in
+        let mut field_registers = vec![RegisterId(0); num_fields];
+        for (field_name, value) in fields {
+            let value = self.translate_node(builder, *value);
+
+            let field_name = self
+                .typechecker
+                .parse_results
+                .contents_for_node(*field_name);
+            let (idx, _) = self
+                .typechecker
+                .local_struct(struct_type)
+                .and_then(|local_struct| local_struct.field(field_name))
+                .expect("internal error: struct literal with unknown field");
+
+            field_registers[idx] = value;
+        }
+
+        builder.new_struct(node_id, struct_type, field_registers)
+    }
+
+    pub fn translate_unary_op(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        op: NodeId,
+        operand: NodeId,
+    ) -> RegisterId {
+        // The literal is negated before it's parsed, so `i64::MIN` can be written
+        if self.typechecker.is_negated_int(op, operand) {
+            let contents = self.typechecker.parse_results.contents_for_node(operand);
+            let constant = format!("-{}", String::from_utf8_lossy(contents))
+                .parse::<i64>()
+                .expect("internal error: int constant could not be parsed");
+
+            return builder.i64_const(constant);
+        }
+
+        let operand = self.translate_node(builder, operand);
+
+        match self.typechecker.parse_results.ast_nodes[op.0] {
+            AstNode::Negate => builder.neg(op, operand),
+            AstNode::Not => builder.not(op, operand),
+            _ => panic!("unsupported operation"),
+        }
+    }
+
+    /// Compare two values, calling the registered `PartialEq` implementation for registered types
+    /// and comparing script structs field by field
+    pub fn translate_eq(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        op: NodeId,
+        lhs: RegisterId,
+        rhs: RegisterId,
+    ) -> RegisterId {
+        let ty = builder.register_types[lhs.0];
+        if let Some(eq) = self.typechecker.permanent_definitions.eq_functions.get(&ty) {
+            let output = builder.new_register(BOOL_TYPE);
+            builder.external_call(op, *eq, vec![lhs, rhs], output);
+
+            output
+        } else if let Some(local_struct) = self.typechecker.local_struct(ty) {
+            let field_types: Vec<_> = local_struct.fields.iter().map(|(_, ty)| *ty).collect();
+
+            let output = builder.new_register(BOOL_TYPE);
+            let equal = builder.bool_const(true);
+            builder.mov(op, output, equal);
+
+            // Stop at the first field that differs
+            let mut brif_locations = vec![];
+            for (field, field_type) in field_types.into_iter().enumerate() {
+                let lhs_field = builder.get_field(op, lhs, field, field_type);
+                let rhs_field = builder.get_field(op, rhs, field, field_type);
+                let equal = builder.eq(op, lhs_field, rhs_field);
+                builder.mov(op, output, equal);
+
+                brif_locations.push(builder.next_position());
+                builder.brif(op, output, output, InstructionId(0), InstructionId(0));
+            }
+
+            let end = InstructionId(builder.next_position());
+            for location in brif_locations {
+                builder.instructions[location] = Instruction::BRIF {
+                    condition: output,
+                    then_branch: InstructionId(location + 1),
+                    else_branch: end,
+                };
+            }
+
+            output
+        } else {
+            builder.eq(op, lhs, rhs)
+        }
+    }
+
+    pub fn translate_field(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        target: NodeId,
+        node_id: NodeId,
+    ) -> RegisterId {
+        let object = self.translate_node(builder, target);
+
+        if let Some(external_field) = self.typechecker.external_field_resolution.get(&node_id) {
+            let output = builder.new_register(external_field.ty);
+            builder.external_call(node_id, external_field.getter, vec![object], output);
+
+            return output;
+        }
+
+        let field = *self
+            .typechecker
+            .field_resolution
+            .get(&node_id)
+            .expect("internal error: unresolved field");
+
+        builder.get_field(
+            node_id,
+            object,
+            field,
+            self.typechecker.node_types[node_id.0],
+        )
+    }
+
+    pub fn translate_field_assignment(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        lhs: NodeId,
+        op: NodeId,
+        rhs: NodeId,
+    ) -> RegisterId {
+        let AstNode::Field { target, .. } = self.typechecker.parse_results.ast_nodes[lhs.0] else {
+            panic!("internal error: field assignment without a field")
+        };
+
+        let object = self.translate_node(builder, target);
+
+        if let Some(external_field) = self.typechecker.external_field_resolution.get(&lhs) {
+            let (getter, setter, field_type) = (
+                external_field.getter,
+                external_field.setter,
+                external_field.ty,
+            );
+            let rhs = self.translate_node(builder, rhs);
+            let output = builder.new_register(UNIT_TYPE);
+
+            if matches!(
+                self.typechecker.parse_results.ast_nodes[op.0],
+                AstNode::Assignment
+            ) {
+                builder.external_call(op, setter, vec![object, rhs], output);
+
+                return output;
+            }
+
+            let current = builder.new_register(field_type);
+            builder.external_call(op, getter, vec![object], current);
+            let value = self.translate_compound_op(builder, op, current, rhs);
+            builder.external_call(op, setter, vec![object, value], output);
+
+            return value;
+        }
+
+        let field = *self
+            .typechecker
+            .field_resolution
+            .get(&lhs)
+            .expect("internal error: unresolved field");
+        let rhs = self.translate_node(builder, rhs);
+
+        if matches!(
+            self.typechecker.parse_results.ast_nodes[op.0],
+            AstNode::Assignment
+        ) {
+            builder.set_field(op, object, field, rhs);
+
+            return builder.new_register(UNIT_TYPE);
+        }
+
+        let field_type = self.typechecker.node_types[lhs.0];
+        let current = builder.get_field(op, object, field, field_type);
+        let value = self.translate_compound_op(builder, op, current, rhs);
+        builder.set_field(op, object, field, value);
+
+        value
+    }
+
+    fn translate_compound_op(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        op: NodeId,
+        current: RegisterId,
+        rhs: RegisterId,
+    ) -> RegisterId {
+        match self.typechecker.parse_results.ast_nodes[op.0] {
+            AstNode::AddAssignment => builder.add(op, current, rhs),
+            AstNode::MinusAssignment => builder.sub(op, current, rhs),
+            AstNode::MultiplyAssignment => builder.mul(op, current, rhs),
+            AstNode::DivideAssignment => builder.div(op, current, rhs),
+            _ => panic!("internal error: unsupported field assignment"),
+        }
+    }
+
+    pub fn translate_for(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        variable: NodeId,
+        range: NodeId,
+        block: NodeId,
+    ) -> RegisterId {
+        let AstNode::Range {
+            lhs,
+            rhs,
+            inclusive,
+        } = self.typechecker.parse_results.ast_nodes[range.0]
+        else {
+            return self.translate_for_array(builder, variable, range, block);
+        };
+
+        let output = builder.new_register(UNIT_TYPE);
+
+        // The bounds are only evaluated once, before the loop starts. A bound that's a variable
+        // gives back the variable's own register, so the end is copied to keep the body's
+        // assignments from moving it
+        let lhs = self.translate_node(builder, lhs);
+        let rhs_node = rhs;
+        let rhs = self.translate_node(builder, rhs);
+
+        let index = builder.new_register(I64_TYPE);
+        builder.mov(range, index, lhs);
+        let end = builder.new_register(I64_TYPE);
+        let rhs = builder.mov(rhs_node, end, rhs);
+        self.var_lookup.insert(variable, index);
+
+        let one = builder.i64_const(1);
+
+        let top = builder.next_position();
+        let condition = if inclusive {
+            builder.lte(range, index, rhs)
+        } else {
+            builder.lt(range, index, rhs)
+        };
+
+        let brif_location = builder.next_position();
+        builder.brif(block, output, condition, InstructionId(0), InstructionId(0));
+
+        let block_begin = InstructionId(builder.next_position());
+        self.loops.push(LoopJumps::default());
+        self.translate_node(builder, block);
+        let block_done = InstructionId(builder.next_position());
+
+        // An inclusive range may end at i64::MAX, so check for the last value before stepping
+        // past it
+        let last_check_location = if inclusive {
+            let not_last = builder.lt(range, index, rhs);
+            let location = builder.next_position();
+            builder.brif(block, output, not_last, InstructionId(0), InstructionId(0));
+
+            Some((location, not_last))
+        } else {
+            None
+        };
+
+        let step = InstructionId(builder.next_position());
+        let next = builder.add(range, index, one);
+        builder.mov(range, index, next);
+        builder.jmp(block, InstructionId(top));
+
+        let block_end = InstructionId(builder.next_position());
+
+        builder.instructions[brif_location] = Instruction::BRIF {
+            condition,
+            then_branch: block_begin,
+            else_branch: block_end,
+        };
+
+        if let Some((location, not_last)) = last_check_location {
+            builder.instructions[location] = Instruction::BRIF {
+                condition: not_last,
+                then_branch: step,
+                else_branch: block_end,
+            };
+        }
+        self.patch_loop_jumps(builder, block_done, block_end);
+
+        output
+    }
+
+    pub fn translate_for_array(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        variable: NodeId,
+        array: NodeId,
+        block: NodeId,
+    ) -> RegisterId {
+        let output = builder.new_register(UNIT_TYPE);
+
+        let element_type = self.typechecker.node_types[variable.0];
+
+        // Like ranges, the array and its length are only evaluated once, before the loop starts,
+        // and the array is copied out of the variable in case the body assigns it a new one
+        let array_node = array;
+        let source = self.translate_node(builder, array);
+        let array = builder.new_register(self.typechecker.node_types[array_node.0]);
+        builder.mov(array_node, array, source);
+        let len = builder.array_len(array_node, array, element_type);
+
+        let index = builder.new_register(I64_TYPE);
+        let zero = builder.i64_const(0);
+        builder.mov(array_node, index, zero);
+
+        let element = builder.new_register(element_type);
+        self.var_lookup.insert(variable, element);
+
+        let one = builder.i64_const(1);
+
+        let top = builder.next_position();
+        let condition = builder.lt(array_node, index, len);
+
+        let brif_location = builder.next_position();
+        builder.brif(block, output, condition, InstructionId(0), InstructionId(0));
+
+        let block_begin = InstructionId(builder.next_position());
+        builder.index(array_node, array, index, element);
+        self.loops.push(LoopJumps::default());
+        self.translate_node(builder, block);
+
+        let step = InstructionId(builder.next_position());
+        let next = builder.add(array_node, index, one);
+        builder.mov(array_node, index, next);
+        builder.jmp(block, InstructionId(top));
+
+        let block_end = InstructionId(builder.next_position());
+
+        builder.instructions[brif_location] = Instruction::BRIF {
+            condition,
+            then_branch: block_begin,
+            else_branch: block_end,
+        };
+        self.patch_loop_jumps(builder, step, block_end);
+
+        output
+    }
+
+    pub fn translate_block(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        nodes: &[NodeId],
+    ) -> RegisterId {
+        if nodes.is_empty() {
+            builder.new_register(UNIT_TYPE)
+        } else {
+            let mut idx = 0;
+
+            loop {
+                let output = self.translate_node(builder, nodes[idx]);
+                if idx == (nodes.len() - 1) {
+                    return output;
                 }
                 idx += 1;
             }
         }
     }
 
+    pub fn translate_closure(
+        &mut self,
+        builder: &mut FunctionCodegen,
+        node_id: NodeId,
+    ) -> RegisterId {
+        let idx = self
+            .typechecker
+            .local_functions
+            .iter()
+            .position(|local_function| local_function.node_id == node_id)
+            .expect("internal error: closure should have a function");
+
+        let captures = self.typechecker.local_functions[idx]
+            .captures
+            .clone()
+            .into_iter()
+            .map(|capture| match capture {
+                Capture::Variable(variable) => *self
+                    .var_lookup
+                    .get(&variable)
+                    .expect("internal error: captured variable missing definition"),
+                Capture::Global(global) => self.global_register(builder, global, node_id),
+            })
+            .collect();
+
+        builder.new_closure(
+            node_id,
+            self.typechecker.node_types[node_id.0],
+            FunctionId(idx + 1),
+            captures,
+        )
+    }
+
     pub fn translate_call(
         &mut self,
         builder: &mut FunctionCodegen,
@@ -845,13 +2488,21 @@ impl<'permanent> Translater<'permanent> {
             translated_args.push(self.translate_node(builder, *node_id));
         }
 
-        let head = self
-            .typechecker
-            .call_resolution
-            .get(&head)
-            .expect("internal error: call should be resolved");
+        let head_type = self.typechecker.node_types[head.0];
+        if self.typechecker.function_type(head_type).is_some() {
+            let closure = self.translate_variable(builder, head);
+            builder.call_closure(node_id, closure, translated_args, output);
+        } else if let Some(head) = self.typechecker.local_call_resolution.get(&head) {
+            builder.call(node_id, *head, translated_args, output);
+        } else {
+            let head = self
+                .typechecker
+                .call_resolution
+                .get(&head)
+                .expect("internal error: call should be resolved");
 
-        builder.external_call(node_id, *head, translated_args, output);
+            builder.external_call(node_id, *head, translated_args, output);
+        }
 
         output
     }
@@ -1,4 +1,8 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 #[cfg(feature = "lsp")]
 use lsp_types::Url;
@@ -7,14 +11,120 @@ use crate::parser::Span;
 use crate::Type;
 
 use crate::{
-    parser::NodeId, typechecker::ExternalFunctionId, ErrorBatch, Evaluator, Function, FunctionId,
-    Lexer, ParseResults, Parser, ReturnValue, Translater, TypeChecker, TypeId, Value,
+    codegen::InstructionId,
+    eval::ScriptId,
+    parser::NodeId,
+    typechecker::{ExternalFunctionId, Global},
+    ArithmeticMode, Callback, ErrorBatch, ErrorKind, EvalLimits, Evaluator, FromScript, Function,
+    FunctionCodegen, FunctionId, Lexer, ParseResults, Parser, ReturnValue, RuntimeType,
+    ScriptError, Translater, TypeChecker, TypeId, Value, BOOL_TYPE, UNIT_TYPE,
 };
 
 #[cfg_attr(feature = "lsp", derive(serde::Serialize, serde::Deserialize))]
 pub struct Engine {
     permanent_definitions: PermanentDefinitions,
     app_name: Option<String>,
+    #[cfg_attr(feature = "lsp", serde(skip))]
+    limits: EvalLimits,
+    #[cfg_attr(feature = "lsp", serde(skip))]
+    arithmetic: ArithmeticMode,
+    numeric_promotion: bool,
+
+    // Globals every script can use, along with how to make each run's copy of their values
+    globals: Vec<Global>,
+    #[cfg_attr(feature = "lsp", serde(skip))]
+    global_values: Vec<Box<dyn Fn() -> Value + Send + Sync>>,
+
+    #[cfg_attr(feature = "lsp", serde(skip, default = "EngineId::next"))]
+    id: EngineId,
+}
+
+/// Tells engines apart, so a compiled script can only be run with the functions it was compiled
+/// against
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct EngineId(u64);
+
+impl EngineId {
+    fn next() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Values the host binds as globals for a single run of a script
+///
+/// Globals added with `push` can't be assigned by the script, while ones added with `push_mut`
+/// can. Either way, the script works on the values held here, so after the run they reflect what
+/// the script did with them, including any changes made by methods taking `&mut self`.
+#[derive(Default)]
+pub struct Bindings {
+    bindings: Vec<Binding>,
+}
+
+struct Binding {
+    name: String,
+    value: Value,
+    type_id: std::any::TypeId,
+    type_name: &'static str,
+    is_mutable: bool,
+}
+
+impl Bindings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind a global the script can read but not assign
+    pub fn push<T: Type>(&mut self, name: impl Into<String>, value: T) {
+        self.bind(name.into(), value, false)
+    }
+
+    /// Bind a global the script can assign, which holds the script's last value for it afterwards
+    pub fn push_mut<T: Type>(&mut self, name: impl Into<String>, value: T) {
+        self.bind(name.into(), value, true)
+    }
+
+    pub fn get<T: Type>(&self, name: &str) -> Option<&T> {
+        self.bindings
+            .iter()
+            .rfind(|binding| binding.name == name)?
+            .value
+            .downcast_ref()
+    }
+
+    pub fn get_mut<T: Type>(&mut self, name: &str) -> Option<&mut T> {
+        self.bindings
+            .iter_mut()
+            .rfind(|binding| binding.name == name)?
+            .value
+            .downcast_mut()
+    }
+
+    /// Move the values out for a run, which hands them back with `restore_values`
+    fn take_values(&mut self) -> Vec<Value> {
+        self.bindings
+            .iter_mut()
+            .map(|binding| std::mem::replace(&mut binding.value, Box::new(())))
+            .collect()
+    }
+
+    fn restore_values(&mut self, values: Vec<Value>) {
+        for (binding, value) in self.bindings.iter_mut().zip(values) {
+            binding.value = value;
+        }
+    }
+
+    fn bind<T: Type>(&mut self, name: String, value: T, is_mutable: bool) {
+        self.bindings.retain(|binding| binding.name != name);
+        self.bindings.push(Binding {
+            name,
+            value: Box::new(value),
+            type_id: std::any::TypeId::of::<T>(),
+            type_name: std::any::type_name::<T>(),
+            is_mutable,
+        });
+    }
 }
 
 #[cfg_attr(feature = "lsp", derive(serde::Serialize, serde::Deserialize))]
@@ -32,6 +142,21 @@ pub struct PermanentDefinitions {
     // Map between future and its output
     pub future_of_map: HashMap<TypeId, TypeId>,
 
+    // Map between array and its element type
+    pub array_of_map: HashMap<TypeId, TypeId>,
+
+    // The builtin methods of arrays, which completion only offers after an array
+    pub array_methods: HashSet<ExternalFunctionId>,
+
+    // Fields of registered types that scripts can read and assign
+    pub fields: HashMap<TypeId, Vec<ExternalField>>,
+
+    // Registered `PartialEq` implementations, which `==` and `!=` call for registered types
+    pub eq_functions: HashMap<TypeId, ExternalFunctionId>,
+
+    // The `Option` and `Result` types scripts can use, and how to work with their values
+    pub enum_types: HashMap<TypeId, EnumType>,
+
     // List of all registered functions
     pub functions: Vec<ExternalFnRecord>,
 
@@ -56,13 +181,15 @@ impl PermanentDefinitions {
     where
         T: Type,
     {
-        for (idx, tid) in self.types.iter().enumerate() {
-            if tid == &std::any::TypeId::of::<T>() {
-                return Some(TypeId(idx));
-            }
-        }
+        self.find_type(std::any::TypeId::of::<T>())
+    }
 
-        None
+    /// The TypeId scripts know a Rust type by, if it's been registered
+    pub fn find_type(&self, type_id: std::any::TypeId) -> Option<TypeId> {
+        self.types
+            .iter()
+            .position(|tid| *tid == type_id)
+            .map(TypeId)
     }
 }
 
@@ -91,28 +218,304 @@ impl Engine {
             ],
             reference_of_map: HashMap::new(),
             future_of_map: HashMap::new(),
+            array_of_map: HashMap::new(),
+            array_methods: HashSet::new(),
+            fields: HashMap::new(),
+            eq_functions: HashMap::new(),
+            enum_types: HashMap::new(),
             external_functions: HashMap::new(),
             functions: vec![],
             #[cfg(feature = "lsp")]
             function_infos: HashMap::new(),
         };
 
-        Self {
+        let mut engine = Self {
             permanent_definitions,
             app_name: None,
-        }
+            limits: EvalLimits::default(),
+            arithmetic: ArithmeticMode::default(),
+            numeric_promotion: false,
+            globals: vec![],
+            global_values: vec![],
+            id: EngineId::next(),
+        };
+
+        engine.register_array_type::<i64>();
+        engine.register_array_type::<f64>();
+        engine.register_array_type::<bool>();
+        engine.register_array_type::<String>();
+
+        engine.register_builtin_enum_types::<i64>();
+        engine.register_builtin_enum_types::<f64>();
+        engine.register_builtin_enum_types::<bool>();
+        engine.register_builtin_enum_types::<String>();
+
+        // Closures can leave a script as its value, not only as arguments of registered functions
+        engine.register_type::<Callback>();
+
+        engine
+    }
+
+    /// Register `Option<T>` and `Result<T, String>` for one of the basic types, comparable with
+    /// `==` like the basic types themselves
+    fn register_builtin_enum_types<T>(&mut self)
+    where
+        T: Clone + PartialEq + Type,
+    {
+        self.register_option_type::<T>();
+        self.register_result_type::<T, String>();
+        self.register_eq::<Option<T>>();
+        self.register_eq::<Result<T, String>>();
+    }
+
+    /// Let scripts use `Option<T>`, building it with `Some` and `None` and taking it apart with
+    /// `match`, `if let` and `?`. Registered functions can then take and return `Option<T>`.
+    ///
+    /// This is already done for the basic types.
+    pub fn register_option_type<T>(&mut self)
+    where
+        T: Clone + Type,
+    {
+        let value_type = self.get_or_register_type::<T>();
+        let option_type = self.get_or_register_type::<Option<T>>();
+
+        let some = self.add_builtin_fn(vec![value_type], option_type, |args| {
+            Ok(Box::new(Some(first_arg::<T>(args)?.clone())))
+        });
+        let none = self.add_builtin_fn(vec![], option_type, |_| Ok(Box::new(None::<T>)));
+        let is_some = self.add_builtin_fn(vec![option_type], BOOL_TYPE, |args| {
+            Ok(Box::new(first_arg::<Option<T>>(args)?.is_some()))
+        });
+        let unwrap = self.add_builtin_fn(vec![option_type], value_type, |args| {
+            match first_arg::<Option<T>>(args)? {
+                Some(value) => Ok(Box::new(value.clone())),
+                None => Err("internal error: expected Some".into()),
+            }
+        });
+
+        self.permanent_definitions.enum_types.insert(
+            option_type,
+            EnumType {
+                kind: EnumKind::Option,
+                value: value_type,
+                error: UNIT_TYPE,
+                wrap_value: some,
+                wrap_error: none,
+                is_value: is_some,
+                unwrap_value: unwrap,
+                unwrap_error: None,
+            },
+        );
+    }
+
+    /// Let scripts use `Result<T, E>`, building it with `Ok` and `Err` and taking it apart with
+    /// `match`, `if let` and `?`. Registered functions can then take and return `Result<T, E>`
+    /// as a value, rather than stopping the script on an `Err` like `register_fallible_fn` does.
+    ///
+    /// This is already done for the basic types, with `String` errors.
+    pub fn register_result_type<T, E>(&mut self)
+    where
+        T: Clone + Type,
+        E: Clone + Type,
+    {
+        let value_type = self.get_or_register_type::<T>();
+        let error_type = self.get_or_register_type::<E>();
+        let result_type = self.get_or_register_type::<Result<T, E>>();
+
+        let ok = self.add_builtin_fn(vec![value_type], result_type, |args| {
+            Ok(Box::new(Ok::<T, E>(first_arg::<T>(args)?.clone())))
+        });
+        let err = self.add_builtin_fn(vec![error_type], result_type, |args| {
+            Ok(Box::new(Err::<T, E>(first_arg::<E>(args)?.clone())))
+        });
+        let is_ok = self.add_builtin_fn(vec![result_type], BOOL_TYPE, |args| {
+            Ok(Box::new(first_arg::<Result<T, E>>(args)?.is_ok()))
+        });
+        let unwrap = self.add_builtin_fn(vec![result_type], value_type, |args| {
+            match first_arg::<Result<T, E>>(args)? {
+                Ok(value) => Ok(Box::new(value.clone())),
+                Err(_) => Err("internal error: expected Ok".into()),
+            }
+        });
+        let unwrap_err =
+            self.add_builtin_fn(vec![result_type], error_type, |args| {
+                match first_arg::<Result<T, E>>(args)? {
+                    Ok(_) => Err("internal error: expected Err".into()),
+                    Err(error) => Ok(Box::new(error.clone())),
+                }
+            });
+
+        self.permanent_definitions.enum_types.insert(
+            result_type,
+            EnumType {
+                kind: EnumKind::Result,
+                value: value_type,
+                error: error_type,
+                wrap_value: ok,
+                wrap_error: err,
+                is_value: is_ok,
+                unwrap_value: unwrap,
+                unwrap_error: Some(unwrap_err),
+            },
+        );
+    }
+
+    /// Add a function without a name, which only the code generated for builtin syntax calls
+    fn add_builtin_fn(
+        &mut self,
+        params: Vec<TypeId>,
+        ret: TypeId,
+        fun: impl Fn(&mut [Value]) -> Result<Value, String> + Send + Sync + 'static,
+    ) -> ExternalFunctionId {
+        self.permanent_definitions.functions.push(ExternalFnRecord {
+            params,
+            ret,
+            fun: Function::ExternalFn(Box::new(fun)),
+        });
+
+        ExternalFunctionId(self.permanent_definitions.functions.len() - 1)
+    }
+
+    /// Register `Vec<T>` as the script's array of `T`, along with its builtin methods
+    fn register_array_type<T>(&mut self)
+    where
+        T: Clone + PartialEq + Type,
+    {
+        let element_type = self
+            .get_type::<T>()
+            .expect("internal error: array element types should already be registered");
+        let array_type = self.register_type::<Vec<T>>();
+
+        self.permanent_definitions
+            .array_of_map
+            .insert(array_type, element_type);
+
+        self.register_fn("len", array_len::<T>, None);
+        self.register_fn("push", array_push::<T>, None);
+        let num_functions = self.permanent_definitions.functions.len();
+        self.permanent_definitions
+            .array_methods
+            .extend((num_functions - 2..num_functions).map(ExternalFunctionId));
+        self.register_eq::<Vec<T>>();
     }
 
     pub fn app_name(&self) -> Option<&str> {
         self.app_name.as_deref()
     }
 
-    pub fn eval_source(
+    /// Lex, parse, typecheck and translate a script without running it
+    ///
+    /// The resulting `CompiledScript` can be run as many times as needed against this engine.
+    pub fn compile(
+        &self,
+        _fname: impl Into<PathBuf>,
+        contents: &[u8],
+    ) -> Result<CompiledScript, ErrorBatch> {
+        let (functions, types) = self.translate(contents, vec![], None, false)?;
+
+        Ok(CompiledScript {
+            functions,
+            types,
+            globals: self.globals.clone(),
+            engine: self.id,
+            id: ScriptId::next(),
+        })
+    }
+
+    /// Compile a script whose value has to be a `T`, like a filter that has to produce a `bool`
+    ///
+    /// A script whose value has a different type is rejected here, before it ever runs, with an
+    /// error pointing at its last expression.
+    pub fn compile_expecting<T: FromScript>(
         &self,
         _fname: impl Into<PathBuf>,
         contents: &[u8],
+    ) -> Result<CompiledScript, ErrorBatch> {
+        let expected = self.expected_type::<T>()?;
+        let (functions, types) = self.translate(contents, vec![], Some(expected), false)?;
+
+        Ok(CompiledScript {
+            functions,
+            types,
+            globals: self.globals.clone(),
+            engine: self.id,
+            id: ScriptId::next(),
+        })
+    }
+
+    /// Bind a global that every script this engine runs can use, like a configuration value
+    ///
+    /// Each run gets its own copy of the value, and scripts can't assign to it. Setting a global
+    /// with the same name as an existing one replaces it. Compiled scripts pick up new values, but
+    /// can't be run anymore once a global is added or changes type.
+    pub fn set_global<T>(&mut self, name: impl Into<String>, value: T)
+    where
+        T: Clone + Type,
+    {
+        let name = name.into();
+        let type_id = self.get_or_register_type::<T>();
+
+        let global = Global {
+            name,
+            type_id,
+            is_mutable: false,
+        };
+        let value: Box<dyn Fn() -> Value + Send + Sync> = Box::new(move || Box::new(value.clone()));
+
+        match self
+            .globals
+            .iter()
+            .position(|other| other.name == global.name)
+        {
+            Some(idx) => {
+                self.globals[idx] = global;
+                self.global_values[idx] = value;
+            }
+            None => {
+                self.globals.push(global);
+                self.global_values.push(value);
+            }
+        }
+    }
+
+    /// Fresh copies of the values of the engine's globals, for a run of a script
+    fn global_values(&self) -> Vec<Value> {
+        self.global_values.iter().map(|value| value()).collect()
+    }
+
+    /// The globals for the given bindings, which come after the engine's own
+    fn binding_globals(&self, bindings: &Bindings) -> Result<Vec<Global>, ErrorBatch> {
+        bindings
+            .bindings
+            .iter()
+            .map(|binding| {
+                let Some(type_id) = self.permanent_definitions.find_type(binding.type_id) else {
+                    return Err(ErrorBatch::one(ScriptError {
+                        message: format!(
+                            "global `{}` has type {}, which needs to be registered with the engine",
+                            binding.name, binding.type_name
+                        ),
+                        span: Span { start: 0, end: 0 },
+                        kind: ErrorKind::Script,
+                    }));
+                };
+
+                Ok(Global {
+                    name: binding.name.clone(),
+                    type_id,
+                    is_mutable: binding.is_mutable,
+                })
+            })
+            .collect()
+    }
+
+    fn translate(
+        &self,
+        contents: &[u8],
+        globals: Vec<Global>,
+        expected: Option<TypeId>,
         debug_output: bool,
-    ) -> Result<ReturnValue, ErrorBatch> {
+    ) -> Result<(Vec<FunctionCodegen>, Vec<RuntimeType>), ErrorBatch> {
         let mut lexer = Lexer::new(contents.to_vec(), 0);
 
         let tokens = match lexer.lex() {
@@ -134,7 +537,9 @@ impl Engine {
             parser.results.print();
         }
 
-        let mut typechecker = TypeChecker::new(parser.results, &self.permanent_definitions);
+        let mut typechecker = self.new_typechecker(parser.results);
+        typechecker.globals.extend(globals);
+        typechecker.expected_type = expected;
 
         match typechecker.typecheck() {
             Ok(_) => {}
@@ -142,70 +547,198 @@ impl Engine {
                 return Err(errors);
             }
         }
+        if debug_output {
+            typechecker.print_node_types();
+        }
 
         let mut translater = Translater::new(typechecker);
 
-        #[allow(unused_mut)]
-        let mut output = translater.translate();
+        let output = translater.translate();
 
         if debug_output {
-            output.debug_output();
+            for function in &output {
+                function.debug_output();
+            }
         }
 
-        let mut evaluator = Evaluator::default();
-        evaluator.add_function(output);
+        Ok((output, translater.typechecker.runtime_types()))
+    }
 
-        evaluator
-            .eval(FunctionId(0), &self.permanent_definitions.functions)
-            .map_err(ErrorBatch::one)
+    pub fn eval_source(
+        &self,
+        fname: impl Into<PathBuf>,
+        contents: &[u8],
+        debug_output: bool,
+    ) -> Result<ReturnValue, ErrorBatch> {
+        self.eval_source_with(fname, contents, &mut Bindings::new(), debug_output)
+    }
+
+    /// Run a script with the given bindings as globals, next to the engine's own
+    pub fn eval_source_with(
+        &self,
+        _fname: impl Into<PathBuf>,
+        contents: &[u8],
+        bindings: &mut Bindings,
+        debug_output: bool,
+    ) -> Result<ReturnValue, ErrorBatch> {
+        self.run_source(contents, bindings, None, debug_output)
     }
 
     #[cfg(feature = "async")]
     pub async fn eval_source_async(
+        &self,
+        fname: impl Into<PathBuf>,
+        contents: &[u8],
+        debug_output: bool,
+    ) -> Result<ReturnValue, ErrorBatch> {
+        self.eval_source_with_async(fname, contents, &mut Bindings::new(), debug_output)
+            .await
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn eval_source_with_async(
         &self,
         _fname: impl Into<PathBuf>,
         contents: &[u8],
+        bindings: &mut Bindings,
         debug_output: bool,
     ) -> Result<ReturnValue, ErrorBatch> {
-        // let fname = fname.as_ref();
+        self.run_source_async(contents, bindings, None, debug_output)
+            .await
+    }
 
-        let mut lexer = Lexer::new(contents.to_vec(), 0);
-        let tokens = match lexer.lex() {
-            Ok(tokens) => tokens,
-            Err(errors) => return Err(errors),
-        };
+    /// Run a script and take its value out as a `T`
+    ///
+    /// A script whose value has a different type is rejected before it runs.
+    pub fn eval<T: FromScript>(
+        &self,
+        _fname: impl Into<PathBuf>,
+        contents: &[u8],
+    ) -> Result<T, ErrorBatch> {
+        let expected = self.expected_type::<T>()?;
+        let value = self.run_source(contents, &mut Bindings::new(), Some(expected), false)?;
 
-        let mut parser = Parser::new(tokens, contents.to_vec(), 0);
-        match parser.parse() {
-            Ok(()) => {}
-            Err(errors) => return Err(errors),
-        }
-        if debug_output {
-            parser.results.print();
-        }
+        self.take_value(value, expected)
+    }
 
-        let mut typechecker = TypeChecker::new(parser.results, &self.permanent_definitions);
+    #[cfg(feature = "async")]
+    pub async fn eval_async<T: FromScript>(
+        &self,
+        _fname: impl Into<PathBuf>,
+        contents: &[u8],
+    ) -> Result<T, ErrorBatch> {
+        let expected = self.expected_type::<T>()?;
+        let value = self
+            .run_source_async(contents, &mut Bindings::new(), Some(expected), false)
+            .await?;
 
-        match typechecker.typecheck() {
-            Ok(_) => {}
-            Err(errors) => return Err(errors),
+        self.take_value(value, expected)
+    }
+
+    fn run_source(
+        &self,
+        contents: &[u8],
+        bindings: &mut Bindings,
+        expected: Option<TypeId>,
+        debug_output: bool,
+    ) -> Result<ReturnValue, ErrorBatch> {
+        let globals = self.binding_globals(bindings)?;
+        let (output, _) = self.translate(contents, globals, expected, debug_output)?;
+
+        let mut evaluator = self.new_evaluator();
+        for function in output {
+            evaluator.add_function(function);
         }
-        if debug_output {
-            typechecker.print_node_types();
+
+        let mut globals = self.global_values();
+        globals.extend(bindings.take_values());
+
+        let result = evaluator.eval_with_globals(
+            FunctionId(0),
+            &mut globals,
+            &self.permanent_definitions.functions,
+        );
+
+        bindings.restore_values(globals.split_off(self.globals.len()));
+
+        result.map_err(ErrorBatch::one)
+    }
+
+    #[cfg(feature = "async")]
+    async fn run_source_async(
+        &self,
+        contents: &[u8],
+        bindings: &mut Bindings,
+        expected: Option<TypeId>,
+        debug_output: bool,
+    ) -> Result<ReturnValue, ErrorBatch> {
+        let globals = self.binding_globals(bindings)?;
+        let (output, _) = self.translate(contents, globals, expected, debug_output)?;
+
+        let mut evaluator = self.new_evaluator();
+        for function in output {
+            evaluator.add_function(function);
         }
 
-        let mut translater = Translater::new(typechecker);
+        let mut globals = self.global_values();
+        globals.extend(bindings.take_values());
 
-        #[allow(unused_mut)]
-        let mut output = translater.translate();
+        let result = evaluator
+            .eval_with_globals_async(
+                FunctionId(0),
+                &mut globals,
+                &self.permanent_definitions.functions,
+            )
+            .await;
 
-        let mut evaluator = Evaluator::default();
-        evaluator.add_function(output);
+        bindings.restore_values(globals.split_off(self.globals.len()));
 
-        evaluator
-            .eval_async(FunctionId(0), &self.permanent_definitions.functions)
-            .await
-            .map_err(ErrorBatch::one)
+        result.map_err(ErrorBatch::one)
+    }
+
+    /// The type a script's value needs to have to be taken out as a `T`
+    fn expected_type<T: FromScript>(&self) -> Result<TypeId, ErrorBatch> {
+        self.permanent_definitions.get_type::<T>().ok_or_else(|| {
+            ErrorBatch::one(ScriptError {
+                message: format!(
+                    "{} needs to be registered with the engine before a script's value can be \
+                     taken out as one",
+                    std::any::type_name::<T>()
+                ),
+                span: Span { start: 0, end: 0 },
+                kind: ErrorKind::Script,
+            })
+        })
+    }
+
+    fn take_value<T: FromScript>(
+        &self,
+        value: ReturnValue,
+        expected: TypeId,
+    ) -> Result<T, ErrorBatch> {
+        T::from_script(value).map_err(|_| {
+            // The types were checked before running, so this is a value the script's registers
+            // still hold and that couldn't be copied out of them
+            ErrorBatch::one(ScriptError {
+                message: format!(
+                    "the script's value of type {} is still in use, and can't be copied out",
+                    self.permanent_definitions.typenames[expected.0]
+                ),
+                span: Span { start: 0, end: 0 },
+                kind: ErrorKind::Script,
+            })
+        })
+    }
+
+    fn get_or_register_type<T>(&mut self) -> TypeId
+    where
+        T: Type,
+    {
+        if let Some(id) = self.permanent_definitions.get_type::<T>() {
+            id
+        } else {
+            self.register_type::<T>()
+        }
     }
 
     pub fn register_type<T>(&mut self) -> TypeId
@@ -250,6 +783,108 @@ impl Engine {
         self.permanent_definitions.get_type::<T>()
     }
 
+    /// Make the fields of `T` readable and assignable from scripts
+    pub fn register_fields<T>(&mut self)
+    where
+        T: Fields,
+    {
+        T::register_fields(self)
+    }
+
+    /// Let scripts compare values of `T` with `==` and `!=`, using its `PartialEq` implementation
+    pub fn register_eq<T>(&mut self)
+    where
+        T: PartialEq + Type,
+    {
+        let eq: Box<dyn Fn(&mut [Value]) -> Result<Value, String> + Send + Sync> =
+            Box::new(|args: &mut [Value]| {
+                if args.len() != 2 {
+                    return Err(format!("unexpected number of arguments: {}", args.len()));
+                }
+
+                Ok(Box::new(arg_ref::<T>(args, 0)? == arg_ref::<T>(args, 1)?) as Value)
+            });
+
+        let type_id = self.get_or_register_type::<T>();
+
+        // Like field getters, this isn't given a name, so scripts can only reach it through `==`
+        self.permanent_definitions.functions.push(ExternalFnRecord {
+            params: vec![type_id, type_id],
+            ret: BOOL_TYPE,
+            fun: Function::ExternalFn(eq),
+        });
+        let eq = ExternalFunctionId(self.permanent_definitions.functions.len() - 1);
+
+        self.permanent_definitions.eq_functions.insert(type_id, eq);
+    }
+
+    /// Make a single field of `T` readable and assignable from scripts, using the given getter
+    /// and setter
+    ///
+    /// This is what `#[derive(truffle::Fields)]` calls for each field.
+    pub fn register_field<T, F>(
+        &mut self,
+        name: &str,
+        get: impl Fn(&T) -> F + Send + Sync + 'static,
+        set: impl Fn(&mut T, F) + Send + Sync + 'static,
+    ) where
+        T: Type,
+        F: Clone + Type,
+    {
+        let getter: Box<dyn Fn(&mut [Value]) -> Result<Value, String> + Send + Sync> = Box::new(
+            move |args: &mut [Value]| match args[0].downcast_ref::<T>() {
+                Some(this) => Ok(Box::new(get(this)) as Value),
+                None => Err(conversion_error::<T>(0)),
+            },
+        );
+
+        let setter: Box<dyn Fn(&mut [Value]) -> Result<Value, String> + Send + Sync> =
+            Box::new(move |args: &mut [Value]| {
+                if args.len() != 2 {
+                    return Err(format!("unexpected number of arguments: {}", args.len()));
+                }
+                let value = arg_ref::<F>(args, 1)?.clone();
+                let Some(this) = args[0].downcast_mut::<T>() else {
+                    return Err(conversion_error::<T>(0));
+                };
+
+                set(this, value);
+                Ok(Box::new(()) as Value)
+            });
+
+        let this_type = self.get_or_register_type::<T>();
+        let field_type = self.get_or_register_type::<F>();
+
+        // The getter and setter aren't given names, so scripts can only reach them through the
+        // field itself
+        self.permanent_definitions.functions.push(ExternalFnRecord {
+            params: vec![this_type],
+            ret: field_type,
+            fun: Function::ExternalFn(getter),
+        });
+        let getter = ExternalFunctionId(self.permanent_definitions.functions.len() - 1);
+
+        self.permanent_definitions.functions.push(ExternalFnRecord {
+            params: vec![this_type, field_type],
+            ret: UNIT_TYPE,
+            fun: Function::ExternalFn(setter),
+        });
+        let setter = ExternalFunctionId(self.permanent_definitions.functions.len() - 1);
+
+        let fields = self
+            .permanent_definitions
+            .fields
+            .entry(this_type)
+            .or_default();
+        fields.retain(|field| field.name != name);
+        fields.push(ExternalField {
+            name: name.into(),
+            ty: field_type,
+            getter,
+            setter,
+        });
+    }
+
     #[cfg(feature = "async")]
     pub fn add_async_call(
         &mut self,
@@ -257,8 +892,20 @@ impl Engine {
         ret: TypeId,
         fun: Function,
         name: &str,
-        #[cfg_attr(not(feature = "lsp"), allow(unused_variables))]
         location: &'static std::panic::Location<'static>,
+    ) {
+        self.add_call(name, params, ret, fun, Some(location))
+    }
+
+    fn add_call(
+        &mut self,
+        name: &str,
+        params: Vec<TypeId>,
+        ret: TypeId,
+        fun: Function,
+        #[cfg_attr(not(feature = "lsp"), allow(unused_variables))] location: Option<
+            &'static std::panic::Location<'static>,
+        >,
     ) {
         self.permanent_definitions
             .functions
@@ -267,14 +914,16 @@ impl Engine {
         let id = self.permanent_definitions.functions.len() - 1;
 
         #[cfg(feature = "lsp")]
-        self.permanent_definitions.function_infos.insert(
-            name.as_bytes().to_vec(),
-            ExternalFunctionLocation {
-                path: location.file().into(),
-                line: location.line(),
-                column: location.column(),
-            },
-        );
+        if let Some(location) = location {
+            self.permanent_definitions.function_infos.insert(
+                name.as_bytes().to_vec(),
+                ExternalFunctionLocation {
+                    path: location.file().into(),
+                    line: location.line(),
+                    column: location.column(),
+                },
+            );
+        }
 
         let ent = self
             .permanent_definitions
@@ -319,7 +968,7 @@ impl Engine {
         let mut parser = Parser::new(tokens, contents.to_vec(), 0);
         let _ = parser.parse();
 
-        let mut typechecker = TypeChecker::new(parser.results, &self.permanent_definitions);
+        let mut typechecker = self.new_typechecker(parser.results);
         let _ = typechecker.typecheck();
 
         let node_id = self.get_node_id_at_location(location, &typechecker.parse_results);
@@ -366,7 +1015,7 @@ impl Engine {
         let mut parser = Parser::new(tokens, contents.to_vec(), 0);
         let _ = parser.parse();
 
-        let mut typechecker = TypeChecker::new(parser.results, &self.permanent_definitions);
+        let mut typechecker = self.new_typechecker(parser.results);
         let _ = typechecker.typecheck();
 
         let node_id = self.get_node_id_at_location(location, &typechecker.parse_results)?;
@@ -411,12 +1060,9 @@ impl Engine {
             }
         }
 
-        let mut typechecker = TypeChecker::new(parser.results, &self.permanent_definitions);
+        let mut typechecker = self.new_typechecker(parser.results);
 
-        match typechecker.typecheck() {
-            Ok(_) => None,
-            Err(errors) => Some(errors),
-        }
+        typechecker.typecheck().err()
     }
 
     #[cfg(feature = "lsp")]
@@ -434,7 +1080,7 @@ impl Engine {
 
         eprintln!("parse results: {:?}", parser.results);
 
-        let mut typechecker = TypeChecker::new(parser.results, &self.permanent_definitions);
+        let mut typechecker = self.new_typechecker(parser.results);
         let _ = typechecker.typecheck();
 
         let node_id = self.get_node_id_at_location(location, &typechecker.parse_results);
@@ -508,7 +1154,7 @@ impl Engine {
         let mut parser = Parser::new(tokens, contents.to_vec(), 0);
         let _ = parser.parse();
 
-        let mut typechecker = TypeChecker::new(parser.results, &self.permanent_definitions);
+        let mut typechecker = self.new_typechecker(parser.results);
         let _ = typechecker.typecheck();
 
         if prefix.ends_with(b".") {
@@ -517,6 +1163,10 @@ impl Engine {
             if let Some(node_id) = node_id {
                 let type_id = typechecker.node_types[node_id.0];
                 if TypeChecker::is_custom_type(type_id) {
+                    if let Some(fields) = self.permanent_definitions.fields.get(&type_id) {
+                        output.extend(fields.iter().map(|field| field.name.clone()));
+                    }
+
                     for (id, external_fn) in self.permanent_definitions.functions.iter().enumerate()
                     {
                         let current_id = ExternalFunctionId(id);
@@ -552,8 +1202,12 @@ impl Engine {
                 }
             }
 
-            for name in self.permanent_definitions.external_functions.keys() {
-                if name.starts_with(prefix) {
+            for (name, fns) in &self.permanent_definitions.external_functions {
+                let is_array_method = fns
+                    .iter()
+                    .all(|id| self.permanent_definitions.array_methods.contains(id));
+
+                if name.starts_with(prefix) && !is_array_method {
                     output.push(String::from_utf8_lossy(name).to_string())
                 }
             }
@@ -583,6 +1237,361 @@ impl Engine {
     {
         self.app_name = app_name.into().map(String::from);
     }
+
+    /// Limit how much work each run of a script may do, so scripts that never finish get stopped
+    pub fn set_limits(&mut self, limits: EvalLimits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> &EvalLimits {
+        &self.limits
+    }
+
+    /// Choose what integer arithmetic does on overflow. Scripts stop with an error by default.
+    pub fn set_arithmetic_mode(&mut self, arithmetic: ArithmeticMode) {
+        self.arithmetic = arithmetic;
+    }
+
+    pub fn arithmetic_mode(&self) -> ArithmeticMode {
+        self.arithmetic
+    }
+
+    /// Let arithmetic and comparisons mix i64 and f64 values by converting the i64 side to f64,
+    /// rather than requiring an explicit `as f64`
+    pub fn set_numeric_promotion(&mut self, numeric_promotion: bool) {
+        self.numeric_promotion = numeric_promotion;
+    }
+
+    pub fn numeric_promotion(&self) -> bool {
+        self.numeric_promotion
+    }
+
+    fn new_typechecker(&self, parse_results: ParseResults) -> TypeChecker<'_> {
+        let mut typechecker = TypeChecker::new(parse_results, &self.permanent_definitions);
+        typechecker.numeric_promotion = self.numeric_promotion;
+        typechecker.globals = self.globals.clone();
+
+        typechecker
+    }
+
+    fn new_evaluator(&self) -> Evaluator {
+        let mut evaluator = Evaluator::default();
+        evaluator.limits = self.limits.clone();
+        evaluator.arithmetic = self.arithmetic;
+
+        evaluator
+    }
+}
+
+// Methods are looked up by the type stored in the register, so this has to take the `Vec` itself
+#[allow(clippy::ptr_arg)]
+fn array_len<T>(array: &mut Vec<T>) -> i64 {
+    array.len() as i64
+}
+
+fn array_push<T>(array: &mut Vec<T>, value: T) {
+    array.push(value)
+}
+
+/// A script that has already been lexed, parsed, typechecked and translated
+///
+/// Created with `Engine::compile`. Each run gets a fresh `Evaluator`, so the same script can be
+/// run repeatedly without paying for compilation again.
+pub struct CompiledScript {
+    functions: Vec<FunctionCodegen>,
+
+    // The engine's globals when the script was compiled, which its code expects to find
+    globals: Vec<Global>,
+
+    // How values of each of the script's types are held, to check values coming from Rust against
+    types: Vec<RuntimeType>,
+
+    // The engine the script was compiled with, whose registered functions its code calls by index
+    engine: EngineId,
+
+    // Closures created by runs of the script can only be called on the script itself
+    id: ScriptId,
+}
+
+impl CompiledScript {
+    pub fn run(&self, engine: &Engine) -> Result<ReturnValue, ErrorBatch> {
+        self.check_engine(engine)?;
+
+        let mut evaluator = self.new_evaluator(engine);
+
+        evaluator
+            .eval_with_globals(
+                FunctionId(0),
+                &mut engine.global_values(),
+                &engine.permanent_definitions.functions,
+            )
+            .map_err(ErrorBatch::one)
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn run_async(&self, engine: &Engine) -> Result<ReturnValue, ErrorBatch> {
+        self.check_engine(engine)?;
+
+        let mut evaluator = self.new_evaluator(engine);
+
+        evaluator
+            .eval_with_globals_async(
+                FunctionId(0),
+                &mut engine.global_values(),
+                &engine.permanent_definitions.functions,
+            )
+            .await
+            .map_err(ErrorBatch::one)
+    }
+
+    fn check_engine(&self, engine: &Engine) -> Result<(), ErrorBatch> {
+        let message = if self.engine != engine.id {
+            "the script was compiled with a different engine"
+        } else if self.globals != engine.globals {
+            "the engine's globals changed since the script was compiled"
+        } else {
+            return Ok(());
+        };
+
+        Err(ErrorBatch::one(ScriptError {
+            message: message.into(),
+            span: Span { start: 0, end: 0 },
+            kind: ErrorKind::Script,
+        }))
+    }
+
+    /// Call a closure that a run of this script created, such as one it passed to a registered
+    /// function
+    pub fn call(
+        &self,
+        engine: &Engine,
+        callback: &Callback,
+        args: Vec<Value>,
+    ) -> Result<ReturnValue, ErrorBatch> {
+        self.check_engine(engine)?;
+
+        let mut evaluator = self.new_callback_evaluator(engine);
+
+        evaluator
+            .call(
+                callback,
+                args,
+                &self.types,
+                &engine.permanent_definitions.functions,
+            )
+            .map_err(ErrorBatch::one)
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn call_async(
+        &self,
+        engine: &Engine,
+        callback: &Callback,
+        args: Vec<Value>,
+    ) -> Result<ReturnValue, ErrorBatch> {
+        self.check_engine(engine)?;
+
+        let mut evaluator = self.new_callback_evaluator(engine);
+
+        evaluator
+            .call_async(
+                callback,
+                args,
+                &self.types,
+                &engine.permanent_definitions.functions,
+            )
+            .await
+            .map_err(ErrorBatch::one)
+    }
+
+    /// A fresh evaluator holding the script's code, for a single run or call
+    fn new_evaluator(&self, engine: &Engine) -> Evaluator {
+        let mut evaluator = engine.new_evaluator();
+        evaluator.script = self.id;
+        for function in &self.functions {
+            evaluator.add_function(function.clone());
+        }
+
+        evaluator
+    }
+
+    /// A fresh evaluator for calling one of the script's closures, with the engine's globals
+    /// loaded for any functions the closure calls
+    fn new_callback_evaluator(&self, engine: &Engine) -> Evaluator {
+        let mut evaluator = self.new_evaluator(engine);
+        let types = engine.globals.iter().map(|global| global.type_id);
+        evaluator.add_globals(types.zip(engine.global_values()).collect());
+
+        evaluator
+    }
+
+    pub fn debug_output(&self) {
+        for function in &self.functions {
+            function.debug_output();
+        }
+    }
+}
+
+/// A script run one snippet at a time, as in a REPL
+///
+/// Variables, functions and structs that a snippet defines stay around for the snippets after it.
+/// A snippet that fails to compile leaves nothing behind, and one that fails at runtime keeps its
+/// functions and structs but forgets its variables.
+pub struct Session<'engine> {
+    engine: &'engine Engine,
+    translater: Translater<'engine>,
+    evaluator: Evaluator,
+}
+
+impl<'engine> Session<'engine> {
+    pub fn new(engine: &'engine Engine) -> Self {
+        let typechecker = engine.new_typechecker(ParseResults::new(0, vec![]));
+
+        let mut evaluator = engine.new_evaluator();
+        let types = engine.globals.iter().map(|global| global.type_id);
+        evaluator.add_globals(types.zip(engine.global_values()).collect());
+
+        Self {
+            engine,
+            translater: Translater::new(typechecker),
+            evaluator,
+        }
+    }
+
+    pub fn eval(&mut self, contents: &[u8], debug_output: bool) -> Result<ReturnValue, ErrorBatch> {
+        let (entry, variables) = self.compile_snippet(contents, debug_output)?;
+
+        let result = self
+            .evaluator
+            .eval_snippet(entry, &self.engine.permanent_definitions.functions);
+        if result.is_err() {
+            self.translater.typechecker.restore_variables(variables);
+        }
+
+        result.map_err(ErrorBatch::one)
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn eval_async(
+        &mut self,
+        contents: &[u8],
+        debug_output: bool,
+    ) -> Result<ReturnValue, ErrorBatch> {
+        let (entry, variables) = self.compile_snippet(contents, debug_output)?;
+
+        let result = self
+            .evaluator
+            .eval_snippet_async(entry, &self.engine.permanent_definitions.functions)
+            .await;
+        if result.is_err() {
+            self.translater.typechecker.restore_variables(variables);
+        }
+
+        result.map_err(ErrorBatch::one)
+    }
+
+    /// Call a closure that one of the session's snippets created
+    pub fn call(
+        &mut self,
+        callback: &Callback,
+        args: Vec<Value>,
+    ) -> Result<ReturnValue, ErrorBatch> {
+        let types = self.translater.typechecker.runtime_types();
+
+        self.evaluator
+            .call(
+                callback,
+                args,
+                &types,
+                &self.engine.permanent_definitions.functions,
+            )
+            .map_err(ErrorBatch::one)
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn call_async(
+        &mut self,
+        callback: &Callback,
+        args: Vec<Value>,
+    ) -> Result<ReturnValue, ErrorBatch> {
+        let types = self.translater.typechecker.runtime_types();
+
+        self.evaluator
+            .call_async(
+                callback,
+                args,
+                &types,
+                &self.engine.permanent_definitions.functions,
+            )
+            .await
+            .map_err(ErrorBatch::one)
+    }
+
+    /// All the snippets given to the session so far, which error spans point into
+    pub fn source(&self) -> &[u8] {
+        &self.translater.typechecker.parse_results.contents
+    }
+
+    /// Compile a snippet into the evaluator, returning where its code starts along with the
+    /// variables that were defined before it
+    fn compile_snippet(
+        &mut self,
+        contents: &[u8],
+        debug_output: bool,
+    ) -> Result<(InstructionId, HashMap<Vec<u8>, NodeId>), ErrorBatch> {
+        let typechecker = &mut self.translater.typechecker;
+
+        // Spans point into the whole session's source, so keep every snippet, even failed ones
+        let span_offset = typechecker.parse_results.contents.len();
+        typechecker
+            .parse_results
+            .contents
+            .extend_from_slice(contents);
+        typechecker.parse_results.contents.push(b'\n');
+        let source = typechecker.parse_results.contents.clone();
+
+        let mut lexer = Lexer::new(source.clone(), span_offset);
+        let tokens = lexer.lex()?;
+
+        let mut parser = Parser::new(tokens, source, typechecker.parse_results.ast_nodes.len());
+        parser.parse()?;
+        if debug_output {
+            parser.results.print();
+        }
+
+        let variables = typechecker
+            .scope
+            .first()
+            .map(|scope| scope.variables.clone())
+            .unwrap_or_default();
+        let first_function = typechecker.local_functions.len();
+
+        let block = typechecker.typecheck_snippet(parser.results)?;
+        if debug_output {
+            typechecker.print_node_types();
+        }
+
+        let output = self.translater.translate_snippet(
+            block,
+            self.evaluator.main_register_types(),
+            first_function,
+        );
+        if debug_output {
+            for function in &output {
+                function.debug_output();
+            }
+        }
+
+        let mut output = output.into_iter();
+        let entry = self
+            .evaluator
+            .add_snippet(output.next().expect("snippet should translate to code"));
+        for function in output {
+            self.evaluator.add_function(function);
+        }
+
+        Ok((entry, variables))
+    }
 }
 
 #[cfg_attr(feature = "lsp", derive(serde::Serialize, serde::Deserialize))]
@@ -593,6 +1602,48 @@ pub struct ExternalFnRecord {
     pub fun: Function,
 }
 
+/// A field of a registered type, read and assigned through registered functions
+#[cfg_attr(feature = "lsp", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExternalField {
+    pub name: String,
+    pub ty: TypeId,
+    pub getter: ExternalFunctionId,
+    pub setter: ExternalFunctionId,
+}
+
+/// Which of the builtin generic types an `EnumType` is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "lsp", derive(serde::Serialize, serde::Deserialize))]
+pub enum EnumKind {
+    Option,
+    Result,
+}
+
+/// An `Option<T>` or `Result<T, E>` that scripts can use, along with the unnamed functions that
+/// build, test and unpack its values
+///
+/// `Some` and `Ok` are the value variants, while `None` and `Err` are the error variants. `None`
+/// holds nothing, so an `Option` has a unit error type and no `unwrap_error`.
+#[cfg_attr(feature = "lsp", derive(serde::Serialize, serde::Deserialize))]
+pub struct EnumType {
+    pub kind: EnumKind,
+    pub value: TypeId,
+    pub error: TypeId,
+    pub wrap_value: ExternalFunctionId,
+    pub wrap_error: ExternalFunctionId,
+    pub is_value: ExternalFunctionId,
+    pub unwrap_value: ExternalFunctionId,
+    pub unwrap_error: Option<ExternalFunctionId>,
+}
+
+/// Types that can expose their fields to scripts
+///
+/// Usually implemented with `#[derive(truffle::Fields)]`, then registered with
+/// `Engine::register_fields`.
+pub trait Fields: Type {
+    fn register_fields(engine: &mut Engine);
+}
+
 #[cfg(feature = "lsp")]
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct ExternalFunctionLocation {
@@ -612,761 +1663,235 @@ pub trait FnRegister<A, RetVal, Args> {
     );
 }
 
-impl<A, U> FnRegister<A, U, ()> for Engine
-where
-    A: 'static + Fn() -> U,
-    U: Type,
-{
-    fn register_fn(
+/// Registration of functions returning `Result<T, E>`. An `Ok` value is the result of the call,
+/// while an `Err` stops the script with a runtime error at the call.
+pub trait FallibleFnRegister<A, RetVal, Args> {
+    fn register_fallible_fn(
         &mut self,
         name: &str,
         fun: A,
         #[cfg_attr(not(feature = "lsp"), allow(unused_variables))] location: Option<
             &'static std::panic::Location<'static>,
         >,
-    ) {
-        let wrapped: Box<dyn Fn() -> Result<Value, String>> =
-            Box::new(move || Ok(Box::new(fun()) as Value));
+    );
+}
 
-        let ret = if let Some(id) = self.permanent_definitions.get_type::<U>() {
-            id
-        } else {
-            self.register_type::<U>()
-        };
+fn conversion_error<T>(position: usize) -> String {
+    const ORDINALS: [&str; 12] = [
+        "first", "second", "third", "fourth", "fifth", "sixth", "seventh", "eighth", "ninth",
+        "tenth", "eleventh", "twelfth",
+    ];
+
+    format!(
+        "can't convert {} argument to {}",
+        ORDINALS[position],
+        std::any::type_name::<T>()
+    )
+}
 
-        self.permanent_definitions.functions.push(ExternalFnRecord {
-            params: vec![],
-            ret,
-            fun: Function::ExternalFn0(wrapped),
-        });
+/// Stands in for an argument of a registered function that's the same script value as an
+/// earlier argument of the call, which holds the value itself
+#[doc(hidden)]
+pub struct SharedArg(pub usize);
 
-        #[cfg(feature = "lsp")]
-        if let Some(location) = location {
-            self.permanent_definitions.function_infos.insert(
-                name.as_bytes().to_vec(),
-                ExternalFunctionLocation {
-                    path: location.file().into(),
-                    line: location.line(),
-                    column: location.column(),
-                },
-            );
-        }
+/// The argument at `position` of a call to a registered function, following an argument that's
+/// the same value as an earlier one back to it
+#[doc(hidden)]
+pub fn arg_ref<T>(args: &[Value], position: usize) -> Result<&T, String>
+where
+    T: Type,
+{
+    let arg = match args[position].downcast_ref::<SharedArg>() {
+        Some(SharedArg(earlier)) => &args[*earlier],
+        None => &args[position],
+    };
 
-        let id = self.permanent_definitions.functions.len() - 1;
+    arg.downcast_ref::<T>()
+        .ok_or_else(|| conversion_error::<T>(position))
+}
 
-        let ent = self
-            .permanent_definitions
-            .external_functions
-            .entry(name.as_bytes().to_vec())
-            .or_default();
-        (*ent).push(ExternalFunctionId(id));
-    }
+/// The first argument of a builtin function
+fn first_arg<T>(args: &[Value]) -> Result<&T, String>
+where
+    T: Type,
+{
+    args.first()
+        .and_then(|arg| arg.downcast_ref::<T>())
+        .ok_or_else(|| conversion_error::<T>(0))
 }
 
-impl<'a, A, T, U> FnRegister<A, U, (&'a T,)> for Engine
+fn plain_return<T>(value: T) -> Result<Value, String>
 where
-    A: 'static + Fn(T) -> U,
-    T: Clone + Type,
-    U: Type,
+    T: Type,
 {
-    fn register_fn(
-        &mut self,
-        name: &str,
-        fun: A,
-        #[cfg_attr(not(feature = "lsp"), allow(unused_variables))] location: Option<
-            &'static std::panic::Location<'static>,
-        >,
-    ) {
-        let wrapped: Box<dyn Fn(&mut Value) -> Result<Value, String>> =
-            Box::new(move |arg: &mut Value| {
-                let inside = (*arg).downcast_mut() as Option<&mut T>;
-                match inside {
-                    Some(b) => Ok(Box::new(fun(b.clone())) as Value),
-                    None => Err(format!(
-                        "can't convert first argument to {}",
-                        std::any::type_name::<T>()
-                    )),
-                }
-            });
+    Ok(Box::new(value))
+}
 
-        let param1 = if let Some(id) = self.permanent_definitions.get_type::<T>() {
-            id
-        } else {
-            self.register_type::<T>()
-        };
+fn fallible_return<T, E>(value: Result<T, E>) -> Result<Value, String>
+where
+    T: Type,
+    E: std::fmt::Display,
+{
+    match value {
+        Ok(value) => Ok(Box::new(value)),
+        Err(error) => Err(error.to_string()),
+    }
+}
 
-        let ret = if let Some(id) = self.permanent_definitions.get_type::<U>() {
-            id
-        } else {
-            self.register_type::<U>()
-        };
+/// Implement a registration trait for functions taking the given parameters. Parameters are
+/// cloned out of their registers, except that the first one can also be taken by mutable
+/// reference. `$convert` turns the function's output into the result of the call.
+macro_rules! impl_fn_register {
+    (
+        $trait:ident, $method:ident, $convert:ident, [$($extra:ident),*], $output:ty, [$($bounds:tt)*];
+    ) => {
+        impl<A, Ret, $($extra),*> $trait<A, Ret, ()> for Engine
+        where
+            A: 'static + Send + Sync + Fn() -> $output,
+            $($bounds)*
+        {
+            fn $method(
+                &mut self,
+                name: &str,
+                fun: A,
+                location: Option<&'static std::panic::Location<'static>>,
+            ) {
+                let wrapped: Box<dyn Fn(&mut [Value]) -> Result<Value, String> + Send + Sync> =
+                    Box::new(move |_: &mut [Value]| $convert(fun()));
+
+                let ret = self.get_or_register_type::<Ret>();
+
+                self.add_call(name, vec![], ret, Function::ExternalFn(wrapped), location);
+            }
+        }
+    };
+    (
+        $trait:ident, $method:ident, $convert:ident, [$($extra:ident),*], $output:ty, [$($bounds:tt)*];
+        $first:ident $first_arg:ident $first_position:literal $(, $param:ident $arg:ident $position:literal)*
+    ) => {
+        impl<A, Ret, $($extra,)* $first, $($param),*> $trait<A, Ret, (&$first, $($param,)*)> for Engine
+        where
+            A: 'static + Send + Sync + Fn($first, $($param),*) -> $output,
+            $first: Clone + Type,
+            $($param: Clone + Type,)*
+            $($bounds)*
+        {
+            fn $method(
+                &mut self,
+                name: &str,
+                fun: A,
+                location: Option<&'static std::panic::Location<'static>>,
+            ) {
+                let wrapped: Box<dyn Fn(&mut [Value]) -> Result<Value, String> + Send + Sync> =
+                    Box::new(move |args: &mut [Value]| {
+                        if args.len() != [$first_position $(, $position)*].len() {
+                            return Err(format!("unexpected number of arguments: {}", args.len()));
+                        }
 
-        self.permanent_definitions.functions.push(ExternalFnRecord {
-            params: vec![param1],
-            ret,
-            fun: Function::ExternalFn1(wrapped),
-        });
+                        let $first_arg = arg_ref::<$first>(args, $first_position)?.clone();
+                        $(
+                        let $arg = arg_ref::<$param>(args, $position)?.clone();
+                        )*
 
-        #[cfg(feature = "lsp")]
-        if let Some(location) = location {
-            self.permanent_definitions.function_infos.insert(
-                name.as_bytes().to_vec(),
-                ExternalFunctionLocation {
-                    path: location.file().into(),
-                    line: location.line(),
-                    column: location.column(),
-                },
-            );
-        }
-
-        let id = self.permanent_definitions.functions.len() - 1;
-
-        let ent = self
-            .permanent_definitions
-            .external_functions
-            .entry(name.as_bytes().to_vec())
-            .or_default();
-        (*ent).push(ExternalFunctionId(id));
-    }
-}
-
-impl<A, T, U> FnRegister<A, U, (&mut T,)> for Engine
-where
-    A: 'static + Fn(&mut T) -> U,
-    T: Type,
-    U: Type,
-{
-    fn register_fn(
-        &mut self,
-        name: &str,
-        fun: A,
-        #[cfg_attr(not(feature = "lsp"), allow(unused_variables))] location: Option<
-            &'static std::panic::Location<'static>,
-        >,
-    ) {
-        let wrapped: Box<dyn Fn(&mut Value) -> Result<Value, String>> =
-            Box::new(move |arg: &mut Value| {
-                let inside = (*arg).downcast_mut() as Option<&mut T>;
-                match inside {
-                    Some(b) => Ok(Box::new(fun(b)) as Value),
-                    None => Err(format!(
-                        "can't convert first argument to {}",
-                        std::any::type_name::<T>()
-                    )),
-                }
-            });
-
-        let param1 = if let Some(id) = self.permanent_definitions.get_type::<T>() {
-            id
-        } else {
-            self.register_type::<T>()
-        };
-
-        let ret = if let Some(id) = self.permanent_definitions.get_type::<U>() {
-            id
-        } else {
-            self.register_type::<U>()
-        };
-
-        self.permanent_definitions.functions.push(ExternalFnRecord {
-            params: vec![param1],
-            ret,
-            fun: Function::ExternalFn1(wrapped),
-        });
-
-        #[cfg(feature = "lsp")]
-        if let Some(location) = location {
-            self.permanent_definitions.function_infos.insert(
-                name.as_bytes().to_vec(),
-                ExternalFunctionLocation {
-                    path: location.file().into(),
-                    line: location.line(),
-                    column: location.column(),
-                },
-            );
-        }
-
-        let id = self.permanent_definitions.functions.len() - 1;
-
-        let ent = self
-            .permanent_definitions
-            .external_functions
-            .entry(name.as_bytes().to_vec())
-            .or_default();
-        (*ent).push(ExternalFunctionId(id));
-    }
-}
-
-impl<A, T, U, V> FnRegister<A, V, (&mut T, U)> for Engine
-where
-    A: 'static + Fn(&mut T, U) -> V,
-    T: Type,
-    U: Clone + Type,
-    V: Type,
-{
-    fn register_fn(
-        &mut self,
-        name: &str,
-        fun: A,
-        #[cfg_attr(not(feature = "lsp"), allow(unused_variables))] location: Option<
-            &'static std::panic::Location<'static>,
-        >,
-    ) {
-        let wrapped: Box<dyn Fn(&mut Value, &mut Value) -> Result<Value, String>> =
-            Box::new(move |arg1: &mut Value, arg2: &mut Value| {
-                let inside1 = (*arg1).downcast_mut() as Option<&mut T>;
-                let inside2 = (*arg2).downcast_mut() as Option<&mut U>;
-
-                match (inside1, inside2) {
-                    (Some(b), Some(c)) => Ok(Box::new(fun(b, c.clone())) as Value),
-                    (Some(_), None) => Err(format!(
-                        "can't convert second argument to {}",
-                        std::any::type_name::<U>()
-                    )),
-                    (None, _) => Err(format!(
-                        "can't convert first argument to {}",
-                        std::any::type_name::<T>()
-                    )),
-                }
-            });
+                        $convert(fun($first_arg, $($arg),*))
+                    });
 
-        let param1 = if let Some(id) = self.permanent_definitions.get_type::<T>() {
-            id
-        } else {
-            self.register_type::<T>()
-        };
+                let params = vec![
+                    self.get_or_register_type::<$first>(),
+                    $(self.get_or_register_type::<$param>()),*
+                ];
+                let ret = self.get_or_register_type::<Ret>();
 
-        let param2 = if let Some(id) = self.permanent_definitions.get_type::<U>() {
-            id
-        } else {
-            self.register_type::<U>()
-        };
-
-        let ret = if let Some(id) = self.permanent_definitions.get_type::<V>() {
-            id
-        } else {
-            self.register_type::<V>()
-        };
-
-        let fn_record = ExternalFnRecord {
-            params: vec![param1, param2],
-            ret,
-            fun: Function::ExternalFn2(wrapped),
-        };
-        self.permanent_definitions.functions.push(fn_record);
-        #[cfg(feature = "lsp")]
-        if let Some(location) = location {
-            self.permanent_definitions.function_infos.insert(
-                name.as_bytes().to_vec(),
-                ExternalFunctionLocation {
-                    path: location.file().into(),
-                    line: location.line(),
-                    column: location.column(),
-                },
-            );
-        }
-
-        let id = self.permanent_definitions.functions.len() - 1;
-
-        let ent = self
-            .permanent_definitions
-            .external_functions
-            .entry(name.as_bytes().to_vec())
-            .or_default();
-        (*ent).push(ExternalFunctionId(id));
-    }
-}
-
-impl<'a, A, T, U, V> FnRegister<A, V, (&'a T, U)> for Engine
-where
-    A: 'static + Fn(T, U) -> V,
-    T: Clone + Type,
-    U: Clone + Type,
-    V: Type,
-{
-    fn register_fn(
-        &mut self,
-        name: &str,
-        fun: A,
-        #[cfg_attr(not(feature = "lsp"), allow(unused_variables))] location: Option<
-            &'static std::panic::Location<'static>,
-        >,
-    ) {
-        let wrapped: Box<dyn Fn(&mut Value, &mut Value) -> Result<Value, String>> =
-            Box::new(move |arg1: &mut Value, arg2: &mut Value| {
-                let inside1 = (*arg1).downcast_mut() as Option<&mut T>;
-                let inside2 = (*arg2).downcast_mut() as Option<&mut U>;
-
-                match (inside1, inside2) {
-                    (Some(b), Some(c)) => Ok(Box::new(fun(b.clone(), c.clone())) as Value),
-                    (Some(_), None) => Err(format!(
-                        "can't convert second argument to {}",
-                        std::any::type_name::<U>()
-                    )),
-                    (None, _) => Err(format!(
-                        "can't convert first argument to {}",
-                        std::any::type_name::<T>()
-                    )),
-                }
-            });
-
-        let param1 = if let Some(id) = self.permanent_definitions.get_type::<T>() {
-            id
-        } else {
-            self.register_type::<T>()
-        };
-
-        let param2 = if let Some(id) = self.permanent_definitions.get_type::<U>() {
-            id
-        } else {
-            self.register_type::<U>()
-        };
-
-        let ret = if let Some(id) = self.permanent_definitions.get_type::<V>() {
-            id
-        } else {
-            self.register_type::<V>()
-        };
-
-        let fn_record = ExternalFnRecord {
-            params: vec![param1, param2],
-            ret,
-            fun: Function::ExternalFn2(wrapped),
-        };
-        self.permanent_definitions.functions.push(fn_record);
-        #[cfg(feature = "lsp")]
-        if let Some(location) = location {
-            self.permanent_definitions.function_infos.insert(
-                name.as_bytes().to_vec(),
-                ExternalFunctionLocation {
-                    path: location.file().into(),
-                    line: location.line(),
-                    column: location.column(),
-                },
-            );
-        }
-
-        let id = self.permanent_definitions.functions.len() - 1;
-
-        let ent = self
-            .permanent_definitions
-            .external_functions
-            .entry(name.as_bytes().to_vec())
-            .or_default();
-        (*ent).push(ExternalFunctionId(id));
-    }
-}
-
-impl<'a, A, T, U, V, W> FnRegister<A, V, (&'a T, U, W)> for Engine
-where
-    A: 'static + Fn(T, U, W) -> V,
-    T: Clone + Type,
-    U: Clone + Type,
-    W: Clone + Type,
-    V: Type,
-{
-    fn register_fn(
-        &mut self,
-        name: &str,
-        fun: A,
-        #[cfg_attr(not(feature = "lsp"), allow(unused_variables))] location: Option<
-            &'static std::panic::Location<'static>,
-        >,
-    ) {
-        let wrapped: Box<dyn Fn(&mut Value, &mut Value, &mut Value) -> Result<Value, String>> =
-            Box::new(
-                move |arg1: &mut Value, arg2: &mut Value, arg3: &mut Value| {
-                    let inside1 = (*arg1).downcast_mut() as Option<&mut T>;
-                    let inside2 = (*arg2).downcast_mut() as Option<&mut U>;
-                    let inside3 = (*arg3).downcast_mut() as Option<&mut W>;
-
-                    match (inside1, inside2, inside3) {
-                        (Some(b), Some(c), Some(d)) => {
-                            Ok(Box::new(fun(b.clone(), c.clone(), d.clone())) as Value)
-                        }
-                        (Some(_), Some(_), None) => Err(format!(
-                            "can't convert third argument to {}",
-                            std::any::type_name::<W>()
-                        )),
-                        (Some(_), None, _) => Err(format!(
-                            "can't convert second argument to {}",
-                            std::any::type_name::<U>()
-                        )),
-                        (None, _, _) => Err(format!(
-                            "can't convert first argument to {}",
-                            std::any::type_name::<T>()
-                        )),
-                    }
-                },
-            );
-
-        let param1 = if let Some(id) = self.permanent_definitions.get_type::<T>() {
-            id
-        } else {
-            self.register_type::<T>()
-        };
-
-        let param2 = if let Some(id) = self.permanent_definitions.get_type::<U>() {
-            id
-        } else {
-            self.register_type::<U>()
-        };
-
-        let param3 = if let Some(id) = self.permanent_definitions.get_type::<W>() {
-            id
-        } else {
-            self.register_type::<W>()
-        };
-
-        let ret = if let Some(id) = self.permanent_definitions.get_type::<V>() {
-            id
-        } else {
-            self.register_type::<V>()
-        };
-
-        let fn_record = ExternalFnRecord {
-            params: vec![param1, param2, param3],
-            ret,
-            fun: Function::ExternalFn3(wrapped),
-        };
-        self.permanent_definitions.functions.push(fn_record);
-        #[cfg(feature = "lsp")]
-        if let Some(location) = location {
-            self.permanent_definitions.function_infos.insert(
-                name.as_bytes().to_vec(),
-                ExternalFunctionLocation {
-                    path: location.file().into(),
-                    line: location.line(),
-                    column: location.column(),
-                },
-            );
+                self.add_call(name, params, ret, Function::ExternalFn(wrapped), location);
+            }
         }
 
-        let id = self.permanent_definitions.functions.len() - 1;
-
-        let ent = self
-            .permanent_definitions
-            .external_functions
-            .entry(name.as_bytes().to_vec())
-            .or_default();
-        (*ent).push(ExternalFunctionId(id));
-    }
-}
-
-impl<A, T, U, V, W> FnRegister<A, V, (&mut T, U, W)> for Engine
-where
-    A: 'static + Fn(&mut T, U, W) -> V,
-    T: Type,
-    U: Clone + Type,
-    W: Clone + Type,
-    V: Type,
-{
-    fn register_fn(
-        &mut self,
-        name: &str,
-        fun: A,
-        #[cfg_attr(not(feature = "lsp"), allow(unused_variables))] location: Option<
-            &'static std::panic::Location<'static>,
-        >,
-    ) {
-        let wrapped: Box<dyn Fn(&mut Value, &mut Value, &mut Value) -> Result<Value, String>> =
-            Box::new(
-                move |arg1: &mut Value, arg2: &mut Value, arg3: &mut Value| {
-                    let inside1 = (*arg1).downcast_mut() as Option<&mut T>;
-                    let inside2 = (*arg2).downcast_mut() as Option<&mut U>;
-                    let inside3 = (*arg3).downcast_mut() as Option<&mut W>;
-
-                    match (inside1, inside2, inside3) {
-                        (Some(b), Some(c), Some(d)) => {
-                            Ok(Box::new(fun(b, c.clone(), d.clone())) as Value)
+        impl<A, Ret, $($extra,)* $first, $($param),*> $trait<A, Ret, (&mut $first, $($param,)*)> for Engine
+        where
+            A: 'static + Send + Sync + Fn(&mut $first, $($param),*) -> $output,
+            $first: Type,
+            $($param: Clone + Type,)*
+            $($bounds)*
+        {
+            fn $method(
+                &mut self,
+                name: &str,
+                fun: A,
+                location: Option<&'static std::panic::Location<'static>>,
+            ) {
+                let wrapped: Box<dyn Fn(&mut [Value]) -> Result<Value, String> + Send + Sync> =
+                    Box::new(move |args: &mut [Value]| {
+                        if args.len() != [$first_position $(, $position)*].len() {
+                            return Err(format!("unexpected number of arguments: {}", args.len()));
                         }
-                        (Some(_), Some(_), None) => Err(format!(
-                            "can't convert third argument to {}",
-                            std::any::type_name::<W>()
-                        )),
-                        (Some(_), None, _) => Err(format!(
-                            "can't convert second argument to {}",
-                            std::any::type_name::<U>()
-                        )),
-                        (None, _, _) => Err(format!(
-                            "can't convert first argument to {}",
-                            std::any::type_name::<T>()
-                        )),
-                    }
-                },
-            );
 
-        let param1 = if let Some(id) = self.permanent_definitions.get_type::<T>() {
-            id
-        } else {
-            self.register_type::<T>()
-        };
-
-        let param2 = if let Some(id) = self.permanent_definitions.get_type::<U>() {
-            id
-        } else {
-            self.register_type::<U>()
-        };
-
-        let param3 = if let Some(id) = self.permanent_definitions.get_type::<W>() {
-            id
-        } else {
-            self.register_type::<W>()
-        };
-
-        let ret = if let Some(id) = self.permanent_definitions.get_type::<V>() {
-            id
-        } else {
-            self.register_type::<V>()
-        };
-
-        let fn_record = ExternalFnRecord {
-            params: vec![param1, param2, param3],
-            ret,
-            fun: Function::ExternalFn3(wrapped),
-        };
-        self.permanent_definitions.functions.push(fn_record);
-        #[cfg(feature = "lsp")]
-        if let Some(location) = location {
-            self.permanent_definitions.function_infos.insert(
-                name.as_bytes().to_vec(),
-                ExternalFunctionLocation {
-                    path: location.file().into(),
-                    line: location.line(),
-                    column: location.column(),
-                },
-            );
+                        // Later arguments are cloned first, as they may be the same value as the
+                        // first one
+                        $(
+                        let $arg = arg_ref::<$param>(args, $position)?.clone();
+                        )*
+                        let Some($first_arg) = args[$first_position].downcast_mut::<$first>() else {
+                            return Err(conversion_error::<$first>($first_position));
+                        };
+
+                        $convert(fun($first_arg, $($arg),*))
+                    });
+
+                let params = vec![
+                    self.get_or_register_type::<$first>(),
+                    $(self.get_or_register_type::<$param>()),*
+                ];
+                let ret = self.get_or_register_type::<Ret>();
+
+                self.add_call(name, params, ret, Function::ExternalFn(wrapped), location);
+            }
         }
-
-        let id = self.permanent_definitions.functions.len() - 1;
-
-        let ent = self
-            .permanent_definitions
-            .external_functions
-            .entry(name.as_bytes().to_vec())
-            .or_default();
-        (*ent).push(ExternalFunctionId(id));
-    }
+    };
 }
 
-impl<'a, A, T, U, V, W, X> FnRegister<A, V, (&'a T, U, W, X)> for Engine
-where
-    A: 'static + Fn(T, U, W, X) -> V,
-    T: Clone + Type,
-    U: Clone + Type,
-    W: Clone + Type,
-    X: Clone + Type,
-    V: Type,
-{
-    fn register_fn(
-        &mut self,
-        name: &str,
-        fun: A,
-        #[cfg_attr(not(feature = "lsp"), allow(unused_variables))] location: Option<
-            &'static std::panic::Location<'static>,
-        >,
-    ) {
-        let wrapped: Box<
-            dyn Fn(&mut Value, &mut Value, &mut Value, &mut Value) -> Result<Value, String>,
-        > = Box::new(
-            move |arg1: &mut Value, arg2: &mut Value, arg3: &mut Value, arg4: &mut Value| {
-                let inside1 = (*arg1).downcast_mut() as Option<&mut T>;
-                let inside2 = (*arg2).downcast_mut() as Option<&mut U>;
-                let inside3 = (*arg3).downcast_mut() as Option<&mut W>;
-                let inside4 = (*arg4).downcast_mut() as Option<&mut X>;
-
-                match (inside1, inside2, inside3, inside4) {
-                    (Some(b), Some(c), Some(d), Some(e)) => {
-                        Ok(Box::new(fun(b.clone(), c.clone(), d.clone(), e.clone())) as Value)
-                    }
-                    (Some(_), Some(_), Some(_), None) => Err(format!(
-                        "can't convert fourth argument to {}",
-                        std::any::type_name::<X>()
-                    )),
-                    (Some(_), Some(_), None, _) => Err(format!(
-                        "can't convert third argument to {}",
-                        std::any::type_name::<W>()
-                    )),
-                    (Some(_), None, _, _) => Err(format!(
-                        "can't convert second argument to {}",
-                        std::any::type_name::<U>()
-                    )),
-                    (None, _, _, _) => Err(format!(
-                        "can't convert first argument to {}",
-                        std::any::type_name::<T>()
-                    )),
-                }
-            },
+/// Implement both `FnRegister` and `FallibleFnRegister` for functions taking the given parameters
+macro_rules! impl_fn_registers {
+    ($($params:tt)*) => {
+        impl_fn_register!(
+            FnRegister, register_fn, plain_return, [], Ret, [Ret: Type,];
+            $($params)*
         );
-
-        let param1 = if let Some(id) = self.permanent_definitions.get_type::<T>() {
-            id
-        } else {
-            self.register_type::<T>()
-        };
-
-        let param2 = if let Some(id) = self.permanent_definitions.get_type::<U>() {
-            id
-        } else {
-            self.register_type::<U>()
-        };
-
-        let param3 = if let Some(id) = self.permanent_definitions.get_type::<W>() {
-            id
-        } else {
-            self.register_type::<W>()
-        };
-
-        let param4 = if let Some(id) = self.permanent_definitions.get_type::<X>() {
-            id
-        } else {
-            self.register_type::<X>()
-        };
-
-        let ret = if let Some(id) = self.permanent_definitions.get_type::<V>() {
-            id
-        } else {
-            self.register_type::<V>()
-        };
-
-        let fn_record = ExternalFnRecord {
-            params: vec![param1, param2, param3, param4],
-            ret,
-            fun: Function::ExternalFn4(wrapped),
-        };
-        self.permanent_definitions.functions.push(fn_record);
-        #[cfg(feature = "lsp")]
-        if let Some(location) = location {
-            self.permanent_definitions.function_infos.insert(
-                name.as_bytes().to_vec(),
-                ExternalFunctionLocation {
-                    path: location.file().into(),
-                    line: location.line(),
-                    column: location.column(),
-                },
-            );
-        }
-
-        let id = self.permanent_definitions.functions.len() - 1;
-
-        let ent = self
-            .permanent_definitions
-            .external_functions
-            .entry(name.as_bytes().to_vec())
-            .or_default();
-        (*ent).push(ExternalFunctionId(id));
-    }
-}
-
-impl<A, T, U, V, W, X> FnRegister<A, V, (&mut T, U, W, X)> for Engine
-where
-    A: 'static + Fn(&mut T, U, W, X) -> V,
-    T: Type,
-    U: Clone + Type,
-    W: Clone + Type,
-    X: Clone + Type,
-    V: Type,
-{
-    fn register_fn(
-        &mut self,
-        name: &str,
-        fun: A,
-        #[cfg_attr(not(feature = "lsp"), allow(unused_variables))] location: Option<
-            &'static std::panic::Location<'static>,
-        >,
-    ) {
-        let wrapped: Box<
-            dyn Fn(&mut Value, &mut Value, &mut Value, &mut Value) -> Result<Value, String>,
-        > = Box::new(
-            move |arg1: &mut Value, arg2: &mut Value, arg3: &mut Value, arg4: &mut Value| {
-                let inside1 = (*arg1).downcast_mut() as Option<&mut T>;
-                let inside2 = (*arg2).downcast_mut() as Option<&mut U>;
-                let inside3 = (*arg3).downcast_mut() as Option<&mut W>;
-                let inside4 = (*arg4).downcast_mut() as Option<&mut X>;
-
-                match (inside1, inside2, inside3, inside4) {
-                    (Some(b), Some(c), Some(d), Some(e)) => {
-                        Ok(Box::new(fun(b, c.clone(), d.clone(), e.clone())) as Value)
-                    }
-                    (Some(_), Some(_), Some(_), None) => Err(format!(
-                        "can't convert fourth argument to {}",
-                        std::any::type_name::<X>()
-                    )),
-                    (Some(_), Some(_), None, _) => Err(format!(
-                        "can't convert third argument to {}",
-                        std::any::type_name::<W>()
-                    )),
-                    (Some(_), None, _, _) => Err(format!(
-                        "can't convert second argument to {}",
-                        std::any::type_name::<U>()
-                    )),
-                    (None, _, _, _) => Err(format!(
-                        "can't convert first argument to {}",
-                        std::any::type_name::<T>()
-                    )),
-                }
-            },
+        impl_fn_register!(
+            FallibleFnRegister, register_fallible_fn, fallible_return, [Err], Result<Ret, Err>,
+            [Ret: Type, Err: std::fmt::Display,];
+            $($params)*
         );
-
-        let param1 = if let Some(id) = self.permanent_definitions.get_type::<T>() {
-            id
-        } else {
-            self.register_type::<T>()
-        };
-
-        let param2 = if let Some(id) = self.permanent_definitions.get_type::<U>() {
-            id
-        } else {
-            self.register_type::<U>()
-        };
-
-        let param3 = if let Some(id) = self.permanent_definitions.get_type::<W>() {
-            id
-        } else {
-            self.register_type::<W>()
-        };
-
-        let param4 = if let Some(id) = self.permanent_definitions.get_type::<X>() {
-            id
-        } else {
-            self.register_type::<X>()
-        };
-
-        let ret = if let Some(id) = self.permanent_definitions.get_type::<V>() {
-            id
-        } else {
-            self.register_type::<V>()
-        };
-
-        let fn_record = ExternalFnRecord {
-            params: vec![param1, param2, param3, param4],
-            ret,
-            fun: Function::ExternalFn4(wrapped),
-        };
-        self.permanent_definitions.functions.push(fn_record);
-        #[cfg(feature = "lsp")]
-        if let Some(location) = location {
-            self.permanent_definitions.function_infos.insert(
-                name.as_bytes().to_vec(),
-                ExternalFunctionLocation {
-                    path: location.file().into(),
-                    line: location.line(),
-                    column: location.column(),
-                },
-            );
-        }
-
-        let id = self.permanent_definitions.functions.len() - 1;
-
-        let ent = self
-            .permanent_definitions
-            .external_functions
-            .entry(name.as_bytes().to_vec())
-            .or_default();
-        (*ent).push(ExternalFunctionId(id));
-    }
+    };
 }
 
+impl_fn_registers!();
+impl_fn_registers!(T1 arg1 0);
+impl_fn_registers!(T1 arg1 0, T2 arg2 1);
+impl_fn_registers!(T1 arg1 0, T2 arg2 1, T3 arg3 2);
+impl_fn_registers!(T1 arg1 0, T2 arg2 1, T3 arg3 2, T4 arg4 3);
+impl_fn_registers!(T1 arg1 0, T2 arg2 1, T3 arg3 2, T4 arg4 3, T5 arg5 4);
+impl_fn_registers!(T1 arg1 0, T2 arg2 1, T3 arg3 2, T4 arg4 3, T5 arg5 4, T6 arg6 5);
+impl_fn_registers!(T1 arg1 0, T2 arg2 1, T3 arg3 2, T4 arg4 3, T5 arg5 4, T6 arg6 5, T7 arg7 6);
+impl_fn_registers!(
+    T1 arg1 0, T2 arg2 1, T3 arg3 2, T4 arg4 3, T5 arg5 4, T6 arg6 5, T7 arg7 6, T8 arg8 7
+);
+impl_fn_registers!(
+    T1 arg1 0, T2 arg2 1, T3 arg3 2, T4 arg4 3, T5 arg5 4, T6 arg6 5, T7 arg7 6, T8 arg8 7,
+    T9 arg9 8
+);
+impl_fn_registers!(
+    T1 arg1 0, T2 arg2 1, T3 arg3 2, T4 arg4 3, T5 arg5 4, T6 arg6 5, T7 arg7 6, T8 arg8 7,
+    T9 arg9 8, T10 arg10 9
+);
+impl_fn_registers!(
+    T1 arg1 0, T2 arg2 1, T3 arg3 2, T4 arg4 3, T5 arg5 4, T6 arg6 5, T7 arg7 6, T8 arg8 7,
+    T9 arg9 8, T10 arg10 9, T11 arg11 10
+);
+impl_fn_registers!(
+    T1 arg1 0, T2 arg2 1, T3 arg3 2, T4 arg4 3, T5 arg5 4, T6 arg6 5, T7 arg7 6, T8 arg8 7,
+    T9 arg9 8, T10 arg10 9, T11 arg11 10, T12 arg12 11
+);
+
 #[cfg(not(any(feature = "async", feature = "lsp")))]
 #[macro_export]
 macro_rules! register_fn {
@@ -9,6 +9,23 @@ use crate::parser::Span;
 pub struct ScriptError {
     pub message: String,
     pub span: Span,
+    pub kind: ErrorKind,
+}
+
+/// Why a script failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The script has a mistake in it, or hit an error while running
+    Script,
+
+    /// The script ran more instructions than `EvalLimits::max_instructions` allows
+    InstructionLimit,
+
+    /// The script ran for longer than `EvalLimits::max_duration` allows
+    TimeLimit,
+
+    /// The script was cancelled through its `CancellationToken`
+    Cancelled,
 }
 
 fn write_error(
@@ -17,7 +34,7 @@ fn write_error(
     contents: &[u8],
     f: &mut fmt::Formatter<'_>,
 ) -> fmt::Result {
-    let ScriptError { message, span } = script_error;
+    let ScriptError { message, span, .. } = script_error;
 
     let span_start = span.start;
     let span_end = span.end;
@@ -1,9 +1,17 @@
 use crate::{
     codegen::{FunctionCodegen, Instruction, InstructionId, RegisterId, RegisterValue},
-    engine::ExternalFnRecord,
+    engine::{ExternalFnRecord, SharedArg},
     parser::{NodeId, Span},
     typechecker::{ExternalFunctionId, Function, FunctionId, STRING_TYPE},
-    ScriptError, TypeChecker, TypeId, Value, BOOL_TYPE, F64_TYPE, I64_TYPE, UNIT_TYPE,
+    ErrorKind, ScriptError, Type, TypeChecker, TypeId, Value, BOOL_TYPE, F64_TYPE, I64_TYPE,
+    UNIT_TYPE,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
 };
 
 #[derive(Clone)]
@@ -11,6 +19,12 @@ pub struct StackFrame {
     pub register_values: Vec<RegisterValue>,
     pub register_types: Vec<TypeId>,
     pub instruction_pointer: InstructionId,
+
+    // Parameters live in the registers right after the return register
+    pub num_params: usize,
+
+    // The register in the caller's frame that receives our return value
+    pub return_register: RegisterId,
 }
 
 #[derive(Default)]
@@ -26,20 +40,200 @@ pub struct Evaluator {
 
     // The live stack frames during evaluation
     pub stack_frames: Vec<StackFrame>,
+
+    // The frame the current run started in, whose return hands its value back to the host
+    entry_frame: usize,
+
+    // Whether the entry frame goes away once the run is done, unlike a session's main function,
+    // and how many of its registers after the return register hold globals
+    ends_entry_frame: bool,
+    num_globals: usize,
+
+    // The frame holding the globals, which functions other than the script body load them from
+    globals_frame: usize,
+
+    // What integer operations do when their result doesn't fit in an i64
+    pub arithmetic: ArithmeticMode,
+
+    // How much work a run may do, and how much the current run has done so far
+    pub limits: EvalLimits,
+    instructions_run: u64,
+    started: Option<Instant>,
+
+    // The script whose code this evaluator runs, which the closures it creates belong to
+    pub(crate) script: ScriptId,
 }
 
-impl Drop for Evaluator {
-    fn drop(&mut self) {
-        let num_frames = self.stack_frames.len();
-        for frame_id in (0..num_frames).rev() {
-            let num_registers = self.stack_frames[frame_id].register_values.len();
+/// Tells scripts apart, so a closure can only be called on the script that created it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ScriptId(u64);
 
-            for register_id in (1..num_registers).map(RegisterId).rev() {
-                self.maybe_free_register(register_id);
-                self.stack_frames[frame_id].register_values[register_id.0].i64 = 0;
-            }
+impl ScriptId {
+    pub(crate) fn next() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Default for ScriptId {
+    fn default() -> Self {
+        Self::next()
+    }
+}
+
+/// How a value of a script type is held at runtime, indexed by `TypeId`
+///
+/// Values Rust hands to a script, like the arguments of a callback, are checked against this
+/// before they go in a register.
+#[derive(Clone, Debug)]
+pub enum RuntimeType {
+    /// The basic types, arrays and registered types, held as the Rust type itself
+    Rust(std::any::TypeId),
+
+    /// A struct defined in the script, held as a `ScriptStruct` with fields of the given types
+    Struct(Vec<TypeId>),
+
+    /// A closure taking and returning the given types, held as a `Callback`
+    Function(Vec<TypeId>, TypeId),
+}
+
+/// Limits on how much work a single run of a script may do
+///
+/// Limits are checked whenever a loop jumps back to its start or a function is called, which is
+/// how a script can keep running forever. Going over any of them stops the run with an error of
+/// the matching `ErrorKind`. Since the checks only happen there, a run may go a little past
+/// `max_instructions` before it's stopped.
+#[derive(Clone, Debug, Default)]
+pub struct EvalLimits {
+    /// The most instructions a run may execute, counting each one every time it runs
+    pub max_instructions: Option<u64>,
+
+    /// The longest a run may take, including time spent in registered functions
+    pub max_duration: Option<Duration>,
+
+    /// Stops the run once it's cancelled
+    pub cancellation: Option<CancellationToken>,
+}
+
+/// What integer arithmetic does when the result doesn't fit in an i64
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ArithmeticMode {
+    /// Stop the script with an error pointing at the operator
+    #[default]
+    Checked,
+
+    /// Wrap around at the boundary of the type, like `i64::wrapping_add`
+    Wrapping,
+
+    /// Clamp to `i64::MIN` or `i64::MAX`, like `i64::saturating_add`
+    Saturating,
+}
+
+/// Stops script runs from outside the evaluator, such as from another thread
+///
+/// Clones share the same state, so cancelling one cancels all of them.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value of a struct type defined in a script
+///
+/// Fields are kept in the order they were declared in, each boxed as its Rust type (`i64`, `f64`,
+/// `bool` or `String`).
+#[derive(Debug)]
+pub struct ScriptStruct {
+    pub fields: Vec<Value>,
+}
+
+/// Copy a value that a script can create, so it can be handed out while the script keeps its own
+///
+/// Values of registered Rust types can't be copied, as they don't need to implement `Clone`.
+fn clone_script_value(value: &Value) -> Option<Value> {
+    fn clone_basic(value: &Value) -> Option<Value> {
+        if let Some(value) = value.downcast_ref::<i64>() {
+            Some(Box::new(*value))
+        } else if let Some(value) = value.downcast_ref::<f64>() {
+            Some(Box::new(*value))
+        } else if let Some(value) = value.downcast_ref::<bool>() {
+            Some(Box::new(*value))
+        } else {
+            value
+                .downcast_ref::<String>()
+                .map(|value| Box::new(value.clone()) as Value)
         }
     }
+
+    if let Some(value) = value.downcast_ref::<Vec<i64>>() {
+        Some(Box::new(value.clone()))
+    } else if let Some(value) = value.downcast_ref::<Vec<f64>>() {
+        Some(Box::new(value.clone()))
+    } else if let Some(value) = value.downcast_ref::<Vec<bool>>() {
+        Some(Box::new(value.clone()))
+    } else if let Some(value) = value.downcast_ref::<Vec<String>>() {
+        Some(Box::new(value.clone()))
+    } else if let Some(value) = value.downcast_ref::<ScriptStruct>() {
+        let fields = value
+            .fields
+            .iter()
+            .map(clone_basic)
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(Box::new(ScriptStruct { fields }))
+    } else if let Some(value) = value.downcast_ref::<Callback>() {
+        Some(Box::new(value.clone()))
+    } else {
+        clone_enum::<i64>(value)
+            .or_else(|| clone_enum::<f64>(value))
+            .or_else(|| clone_enum::<bool>(value))
+            .or_else(|| clone_enum::<String>(value))
+            .or_else(|| clone_basic(value))
+    }
+}
+
+/// Copy an `Option<T>` or `Result<T, String>`, the ones scripts have for the basic types
+fn clone_enum<T: Clone + Send + Sync + 'static>(value: &Value) -> Option<Value> {
+    if let Some(value) = value.downcast_ref::<Option<T>>() {
+        Some(Box::new(value.clone()))
+    } else {
+        value
+            .downcast_ref::<Result<T, String>>()
+            .map(|value| Box::new(value.clone()) as Value)
+    }
+}
+
+/// A closure created by a script, which Rust code can hold on to and call later
+///
+/// Registered functions take one by having a `Callback` parameter, which any closure can be passed
+/// to. Call it with `CompiledScript::call` or `Session::call`, on the same script that created it.
+/// The closure keeps its own copy of the variables it captured, so it can be called after the
+/// script has finished running.
+#[derive(Clone)]
+pub struct Callback {
+    script: ScriptId,
+    function: FunctionId,
+    captures: Arc<[Value]>,
+}
+
+impl std::fmt::Debug for Callback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Callback")
+            .field("function", &self.function)
+            .finish_non_exhaustive()
+    }
 }
 
 #[derive(Debug)]
@@ -52,6 +246,55 @@ pub enum ReturnValue {
     Custom(Value),
 }
 
+impl ReturnValue {
+    /// Take the value out as a `T`, or get the return value back if it holds something else
+    pub fn downcast<T: Type>(self) -> Result<T, ReturnValue> {
+        let value: Value = match self {
+            ReturnValue::Unit => Box::new(()),
+            ReturnValue::I64(value) => Box::new(value),
+            ReturnValue::F64(value) => Box::new(value),
+            ReturnValue::Bool(value) => Box::new(value),
+            ReturnValue::String(value) => Box::new(value),
+            ReturnValue::Custom(value) => value,
+        };
+
+        value
+            .downcast::<T>()
+            .map(|value| *value)
+            .map_err(ReturnValue::from_value)
+    }
+
+    fn from_value(value: Value) -> ReturnValue {
+        if value.is::<()>() {
+            ReturnValue::Unit
+        } else if let Some(value) = value.downcast_ref::<i64>() {
+            ReturnValue::I64(*value)
+        } else if let Some(value) = value.downcast_ref::<f64>() {
+            ReturnValue::F64(*value)
+        } else if let Some(value) = value.downcast_ref::<bool>() {
+            ReturnValue::Bool(*value)
+        } else {
+            match value.downcast::<String>() {
+                Ok(value) => ReturnValue::String(*value),
+                Err(value) => ReturnValue::Custom(value),
+            }
+        }
+    }
+}
+
+/// A type that a script's value can be taken out as, with `Engine::eval`
+///
+/// This covers unit, the basic types, and any type registered with the engine.
+pub trait FromScript: Type + Sized {
+    fn from_script(value: ReturnValue) -> Result<Self, ReturnValue>;
+}
+
+impl<T: Type> FromScript for T {
+    fn from_script(value: ReturnValue) -> Result<Self, ReturnValue> {
+        value.downcast()
+    }
+}
+
 impl Evaluator {
     pub fn add_function(&mut self, mut function_codegen: FunctionCodegen) {
         let function_entry = self.instructions.len();
@@ -60,6 +303,8 @@ impl Evaluator {
             register_values: function_codegen.register_values,
             register_types: function_codegen.register_types,
             instruction_pointer: InstructionId(function_entry),
+            num_params: function_codegen.num_params,
+            return_register: RegisterId(0),
         };
         self.instructions.append(&mut function_codegen.instructions);
         self.source_map.append(&mut function_codegen.source_map);
@@ -69,48 +314,358 @@ impl Evaluator {
         self.functions.push(stack_frame);
     }
 
+    /// Add a snippet that continues the main function, returning where its code starts
+    ///
+    /// The main function's frame is kept alive between snippets, so the snippet only brings the
+    /// registers it added on top of the ones the frame already has.
+    pub fn add_snippet(&mut self, mut snippet: FunctionCodegen) -> InstructionId {
+        self.start_session();
+
+        let entry = self.instructions.len();
+        snippet.offset_instruction_addresses(entry);
+        self.instructions.append(&mut snippet.instructions);
+        self.source_map.append(&mut snippet.source_map);
+        self.spans = snippet.spans;
+
+        let frame = &mut self.stack_frames[0];
+        let num_registers = frame.register_values.len();
+        frame
+            .register_values
+            .extend(snippet.register_values.drain(num_registers..));
+        frame
+            .register_types
+            .extend(snippet.register_types.drain(num_registers..));
+        frame.register_types[0] = snippet.register_types[0];
+
+        InstructionId(entry)
+    }
+
+    /// Load a session's globals into the registers after the main function's return register,
+    /// before any snippets are added
+    pub fn add_globals(&mut self, globals: Vec<(TypeId, Value)>) {
+        self.start_session();
+        self.current_frame = 0;
+        self.globals_frame = 0;
+
+        for (type_id, value) in globals {
+            let frame = &mut self.stack_frames[0];
+            frame.register_values.push(RegisterValue::Unit);
+            frame.register_types.push(type_id);
+
+            let register_id = RegisterId(frame.register_values.len() - 1);
+            self.unbox_to_register(value, register_id);
+        }
+    }
+
+    /// Set up the main function's frame for a session, which its snippets share
+    fn start_session(&mut self) {
+        if self.functions.is_empty() {
+            // Local functions are numbered from 1, after the main function
+            self.functions.push(StackFrame {
+                register_values: vec![],
+                register_types: vec![],
+                instruction_pointer: InstructionId(0),
+                num_params: 0,
+                return_register: RegisterId(0),
+            });
+        }
+        if self.stack_frames.is_empty() {
+            self.stack_frames.push(StackFrame {
+                register_values: vec![RegisterValue::Unit],
+                register_types: vec![UNIT_TYPE],
+                instruction_pointer: InstructionId(0),
+                num_params: 0,
+                return_register: RegisterId(0),
+            });
+        }
+    }
+
+    /// The types of the main function's registers, which a snippet's code is added on top of
+    pub fn main_register_types(&self) -> &[TypeId] {
+        match self.stack_frames.first() {
+            Some(frame) => &frame.register_types,
+            None => &[UNIT_TYPE],
+        }
+    }
+
+    /// Run a snippet added with `add_snippet`, keeping the main function's registers alive
+    /// afterwards
+    pub fn eval_snippet(
+        &mut self,
+        entry: InstructionId,
+        external_functions: &[ExternalFnRecord],
+    ) -> Result<ReturnValue, ScriptError> {
+        self.current_frame = 0;
+        self.entry_frame = 0;
+        self.ends_entry_frame = false;
+
+        let result = self.run(entry.0, external_functions);
+
+        self.finish_snippet(result)
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn eval_snippet_async(
+        &mut self,
+        entry: InstructionId,
+        external_functions: &[ExternalFnRecord],
+    ) -> Result<ReturnValue, ScriptError> {
+        self.current_frame = 0;
+        self.entry_frame = 0;
+        self.ends_entry_frame = false;
+
+        let result = self.run_async(entry.0, external_functions).await;
+
+        self.finish_snippet(result)
+    }
+
+    /// Unwind the frames of any calls a snippet's error interrupted, and make sure the main
+    /// function keeps whatever its variables still refer to
+    fn finish_snippet(
+        &mut self,
+        result: Result<ReturnValue, ScriptError>,
+    ) -> Result<ReturnValue, ScriptError> {
+        self.stack_frames.truncate(1);
+        self.current_frame = 0;
+
+        // The return value was taken out of its register, if we got that far
+        self.stack_frames[0].register_values[0] = RegisterValue::Unit;
+        self.stack_frames[0].register_types[0] = UNIT_TYPE;
+
+        result
+    }
+
+    /// Take the return value of the run's first frame out of its register
+    ///
+    /// If a variable still holds the value, it keeps it and we hand out a copy instead. Values of
+    /// registered types can't be copied, so those are an error pointing at the return.
+    fn take_return_value(
+        &mut self,
+        instruction_pointer: usize,
+    ) -> Result<ReturnValue, ScriptError> {
+        let frame = &mut self.stack_frames[self.current_frame];
+
+        // The frame's temporaries are done with once the run is over, so they let go of the value
+        if self.ends_entry_frame {
+            for value in frame.register_values.iter_mut().skip(self.num_globals + 1) {
+                *value = RegisterValue::Unit;
+            }
+        }
+
+        let value = std::mem::take(&mut frame.register_values[0]);
+        if frame.register_types[0] == UNIT_TYPE {
+            return Ok(ReturnValue::Unit);
+        }
+
+        let value =
+            match value {
+                RegisterValue::Unit => ReturnValue::Unit,
+                RegisterValue::I64(value) => ReturnValue::I64(value),
+                RegisterValue::F64(value) => ReturnValue::F64(value),
+                RegisterValue::Bool(value) => ReturnValue::Bool(value),
+                RegisterValue::String(string) => ReturnValue::String(Arc::unwrap_or_clone(string)),
+                RegisterValue::Object(object) => match Arc::try_unwrap(object) {
+                    Ok(object) => ReturnValue::Custom(
+                        object.into_inner().unwrap_or_else(PoisonError::into_inner),
+                    ),
+                    Err(object) => match clone_script_value(&lock(&object)) {
+                        Some(value) => ReturnValue::Custom(value),
+                        None => return Err(self.error(
+                            "the script's value is still in use, and values of registered types \
+                             can't be copied out",
+                            self.source_map[instruction_pointer],
+                        )),
+                    },
+                },
+            };
+
+        Ok(value)
+    }
+
+    fn start_run(&mut self) {
+        self.instructions_run = 0;
+        self.started = self.limits.max_duration.map(|_| Instant::now());
+    }
+
+    /// Stop the run if it went over its limits
+    fn check_limits(&mut self, instruction_pointer: usize) -> Result<(), ScriptError> {
+        let is_cancelled = self
+            .limits
+            .cancellation
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled);
+        if is_cancelled {
+            return Err(self.limit_error(
+                ErrorKind::Cancelled,
+                "script was cancelled".into(),
+                instruction_pointer,
+            ));
+        }
+
+        if let Some(max_instructions) = self.limits.max_instructions {
+            if self.instructions_run > max_instructions {
+                return Err(self.limit_error(
+                    ErrorKind::InstructionLimit,
+                    format!("script ran more than {max_instructions} instructions"),
+                    instruction_pointer,
+                ));
+            }
+        }
+
+        if let (Some(max_duration), Some(started)) = (self.limits.max_duration, self.started) {
+            if started.elapsed() > max_duration {
+                return Err(self.limit_error(
+                    ErrorKind::TimeLimit,
+                    format!("script ran for longer than {max_duration:?}"),
+                    instruction_pointer,
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn limit_error(
+        &self,
+        kind: ErrorKind,
+        message: String,
+        instruction_pointer: usize,
+    ) -> ScriptError {
+        ScriptError {
+            kind,
+            ..self.error(message, self.source_map[instruction_pointer])
+        }
+    }
+
+    /// Create a fresh stack frame for a call to the given function
+    ///
+    /// The frame shares the function's string constants, which are never changed in place.
+    pub fn new_frame(&self, function_id: FunctionId) -> StackFrame {
+        self.functions[function_id.0].clone()
+    }
+
+    #[inline]
+    fn reg(&self, register_id: RegisterId) -> &RegisterValue {
+        &self.stack_frames[self.current_frame].register_values[register_id.0]
+    }
+
+    /// Store a value in the given register, letting go of the one it held
+    #[inline]
+    fn set_reg(&mut self, register_id: RegisterId, value: RegisterValue) {
+        self.stack_frames[self.current_frame].register_values[register_id.0] = value;
+    }
+
     #[inline]
     pub fn get_reg_i64(&self, register_id: RegisterId) -> i64 {
-        unsafe { self.stack_frames[self.current_frame].register_values[register_id.0].i64 }
+        match self.reg(register_id) {
+            RegisterValue::I64(value) => *value,
+            other => panic!("internal error: expected an i64 register, found {other:?}"),
+        }
     }
 
     #[inline]
     pub fn get_reg_f64(&self, register_id: RegisterId) -> f64 {
-        unsafe { self.stack_frames[self.current_frame].register_values[register_id.0].f64 }
+        match self.reg(register_id) {
+            RegisterValue::F64(value) => *value,
+            other => panic!("internal error: expected an f64 register, found {other:?}"),
+        }
     }
 
     #[inline]
     pub fn get_reg_bool(&self, register_id: RegisterId) -> bool {
-        unsafe { self.stack_frames[self.current_frame].register_values[register_id.0].bool }
+        match self.reg(register_id) {
+            RegisterValue::Bool(value) => *value,
+            other => panic!("internal error: expected a bool register, found {other:?}"),
+        }
     }
 
-    #[inline]
-    pub fn get_reg_string(&self, register_id: RegisterId) -> Box<String> {
-        let ptr =
-            unsafe { self.stack_frames[self.current_frame].register_values[register_id.0].ptr };
-        unsafe { Box::from_raw(ptr as _) }
+    /// Put a new string in the given register
+    pub fn set_reg_string(&mut self, register_id: RegisterId, string: String) {
+        self.set_reg(register_id, RegisterValue::String(Arc::new(string)));
     }
 
+    /// Borrow the string held in the given register
     #[inline]
-    /// Take a given register's contents, leaving a copy of the pointer in the register. Users must
-    /// leak the box to prevent the underlying storage from being freed.
-    ///
-    /// SAFETY: the underlying storage of the data stored at `register_id` must not have been freed
-    /// by a previous access
-    ///
-    /// TODO: we need to either mark this function unsafe or change how we do this in the future
-    pub fn get_user_type(&self, register_id: RegisterId) -> Value {
-        let boxed = unsafe {
-            std::mem::transmute::<i64, Box<Value>>(
-                self.stack_frames[self.current_frame].register_values[register_id.0].i64,
-            )
-        };
+    pub fn reg_str(&self, register_id: RegisterId) -> &str {
+        match self.reg(register_id) {
+            RegisterValue::String(string) => string,
+            other => panic!("internal error: expected a string register, found {other:?}"),
+        }
+    }
+
+    /// Lock the value held in the given register, one that lives in a shared slot
+    fn object(&self, register_id: RegisterId) -> MutexGuard<'_, Value> {
+        match self.reg(register_id) {
+            RegisterValue::Object(object) => lock(object),
+            other => panic!("internal error: expected an object register, found {other:?}"),
+        }
+    }
+
+    /// Store a new value that lives in a shared slot in the given register
+    fn set_reg_object(&mut self, register_id: RegisterId, value: Value) {
+        self.set_reg(
+            register_id,
+            RegisterValue::Object(Arc::new(Mutex::new(value))),
+        );
+    }
 
-        let boxed = Box::leak(boxed);
+    /// Call `f` with the array held in the given register
+    pub fn with_array<T: 'static, R>(
+        &self,
+        register_id: RegisterId,
+        f: impl FnOnce(&Vec<T>) -> R,
+    ) -> R {
+        let object = self.object(register_id);
+
+        f(object
+            .downcast_ref()
+            .expect("internal error: array register holds a different type of array"))
+    }
 
-        let leaked = &mut **boxed as *mut _;
+    /// Call `f` with the script struct held in the given register
+    pub fn with_script_struct<R>(
+        &self,
+        register_id: RegisterId,
+        f: impl FnOnce(&mut ScriptStruct) -> R,
+    ) -> R {
+        let mut object = self.object(register_id);
+
+        f(object
+            .downcast_mut()
+            .expect("internal error: struct register holds a different type"))
+    }
 
-        unsafe { Box::from_raw(leaked) }
+    /// Copy the value in the given register into a new struct field
+    fn field_value(&self, register_id: RegisterId) -> Value {
+        match self.stack_frames[self.current_frame].register_types[register_id.0] {
+            I64_TYPE => Box::new(self.get_reg_i64(register_id)),
+            F64_TYPE => Box::new(self.get_reg_f64(register_id)),
+            BOOL_TYPE => Box::new(self.get_reg_bool(register_id)),
+            STRING_TYPE => Box::new(self.reg_str(register_id).to_string()),
+            _ => panic!("internal error: unsupported field type"),
+        }
+    }
+
+    fn array_element<T: Clone + 'static>(
+        &self,
+        array: RegisterId,
+        index: i64,
+        node_id: NodeId,
+    ) -> Result<T, ScriptError> {
+        self.with_array::<T, _>(array, |array| {
+            if index < 0 || index as usize >= array.len() {
+                Err(self.error(
+                    format!(
+                        "index out of bounds: the len is {} but the index is {}",
+                        array.len(),
+                        index
+                    ),
+                    node_id,
+                ))
+            } else {
+                Ok(array[index as usize].clone())
+            }
+        })
     }
 
     #[inline]
@@ -120,19 +675,50 @@ impl Evaluator {
     ) -> Option<Result<ReturnValue, ScriptError>> {
         match self.instructions[*instruction_pointer] {
             Instruction::IADD { lhs, rhs, target } => {
-                self.stack_frames[self.current_frame].register_values[target.0].i64 =
-                    self.get_reg_i64(lhs) + self.get_reg_i64(rhs);
+                let (lhs, rhs) = (self.get_reg_i64(lhs), self.get_reg_i64(rhs));
+                let result = self.integer_op(
+                    *instruction_pointer,
+                    "add",
+                    || lhs.checked_add(rhs),
+                    || lhs.wrapping_add(rhs),
+                    || lhs.saturating_add(rhs),
+                );
+                match result {
+                    Ok(value) => self.set_reg(target, RegisterValue::I64(value)),
+                    Err(error) => return Some(Err(error)),
+                }
+
                 *instruction_pointer += 1;
             }
             Instruction::ISUB { lhs, rhs, target } => {
-                self.stack_frames[self.current_frame].register_values[target.0].i64 =
-                    self.get_reg_i64(lhs) - self.get_reg_i64(rhs);
+                let (lhs, rhs) = (self.get_reg_i64(lhs), self.get_reg_i64(rhs));
+                let result = self.integer_op(
+                    *instruction_pointer,
+                    "subtract",
+                    || lhs.checked_sub(rhs),
+                    || lhs.wrapping_sub(rhs),
+                    || lhs.saturating_sub(rhs),
+                );
+                match result {
+                    Ok(value) => self.set_reg(target, RegisterValue::I64(value)),
+                    Err(error) => return Some(Err(error)),
+                }
 
                 *instruction_pointer += 1;
             }
             Instruction::IMUL { lhs, rhs, target } => {
-                self.stack_frames[self.current_frame].register_values[target.0].i64 =
-                    self.get_reg_i64(lhs) * self.get_reg_i64(rhs);
+                let (lhs, rhs) = (self.get_reg_i64(lhs), self.get_reg_i64(rhs));
+                let result = self.integer_op(
+                    *instruction_pointer,
+                    "multiply",
+                    || lhs.checked_mul(rhs),
+                    || lhs.wrapping_mul(rhs),
+                    || lhs.saturating_mul(rhs),
+                );
+                match result {
+                    Ok(value) => self.set_reg(target, RegisterValue::I64(value)),
+                    Err(error) => return Some(Err(error)),
+                }
 
                 *instruction_pointer += 1;
             }
@@ -142,139 +728,604 @@ impl Evaluator {
                         self.error("division by zero", self.source_map[*instruction_pointer])
                     ));
                 }
-                self.stack_frames[self.current_frame].register_values[target.0].i64 =
-                    self.get_reg_i64(lhs) / self.get_reg_i64(rhs);
+                let (lhs, rhs) = (self.get_reg_i64(lhs), self.get_reg_i64(rhs));
+                let result = self.integer_op(
+                    *instruction_pointer,
+                    "divide",
+                    || lhs.checked_div(rhs),
+                    || lhs.wrapping_div(rhs),
+                    || lhs.saturating_div(rhs),
+                );
+                match result {
+                    Ok(value) => self.set_reg(target, RegisterValue::I64(value)),
+                    Err(error) => return Some(Err(error)),
+                }
+
+                *instruction_pointer += 1
+            }
+            Instruction::IMOD { lhs, rhs, target } => {
+                if self.get_reg_i64(rhs) == 0 {
+                    return Some(Err(
+                        self.error("division by zero", self.source_map[*instruction_pointer])
+                    ));
+                }
+                let (lhs, rhs) = (self.get_reg_i64(lhs), self.get_reg_i64(rhs));
+                let result = self.integer_op(
+                    *instruction_pointer,
+                    "calculate the remainder",
+                    || lhs.checked_rem(rhs),
+                    || lhs.wrapping_rem(rhs),
+                    || lhs.wrapping_rem(rhs),
+                );
+                match result {
+                    Ok(value) => self.set_reg(target, RegisterValue::I64(value)),
+                    Err(error) => return Some(Err(error)),
+                }
+
+                *instruction_pointer += 1
+            }
+            Instruction::IPOW { lhs, rhs, target } => {
+                let Ok(exponent) = u32::try_from(self.get_reg_i64(rhs)) else {
+                    return Some(Err(self.error(
+                        "exponent must be a non-negative integer that fits in 32 bits",
+                        self.source_map[*instruction_pointer],
+                    )));
+                };
+                let lhs = self.get_reg_i64(lhs);
+                let result = self.integer_op(
+                    *instruction_pointer,
+                    "raise to a power",
+                    || lhs.checked_pow(exponent),
+                    || lhs.wrapping_pow(exponent),
+                    || lhs.saturating_pow(exponent),
+                );
+                match result {
+                    Ok(value) => self.set_reg(target, RegisterValue::I64(value)),
+                    Err(error) => return Some(Err(error)),
+                }
+
+                *instruction_pointer += 1
+            }
+            Instruction::IAND { lhs, rhs, target } => {
+                self.set_reg(
+                    target,
+                    RegisterValue::I64(self.get_reg_i64(lhs) & self.get_reg_i64(rhs)),
+                );
+
+                *instruction_pointer += 1
+            }
+            Instruction::IOR { lhs, rhs, target } => {
+                self.set_reg(
+                    target,
+                    RegisterValue::I64(self.get_reg_i64(lhs) | self.get_reg_i64(rhs)),
+                );
+
+                *instruction_pointer += 1
+            }
+            Instruction::IXOR { lhs, rhs, target } => {
+                self.set_reg(
+                    target,
+                    RegisterValue::I64(self.get_reg_i64(lhs) ^ self.get_reg_i64(rhs)),
+                );
+
+                *instruction_pointer += 1
+            }
+            Instruction::ISHL { lhs, rhs, target } => {
+                let shifted = u32::try_from(self.get_reg_i64(rhs))
+                    .ok()
+                    .and_then(|shift| self.get_reg_i64(lhs).checked_shl(shift));
+                let Some(shifted) = shifted else {
+                    return Some(Err(self.error(
+                        "shift amount must be between 0 and 63",
+                        self.source_map[*instruction_pointer],
+                    )));
+                };
+                self.set_reg(target, RegisterValue::I64(shifted));
+
+                *instruction_pointer += 1
+            }
+            Instruction::ISHR { lhs, rhs, target } => {
+                let shifted = u32::try_from(self.get_reg_i64(rhs))
+                    .ok()
+                    .and_then(|shift| self.get_reg_i64(lhs).checked_shr(shift));
+                let Some(shifted) = shifted else {
+                    return Some(Err(self.error(
+                        "shift amount must be between 0 and 63",
+                        self.source_map[*instruction_pointer],
+                    )));
+                };
+                self.set_reg(target, RegisterValue::I64(shifted));
 
                 *instruction_pointer += 1
             }
             Instruction::ILT { lhs, rhs, target } => {
-                self.stack_frames[self.current_frame].register_values[target.0].bool =
-                    self.get_reg_i64(lhs) < self.get_reg_i64(rhs);
+                self.set_reg(
+                    target,
+                    RegisterValue::Bool(self.get_reg_i64(lhs) < self.get_reg_i64(rhs)),
+                );
 
                 *instruction_pointer += 1;
             }
             Instruction::ILTE { lhs, rhs, target } => {
-                self.stack_frames[self.current_frame].register_values[target.0].bool =
-                    self.get_reg_i64(lhs) <= self.get_reg_i64(rhs);
+                self.set_reg(
+                    target,
+                    RegisterValue::Bool(self.get_reg_i64(lhs) <= self.get_reg_i64(rhs)),
+                );
 
                 *instruction_pointer += 1;
             }
             Instruction::IGT { lhs, rhs, target } => {
-                self.stack_frames[self.current_frame].register_values[target.0].bool =
-                    self.get_reg_i64(lhs) > self.get_reg_i64(rhs);
+                self.set_reg(
+                    target,
+                    RegisterValue::Bool(self.get_reg_i64(lhs) > self.get_reg_i64(rhs)),
+                );
 
                 *instruction_pointer += 1;
             }
             Instruction::IGTE { lhs, rhs, target } => {
-                self.stack_frames[self.current_frame].register_values[target.0].bool =
-                    self.get_reg_i64(lhs) >= self.get_reg_i64(rhs);
+                self.set_reg(
+                    target,
+                    RegisterValue::Bool(self.get_reg_i64(lhs) >= self.get_reg_i64(rhs)),
+                );
 
                 *instruction_pointer += 1;
             }
             Instruction::FADD { lhs, rhs, target } => {
-                self.stack_frames[self.current_frame].register_values[target.0].f64 =
-                    self.get_reg_f64(lhs) + self.get_reg_f64(rhs);
+                self.set_reg(
+                    target,
+                    RegisterValue::F64(self.get_reg_f64(lhs) + self.get_reg_f64(rhs)),
+                );
 
                 *instruction_pointer += 1;
             }
             Instruction::FSUB { lhs, rhs, target } => {
-                self.stack_frames[self.current_frame].register_values[target.0].f64 =
-                    self.get_reg_f64(lhs) - self.get_reg_f64(rhs);
+                self.set_reg(
+                    target,
+                    RegisterValue::F64(self.get_reg_f64(lhs) - self.get_reg_f64(rhs)),
+                );
 
                 *instruction_pointer += 1;
             }
             Instruction::FMUL { lhs, rhs, target } => {
-                self.stack_frames[self.current_frame].register_values[target.0].f64 =
-                    self.get_reg_f64(lhs) * self.get_reg_f64(rhs);
+                self.set_reg(
+                    target,
+                    RegisterValue::F64(self.get_reg_f64(lhs) * self.get_reg_f64(rhs)),
+                );
+
+                *instruction_pointer += 1;
+            }
+            Instruction::FDIV { lhs, rhs, target } => {
+                if self.get_reg_f64(rhs) == 0.0 {
+                    return Some(Err(
+                        self.error("division by zero", self.source_map[*instruction_pointer])
+                    ));
+                }
+
+                self.set_reg(
+                    target,
+                    RegisterValue::F64(self.get_reg_f64(lhs) / self.get_reg_f64(rhs)),
+                );
+
+                *instruction_pointer += 1;
+            }
+            Instruction::FPOW { lhs, rhs, target } => {
+                self.set_reg(
+                    target,
+                    RegisterValue::F64(self.get_reg_f64(lhs).powf(self.get_reg_f64(rhs))),
+                );
+
+                *instruction_pointer += 1;
+            }
+            Instruction::SLT { lhs, rhs, target } => {
+                self.set_reg(
+                    target,
+                    RegisterValue::Bool(self.reg_str(lhs) < self.reg_str(rhs)),
+                );
+
+                *instruction_pointer += 1;
+            }
+            Instruction::SLTE { lhs, rhs, target } => {
+                self.set_reg(
+                    target,
+                    RegisterValue::Bool(self.reg_str(lhs) <= self.reg_str(rhs)),
+                );
+
+                *instruction_pointer += 1;
+            }
+            Instruction::SGT { lhs, rhs, target } => {
+                self.set_reg(
+                    target,
+                    RegisterValue::Bool(self.reg_str(lhs) > self.reg_str(rhs)),
+                );
+
+                *instruction_pointer += 1;
+            }
+            Instruction::SGTE { lhs, rhs, target } => {
+                self.set_reg(
+                    target,
+                    RegisterValue::Bool(self.reg_str(lhs) >= self.reg_str(rhs)),
+                );
+
+                *instruction_pointer += 1;
+            }
+            Instruction::INEG { source, target } => {
+                let source = self.get_reg_i64(source);
+                let result = self.integer_op(
+                    *instruction_pointer,
+                    "negate",
+                    || source.checked_neg(),
+                    || source.wrapping_neg(),
+                    || source.saturating_neg(),
+                );
+                match result {
+                    Ok(value) => self.set_reg(target, RegisterValue::I64(value)),
+                    Err(error) => return Some(Err(error)),
+                }
+
+                *instruction_pointer += 1;
+            }
+            Instruction::FNEG { source, target } => {
+                self.set_reg(target, RegisterValue::F64(-self.get_reg_f64(source)));
+
+                *instruction_pointer += 1;
+            }
+            Instruction::NOT { source, target } => {
+                self.set_reg(target, RegisterValue::Bool(!self.get_reg_bool(source)));
+
+                *instruction_pointer += 1;
+            }
+            Instruction::SCONCAT { lhs, rhs, target } => {
+                let output = format!("{}{}", self.reg_str(lhs), self.reg_str(rhs));
+                self.set_reg_string(target, output);
+
+                *instruction_pointer += 1;
+            }
+            Instruction::ITOF { source, target } => {
+                self.set_reg(target, RegisterValue::F64(self.get_reg_i64(source) as f64));
+
+                *instruction_pointer += 1;
+            }
+            Instruction::FTOI { source, target } => {
+                // Truncates toward zero, saturating at the bounds of i64, with NaN becoming 0
+                self.set_reg(target, RegisterValue::I64(self.get_reg_f64(source) as i64));
+
+                *instruction_pointer += 1;
+            }
+            Instruction::ITOS { source, target } => {
+                let output = self.get_reg_i64(source).to_string();
+                self.set_reg_string(target, output);
+
+                *instruction_pointer += 1;
+            }
+            Instruction::FTOS { source, target } => {
+                let output = self.get_reg_f64(source).to_string();
+                self.set_reg_string(target, output);
+
+                *instruction_pointer += 1;
+            }
+            Instruction::BTOS { source, target } => {
+                let output = self.get_reg_bool(source).to_string();
+                self.set_reg_string(target, output);
+
+                *instruction_pointer += 1;
+            }
+            Instruction::IEQ { lhs, rhs, target } => {
+                self.set_reg(
+                    target,
+                    RegisterValue::Bool(self.get_reg_i64(lhs) == self.get_reg_i64(rhs)),
+                );
+
+                *instruction_pointer += 1;
+            }
+            Instruction::FEQ { lhs, rhs, target } => {
+                self.set_reg(
+                    target,
+                    RegisterValue::Bool(self.get_reg_f64(lhs) == self.get_reg_f64(rhs)),
+                );
+
+                *instruction_pointer += 1;
+            }
+            Instruction::BEQ { lhs, rhs, target } => {
+                self.set_reg(
+                    target,
+                    RegisterValue::Bool(self.get_reg_bool(lhs) == self.get_reg_bool(rhs)),
+                );
+
+                *instruction_pointer += 1;
+            }
+            Instruction::SEQ { lhs, rhs, target } => {
+                self.set_reg(
+                    target,
+                    RegisterValue::Bool(self.reg_str(lhs) == self.reg_str(rhs)),
+                );
+
+                *instruction_pointer += 1;
+            }
+            Instruction::FLT { lhs, rhs, target } => {
+                self.set_reg(
+                    target,
+                    RegisterValue::Bool(self.get_reg_f64(lhs) < self.get_reg_f64(rhs)),
+                );
+
+                *instruction_pointer += 1;
+            }
+            Instruction::FLTE { lhs, rhs, target } => {
+                self.set_reg(
+                    target,
+                    RegisterValue::Bool(self.get_reg_f64(lhs) <= self.get_reg_f64(rhs)),
+                );
+
+                *instruction_pointer += 1;
+            }
+            Instruction::FGT { lhs, rhs, target } => {
+                self.set_reg(
+                    target,
+                    RegisterValue::Bool(self.get_reg_f64(lhs) > self.get_reg_f64(rhs)),
+                );
+
+                *instruction_pointer += 1;
+            }
+            Instruction::FGTE { lhs, rhs, target } => {
+                self.set_reg(
+                    target,
+                    RegisterValue::Bool(self.get_reg_f64(lhs) >= self.get_reg_f64(rhs)),
+                );
+
+                *instruction_pointer += 1;
+            }
+            Instruction::MOV { target, source } => {
+                self.set_reg(target, self.reg(source).clone());
+
+                *instruction_pointer += 1;
+            }
+            Instruction::BRIF {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                let condition = self.get_reg_bool(condition);
+
+                if condition {
+                    *instruction_pointer = then_branch.0;
+                } else {
+                    *instruction_pointer = else_branch.0;
+                }
+            }
+            Instruction::JMP(location) => {
+                // Jumping backwards means a loop is going around again
+                if location.0 <= *instruction_pointer {
+                    if let Err(error) = self.check_limits(*instruction_pointer) {
+                        return Some(Err(error));
+                    }
+                }
+
+                *instruction_pointer = location.0;
+            }
+            Instruction::CALL {
+                head,
+                ref args,
+                target,
+            } => {
+                let call_site = *instruction_pointer;
+                let mut frame = self.new_frame(head);
+                for (idx, arg) in args.iter().enumerate() {
+                    frame.register_values[idx + 1] = self.reg(*arg).clone();
+                }
+                frame.return_register = target;
+
+                self.stack_frames[self.current_frame].instruction_pointer =
+                    InstructionId(*instruction_pointer + 1);
+                *instruction_pointer = frame.instruction_pointer.0;
+
+                self.stack_frames.push(frame);
+                self.current_frame += 1;
+
+                // Recursion can run forever without ever jumping backwards
+                if let Err(error) = self.check_limits(call_site) {
+                    return Some(Err(error));
+                }
+            }
+            Instruction::LOADGLOBAL { global, target } => {
+                let value =
+                    self.stack_frames[self.globals_frame].register_values[global + 1].clone();
+                self.set_reg(target, value);
+
+                *instruction_pointer += 1;
+            }
+            Instruction::STOREGLOBAL { global, source } => {
+                let value = self.reg(source).clone();
+                self.stack_frames[self.globals_frame].register_values[global + 1] = value;
+
+                *instruction_pointer += 1;
+            }
+            Instruction::NEWCLOSURE {
+                function,
+                ref captures,
+                target,
+            } => {
+                let captures = captures
+                    .iter()
+                    .map(|capture| self.capture_value(*capture))
+                    .collect();
+                let callback = Callback {
+                    script: self.script,
+                    function,
+                    captures,
+                };
+                self.set_reg_object(target, Box::new(callback));
 
                 *instruction_pointer += 1;
             }
-            Instruction::FDIV { lhs, rhs, target } => {
-                if self.get_reg_f64(rhs) == 0.0 {
-                    return Some(Err(
-                        self.error("division by zero", self.source_map[*instruction_pointer])
-                    ));
+            Instruction::CALLCLOSURE {
+                closure,
+                ref args,
+                target,
+            } => {
+                let call_site = *instruction_pointer;
+                let callback = self.callback(closure);
+                let mut frame = self.new_frame(callback.function);
+                let num_params = frame.num_params;
+                for (idx, arg) in args.iter().enumerate() {
+                    frame.register_values[idx + 1] = self.reg(*arg).clone();
                 }
+                frame.return_register = target;
 
-                self.stack_frames[self.current_frame].register_values[target.0].f64 =
-                    self.get_reg_f64(lhs) / self.get_reg_f64(rhs);
+                self.stack_frames[self.current_frame].instruction_pointer =
+                    InstructionId(*instruction_pointer + 1);
+                *instruction_pointer = frame.instruction_pointer.0;
 
-                *instruction_pointer += 1;
+                self.stack_frames.push(frame);
+                self.current_frame += 1;
+                self.unbox_captures(&callback, num_params);
+
+                if let Err(error) = self.check_limits(call_site) {
+                    return Some(Err(error));
+                }
             }
-            Instruction::FLT { lhs, rhs, target } => {
-                self.stack_frames[self.current_frame].register_values[target.0].bool =
-                    self.get_reg_f64(lhs) < self.get_reg_f64(rhs);
+            Instruction::NEWARRAY {
+                element_type,
+                ref items,
+                target,
+            } => {
+                let array: Value = match element_type {
+                    I64_TYPE => Box::new(
+                        items
+                            .iter()
+                            .map(|item| self.get_reg_i64(*item))
+                            .collect::<Vec<_>>(),
+                    ),
+                    F64_TYPE => Box::new(
+                        items
+                            .iter()
+                            .map(|item| self.get_reg_f64(*item))
+                            .collect::<Vec<_>>(),
+                    ),
+                    BOOL_TYPE => Box::new(
+                        items
+                            .iter()
+                            .map(|item| self.get_reg_bool(*item))
+                            .collect::<Vec<_>>(),
+                    ),
+                    STRING_TYPE => Box::new(
+                        items
+                            .iter()
+                            .map(|item| self.reg_str(*item).to_string())
+                            .collect::<Vec<_>>(),
+                    ),
+                    _ => panic!("internal error: unsupported array element type"),
+                };
+
+                self.set_reg_object(target, array);
 
                 *instruction_pointer += 1;
             }
-            Instruction::FLTE { lhs, rhs, target } => {
-                self.stack_frames[self.current_frame].register_values[target.0].bool =
-                    self.get_reg_f64(lhs) <= self.get_reg_f64(rhs);
+            Instruction::INDEX {
+                array,
+                index,
+                target,
+            } => {
+                let index = self.get_reg_i64(index);
+                let node_id = self.source_map[*instruction_pointer];
+
+                match self.stack_frames[self.current_frame].register_types[target.0] {
+                    I64_TYPE => match self.array_element::<i64>(array, index, node_id) {
+                        Ok(value) => self.set_reg(target, RegisterValue::I64(value)),
+                        Err(error) => return Some(Err(error)),
+                    },
+                    F64_TYPE => match self.array_element::<f64>(array, index, node_id) {
+                        Ok(value) => self.set_reg(target, RegisterValue::F64(value)),
+                        Err(error) => return Some(Err(error)),
+                    },
+                    BOOL_TYPE => match self.array_element::<bool>(array, index, node_id) {
+                        Ok(value) => self.set_reg(target, RegisterValue::Bool(value)),
+                        Err(error) => return Some(Err(error)),
+                    },
+                    STRING_TYPE => match self.array_element::<String>(array, index, node_id) {
+                        Ok(value) => self.set_reg_string(target, value),
+                        Err(error) => return Some(Err(error)),
+                    },
+                    _ => panic!("internal error: unsupported array element type"),
+                }
 
                 *instruction_pointer += 1;
             }
-            Instruction::FGT { lhs, rhs, target } => {
-                self.stack_frames[self.current_frame].register_values[target.0].bool =
-                    self.get_reg_f64(lhs) > self.get_reg_f64(rhs);
+            Instruction::ARRAYLEN {
+                array,
+                element_type,
+                target,
+            } => {
+                let len = match element_type {
+                    I64_TYPE => self.with_array::<i64, _>(array, Vec::len),
+                    F64_TYPE => self.with_array::<f64, _>(array, Vec::len),
+                    BOOL_TYPE => self.with_array::<bool, _>(array, Vec::len),
+                    STRING_TYPE => self.with_array::<String, _>(array, Vec::len),
+                    _ => panic!("internal error: unsupported array element type"),
+                };
+
+                self.set_reg(target, RegisterValue::I64(len as i64));
 
                 *instruction_pointer += 1;
             }
-            Instruction::FGTE { lhs, rhs, target } => {
-                self.stack_frames[self.current_frame].register_values[target.0].bool =
-                    self.get_reg_f64(lhs) >= self.get_reg_f64(rhs);
+            Instruction::NEWSTRUCT { ref fields, target } => {
+                let fields = fields
+                    .iter()
+                    .map(|field| self.field_value(*field))
+                    .collect();
+                self.set_reg_object(target, Box::new(ScriptStruct { fields }));
 
                 *instruction_pointer += 1;
             }
-            Instruction::MOV { target, source } => {
-                self.maybe_free_register(target);
-                self.stack_frames[self.current_frame].register_values[target.0] =
-                    self.stack_frames[self.current_frame].register_values[source.0];
+            Instruction::GETFIELD {
+                object,
+                field,
+                target,
+            } => {
+                let target_type = self.stack_frames[self.current_frame].register_types[target.0];
+                let value = self.with_script_struct(object, |object| {
+                    let value = &object.fields[field];
+                    let wrong_type = "internal error: field has the wrong type";
+
+                    match target_type {
+                        I64_TYPE => RegisterValue::I64(*value.downcast_ref().expect(wrong_type)),
+                        F64_TYPE => RegisterValue::F64(*value.downcast_ref().expect(wrong_type)),
+                        BOOL_TYPE => RegisterValue::Bool(*value.downcast_ref().expect(wrong_type)),
+                        STRING_TYPE => RegisterValue::String(Arc::new(
+                            value.downcast_ref::<String>().expect(wrong_type).clone(),
+                        )),
+                        _ => panic!("internal error: unsupported field type"),
+                    }
+                });
+                self.set_reg(target, value);
 
                 *instruction_pointer += 1;
             }
-            Instruction::BRIF {
-                condition,
-                then_branch,
-                else_branch,
+            Instruction::SETFIELD {
+                object,
+                field,
+                value,
             } => {
-                let condition = self.get_reg_bool(condition);
+                let value = self.field_value(value);
+                self.with_script_struct(object, |object| object.fields[field] = value);
 
-                if condition {
-                    *instruction_pointer = then_branch.0;
-                } else {
-                    *instruction_pointer = else_branch.0;
-                }
-            }
-            Instruction::JMP(location) => {
-                *instruction_pointer = location.0;
+                *instruction_pointer += 1;
             }
             Instruction::RET => {
-                if self.stack_frames.len() > 1 {
-                    self.stack_frames.pop();
+                if self.current_frame > self.entry_frame {
+                    // The frame's registers let go of their values when it's dropped, apart
+                    // from the return value, which now belongs to the caller
+                    let mut frame = self
+                        .stack_frames
+                        .pop()
+                        .expect("internal error: returning without a frame");
                     self.current_frame -= 1;
+
+                    let return_value = std::mem::take(&mut frame.register_values[0]);
+                    self.set_reg(frame.return_register, return_value);
+
                     *instruction_pointer =
                         self.stack_frames[self.current_frame].instruction_pointer.0;
                 } else {
-                    match self.stack_frames[self.current_frame].register_types[0] {
-                        UNIT_TYPE => return Some(Ok(ReturnValue::Unit)),
-                        BOOL_TYPE => {
-                            return Some(Ok(ReturnValue::Bool(self.get_reg_bool(RegisterId(0)))))
-                        }
-                        I64_TYPE => {
-                            return Some(Ok(ReturnValue::I64(self.get_reg_i64(RegisterId(0)))))
-                        }
-                        F64_TYPE => {
-                            return Some(Ok(ReturnValue::F64(self.get_reg_f64(RegisterId(0)))))
-                        }
-                        STRING_TYPE => {
-                            let string = self.get_reg_string(RegisterId(0));
-                            return Some(Ok(ReturnValue::String(*string)));
-                        }
-                        _ => {
-                            let value = self.get_user_type(RegisterId(0));
-                            return Some(Ok(ReturnValue::Custom(value)));
-                        }
-                    }
+                    return Some(self.take_return_value(*instruction_pointer));
                 }
             }
             _ => {
@@ -290,21 +1341,76 @@ impl Evaluator {
         starting_function: FunctionId,
         external_functions: &[ExternalFnRecord],
     ) -> Result<ReturnValue, ScriptError> {
-        self.current_frame = self.stack_frames.len();
-        self.stack_frames
-            .push(self.functions[starting_function.0].clone());
-        let mut instruction_pointer = self.stack_frames[self.current_frame].instruction_pointer.0;
+        self.eval_with_globals_async(starting_function, &mut vec![], external_functions)
+            .await
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn eval_with_globals_async(
+        &mut self,
+        starting_function: FunctionId,
+        globals: &mut Vec<Value>,
+        external_functions: &[ExternalFnRecord],
+    ) -> Result<ReturnValue, ScriptError> {
+        let num_globals = globals.len();
+        let (frame, instruction_pointer) = self.enter_main(starting_function, globals);
+
+        let result = self
+            .run_async(instruction_pointer, external_functions)
+            .await;
+
+        self.take_globals(frame, num_globals, globals);
+
+        result
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn call_async(
+        &mut self,
+        callback: &Callback,
+        args: Vec<Value>,
+        types: &[RuntimeType],
+        external_functions: &[ExternalFnRecord],
+    ) -> Result<ReturnValue, ScriptError> {
+        let base = self.stack_frames.len();
+        let result = match self.enter_callback(callback, args, types) {
+            Ok(instruction_pointer) => {
+                self.run_async(instruction_pointer, external_functions)
+                    .await
+            }
+            Err(error) => Err(error),
+        };
+
+        self.finish_callback(base, result)
+    }
+
+    #[cfg(feature = "async")]
+    async fn run_async(
+        &mut self,
+        mut instruction_pointer: usize,
+        external_functions: &[ExternalFnRecord],
+    ) -> Result<ReturnValue, ScriptError> {
+        self.start_run();
 
         loop {
+            self.instructions_run += 1;
+
             match &self.instructions[instruction_pointer] {
                 Instruction::EXTERNALCALL { head, args, target } => {
                     let target = *target;
 
+                    let mut boxed_args = self.take_args(args);
                     let output = self
-                        .eval_external_call_async(*head, args, external_functions)
+                        .eval_external_call_async(
+                            instruction_pointer,
+                            *head,
+                            &mut boxed_args,
+                            external_functions,
+                        )
                         .await;
+                    self.restore_args(instruction_pointer, boxed_args);
 
-                    self.unbox_to_register(output, target);
+                    self.unbox_to_register(output?, target);
                     instruction_pointer += 1;
                 }
                 _ => {
@@ -321,24 +1427,237 @@ impl Evaluator {
         starting_function: FunctionId,
         external_functions: &[ExternalFnRecord],
     ) -> Result<ReturnValue, ScriptError> {
+        self.eval_with_globals(starting_function, &mut vec![], external_functions)
+    }
+
+    /// Run a script with its globals loaded into the registers after its return register
+    ///
+    /// The globals are handed back afterwards, with any changes the script made to them, whether
+    /// the run succeeded or not.
+    pub fn eval_with_globals(
+        &mut self,
+        starting_function: FunctionId,
+        globals: &mut Vec<Value>,
+        external_functions: &[ExternalFnRecord],
+    ) -> Result<ReturnValue, ScriptError> {
+        let num_globals = globals.len();
+        let (frame, instruction_pointer) = self.enter_main(starting_function, globals);
+
+        let result = self.run(instruction_pointer, external_functions);
+
+        self.take_globals(frame, num_globals, globals);
+
+        result
+    }
+
+    /// Push the frame a run starts in, loading the globals into it, and return the frame along
+    /// with where its code starts
+    fn enter_main(
+        &mut self,
+        starting_function: FunctionId,
+        globals: &mut Vec<Value>,
+    ) -> (usize, usize) {
+        self.current_frame = self.stack_frames.len();
+        self.entry_frame = self.current_frame;
+        self.ends_entry_frame = true;
+        self.num_globals = globals.len();
+        self.globals_frame = self.current_frame;
+        self.stack_frames.push(self.new_frame(starting_function));
+
+        for (idx, global) in globals.drain(..).enumerate() {
+            self.unbox_to_register(global, RegisterId(idx + 1));
+        }
+
+        let instruction_pointer = self.stack_frames[self.current_frame].instruction_pointer.0;
+
+        (self.current_frame, instruction_pointer)
+    }
+
+    /// Take the globals back out of a run's first frame, which ends the run's frames
+    fn take_globals(&mut self, frame: usize, num_globals: usize, globals: &mut Vec<Value>) {
+        let values: Vec<_> = self.stack_frames[frame].register_values[1..=num_globals]
+            .iter_mut()
+            .map(std::mem::take)
+            .collect();
+
+        // Dropping the frames first lets go of any other registers holding the globals
+        self.stack_frames.truncate(frame);
+        self.current_frame = frame.saturating_sub(1);
+
+        globals.extend(values.into_iter().map(into_value));
+    }
+
+    /// Call a closure that the script running in this evaluator created
+    ///
+    /// Any frames the script left behind, like a session's main function, stay as they are.
+    pub fn call(
+        &mut self,
+        callback: &Callback,
+        args: Vec<Value>,
+        types: &[RuntimeType],
+        external_functions: &[ExternalFnRecord],
+    ) -> Result<ReturnValue, ScriptError> {
+        let base = self.stack_frames.len();
+        let result = match self.enter_callback(callback, args, types) {
+            Ok(instruction_pointer) => self.run(instruction_pointer, external_functions),
+            Err(error) => Err(error),
+        };
+
+        self.finish_callback(base, result)
+    }
+
+    /// Push a frame for a call to a closure from Rust, returning where its code starts
+    ///
+    /// Unlike a call from the script, the frame owns its arguments, as there's no caller's
+    /// registers to borrow them from.
+    fn enter_callback(
+        &mut self,
+        callback: &Callback,
+        args: Vec<Value>,
+        types: &[RuntimeType],
+    ) -> Result<usize, ScriptError> {
+        if callback.script != self.script
+            || callback.function.0 == 0
+            || callback.function.0 >= self.functions.len()
+        {
+            return Err(ScriptError {
+                message: "callback was created by a different script".into(),
+                span: Span { start: 0, end: 0 },
+                kind: ErrorKind::Script,
+            });
+        }
+
+        let mut frame = self.new_frame(callback.function);
+        let entry = frame.instruction_pointer.0;
+        let num_params = frame.num_params;
+
+        if args.len() != num_params {
+            return Err(self.error(
+                format!(
+                    "closure takes {} argument(s) but {} were given",
+                    num_params,
+                    args.len()
+                ),
+                self.source_map[entry],
+            ));
+        }
+        for (idx, arg) in args.iter().enumerate() {
+            if !self.has_type(arg, frame.register_types[idx + 1], types) {
+                return Err(self.error(
+                    format!("argument {} has the wrong type for the closure", idx + 1),
+                    self.source_map[entry],
+                ));
+            }
+        }
+
+        frame.return_register = RegisterId(0);
+
         self.current_frame = self.stack_frames.len();
-        self.stack_frames
-            .push(self.functions[starting_function.0].clone());
-        let mut instruction_pointer = self.stack_frames[self.current_frame].instruction_pointer.0;
+        self.entry_frame = self.current_frame;
+        self.ends_entry_frame = true;
+        self.num_globals = 0;
+        self.stack_frames.push(frame);
+
+        for (idx, arg) in args.into_iter().enumerate() {
+            self.unbox_to_register(arg, RegisterId(idx + 1));
+        }
+        self.unbox_captures(callback, num_params);
+
+        Ok(entry)
+    }
+
+    /// Whether a value from Rust is held the way the script holds values of the given type
+    fn has_type(&self, value: &Value, type_id: TypeId, types: &[RuntimeType]) -> bool {
+        match types.get(type_id.0) {
+            Some(RuntimeType::Rust(rust_type)) => (**value).type_id() == *rust_type,
+            Some(RuntimeType::Struct(fields)) => {
+                value.downcast_ref::<ScriptStruct>().is_some_and(|value| {
+                    value.fields.len() == fields.len()
+                        && (value.fields.iter().zip(fields))
+                            .all(|(field, field_type)| self.has_type(field, *field_type, types))
+                })
+            }
+            Some(RuntimeType::Function(params, ret)) => {
+                value.downcast_ref::<Callback>().is_some_and(|callback| {
+                    let Some(function) = self.functions.get(callback.function.0) else {
+                        return false;
+                    };
+
+                    callback.script == self.script
+                        && function.num_params == params.len()
+                        && function.register_types[0] == *ret
+                        && function.register_types[1..=params.len()] == params[..]
+                })
+            }
+            None => false,
+        }
+    }
+
+    /// Unwind the frames of a call from Rust, including any calls an error interrupted
+    fn finish_callback(
+        &mut self,
+        base: usize,
+        result: Result<ReturnValue, ScriptError>,
+    ) -> Result<ReturnValue, ScriptError> {
+        self.stack_frames.truncate(base);
+        self.current_frame = base.saturating_sub(1);
+
+        result
+    }
+
+    /// Copy a closure's captures into the current frame, in the registers after its parameters
+    fn unbox_captures(&mut self, callback: &Callback, num_params: usize) {
+        for (idx, capture) in callback.captures.iter().enumerate() {
+            let capture = clone_script_value(capture)
+                .expect("internal error: closure captured a value that can't be copied");
+            self.unbox_to_register(capture, RegisterId(num_params + idx + 1));
+        }
+    }
+
+    /// Copy the value in the given register so a closure can capture it
+    fn capture_value(&self, register_id: RegisterId) -> Value {
+        match self.stack_frames[self.current_frame].register_types[register_id.0] {
+            I64_TYPE => Box::new(self.get_reg_i64(register_id)),
+            F64_TYPE => Box::new(self.get_reg_f64(register_id)),
+            BOOL_TYPE => Box::new(self.get_reg_bool(register_id)),
+            STRING_TYPE => Box::new(self.reg_str(register_id).to_string()),
+            _ => clone_script_value(&self.object(register_id))
+                .expect("internal error: closure captured a value that can't be copied"),
+        }
+    }
+
+    /// Copy the closure held in the given register
+    fn callback(&self, register_id: RegisterId) -> Callback {
+        self.object(register_id)
+            .downcast_ref::<Callback>()
+            .expect("internal error: closure register holds a different type")
+            .clone()
+    }
+
+    fn run(
+        &mut self,
+        mut instruction_pointer: usize,
+        external_functions: &[ExternalFnRecord],
+    ) -> Result<ReturnValue, ScriptError> {
+        self.start_run();
 
         loop {
+            self.instructions_run += 1;
+
             match &self.instructions[instruction_pointer] {
                 Instruction::EXTERNALCALL { head, args, target } => {
                     let target = *target;
 
+                    let mut boxed_args = self.take_args(args);
                     let output = self.eval_external_call(
                         instruction_pointer,
                         *head,
-                        args,
+                        &mut boxed_args,
                         external_functions,
-                    )?;
+                    );
+                    self.restore_args(instruction_pointer, boxed_args);
 
-                    self.unbox_to_register(output, target);
+                    self.unbox_to_register(output?, target);
                     instruction_pointer += 1;
                 }
                 _ => {
@@ -354,75 +1673,14 @@ impl Evaluator {
         &self,
         instruction_pointer: usize,
         head: ExternalFunctionId,
-        args: &[RegisterId],
+        args: &mut [Value],
         functions: &[ExternalFnRecord],
     ) -> Result<Value, ScriptError> {
-        #[allow(unreachable_patterns)]
         match &functions[head.0].fun {
-            Function::ExternalFn0(fun) => match fun() {
+            Function::ExternalFn(fun) => match fun(args) {
                 Ok(val) => Ok(val),
                 Err(error) => Err(self.error(error, self.source_map[instruction_pointer])),
             },
-            Function::ExternalFn1(fun) => {
-                let mut arg0 = self.box_register(args[0]);
-
-                let result = match fun(&mut arg0) {
-                    Ok(val) => Ok(val),
-                    Err(error) => Err(self.error(error, self.source_map[instruction_pointer])),
-                };
-
-                if self.is_heap_type(args[0]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg0);
-                }
-
-                result
-            }
-            Function::ExternalFn2(fun) => {
-                let mut arg0 = self.box_register(args[0]);
-                let mut arg1 = self.box_register(args[1]);
-
-                let result = match fun(&mut arg0, &mut arg1) {
-                    Ok(val) => Ok(val),
-                    Err(error) => Err(self.error(error, self.source_map[instruction_pointer])),
-                };
-
-                if self.is_heap_type(args[0]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg0);
-                }
-                if self.is_heap_type(args[1]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg1);
-                }
-
-                result
-            }
-            Function::ExternalFn3(fun) => {
-                let mut arg0 = self.box_register(args[0]);
-                let mut arg1 = self.box_register(args[1]);
-                let mut arg2 = self.box_register(args[2]);
-
-                let result = match fun(&mut arg0, &mut arg1, &mut arg2) {
-                    Ok(val) => Ok(val),
-                    Err(error) => Err(self.error(error, self.source_map[instruction_pointer])),
-                };
-
-                if self.is_heap_type(args[0]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg0);
-                }
-                if self.is_heap_type(args[1]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg1);
-                }
-                if self.is_heap_type(args[2]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg2);
-                }
-
-                result
-            }
             _ => unreachable!(),
         }
     }
@@ -430,196 +1688,110 @@ impl Evaluator {
     #[cfg(feature = "async")]
     async fn eval_external_call_async(
         &self,
+        instruction_pointer: usize,
         head: ExternalFunctionId,
-        args: &[RegisterId],
+        args: &mut [Value],
         functions: &[ExternalFnRecord],
-    ) -> Value {
-        match &functions[head.0].fun {
-            Function::ExternalFn0(fun) => fun().unwrap(),
-            Function::ExternalFn1(fun) => {
-                let mut arg0 = self.box_register(args[0]);
-
-                let result = fun(&mut arg0).unwrap();
-
-                if self.is_heap_type(args[0]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg0);
-                }
-
-                result
-            }
-            Function::ExternalFn2(fun) => {
-                let mut arg0 = self.box_register(args[0]);
-                let mut arg1 = self.box_register(args[1]);
-
-                let result = fun(&mut arg0, &mut arg1).unwrap();
-
-                if self.is_heap_type(args[0]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg0);
-                }
-                if self.is_heap_type(args[1]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg1);
-                }
-
-                result
-            }
-            Function::ExternalFn3(fun) => {
-                let mut arg0 = self.box_register(args[0]);
-                let mut arg1 = self.box_register(args[1]);
-                let mut arg2 = self.box_register(args[2]);
-
-                let result = fun(&mut arg0, &mut arg1, &mut arg2).unwrap();
-
-                if self.is_heap_type(args[0]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg0);
-                }
-                if self.is_heap_type(args[1]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg1);
-                }
-                if self.is_heap_type(args[2]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg2);
-                }
-
-                result
-            }
-            Function::ExternalFn4(fun) => {
-                let mut arg0 = self.box_register(args[0]);
-                let mut arg1 = self.box_register(args[1]);
-                let mut arg2 = self.box_register(args[2]);
-                let mut arg3 = self.box_register(args[3]);
-
-                let result = fun(&mut arg0, &mut arg1, &mut arg2, &mut arg3).unwrap();
-
-                if self.is_heap_type(args[0]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg0);
-                }
-                if self.is_heap_type(args[1]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg1);
-                }
-                if self.is_heap_type(args[2]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg2);
-                }
-                if self.is_heap_type(args[3]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg3);
-                }
-
-                result
-            }
-            Function::ExternalAsyncFn0(fun) => fun().await.unwrap(),
-            Function::ExternalAsyncFn1(fun) => {
-                let mut arg0 = self.box_register(args[0]);
-                let result = fun(&mut arg0).await.unwrap();
-
-                if self.is_heap_type(args[0]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg0);
-                }
-
-                result
-            }
-            Function::ExternalAsyncFn2(fun) => {
-                let mut arg0 = self.box_register(args[0]);
-                let mut arg1 = self.box_register(args[1]);
-                let result = fun(&mut arg0, &mut arg1).await.unwrap();
-
-                if self.is_heap_type(args[0]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg0);
-                }
-                if self.is_heap_type(args[1]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg1);
-                }
+    ) -> Result<Value, ScriptError> {
+        let result = match &functions[head.0].fun {
+            Function::ExternalFn(fun) => fun(args),
+            Function::ExternalAsyncFn(fun) => fun(args).await,
+            Function::RemoteFn => unreachable!("lsp instances of engines cannot evaluate scripts or remotely invoke registered functions"),
+        };
 
-                result
-            }
-            Function::ExternalAsyncFn3(fun) => {
-                let mut arg0 = self.box_register(args[0]);
-                let mut arg1 = self.box_register(args[1]);
-                let mut arg2 = self.box_register(args[2]);
-                let result = fun(&mut arg0, &mut arg1, &mut arg2).await.unwrap();
+        match result {
+            Ok(val) => Ok(val),
+            Err(error) => Err(self.error(error, self.source_map[instruction_pointer])),
+        }
+    }
 
-                if self.is_heap_type(args[0]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg0);
-                }
-                if self.is_heap_type(args[1]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg1);
-                }
-                if self.is_heap_type(args[2]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg2);
-                }
+    /// Take the arguments of an external call out of their registers, so they can be handed to
+    /// the registered function
+    ///
+    /// Numbers, bools and strings are copied. Other values are moved out of their shared slots
+    /// for the duration of the call, and put back afterwards by `restore_args`. A value passed
+    /// more than once is handed over once, with the later arguments pointing back to the first
+    /// one through a `SharedArg`.
+    fn take_args(&self, args: &[RegisterId]) -> Vec<Value> {
+        let mut boxed_args: Vec<Value> = Vec::with_capacity(args.len());
+
+        for (idx, arg) in args.iter().enumerate() {
+            let boxed: Value = match self.reg(*arg) {
+                RegisterValue::Unit => Box::new(()),
+                RegisterValue::I64(value) => Box::new(*value),
+                RegisterValue::F64(value) => Box::new(*value),
+                RegisterValue::Bool(value) => Box::new(*value),
+                RegisterValue::String(string) => Box::new(String::clone(string)),
+                RegisterValue::Object(object) => match self.earlier_arg(args, idx) {
+                    Some(earlier) => Box::new(SharedArg(earlier)),
+                    None => std::mem::replace(&mut *lock(object), Box::new(())),
+                },
+            };
+            boxed_args.push(boxed);
+        }
 
-                result
-            }
-            Function::ExternalAsyncFn4(fun) => {
-                let mut arg0 = self.box_register(args[0]);
-                let mut arg1 = self.box_register(args[1]);
-                let mut arg2 = self.box_register(args[2]);
-                let mut arg3 = self.box_register(args[3]);
-                let result = fun(&mut arg0, &mut arg1, &mut arg2, &mut arg3).await.unwrap();
+        boxed_args
+    }
 
-                if self.is_heap_type(args[0]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg0);
-                }
-                if self.is_heap_type(args[1]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg1);
-                }
-                if self.is_heap_type(args[2]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg2);
+    /// Put the arguments of the external call at the given instruction back in their registers
+    ///
+    /// Only the first argument can be changed by the call, as it's the only one a registered
+    /// function can take by mutable reference.
+    fn restore_args(&mut self, instruction_pointer: usize, boxed_args: Vec<Value>) {
+        let Instruction::EXTERNALCALL { args, .. } = &self.instructions[instruction_pointer] else {
+            panic!("internal error: restoring the arguments of something other than a call")
+        };
+        let frame = &mut self.stack_frames[self.current_frame];
+
+        for (idx, (arg, boxed)) in args.iter().zip(boxed_args).enumerate() {
+            match &frame.register_values[arg.0] {
+                RegisterValue::String(string) if idx == 0 => {
+                    if let Ok(value) = boxed.downcast::<String>() {
+                        if *value != **string {
+                            frame.register_values[arg.0] = RegisterValue::String(Arc::new(*value));
+                        }
+                    }
                 }
-                if self.is_heap_type(args[3]) {
-                    // We leak the box here because we manually clean it up later
-                    Box::leak(arg3);
+                RegisterValue::Object(object) => {
+                    let is_first = !args[..idx].iter().any(|earlier| {
+                        matches!(
+                            &frame.register_values[earlier.0],
+                            RegisterValue::Object(other) if Arc::ptr_eq(object, other)
+                        )
+                    });
+                    if is_first {
+                        *lock(object) = boxed;
+                    }
                 }
-
-                result
+                _ => {}
             }
-            Function::RemoteFn => unreachable!("lsp instances of engines cannot evaluate scripts or remotely invoke registered functions"),
         }
     }
 
-    pub fn box_register(&self, register_id: RegisterId) -> Value {
-        if self.stack_frames[self.current_frame].register_types[register_id.0] == F64_TYPE {
-            let val = self.get_reg_f64(register_id);
-            Box::new(val)
-        } else if self.stack_frames[self.current_frame].register_types[register_id.0] == BOOL_TYPE {
-            let val = self.get_reg_bool(register_id);
-            Box::new(val)
-        } else if self.stack_frames[self.current_frame].register_types[register_id.0] == I64_TYPE {
-            Box::new(self.get_reg_i64(register_id))
-        } else if self.stack_frames[self.current_frame].register_types[register_id.0] == STRING_TYPE
-        {
-            self.get_reg_string(register_id)
-        } else {
-            self.get_user_type(register_id)
-        }
+    /// The index of an earlier argument that holds the same shared value as the one at `idx`
+    fn earlier_arg(&self, args: &[RegisterId], idx: usize) -> Option<usize> {
+        let RegisterValue::Object(object) = self.reg(args[idx]) else {
+            return None;
+        };
+
+        args[..idx].iter().position(|earlier| {
+            matches!(
+                self.reg(*earlier),
+                RegisterValue::Object(other) if Arc::ptr_eq(object, other)
+            )
+        })
     }
 
     pub fn unbox_to_register(&mut self, value: Value, target: RegisterId) {
         if self.stack_frames[self.current_frame].register_types[target.0] == F64_TYPE {
             if let Ok(value) = value.downcast::<f64>() {
-                self.stack_frames[self.current_frame].register_values[target.0].f64 = *value;
+                self.set_reg(target, RegisterValue::F64(*value));
             } else {
                 panic!("internal error: could not properly handle conversion of register to f64")
             }
         } else if self.stack_frames[self.current_frame].register_types[target.0] == BOOL_TYPE {
             if let Ok(value) = value.downcast::<bool>() {
-                self.stack_frames[self.current_frame].register_values[target.0].bool = *value;
+                self.set_reg(target, RegisterValue::Bool(*value));
             } else {
                 panic!("internal error: could not properly handle conversion of register to bool")
             }
@@ -627,51 +1799,18 @@ impl Evaluator {
             // Ignore this case, as void creates no changes
         } else if self.stack_frames[self.current_frame].register_types[target.0] == I64_TYPE {
             if let Ok(value) = value.downcast::<i64>() {
-                self.stack_frames[self.current_frame].register_values[target.0].i64 = *value;
+                self.set_reg(target, RegisterValue::I64(*value));
             } else {
                 panic!("internal error: could not properly handle conversion of register to i64")
             }
+        } else if self.stack_frames[self.current_frame].register_types[target.0] == STRING_TYPE {
+            if let Ok(value) = value.downcast::<String>() {
+                self.set_reg_string(target, *value);
+            } else {
+                panic!("internal error: could not properly handle conversion of register to string")
+            }
         } else {
-            self.maybe_free_register(target);
-            self.stack_frames[self.current_frame].register_values[target.0].i64 =
-                unsafe { std::mem::transmute::<Box<Value>, i64>(Box::new(value)) };
-        }
-    }
-
-    fn maybe_free_register(&mut self, target: RegisterId) {
-        let target_type = self.stack_frames[self.current_frame].register_types[target.0];
-        if self.is_string_type(target)
-            && unsafe { self.stack_frames[self.current_frame].register_values[target.0].i64 != 0 }
-            && !self.stack_frames[self.current_frame]
-                .register_values
-                .iter()
-                .enumerate()
-                .any(|(idx, x)| {
-                    idx != target.0
-                        && self.stack_frames[self.current_frame].register_types[idx] == target_type
-                        && unsafe {
-                            self.stack_frames[self.current_frame].register_values[target.0].i64
-                                == x.i64
-                        }
-                })
-        {
-            let _ = self.get_reg_string(target);
-        } else if self.is_user_type(target)
-            && unsafe { self.stack_frames[self.current_frame].register_values[target.0].i64 != 0 }
-            && !self.stack_frames[self.current_frame]
-                .register_values
-                .iter()
-                .enumerate()
-                .any(|(idx, x)| {
-                    idx != target.0
-                        && self.stack_frames[self.current_frame].register_types[idx] == target_type
-                        && unsafe {
-                            self.stack_frames[self.current_frame].register_values[target.0].i64
-                                == x.i64
-                        }
-                })
-        {
-            let _ = self.get_user_type(target);
+            self.set_reg_object(target, value);
         }
     }
 
@@ -687,21 +1826,10 @@ impl Evaluator {
             .iter()
             .enumerate()
         {
-            if self.stack_frames[self.current_frame].register_types[idx] == F64_TYPE {
-                println!(
-                    "    {}: {} ({:?})",
-                    idx,
-                    unsafe { value.f64 },
-                    self.stack_frames[self.current_frame].register_types[idx]
-                );
-            } else {
-                println!(
-                    "    {}: {} ({:?})",
-                    idx,
-                    unsafe { value.i64 },
-                    self.stack_frames[self.current_frame].register_types[idx]
-                );
-            }
+            println!(
+                "    {}: {:?} ({:?})",
+                idx, value, self.stack_frames[self.current_frame].register_types[idx]
+            );
         }
     }
 
@@ -717,36 +1845,36 @@ impl Evaluator {
             .iter()
             .enumerate()
         {
-            if self.stack_frames[self.current_frame].register_types[idx] == F64_TYPE {
-                println!(
-                    "    {}: {} ({})",
-                    idx,
-                    unsafe { value.f64 },
-                    typechecker
-                        .stringify_type(self.stack_frames[self.current_frame].register_types[idx])
-                );
-            } else {
-                println!(
-                    "    {}: {} ({})",
-                    idx,
-                    unsafe { value.i64 },
-                    typechecker
-                        .stringify_type(self.stack_frames[self.current_frame].register_types[idx])
-                );
-            }
+            println!(
+                "    {}: {:?} ({})",
+                idx,
+                value,
+                typechecker
+                    .stringify_type(self.stack_frames[self.current_frame].register_types[idx])
+            );
         }
     }
 
-    pub fn is_heap_type(&self, register_id: RegisterId) -> bool {
-        self.is_string_type(register_id) || self.is_user_type(register_id)
-    }
-
-    pub fn is_string_type(&self, register_id: RegisterId) -> bool {
-        self.stack_frames[self.current_frame].register_types[register_id.0].0 == STRING_TYPE.0
-    }
-
-    pub fn is_user_type(&self, register_id: RegisterId) -> bool {
-        self.stack_frames[self.current_frame].register_types[register_id.0].0 > STRING_TYPE.0
+    /// Picks the result of an integer operation for the current `ArithmeticMode`, reporting an
+    /// overflow at the operator in checked mode
+    fn integer_op(
+        &self,
+        instruction_pointer: usize,
+        operation: &str,
+        checked: impl FnOnce() -> Option<i64>,
+        wrapping: impl FnOnce() -> i64,
+        saturating: impl FnOnce() -> i64,
+    ) -> Result<i64, ScriptError> {
+        match self.arithmetic {
+            ArithmeticMode::Checked => checked().ok_or_else(|| {
+                self.error(
+                    format!("attempt to {operation} with overflow"),
+                    self.source_map[instruction_pointer],
+                )
+            }),
+            ArithmeticMode::Wrapping => Ok(wrapping()),
+            ArithmeticMode::Saturating => Ok(saturating()),
+        }
     }
 
     pub fn error(&self, msg: impl Into<String>, node_id: NodeId) -> ScriptError {
@@ -755,6 +1883,31 @@ impl Evaluator {
         ScriptError {
             message: msg.into(),
             span,
+            kind: ErrorKind::Script,
         }
     }
 }
+
+/// Lock a value that lives in a shared slot
+///
+/// A registered function that panicked can't have left the value half changed, as it's taken out
+/// of its slot for the call, so a poisoned lock is fine to use.
+fn lock(object: &Mutex<Value>) -> MutexGuard<'_, Value> {
+    object.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Turn the contents of a register into a value for the host
+fn into_value(value: RegisterValue) -> Value {
+    match value {
+        RegisterValue::Unit => Box::new(()),
+        RegisterValue::I64(value) => Box::new(value),
+        RegisterValue::F64(value) => Box::new(value),
+        RegisterValue::Bool(value) => Box::new(value),
+        RegisterValue::String(string) => Box::new(Arc::unwrap_or_clone(string)),
+        RegisterValue::Object(object) => match Arc::try_unwrap(object) {
+            Ok(object) => object.into_inner().unwrap_or_else(PoisonError::into_inner),
+            // Whatever else still holds the value is left with unit in its place
+            Err(object) => std::mem::replace(&mut *lock(&object), Box::new(())),
+        },
+    }
+}
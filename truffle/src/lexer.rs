@@ -1,5 +1,5 @@
 use crate::{
-    errors::{ErrorBatch, ScriptError},
+    errors::{ErrorBatch, ErrorKind, ScriptError},
     parser::Span,
 };
 
@@ -9,13 +9,14 @@ pub struct Lexer {
     pub errors: ErrorBatch,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TokenType {
     Number,
     Comma,
     String,
     Dot,
     DotDot,
+    DotDotEquals,
     Name,
     Pipe,
     PipePipe,
@@ -26,6 +27,7 @@ pub enum TokenType {
     PlusEquals,
     Dash,
     DashEquals,
+    DashGreaterThan,
     Exclamation,
     Asterisk,
     AsteriskEquals,
@@ -35,6 +37,7 @@ pub enum TokenType {
     ForwardSlashForwardSlash,
     Equals,
     EqualsEquals,
+    EqualsGreaterThan,
     EqualsTilde,
     ExclamationTilde,
     ExclamationEquals,
@@ -43,13 +46,18 @@ pub enum TokenType {
     LCurly,
     LessThan,
     LessThanEqual,
+    LessThanLessThan,
     RParen,
     RSquare,
     RCurly,
     GreaterThan,
     GreaterThanEqual,
+    GreaterThanGreaterThan,
     Ampersand,
     AmpersandAmpersand,
+    Percent,
+    Caret,
+    Question,
 
     // Unknown token
     Garbage,
@@ -61,10 +69,130 @@ pub struct Token {
     pub span: Span,
 }
 
+/// A piece of a string literal
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StringPart {
+    /// Text, with its escape sequences still in it
+    Text(Span),
+
+    /// An expression between `{` and `}`, not including the braces
+    Interpolation(Span),
+}
+
+/// Split the contents of a string literal, between its quotes, into text and interpolations
+///
+/// Escape sequences are checked along the way, so the text parts can be unescaped later without
+/// failing.
+pub fn split_string(source: &[u8], span: Span) -> Result<Vec<StringPart>, ScriptError> {
+    let error = |message: &str, start: usize, end: usize| ScriptError {
+        message: message.into(),
+        span: Span { start, end },
+        kind: ErrorKind::Script,
+    };
+
+    let mut parts = vec![];
+    let mut text_start = span.start;
+    let mut current_position = span.start;
+    while current_position < span.end {
+        match source[current_position] {
+            b'\\' => {
+                if current_position + 1 >= span.end {
+                    return Err(error(
+                        "unfinished escape sequence",
+                        current_position,
+                        span.end,
+                    ));
+                }
+                if !matches!(
+                    source[current_position + 1],
+                    b'n' | b'r' | b't' | b'0' | b'\\' | b'"' | b'\'' | b'{' | b'}'
+                ) {
+                    return Err(error(
+                        "unknown escape sequence",
+                        current_position,
+                        current_position + 2,
+                    ));
+                }
+                current_position += 2;
+            }
+            b'{' => {
+                let interpolation_start = current_position + 1;
+                let mut depth = 1;
+                current_position += 1;
+                while current_position < span.end {
+                    match source[current_position] {
+                        b'{' => depth += 1,
+                        b'}' => depth -= 1,
+                        _ => {}
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                    current_position += 1;
+                }
+                if depth > 0 {
+                    return Err(error(
+                        "unclosed interpolation, expected '}'",
+                        interpolation_start - 1,
+                        span.end,
+                    ));
+                }
+
+                if text_start < interpolation_start - 1 {
+                    parts.push(StringPart::Text(Span {
+                        start: text_start,
+                        end: interpolation_start - 1,
+                    }));
+                }
+                parts.push(StringPart::Interpolation(Span {
+                    start: interpolation_start,
+                    end: current_position,
+                }));
+
+                current_position += 1;
+                text_start = current_position;
+            }
+            _ => current_position += 1,
+        }
+    }
+
+    if text_start < span.end || parts.is_empty() {
+        parts.push(StringPart::Text(Span {
+            start: text_start,
+            end: span.end,
+        }));
+    }
+
+    Ok(parts)
+}
+
+/// Replace the escape sequences in text that `split_string` has already checked
+pub fn unescape(text: &[u8]) -> String {
+    let mut output = Vec::with_capacity(text.len());
+    let mut bytes = text.iter();
+    while let Some(byte) = bytes.next() {
+        if *byte != b'\\' {
+            output.push(*byte);
+            continue;
+        }
+
+        match bytes.next() {
+            Some(b'n') => output.push(b'\n'),
+            Some(b'r') => output.push(b'\r'),
+            Some(b't') => output.push(b'\t'),
+            Some(b'0') => output.push(b'\0'),
+            Some(escaped) => output.push(*escaped),
+            None => {}
+        }
+    }
+
+    String::from_utf8(output).expect("internal error: string literal could not be parsed")
+}
+
 fn is_symbol(b: u8) -> bool {
     [
         b'+', b'-', b'*', b'/', b'.', b',', b'(', b'[', b'{', b'<', b')', b']', b'}', b'>', b':',
-        b';', b'=', b'$', b'|', b'!', b'~', b'&', b'\'', b'"',
+        b';', b'=', b'$', b'|', b'!', b'~', b'&', b'\'', b'"', b'%', b'^', b'?',
     ]
     .contains(&b)
 }
@@ -82,6 +210,7 @@ impl Lexer {
         self.errors.push(ScriptError {
             message: message.into(),
             span,
+            kind: ErrorKind::Script,
         })
     }
 
@@ -89,6 +218,7 @@ impl Lexer {
         let start = self.span_offset;
         let mut current_position = self.span_offset + 1;
         let mut is_escaped = false;
+        let mut is_terminated = false;
         while current_position < self.source.len() {
             if is_escaped {
                 is_escaped = false;
@@ -96,6 +226,7 @@ impl Lexer {
                 is_escaped = true;
             } else if self.source[current_position] == b'"' {
                 current_position += 1;
+                is_terminated = true;
                 break;
             }
             current_position += 1;
@@ -103,6 +234,24 @@ impl Lexer {
 
         self.span_offset = current_position;
 
+        if !is_terminated {
+            self.error(
+                "unterminated string",
+                Span {
+                    start,
+                    end: current_position,
+                },
+            );
+        } else if let Err(error) = split_string(
+            &self.source,
+            Span {
+                start: start + 1,
+                end: current_position - 1,
+            },
+        ) {
+            self.errors.push(error);
+        }
+
         Some(Token {
             token_type: TokenType::String,
             span: Span {
@@ -194,7 +343,7 @@ impl Lexer {
 
     pub fn skip_space(&mut self) {
         let mut current_position = self.span_offset;
-        let whitespace: &[u8] = &[b' ', b'\t', b'\r', b'\n'];
+        let whitespace: &[u8] = b" \t\r\n";
         while current_position < self.source.len() {
             if !whitespace.contains(&self.source[current_position]) {
                 break;
@@ -264,6 +413,16 @@ impl Lexer {
             },
             b'<' => {
                 if self.source.len() > self.span_offset + 1
+                    && self.source[self.span_offset + 1] == b'<'
+                {
+                    Token {
+                        token_type: TokenType::LessThanLessThan,
+                        span: Span {
+                            start,
+                            end: start + 2,
+                        },
+                    }
+                } else if self.source.len() > self.span_offset + 1
                     && self.source[self.span_offset + 1] == b'='
                 {
                     Token {
@@ -306,6 +465,16 @@ impl Lexer {
             },
             b'>' => {
                 if self.source.len() > self.span_offset + 1
+                    && self.source[self.span_offset + 1] == b'>'
+                {
+                    Token {
+                        token_type: TokenType::GreaterThanGreaterThan,
+                        span: Span {
+                            start,
+                            end: start + 2,
+                        },
+                    }
+                } else if self.source.len() > self.span_offset + 1
                     && self.source[self.span_offset + 1] == b'='
                 {
                     Token {
@@ -367,6 +536,16 @@ impl Lexer {
                             end: start + 2,
                         },
                     }
+                } else if self.source.len() > self.span_offset + 1
+                    && self.source[self.span_offset + 1] == b'>'
+                {
+                    Token {
+                        token_type: TokenType::DashGreaterThan,
+                        span: Span {
+                            start,
+                            end: start + 2,
+                        },
+                    }
                 } else {
                     Token {
                         token_type: TokenType::Dash,
@@ -450,6 +629,16 @@ impl Lexer {
                             end: start + 2,
                         },
                     }
+                } else if self.source.len() > self.span_offset + 1
+                    && self.source[self.span_offset + 1] == b'>'
+                {
+                    Token {
+                        token_type: TokenType::EqualsGreaterThan,
+                        span: Span {
+                            start,
+                            end: start + 2,
+                        },
+                    }
                 } else if self.source.len() > self.span_offset + 1
                     && self.source[self.span_offset + 1] == b'~'
                 {
@@ -485,7 +674,18 @@ impl Lexer {
                 },
             },
             b'.' => {
-                if self.source.len() > self.span_offset + 1
+                if self.source.len() > self.span_offset + 2
+                    && self.source[self.span_offset + 1] == b'.'
+                    && self.source[self.span_offset + 2] == b'='
+                {
+                    Token {
+                        token_type: TokenType::DotDotEquals,
+                        span: Span {
+                            start,
+                            end: start + 3,
+                        },
+                    }
+                } else if self.source.len() > self.span_offset + 1
                     && self.source[self.span_offset + 1] == b'.'
                 {
                     Token {
@@ -578,6 +778,27 @@ impl Lexer {
                     }
                 }
             }
+            b'%' => Token {
+                token_type: TokenType::Percent,
+                span: Span {
+                    start,
+                    end: start + 1,
+                },
+            },
+            b'^' => Token {
+                token_type: TokenType::Caret,
+                span: Span {
+                    start,
+                    end: start + 1,
+                },
+            },
+            b'?' => Token {
+                token_type: TokenType::Question,
+                span: Span {
+                    start,
+                    end: start + 1,
+                },
+            },
             b',' => Token {
                 token_type: TokenType::Comma,
                 span: Span {
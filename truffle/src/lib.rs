@@ -1,4 +1,5 @@
 #![allow(clippy::type_complexity)]
+#![forbid(unsafe_code)]
 
 mod codegen;
 mod engine;
@@ -12,9 +13,15 @@ pub use crate::codegen::Translater;
 
 pub use crate::{
     codegen::FunctionCodegen,
-    engine::{Engine, FnRegister, SpanOrLocation},
-    errors::{ErrorBatch, ScriptError},
-    eval::{Evaluator, ReturnValue},
+    engine::{
+        Bindings, CompiledScript, Engine, FallibleFnRegister, Fields, FnRegister, Session,
+        SpanOrLocation,
+    },
+    errors::{ErrorBatch, ErrorKind, ScriptError},
+    eval::{
+        ArithmeticMode, Callback, CancellationToken, EvalLimits, Evaluator, FromScript,
+        ReturnValue, RuntimeType, ScriptStruct,
+    },
     lexer::Lexer,
     parser::{ParseResults, Parser, Span},
     typechecker::{FunctionId, TypeChecker, TypeId, BOOL_TYPE, F64_TYPE, I64_TYPE, UNIT_TYPE},
@@ -23,6 +30,9 @@ pub use crate::{
 #[cfg(feature = "lsp")]
 pub use errors::LineLookupTable;
 
+#[doc(hidden)]
+pub use crate::engine::{arg_ref, SharedArg};
+
 // TODO: remove this, it's just a temporary hack while massaging APIs
 pub use crate::typechecker::Function;
 
@@ -31,6 +41,9 @@ pub use crate::typechecker::Function;
 #[doc(inline)]
 pub use truffle_attributes::{export, register_fn};
 
+#[doc(inline)]
+pub use truffle_attributes::Fields;
+
 #[cfg(feature = "lsp")]
 pub use postcard;
 
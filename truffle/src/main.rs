@@ -4,24 +4,29 @@ use std::{collections::HashMap, path::Path};
 
 use line_editor::{LineEditor, ReadLineOutput};
 
-use truffle::{register_fn, FnRegister, ReturnValue};
+use truffle::{register_fn, Engine, FnRegister, ReturnValue, Session};
 
 fn main() {
     let args = std::env::args();
 
     let mut debug_output = false;
 
+    let engine = create_engine();
+
     if args.len() > 1 {
         for arg in args.skip(1) {
             let contents = std::fs::read_to_string(&arg).expect("couldn't find file");
 
-            run_line(&arg, &contents, debug_output);
+            run_file(&engine, &arg, &contents, debug_output);
         }
         return;
     }
 
     let mut line_editor = LineEditor::new();
 
+    // Lines share a session, so each one sees what the ones before it defined
+    let mut session = Session::new(&engine);
+
     loop {
         match line_editor.read_line() {
             Ok(ReadLineOutput::Continue) => {
@@ -36,7 +41,7 @@ fn main() {
                 } else if line == "debug" {
                     debug_output = !debug_output;
                 } else {
-                    run_line("<repl>", &line, debug_output);
+                    run_line(&mut session, &line, debug_output);
                 }
             }
             Err(err) => {
@@ -46,6 +51,22 @@ fn main() {
     }
 }
 
+fn create_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_app_name("repl");
+
+    register_fn!(engine, "print", print::<i64>);
+    register_fn!(engine, "print", print::<f64>);
+    register_fn!(engine, "print", print::<bool>);
+    register_fn!(engine, "add", add::<i64>);
+    register_fn!(engine, "add", add::<f64>);
+    register_fn!(engine, "new_env", Env::new_env);
+    register_fn!(engine, "set_var", Env::set_var);
+    register_fn!(engine, "read_var", Env::read_var);
+
+    engine
+}
+
 fn print_result(result: ReturnValue) {
     match result {
         ReturnValue::Unit => {
@@ -69,30 +90,19 @@ fn print_result(result: ReturnValue) {
     }
 }
 
-#[cfg(feature = "async")]
-fn run_line<P>(fname: P, source: &str, debug_output: bool) -> Option<()>
+fn run_file<P>(engine: &Engine, fname: P, source: &str, debug_output: bool) -> Option<()>
 where
     P: AsRef<Path>,
 {
-    use futures::executor::block_on;
-    use truffle::Engine;
-
     let fname = fname.as_ref();
     let contents = source.as_bytes();
 
-    let mut engine = Engine::new();
-    engine.set_app_name("repl");
-
-    register_fn!(engine, "print", print::<i64>);
-    register_fn!(engine, "print", print::<f64>);
-    register_fn!(engine, "print", print::<bool>);
-    register_fn!(engine, "add", add::<i64>);
-    register_fn!(engine, "add", add::<f64>);
-    register_fn!(engine, "new_env", Env::new_env);
-    register_fn!(engine, "set_var", Env::set_var);
-    register_fn!(engine, "read_var", Env::read_var);
+    #[cfg(feature = "async")]
+    let result =
+        futures::executor::block_on(engine.eval_source_async(fname, contents, debug_output));
+    #[cfg(not(feature = "async"))]
+    let result = engine.eval_source(fname, contents, debug_output);
 
-    let result = block_on(engine.eval_source_async(fname, source.as_bytes(), debug_output));
     match result {
         Ok(result) => {
             print_result(result);
@@ -106,35 +116,20 @@ where
     Some(())
 }
 
-#[cfg(not(feature = "async"))]
-fn run_line<P>(fname: P, source: &str, debug_output: bool) -> Option<()>
-where
-    P: AsRef<Path>,
-{
-    use truffle::Engine;
-
-    let fname = fname.as_ref();
+fn run_line(session: &mut Session, source: &str, debug_output: bool) -> Option<()> {
     let contents = source.as_bytes();
 
-    let mut engine = Engine::new();
-    engine.set_app_name("repl");
+    #[cfg(feature = "async")]
+    let result = futures::executor::block_on(session.eval_async(contents, debug_output));
+    #[cfg(not(feature = "async"))]
+    let result = session.eval(contents, debug_output);
 
-    register_fn!(engine, "print", print::<i64>);
-    register_fn!(engine, "print", print::<f64>);
-    register_fn!(engine, "print", print::<bool>);
-    register_fn!(engine, "add", add::<i64>);
-    register_fn!(engine, "add", add::<f64>);
-    register_fn!(engine, "new_env", Env::new_env);
-    register_fn!(engine, "set_var", Env::set_var);
-    register_fn!(engine, "read_var", Env::read_var);
-
-    let result = engine.eval_source(fname, contents, debug_output);
     match result {
         Ok(result) => {
             print_result(result);
         }
         Err(errors) => {
-            errors.print_with(fname, contents);
+            errors.print_with(Path::new("<repl>"), session.source());
             return None;
         }
     }
@@ -1,7 +1,7 @@
 use std::fmt::Display;
 
-use crate::errors::{ErrorBatch, ScriptError};
-use crate::lexer::{Token, TokenType};
+use crate::errors::{ErrorBatch, ErrorKind, ScriptError};
+use crate::lexer::{split_string, Lexer, StringPart, Token, TokenType};
 
 pub struct Parser {
     pub results: ParseResults,
@@ -9,6 +9,10 @@ pub struct Parser {
     pub current_token: usize,
     pub content_length: usize,
     pub errors: ErrorBatch,
+
+    // Whether the expression being parsed comes right before a block, like an `if` condition,
+    // where `name {}` is the name followed by an empty block rather than an empty struct literal
+    before_block: bool,
 }
 
 #[derive(Debug, PartialEq)]
@@ -16,8 +20,25 @@ pub enum AstNode {
     Int,
     Float,
     String,
+
+    // A string literal with `{expression}`s in it, made of its text and expressions in order
+    Interpolation(Vec<NodeId>),
+    // Text between the interpolations of a string literal
+    Text,
+
     Name,
     Type,
+    ArrayType(NodeId),
+    // A type with type parameters, like `Option<i64>`
+    GenericType {
+        name: NodeId,
+        params: Vec<NodeId>,
+    },
+    // The type of a closure, like `fn(i64) -> bool`
+    FunctionType {
+        params: Vec<NodeId>,
+        ret: Option<NodeId>,
+    },
     Variable,
 
     // Booleans
@@ -36,10 +57,19 @@ pub enum AstNode {
     Minus,
     Multiply,
     Divide,
-    // Modulo,
+    Modulo,
     And,
     Or,
     Pow,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+
+    // Unary operators
+    Negate,
+    Not,
 
     Assignment,
     AddAssignment,
@@ -63,11 +93,15 @@ pub enum AstNode {
         range: NodeId,
         block: NodeId,
     },
+    Return(Option<NodeId>),
+    Break,
+    Continue,
 
     // Definitions
     Fn {
         name: NodeId,
         params: NodeId,
+        ret: Option<NodeId>,
         block: NodeId,
     },
     Params(Vec<NodeId>),
@@ -75,11 +109,17 @@ pub enum AstNode {
         name: NodeId,
         ty: Option<NodeId>,
     },
+    Struct {
+        name: NodeId,
+        fields: Vec<(NodeId, NodeId)>,
+    },
 
-    // Closure {
-    //     params: NodeId,
-    //     block: NodeId,
-    // },
+    // `|x, y| x + y`, which may declare its return type if its body is a block
+    Closure {
+        params: NodeId,
+        ret: Option<NodeId>,
+        body: NodeId,
+    },
 
     // Expressions
     Call {
@@ -91,9 +131,31 @@ pub enum AstNode {
         op: NodeId,
         rhs: NodeId,
     },
+    UnaryOp {
+        op: NodeId,
+        operand: NodeId,
+    },
+    Cast {
+        value: NodeId,
+        ty: NodeId,
+    },
     Range {
         lhs: NodeId,
         rhs: NodeId,
+        inclusive: bool,
+    },
+    Array(Vec<NodeId>),
+    Index {
+        target: NodeId,
+        index: NodeId,
+    },
+    StructLiteral {
+        name: NodeId,
+        fields: Vec<(NodeId, NodeId)>,
+    },
+    Field {
+        target: NodeId,
+        field: NodeId,
     },
     Block(Vec<NodeId>),
     If {
@@ -101,18 +163,61 @@ pub enum AstNode {
         then_block: NodeId,
         else_expression: Option<NodeId>,
     },
+    // `Some(x)`, `None`, `Ok(x)` or `Err(x)`, building a value or, in a pattern, taking one apart
+    Variant {
+        variant: Variant,
+        payload: Option<NodeId>,
+    },
+    Match {
+        target: NodeId,
+        arms: Vec<(NodeId, NodeId)>,
+    },
+    IfLet {
+        pattern: NodeId,
+        value: NodeId,
+        then_block: NodeId,
+        else_expression: Option<NodeId>,
+    },
+    // The `?` operator
+    Try(NodeId),
+    // `_` in a pattern
+    Wildcard,
     Await(NodeId),
     Statement(NodeId),
     Garbage,
 }
 
+/// The variants of the builtin `Option` and `Result` types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Some,
+    None,
+    Ok,
+    Err,
+}
+
+impl Variant {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Variant::Some => "Some",
+            Variant::None => "None",
+            Variant::Ok => "Ok",
+            Variant::Err => "Err",
+        }
+    }
+}
+
 impl AstNode {
     pub fn precedence(&self) -> usize {
         match self {
             AstNode::Pow => 100,
-            AstNode::Multiply | AstNode::Divide => 95,
-            //AstNode::Modulo => 95,
-            AstNode::Plus | AstNode::Minus => 90,
+            AstNode::Negate | AstNode::Not => 97,
+            AstNode::Multiply | AstNode::Divide | AstNode::Modulo => 95,
+            AstNode::Plus | AstNode::Minus | AstNode::Append => 90,
+            AstNode::ShiftLeft | AstNode::ShiftRight => 85,
+            AstNode::BitAnd => 84,
+            AstNode::BitXor => 83,
+            AstNode::BitOr => 82,
             AstNode::LessThan
             | AstNode::LessThanOrEqual
             | AstNode::GreaterThan
@@ -129,6 +234,11 @@ impl AstNode {
             _ => 0,
         }
     }
+
+    /// Whether a chain of this operator groups from the right, so `2 ** 3 ** 2` is `2 ** (3 ** 2)`
+    pub fn is_right_associative(&self) -> bool {
+        matches!(self, AstNode::Pow)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -165,6 +275,7 @@ impl Parser {
             current_token: 0,
             content_length,
             errors: ErrorBatch::empty(),
+            before_block: false,
         }
     }
 
@@ -185,6 +296,29 @@ impl Parser {
         }
     }
 
+    fn is_token_at(&self, position: usize, token_type: TokenType) -> bool {
+        matches!(self.tokens.get(position), Some(token) if token.token_type == token_type)
+    }
+
+    fn is_token_after_next(&self, token_type: TokenType) -> bool {
+        self.is_token_at(self.current_token + 1, token_type)
+    }
+
+    /// Whether the next token is a `-` that starts its line
+    fn is_dash_on_new_line(&self) -> bool {
+        let (Some(previous), Some(next)) = (
+            self.current_token
+                .checked_sub(1)
+                .and_then(|idx| self.tokens.get(idx)),
+            self.peek(),
+        ) else {
+            return false;
+        };
+
+        next.token_type == TokenType::Dash
+            && self.results.contents[previous.span.end..next.span.start].contains(&b'\n')
+    }
+
     fn position(&mut self) -> usize {
         if let Some(Token { span, .. }) = self.peek() {
             span.start
@@ -223,6 +357,7 @@ impl Parser {
                     | TokenType::LessThan
                     | TokenType::LessThanEqual
                     | TokenType::Plus
+                    | TokenType::PlusPlus
                     | TokenType::PlusEquals
                     | TokenType::DashEquals
                     | TokenType::AsteriskEquals
@@ -232,6 +367,12 @@ impl Parser {
                     | TokenType::AmpersandAmpersand
                     | TokenType::PipePipe
                     | TokenType::Equals
+                    | TokenType::Percent
+                    | TokenType::Ampersand
+                    | TokenType::Pipe
+                    | TokenType::Caret
+                    | TokenType::LessThanLessThan
+                    | TokenType::GreaterThanGreaterThan
             ),
             _ => false,
         }
@@ -287,15 +428,15 @@ impl Parser {
         )
     }
 
-    // pub fn is_lsquare(&mut self) -> bool {
-    //     matches!(
-    //         self.peek(),
-    //         Some(Token {
-    //             token_type: TokenType::LSquare,
-    //             ..
-    //         })
-    //     )
-    // }
+    pub fn is_lsquare(&mut self) -> bool {
+        matches!(
+            self.peek(),
+            Some(Token {
+                token_type: TokenType::LSquare,
+                ..
+            })
+        )
+    }
 
     pub fn is_rsquare(&mut self) -> bool {
         matches!(
@@ -307,15 +448,15 @@ impl Parser {
         )
     }
 
-    // pub fn is_less_than(&mut self) -> bool {
-    //     matches!(
-    //         self.peek(),
-    //         Some(Token {
-    //             token_type: TokenType::LessThan,
-    //             ..
-    //         })
-    //     )
-    // }
+    pub fn is_less_than(&mut self) -> bool {
+        matches!(
+            self.peek(),
+            Some(Token {
+                token_type: TokenType::LessThan,
+                ..
+            })
+        )
+    }
 
     // pub fn is_greater_than(&mut self) -> bool {
     //     matches!(
@@ -337,15 +478,15 @@ impl Parser {
         )
     }
 
-    // pub fn is_double_pipe(&mut self) -> bool {
-    //     matches!(
-    //         self.peek(),
-    //         Some(Token {
-    //             token_type: TokenType::PipePipe,
-    //             ..
-    //         })
-    //     )
-    // }
+    pub fn is_double_pipe(&mut self) -> bool {
+        matches!(
+            self.peek(),
+            Some(Token {
+                token_type: TokenType::PipePipe,
+                ..
+            })
+        )
+    }
 
     // pub fn is_double_ampersand(&mut self) -> bool {
     //     matches!(
@@ -377,6 +518,26 @@ impl Parser {
         )
     }
 
+    pub fn is_question(&mut self) -> bool {
+        matches!(
+            self.peek(),
+            Some(Token {
+                token_type: TokenType::Question,
+                ..
+            })
+        )
+    }
+
+    pub fn is_thin_arrow(&mut self) -> bool {
+        matches!(
+            self.peek(),
+            Some(Token {
+                token_type: TokenType::DashGreaterThan,
+                ..
+            })
+        )
+    }
+
     pub fn is_semicolon(&mut self) -> bool {
         matches!(
             self.peek(),
@@ -407,6 +568,16 @@ impl Parser {
         )
     }
 
+    pub fn is_dotdot_equals(&mut self) -> bool {
+        matches!(
+            self.peek(),
+            Some(Token {
+                token_type: TokenType::DotDotEquals,
+                ..
+            })
+        )
+    }
+
     pub fn is_number(&mut self) -> bool {
         matches!(
             self.peek(),
@@ -452,7 +623,11 @@ impl Parser {
     }
 
     pub fn is_expression(&mut self) -> bool {
-        self.is_simple_expression() || self.is_keyword(b"if") || self.is_keyword(b"where")
+        self.is_simple_expression()
+            || self.is_keyword(b"if")
+            || self.is_keyword(b"where")
+            || self.is_pipe()
+            || self.is_double_pipe()
     }
 
     pub fn is_simple_expression(&self) -> bool {
@@ -476,6 +651,14 @@ impl Parser {
             | Some(Token {
                 token_type: TokenType::LParen,
                 ..
+            })
+            | Some(Token {
+                token_type: TokenType::Dash,
+                ..
+            })
+            | Some(Token {
+                token_type: TokenType::Exclamation,
+                ..
             }) => true,
             Some(Token {
                 token_type: TokenType::Name,
@@ -494,7 +677,7 @@ impl Parser {
     }
 
     pub fn get_span_end(&self, node_id: NodeId) -> usize {
-        self.results.spans[node_id.0].end
+        self.results.spans[node_id.0 - self.results.node_id_offset].end
     }
 
     pub fn error(&mut self, message: impl Into<String>) -> NodeId {
@@ -503,6 +686,7 @@ impl Parser {
             self.errors.push(ScriptError {
                 message: message.into(),
                 span,
+                kind: ErrorKind::Script,
             });
 
             node_id
@@ -515,12 +699,29 @@ impl Parser {
             self.errors.push(ScriptError {
                 message: message.into(),
                 span,
+                kind: ErrorKind::Script,
             });
 
             node_id
         }
     }
 
+    /// Report an error at the next token without consuming it
+    pub fn error_at_next(&mut self, message: impl Into<String>) {
+        let span = match self.peek() {
+            Some(Token { span, .. }) => span,
+            None => Span {
+                start: self.content_length,
+                end: self.content_length,
+            },
+        };
+        self.errors.push(ScriptError {
+            message: message.into(),
+            span,
+            kind: ErrorKind::Script,
+        });
+    }
+
     pub fn create_node(&mut self, node_type: AstNode, span: Span) -> NodeId {
         self.results.spans.push(span);
         self.results.ast_nodes.push(node_type);
@@ -537,6 +738,9 @@ impl Parser {
             self.lcurly();
         }
 
+        // Inside the block, a `{` after a name starts a struct literal again
+        let outer_before_block = std::mem::replace(&mut self.before_block, false);
+
         while self.has_tokens() {
             if self.is_rcurly() && expect_parens {
                 end = self.position() + 1;
@@ -548,15 +752,9 @@ impl Parser {
             } else if self.is_keyword(b"fn") {
                 let result = self.fn_definition();
                 code_body.push(result);
-
-                if !self.is_rcurly()
-                    && !self.is_rparen()
-                    && !self.is_semicolon()
-                    && self.has_tokens()
-                {
-                    let p = self.peek();
-                    self.error(format!("expected newline or semicolon but found {:?}", p));
-                }
+            } else if self.is_keyword(b"struct") {
+                let result = self.struct_definition();
+                code_body.push(result);
             } else if self.is_keyword(b"let") {
                 let result = self.let_statement();
                 code_body.push(result);
@@ -590,6 +788,8 @@ impl Parser {
             );
         }
 
+        self.before_block = outer_before_block;
+
         let span = Span { start, end };
         self.create_node(AstNode::Block(code_body), span)
     }
@@ -602,6 +802,15 @@ impl Parser {
 
         let params = self.params();
 
+        let ret = if self.is_thin_arrow() {
+            // We have a return type
+            self.next();
+
+            Some(self.typename())
+        } else {
+            None
+        };
+
         let block = self.block(true);
 
         let end = self.get_span_end(block);
@@ -611,12 +820,70 @@ impl Parser {
             AstNode::Fn {
                 name,
                 params,
+                ret,
                 block,
             },
             span,
         )
     }
 
+    pub fn struct_definition(&mut self) -> NodeId {
+        let start = self.position();
+        self.keyword(b"struct");
+
+        let name = self.name();
+
+        self.lcurly();
+        let mut fields = vec![];
+        while self.has_tokens() && !self.is_rcurly() {
+            if self.is_comma() {
+                self.next();
+                continue;
+            }
+
+            let field_name = self.name();
+            self.colon();
+            let ty = self.typename();
+
+            fields.push((field_name, ty));
+        }
+        let end = self.position() + 1;
+        self.rcurly();
+
+        let span = Span { start, end };
+        self.create_node(AstNode::Struct { name, fields }, span)
+    }
+
+    pub fn struct_literal(&mut self, start: usize, name: NodeId) -> NodeId {
+        self.lcurly();
+        let mut fields = vec![];
+        while self.has_tokens() && !self.is_rcurly() {
+            if self.is_comma() {
+                self.next();
+                continue;
+            }
+
+            let field_name = self.name();
+            self.colon();
+            let value = self.expression();
+
+            fields.push((field_name, value));
+        }
+        let end = self.position() + 1;
+        self.rcurly();
+
+        let span = Span { start, end };
+        self.create_node(AstNode::StructLiteral { name, fields }, span)
+    }
+
+    pub fn field_access(&mut self, start: usize, target: NodeId) -> NodeId {
+        let field = self.name();
+        let end = self.get_span_end(field);
+
+        let span = Span { start, end };
+        self.create_node(AstNode::Field { target, field }, span)
+    }
+
     pub fn method_call(&mut self, start: usize, receiver: NodeId) -> NodeId {
         let method_name = self
             .next()
@@ -642,11 +909,19 @@ impl Parser {
         // Check for special forms
         if self.is_keyword(b"if") {
             return self.if_expression();
+        } else if self.is_keyword(b"match") {
+            return self.match_expression();
+        } else if self.is_keyword(b"return") {
+            return self.return_expression();
+        } else if self.is_keyword(b"break") || self.is_keyword(b"continue") {
+            return self.loop_jump();
+        } else if self.is_pipe() || self.is_double_pipe() {
+            return self.closure();
         }
 
         // Otherwise assume a math expression
         let lhs = if self.is_simple_expression() {
-            self.simple_expression()
+            self.cast_expression()
         } else {
             return self.error("incomplete math expression");
         };
@@ -655,16 +930,30 @@ impl Parser {
 
         while self.has_tokens() {
             if self.is_operator() {
+                if self.is_dash_on_new_line() {
+                    // Reads like a negative number on its own line, but would quietly subtract it
+                    // from the line before
+                    self.error_at_next(
+                        "`-` at the start of a line continues the expression before it; move it to the end of that line, or end that line with `;`",
+                    );
+                }
+
                 let op = self.operator();
                 let op_prec = self.operator_precedence(op);
+                let right_associative = self.is_right_associative(op);
 
                 let rhs = if self.is_simple_expression() {
-                    self.simple_expression()
+                    self.cast_expression()
+                } else if self.is_pipe() || self.is_double_pipe() {
+                    // A closure takes the rest of the expression as its body, as in `f = |x| x + 1`
+                    self.closure()
                 } else {
                     self.error("incomplete math expression")
                 };
 
-                while op_prec <= last_prec && expr_stack.len() > 1 {
+                while (op_prec < last_prec || (op_prec == last_prec && !right_associative))
+                    && expr_stack.len() > 1
+                {
                     let rhs = expr_stack
                         .pop()
                         .expect("internal error: expression stack empty");
@@ -674,7 +963,7 @@ impl Parser {
 
                     last_prec = self.operator_precedence(op);
 
-                    if last_prec < op_prec {
+                    if last_prec < op_prec || (last_prec == op_prec && right_associative) {
                         expr_stack.push(op);
                         expr_stack.push(rhs);
                         break;
@@ -718,6 +1007,89 @@ impl Parser {
             .expect("internal error: expression stack empty")
     }
 
+    /// A unary expression followed by any number of `as` casts, which bind tighter than the
+    /// binary operators
+    pub fn cast_expression(&mut self) -> NodeId {
+        let mut value = self.unary_expression();
+
+        while self.is_keyword(b"as") {
+            self.next();
+            let ty = self.typename();
+
+            let span = self.spanning(value, ty);
+            value = self.create_node(AstNode::Cast { value, ty }, span);
+        }
+
+        value
+    }
+
+    /// Parse an expression that may start with `-` or `!`
+    ///
+    /// Unary operators bind tighter than binary ones, except for the ones with a higher
+    /// precedence (like `**`, so `-2 ** 2` is `-(2 ** 2)`).
+    pub fn unary_expression(&mut self) -> NodeId {
+        let op = match self.peek() {
+            Some(Token {
+                token_type: TokenType::Dash,
+                span,
+            }) => {
+                self.next();
+                self.create_node(AstNode::Negate, span)
+            }
+            Some(Token {
+                token_type: TokenType::Exclamation,
+                span,
+            }) => {
+                self.next();
+                self.create_node(AstNode::Not, span)
+            }
+            _ => return self.simple_expression(),
+        };
+
+        let mut operand = if self.is_simple_expression() {
+            self.unary_expression()
+        } else {
+            self.error("incomplete math expression")
+        };
+
+        let op_prec = self.operator_precedence(op);
+        operand = self.tighter_operators(operand, op_prec);
+
+        let span = self.spanning(op, operand);
+        self.create_node(AstNode::UnaryOp { op, operand }, span)
+    }
+
+    /// Parse the operators after `lhs` that bind tighter than the given precedence
+    fn tighter_operators(&mut self, mut lhs: NodeId, min_prec: usize) -> NodeId {
+        while let Some(prec) = self
+            .peek_operator()
+            .map(|operator| operator.precedence())
+            .filter(|prec| *prec > min_prec)
+        {
+            let operator = self.operator();
+            let mut rhs = if self.is_simple_expression() {
+                self.unary_expression()
+            } else {
+                self.error("incomplete math expression")
+            };
+            if self.is_right_associative(operator) {
+                rhs = self.tighter_operators(rhs, prec - 1);
+            }
+
+            let span = self.spanning(lhs, rhs);
+            lhs = self.create_node(
+                AstNode::BinaryOp {
+                    lhs,
+                    op: operator,
+                    rhs,
+                },
+                span,
+            );
+        }
+
+        lhs
+    }
+
     pub fn simple_expression(&mut self) -> NodeId {
         let start = self.position();
 
@@ -725,7 +1097,9 @@ impl Parser {
             self.block(true)
         } else if self.is_lparen() {
             self.lparen();
+            let outer_before_block = std::mem::replace(&mut self.before_block, false);
             let output = self.expression();
+            self.before_block = outer_before_block;
             self.rparen();
             output
         } else if self.is_keyword(b"true") || self.is_keyword(b"false") {
@@ -734,39 +1108,86 @@ impl Parser {
             self.string()
         } else if self.is_number() {
             self.number()
+        } else if self.is_lsquare() {
+            self.array()
+        } else if let Some(variant) = self.peek_variant() {
+            self.variant(variant, Self::expression)
         } else if self.is_name() {
             self.variable_or_call()
         } else {
             self.error("incomplete expression")
         };
 
-        if self.is_dotdot() {
-            // Range
-            self.next();
-
-            let rhs = self.simple_expression();
-            let end = self.get_span_end(rhs);
-            let span = Span { start, end };
+        let mut expr = expr;
+        loop {
+            if self.is_dot() {
+                self.next();
 
-            self.create_node(AstNode::Range { lhs: expr, rhs }, span)
-        } else if self.is_dot() {
-            self.next();
+                expr = if self.is_keyword(b"await") {
+                    // consume the 'await' keyword
+                    self.next();
 
-            if self.is_keyword(b"await") {
-                // consume the 'await' keyword
+                    let end = self.position();
+                    let span = Span { start, end };
+                    self.create_node(AstNode::Await(expr), span)
+                } else if !self.has_tokens() {
+                    self.error("missing method call")
+                } else if self.is_token_after_next(TokenType::LParen) {
+                    self.method_call(start, expr)
+                } else {
+                    self.field_access(start, expr)
+                };
+            } else if self.is_lsquare() {
+                expr = self.index(start, expr);
+            } else if self.is_question() {
+                let end = self.position() + 1;
                 self.next();
 
-                let end = self.position();
                 let span = Span { start, end };
-                self.create_node(AstNode::Await(expr), span)
-            } else if !self.has_tokens() {
-                self.error("missing method call")
+                expr = self.create_node(AstNode::Try(expr), span);
             } else {
-                self.method_call(start, expr)
+                return expr;
+            }
+        }
+    }
+
+    pub fn array(&mut self) -> NodeId {
+        let start = self.position();
+        self.lsquare();
+
+        let mut items = vec![];
+        loop {
+            if self.is_rsquare() {
+                break;
+            } else if self.is_expression() {
+                items.push(self.expression());
+
+                if self.is_comma() {
+                    self.next();
+                } else if !self.is_rsquare() {
+                    items.push(self.error("unexpected value in array"));
+                }
+            } else {
+                items.push(self.error("unexpected value in array"));
+                break;
             }
-        } else {
-            expr
         }
+
+        let end = self.position() + 1;
+        self.rsquare();
+
+        let span = Span { start, end };
+        self.create_node(AstNode::Array(items), span)
+    }
+
+    pub fn index(&mut self, start: usize, target: NodeId) -> NodeId {
+        self.lsquare();
+        let index = self.expression();
+        let end = self.position() + 1;
+        self.rsquare();
+
+        let span = Span { start, end };
+        self.create_node(AstNode::Index { target, index }, span)
     }
 
     pub fn number(&mut self) -> NodeId {
@@ -813,99 +1234,62 @@ impl Parser {
     }
 
     pub fn operator(&mut self) -> NodeId {
-        match self.peek() {
-            Some(Token {
-                token_type, span, ..
-            }) => match token_type {
-                TokenType::Plus => {
-                    self.next();
-                    self.create_node(AstNode::Plus, span)
-                }
-                TokenType::PlusPlus => {
-                    self.next();
-                    self.create_node(AstNode::Append, span)
-                }
-                TokenType::Dash => {
-                    self.next();
-                    self.create_node(AstNode::Minus, span)
-                }
-                TokenType::Asterisk => {
-                    self.next();
-                    self.create_node(AstNode::Multiply, span)
-                }
-                TokenType::ForwardSlash => {
-                    self.next();
-                    self.create_node(AstNode::Divide, span)
-                }
-                TokenType::LessThan => {
-                    self.next();
-                    self.create_node(AstNode::LessThan, span)
-                }
-                TokenType::LessThanEqual => {
-                    self.next();
-                    self.create_node(AstNode::LessThanOrEqual, span)
-                }
-                TokenType::GreaterThan => {
-                    self.next();
-                    self.create_node(AstNode::GreaterThan, span)
-                }
-                TokenType::GreaterThanEqual => {
-                    self.next();
-                    self.create_node(AstNode::GreaterThanOrEqual, span)
-                }
-                TokenType::EqualsEquals => {
-                    self.next();
-                    self.create_node(AstNode::Equal, span)
-                }
-                TokenType::PlusEquals => {
-                    self.next();
-                    self.create_node(AstNode::AddAssignment, span)
-                }
-                TokenType::DashEquals => {
-                    self.next();
-                    self.create_node(AstNode::MinusAssignment, span)
-                }
-                TokenType::AsteriskEquals => {
-                    self.next();
-                    self.create_node(AstNode::MultiplyAssignment, span)
-                }
-                TokenType::ForwardSlashEquals => {
-                    self.next();
-                    self.create_node(AstNode::DivideAssignment, span)
-                }
-                TokenType::ExclamationEquals => {
-                    self.next();
-                    self.create_node(AstNode::NotEqual, span)
-                }
-                TokenType::AsteriskAsterisk => {
-                    self.next();
-                    self.create_node(AstNode::Pow, span)
-                }
-                TokenType::AmpersandAmpersand => {
-                    self.next();
-                    self.create_node(AstNode::And, span)
-                }
-                TokenType::PipePipe => {
-                    self.next();
-                    self.create_node(AstNode::Or, span)
-                }
-                TokenType::Equals => {
-                    self.next();
-                    self.create_node(AstNode::Assignment, span)
-                }
-                _ => self.error("expected: operator"),
-            },
+        match (self.peek(), self.peek_operator()) {
+            (Some(Token { span, .. }), Some(operator)) => {
+                self.next();
+                self.create_node(operator, span)
+            }
             _ => self.error("expected: operator"),
         }
     }
 
+    /// The binary operator the next token stands for, if any
+    pub fn peek_operator(&self) -> Option<AstNode> {
+        let Token { token_type, .. } = self.peek()?;
+
+        let operator = match token_type {
+            TokenType::Plus => AstNode::Plus,
+            TokenType::PlusPlus => AstNode::Append,
+            TokenType::Dash => AstNode::Minus,
+            TokenType::Asterisk => AstNode::Multiply,
+            TokenType::ForwardSlash => AstNode::Divide,
+            TokenType::LessThan => AstNode::LessThan,
+            TokenType::LessThanEqual => AstNode::LessThanOrEqual,
+            TokenType::GreaterThan => AstNode::GreaterThan,
+            TokenType::GreaterThanEqual => AstNode::GreaterThanOrEqual,
+            TokenType::EqualsEquals => AstNode::Equal,
+            TokenType::PlusEquals => AstNode::AddAssignment,
+            TokenType::DashEquals => AstNode::MinusAssignment,
+            TokenType::AsteriskEquals => AstNode::MultiplyAssignment,
+            TokenType::ForwardSlashEquals => AstNode::DivideAssignment,
+            TokenType::ExclamationEquals => AstNode::NotEqual,
+            TokenType::AsteriskAsterisk => AstNode::Pow,
+            TokenType::AmpersandAmpersand => AstNode::And,
+            TokenType::PipePipe => AstNode::Or,
+            TokenType::Equals => AstNode::Assignment,
+            TokenType::Percent => AstNode::Modulo,
+            TokenType::Ampersand => AstNode::BitAnd,
+            TokenType::Pipe => AstNode::BitOr,
+            TokenType::Caret => AstNode::BitXor,
+            TokenType::LessThanLessThan => AstNode::ShiftLeft,
+            TokenType::GreaterThanGreaterThan => AstNode::ShiftRight,
+            _ => return None,
+        };
+
+        Some(operator)
+    }
+
     pub fn operator_precedence(&mut self, operator: NodeId) -> usize {
-        self.results.ast_nodes[operator.0].precedence()
+        self.results.ast_nodes[operator.0 - self.results.node_id_offset].precedence()
+    }
+
+    pub fn is_right_associative(&self, operator: NodeId) -> bool {
+        self.results.ast_nodes[operator.0 - self.results.node_id_offset].is_right_associative()
     }
 
     pub fn spanning(&mut self, from: NodeId, to: NodeId) -> Span {
-        let start = self.results.spans[from.0].start;
-        let end = self.results.spans[to.0].end;
+        let start = self.results.spans[from.0 - self.results.node_id_offset].start;
+        let end = self.results.spans[to.0 - self.results.node_id_offset].end;
         Span { start, end }
     }
 
@@ -917,12 +1301,64 @@ impl Parser {
                 ..
             }) => {
                 self.next();
-                self.create_node(AstNode::String, span)
+
+                // The lexer already reported any problems with the string's contents
+                let contents = Span {
+                    start: span.start + 1,
+                    end: span.end.saturating_sub(1).max(span.start + 1),
+                };
+                let parts = split_string(&self.results.contents, contents).unwrap_or_default();
+                if !parts
+                    .iter()
+                    .any(|part| matches!(part, StringPart::Interpolation(_)))
+                {
+                    return self.create_node(AstNode::String, span);
+                }
+
+                let parts = parts
+                    .into_iter()
+                    .map(|part| match part {
+                        StringPart::Text(span) => self.create_node(AstNode::Text, span),
+                        StringPart::Interpolation(span) => self.interpolation(span),
+                    })
+                    .collect();
+
+                self.create_node(AstNode::Interpolation(parts), span)
             }
             _ => self.error("expected: string"),
         }
     }
 
+    /// Parse the expression between the braces of an interpolation in a string literal
+    fn interpolation(&mut self, span: Span) -> NodeId {
+        let mut lexer = Lexer::new(self.results.contents[..span.end].to_vec(), span.start);
+        let tokens = match lexer.lex() {
+            Ok(tokens) => tokens,
+            Err(errors) => {
+                for error in &errors {
+                    self.errors.push(error.clone());
+                }
+                return self.create_node(AstNode::Garbage, span);
+            }
+        };
+
+        // Parse the interpolation's tokens as if they were all that's left
+        let tokens = std::mem::replace(&mut self.tokens, tokens);
+        let current_token = std::mem::replace(&mut self.current_token, 0);
+        let content_length = std::mem::replace(&mut self.content_length, span.end);
+
+        let expression = self.expression();
+        if self.has_tokens() {
+            self.error("expected: end of interpolation");
+        }
+
+        self.tokens = tokens;
+        self.current_token = current_token;
+        self.content_length = content_length;
+
+        expression
+    }
+
     pub fn name(&mut self) -> NodeId {
         match self.peek() {
             Some(Token {
@@ -938,6 +1374,19 @@ impl Parser {
     }
 
     pub fn typename(&mut self) -> NodeId {
+        if self.is_lsquare() {
+            let start = self.position();
+            self.lsquare();
+            let element = self.typename();
+            let end = self.position() + 1;
+            self.rsquare();
+
+            let span = Span { start, end };
+            return self.create_node(AstNode::ArrayType(element), span);
+        } else if self.is_keyword(b"fn") {
+            return self.function_type();
+        }
+
         match self.peek() {
             Some(Token {
                 token_type: TokenType::Name,
@@ -945,12 +1394,132 @@ impl Parser {
                 ..
             }) => {
                 self.next();
-                self.create_node(AstNode::Type, span)
+
+                // Only the builtin types take type parameters, so a `<` after any other type is
+                // left alone, as in `x as i64 < y`
+                let contents = self.results.contents_for_span(span);
+                if (contents == b"Option" || contents == b"Result") && self.is_less_than() {
+                    let name = self.create_node(AstNode::Name, span);
+                    self.next();
+
+                    let mut params = vec![self.typename()];
+                    while self.is_comma() {
+                        self.next();
+                        params.push(self.typename());
+                    }
+                    let end = self.position() + 1;
+                    self.greater_than();
+
+                    let span = Span {
+                        start: span.start,
+                        end,
+                    };
+                    self.create_node(AstNode::GenericType { name, params }, span)
+                } else {
+                    self.create_node(AstNode::Type, span)
+                }
             }
             _ => self.error("expect name"),
         }
     }
 
+    /// Parse the type of a closure, like `fn(i64, i64) -> bool`. Without a `->`, the closure
+    /// returns unit.
+    pub fn function_type(&mut self) -> NodeId {
+        let start = self.position();
+        self.keyword(b"fn");
+
+        self.lparen();
+        let mut params = vec![];
+        while self.has_tokens() && !self.is_rparen() {
+            if self.is_comma() {
+                self.next();
+                continue;
+            }
+
+            params.push(self.typename());
+        }
+        let mut end = self.position() + 1;
+        self.rparen();
+
+        let ret = if self.is_thin_arrow() {
+            self.next();
+
+            let ret = self.typename();
+            end = self.get_span_end(ret);
+            Some(ret)
+        } else {
+            None
+        };
+
+        let span = Span { start, end };
+        self.create_node(AstNode::FunctionType { params, ret }, span)
+    }
+
+    /// The variant of `Option` or `Result` the next token names, if any
+    pub fn peek_variant(&self) -> Option<Variant> {
+        let Token {
+            token_type: TokenType::Name,
+            span,
+        } = self.peek()?
+        else {
+            return None;
+        };
+
+        match self.results.contents_for_span(span) {
+            b"Some" => Some(Variant::Some),
+            b"None" => Some(Variant::None),
+            b"Ok" => Some(Variant::Ok),
+            b"Err" => Some(Variant::Err),
+            _ => None,
+        }
+    }
+
+    /// Parse `Some(..)`, `None`, `Ok(..)` or `Err(..)`, using `payload` to parse what's in the
+    /// parentheses
+    pub fn variant(&mut self, variant: Variant, payload: fn(&mut Self) -> NodeId) -> NodeId {
+        let start = self.position();
+        let mut end = start + variant.name().len();
+        self.next();
+
+        let payload = if variant == Variant::None {
+            None
+        } else {
+            self.lparen();
+            let payload = payload(self);
+            end = self.position() + 1;
+            self.rparen();
+
+            Some(payload)
+        };
+
+        let span = Span { start, end };
+        self.create_node(AstNode::Variant { variant, payload }, span)
+    }
+
+    /// Parse a pattern, as used by `match` arms and `if let`
+    pub fn pattern(&mut self) -> NodeId {
+        if self.is_keyword(b"_") {
+            let span = self
+                .next()
+                .expect("internal error: missing token that was expected to be there")
+                .span;
+            self.create_node(AstNode::Wildcard, span)
+        } else if self.is_keyword(b"true") || self.is_keyword(b"false") {
+            self.boolean()
+        } else if self.is_number() {
+            self.number()
+        } else if self.is_string() {
+            self.string()
+        } else if let Some(variant) = self.peek_variant() {
+            self.variant(variant, Self::pattern)
+        } else if self.is_name() {
+            self.variable()
+        } else {
+            self.error("expected: pattern")
+        }
+    }
+
     pub fn params(&mut self) -> NodeId {
         let start = self.position();
 
@@ -987,7 +1556,7 @@ impl Parser {
                 // Optional type
                 self.colon();
 
-                let ty = self.name();
+                let ty = self.typename();
 
                 let end = self.get_span_end(ty);
                 let span = Span { start, end };
@@ -1033,7 +1602,11 @@ impl Parser {
 
         self.keyword(b"if");
 
-        let condition = self.expression();
+        if self.is_keyword(b"let") {
+            return self.if_let_expression(start);
+        }
+
+        let condition = self.expression_before_block();
 
         let then_block = self.block(true);
 
@@ -1058,6 +1631,133 @@ impl Parser {
         )
     }
 
+    /// Parse the rest of an `if let`, after its `if`
+    pub fn if_let_expression(&mut self, start: usize) -> NodeId {
+        let end;
+
+        self.keyword(b"let");
+        let pattern = self.pattern();
+        self.equals();
+        let value = self.expression_before_block();
+
+        let then_block = self.block(true);
+
+        let else_expression = if self.is_keyword(b"else") {
+            self.next();
+            let expr = self.expression();
+            end = self.get_span_end(expr);
+            Some(expr)
+        } else {
+            end = self.get_span_end(then_block);
+            None
+        };
+
+        let span = Span { start, end };
+        self.create_node(
+            AstNode::IfLet {
+                pattern,
+                value,
+                then_block,
+                else_expression,
+            },
+            span,
+        )
+    }
+
+    pub fn match_expression(&mut self) -> NodeId {
+        let start = self.position();
+        self.keyword(b"match");
+
+        let target = self.expression_before_block();
+
+        self.lcurly();
+        let mut arms = vec![];
+        while self.has_tokens() && !self.is_rcurly() {
+            if self.is_comma() {
+                self.next();
+                continue;
+            }
+
+            let pattern = self.pattern();
+            self.fat_arrow();
+            let body = self.expression();
+
+            arms.push((pattern, body));
+        }
+        let end = self.position() + 1;
+        self.rcurly();
+
+        let span = Span { start, end };
+        self.create_node(AstNode::Match { target, arms }, span)
+    }
+
+    /// Parse a closure like `|x| x + 1`. A closure that declares its return type, as in
+    /// `|x: i64| -> i64 { x + 1 }`, needs a block for its body.
+    pub fn closure(&mut self) -> NodeId {
+        let start = self.position();
+
+        let params = if self.is_double_pipe() {
+            let span = self
+                .next()
+                .expect("internal error: missing token that was expected to be there")
+                .span;
+            self.create_node(AstNode::Params(vec![]), span)
+        } else {
+            self.pipe();
+            let param_list = self.param_list();
+            let end = self.position() + 1;
+            self.pipe();
+
+            let span = Span { start, end };
+            self.create_node(AstNode::Params(param_list), span)
+        };
+
+        let (ret, body) = if self.is_thin_arrow() {
+            self.next();
+
+            (Some(self.typename()), self.block(true))
+        } else {
+            (None, self.expression())
+        };
+
+        let end = self.get_span_end(body);
+        let span = Span { start, end };
+        self.create_node(AstNode::Closure { params, ret, body }, span)
+    }
+
+    pub fn return_expression(&mut self) -> NodeId {
+        let start = self.position();
+        let mut end = start + b"return".len();
+        self.keyword(b"return");
+
+        // A bare `return` is followed by the end of its statement or block
+        let value = if self.has_tokens() && !self.is_semicolon() && !self.is_rcurly() {
+            let value = self.expression();
+            end = self.get_span_end(value);
+            Some(value)
+        } else {
+            None
+        };
+
+        let span = Span { start, end };
+        self.create_node(AstNode::Return(value), span)
+    }
+
+    /// A `break` or `continue`
+    pub fn loop_jump(&mut self) -> NodeId {
+        let Some(Token { span, .. }) = self.peek() else {
+            return self.error("expected: break or continue");
+        };
+        let node = if self.is_keyword(b"break") {
+            AstNode::Break
+        } else {
+            AstNode::Continue
+        };
+        self.next();
+
+        self.create_node(node, span)
+    }
+
     pub fn let_statement(&mut self) -> NodeId {
         let mut is_mutable = false;
         let start = self.position();
@@ -1098,11 +1798,20 @@ impl Parser {
         )
     }
 
+    /// Parse an expression that a block comes right after, like the condition of a `while`
+    pub fn expression_before_block(&mut self) -> NodeId {
+        let outer_before_block = std::mem::replace(&mut self.before_block, true);
+        let expression = self.expression();
+        self.before_block = outer_before_block;
+
+        expression
+    }
+
     pub fn while_statement(&mut self) -> NodeId {
         let start = self.position();
         self.keyword(b"while");
 
-        let condition = self.expression();
+        let condition = self.expression_before_block();
         let block = self.block(true);
         let end = self.get_span_end(block);
 
@@ -1117,7 +1826,9 @@ impl Parser {
         let variable = self.variable();
         self.keyword(b"in");
 
-        let range = self.simple_expression();
+        let outer_before_block = std::mem::replace(&mut self.before_block, true);
+        let range = self.iterable();
+        self.before_block = outer_before_block;
         let block = self.block(true);
         let end = self.get_span_end(block);
 
@@ -1132,6 +1843,36 @@ impl Parser {
         )
     }
 
+    /// Parse what a `for` loop iterates over: either a range or an expression that gives an array
+    pub fn iterable(&mut self) -> NodeId {
+        let start = self.position();
+
+        let lhs = self.expression();
+
+        let inclusive = if self.is_dotdot() {
+            self.next();
+            false
+        } else if self.is_dotdot_equals() {
+            self.next();
+            true
+        } else {
+            return lhs;
+        };
+
+        let rhs = self.expression();
+        let end = self.get_span_end(rhs);
+
+        let span = Span { start, end };
+        self.create_node(
+            AstNode::Range {
+                lhs,
+                rhs,
+                inclusive,
+            },
+            span,
+        )
+    }
+
     pub fn variable(&mut self) -> NodeId {
         if self.is_name() {
             let name = self
@@ -1152,7 +1893,15 @@ impl Parser {
                 .next()
                 .expect("internal error: missing token that was expected to be there");
 
-            if self.is_lparen() {
+            let has_fields = self.is_token_after_next(TokenType::Name)
+                && self.is_token_at(self.current_token + 2, TokenType::Colon);
+            let is_empty = !self.before_block && self.is_token_after_next(TokenType::RCurly);
+
+            if self.is_lcurly() && (has_fields || is_empty) {
+                // We're a struct literal, like `Point { x: 1.0, y: 2.0 }` or `Empty {}`
+                let name = self.create_node(AstNode::Name, name.span);
+                self.struct_literal(start, name)
+            } else if self.is_lparen() {
                 let head = self.create_node(AstNode::Name, name.span);
                 // We're a call
                 self.lparen();
@@ -1231,6 +1980,34 @@ impl Parser {
         }
     }
 
+    pub fn lsquare(&mut self) {
+        match self.peek() {
+            Some(Token {
+                token_type: TokenType::LSquare,
+                ..
+            }) => {
+                self.next();
+            }
+            _ => {
+                self.error("expected: left square bracket '['");
+            }
+        }
+    }
+
+    pub fn rsquare(&mut self) {
+        match self.peek() {
+            Some(Token {
+                token_type: TokenType::RSquare,
+                ..
+            }) => {
+                self.next();
+            }
+            _ => {
+                self.error("expected: right square bracket ']'");
+            }
+        }
+    }
+
     pub fn rcurly(&mut self) {
         match self.peek() {
             Some(Token {
@@ -1259,6 +2036,62 @@ impl Parser {
         }
     }
 
+    pub fn fat_arrow(&mut self) {
+        match self.peek() {
+            Some(Token {
+                token_type: TokenType::EqualsGreaterThan,
+                ..
+            }) => {
+                self.next();
+            }
+            _ => {
+                self.error("expected: fat arrow '=>'");
+            }
+        }
+    }
+
+    pub fn pipe(&mut self) {
+        match self.peek() {
+            Some(Token {
+                token_type: TokenType::Pipe,
+                ..
+            }) => {
+                self.next();
+            }
+            _ => {
+                self.error("expected: pipe '|'");
+            }
+        }
+    }
+
+    /// Consume the `>` that closes a list of type parameters. The lexer reads the end of
+    /// `Option<Option<i64>>` as one `>>`, so that's split in two, leaving its second half.
+    pub fn greater_than(&mut self) {
+        match self.peek() {
+            Some(Token {
+                token_type: TokenType::GreaterThan,
+                ..
+            }) => {
+                self.next();
+            }
+            Some(Token {
+                token_type: TokenType::GreaterThanGreaterThan,
+                span,
+            }) => {
+                self.tokens[self.current_token] = Token {
+                    token_type: TokenType::GreaterThan,
+                    span: Span {
+                        start: span.start + 1,
+                        end: span.end,
+                    },
+                };
+            }
+            _ => {
+                self.error("expected: greater than '>'");
+            }
+        }
+    }
+
     pub fn colon(&mut self) {
         match self.peek() {
             Some(Token {
@@ -1292,27 +2125,49 @@ impl ParseResults {
         }
     }
 
+    /// Add the results of parsing a later snippet of the same source, whose node ids continue
+    /// after ours
+    pub fn append(&mut self, mut other: ParseResults) {
+        assert_eq!(
+            other.node_id_offset,
+            self.ast_nodes.len(),
+            "internal error: appended parse results should continue our node ids"
+        );
+
+        self.spans.append(&mut other.spans);
+        self.ast_nodes.append(&mut other.ast_nodes);
+        self.contents = other.contents;
+    }
+
+    /// Forget every node from the given node id onwards
+    pub fn truncate(&mut self, num_nodes: usize) {
+        self.spans.truncate(num_nodes);
+        self.ast_nodes.truncate(num_nodes);
+    }
+
     pub fn print(&self) {
         if self.ast_nodes.is_empty() {
             println!("<empty>");
         } else {
-            self.print_helper(&NodeId(self.ast_nodes.len() - 1), 0)
+            self.print_helper(&NodeId(self.ast_nodes.len() - 1 + self.node_id_offset), 0)
         }
     }
 
     fn print_helper(&self, node_id: &NodeId, indent: usize) {
+        let idx = node_id.0 - self.node_id_offset;
+
         for _ in 0..indent {
             print!(" ")
         }
 
-        match &self.ast_nodes[node_id.0] {
+        match &self.ast_nodes[idx] {
             AstNode::Let {
                 variable_name,
                 ty,
                 initializer,
                 is_mutable,
             } => {
-                println!("Let ({}, mutable: {}):", self.spans[node_id.0], is_mutable);
+                println!("Let ({}, mutable: {}):", self.spans[idx], is_mutable);
                 self.print_helper(variable_name, indent + 2);
                 if let Some(ty) = ty {
                     self.print_helper(ty, indent + 2);
@@ -1320,38 +2175,42 @@ impl ParseResults {
                 self.print_helper(initializer, indent + 2);
             }
             AstNode::Param { name, ty } => {
-                println!("Param {}:", self.spans[node_id.0],);
+                println!("Param {}:", self.spans[idx],);
                 self.print_helper(name, indent + 2);
                 if let Some(ty) = ty {
                     self.print_helper(ty, indent + 2);
                 }
             }
-            // AstNode::Closure { params, block } => {
-            //     println!(
-            //         "Closure {}:",
-            //         self.spans[node_id.0],
-            //     );
-            //     self.print_helper(params, indent + 2);
-            //     self.print_helper(block, indent + 2);
-            // }
+            AstNode::Closure { params, ret, body } => {
+                println!("Closure {}:", self.spans[idx],);
+                self.print_helper(params, indent + 2);
+                if let Some(ret) = ret {
+                    self.print_helper(ret, indent + 2);
+                }
+                self.print_helper(body, indent + 2);
+            }
             AstNode::Fn {
                 name,
                 params,
+                ret,
                 block,
             } => {
-                println!("Fn:",);
+                println!("Fn {}:", self.spans[idx]);
                 self.print_helper(name, indent + 2);
                 self.print_helper(params, indent + 2);
+                if let Some(ret) = ret {
+                    self.print_helper(ret, indent + 2);
+                }
                 self.print_helper(block, indent + 2);
             }
             AstNode::Block(nodes) => {
-                println!("Block {}:", self.spans[node_id.0],);
+                println!("Block {}:", self.spans[idx],);
                 for node in nodes {
                     self.print_helper(node, indent + 2);
                 }
             }
             AstNode::Params(nodes) => {
-                print!("Params {}:", self.spans[node_id.0],);
+                print!("Params {}:", self.spans[idx],);
                 if nodes.is_empty() {
                     println!(" <empty>");
                 } else {
@@ -1363,7 +2222,7 @@ impl ParseResults {
                 }
             }
             AstNode::Call { head, args } => {
-                println!("Call {}:", self.spans[node_id.0],);
+                println!("Call {}:", self.spans[idx],);
                 self.print_helper(head, indent + 2);
 
                 for arg in args {
@@ -1371,37 +2230,158 @@ impl ParseResults {
                 }
             }
             AstNode::BinaryOp { lhs, op, rhs } => {
-                println!("BinaryOp {}:", self.spans[node_id.0],);
+                println!("BinaryOp {}:", self.spans[idx],);
 
                 self.print_helper(lhs, indent + 2);
                 self.print_helper(op, indent + 2);
                 self.print_helper(rhs, indent + 2)
             }
-            AstNode::Range { lhs, rhs } => {
-                println!("Range {}:", self.spans[node_id.0],);
+            AstNode::UnaryOp { op, operand } => {
+                println!("UnaryOp {}:", self.spans[idx],);
+
+                self.print_helper(op, indent + 2);
+                self.print_helper(operand, indent + 2)
+            }
+            AstNode::Cast { value, ty } => {
+                println!("Cast {}:", self.spans[idx],);
+
+                self.print_helper(value, indent + 2);
+                self.print_helper(ty, indent + 2)
+            }
+            AstNode::Range {
+                lhs,
+                rhs,
+                inclusive,
+            } => {
+                println!(
+                    "Range{} {}:",
+                    if *inclusive { " (inclusive)" } else { "" },
+                    self.spans[idx],
+                );
 
                 self.print_helper(lhs, indent + 2);
                 self.print_helper(rhs, indent + 2)
             }
+            AstNode::Array(items) => {
+                println!("Array {}:", self.spans[idx],);
+
+                for item in items {
+                    self.print_helper(item, indent + 2);
+                }
+            }
+            AstNode::Index { target, index } => {
+                println!("Index {}:", self.spans[idx],);
+
+                self.print_helper(target, indent + 2);
+                self.print_helper(index, indent + 2)
+            }
+            AstNode::Struct { name, fields } | AstNode::StructLiteral { name, fields } => {
+                println!(
+                    "{} {}:",
+                    if matches!(self.ast_nodes[idx], AstNode::Struct { .. }) {
+                        "Struct"
+                    } else {
+                        "StructLiteral"
+                    },
+                    self.spans[idx],
+                );
+
+                self.print_helper(name, indent + 2);
+                for (field, value) in fields {
+                    self.print_helper(field, indent + 2);
+                    self.print_helper(value, indent + 4);
+                }
+            }
+            AstNode::Field { target, field } => {
+                println!("Field {}:", self.spans[idx],);
+
+                self.print_helper(target, indent + 2);
+                self.print_helper(field, indent + 2)
+            }
+            AstNode::ArrayType(element) => {
+                println!("ArrayType {}:", self.spans[idx],);
+
+                self.print_helper(element, indent + 2)
+            }
+            AstNode::Interpolation(parts) => {
+                println!("Interpolation {}:", self.spans[idx],);
+
+                for part in parts {
+                    self.print_helper(part, indent + 2)
+                }
+            }
             AstNode::If {
                 condition,
                 then_block,
                 else_expression,
             } => {
-                println!("If {}:", self.spans[node_id.0],);
+                println!("If {}:", self.spans[idx],);
                 self.print_helper(condition, indent + 2);
                 self.print_helper(then_block, indent + 2);
                 if let Some(else_expression) = else_expression {
                     self.print_helper(else_expression, indent + 2)
                 }
             }
+            AstNode::Return(value) => {
+                println!("Return {}:", self.spans[idx],);
+                if let Some(value) = value {
+                    self.print_helper(value, indent + 2)
+                }
+            }
+            AstNode::GenericType { name, params } => {
+                println!("GenericType {}:", self.spans[idx],);
+                self.print_helper(name, indent + 2);
+                for param in params {
+                    self.print_helper(param, indent + 2)
+                }
+            }
+            AstNode::FunctionType { params, ret } => {
+                println!("FunctionType {}:", self.spans[idx],);
+                for param in params {
+                    self.print_helper(param, indent + 2)
+                }
+                if let Some(ret) = ret {
+                    self.print_helper(ret, indent + 2);
+                }
+            }
+            AstNode::Variant { variant, payload } => {
+                println!("Variant ({}) {}:", variant.name(), self.spans[idx],);
+                if let Some(payload) = payload {
+                    self.print_helper(payload, indent + 2)
+                }
+            }
+            AstNode::Match { target, arms } => {
+                println!("Match {}:", self.spans[idx],);
+                self.print_helper(target, indent + 2);
+                for (pattern, body) in arms {
+                    self.print_helper(pattern, indent + 2);
+                    self.print_helper(body, indent + 4);
+                }
+            }
+            AstNode::IfLet {
+                pattern,
+                value,
+                then_block,
+                else_expression,
+            } => {
+                println!("IfLet {}:", self.spans[idx],);
+                self.print_helper(pattern, indent + 2);
+                self.print_helper(value, indent + 2);
+                self.print_helper(then_block, indent + 2);
+                if let Some(else_expression) = else_expression {
+                    self.print_helper(else_expression, indent + 2)
+                }
+            }
+            AstNode::Try(value) => {
+                println!("Try {}:", self.spans[idx],);
+                self.print_helper(value, indent + 2)
+            }
             x => {
-                println!("{:?} ({})", x, self.spans[node_id.0],)
+                println!("{:?} ({})", x, self.spans[idx],)
             }
         }
     }
 
-    #[cfg(feature = "lsp")]
     pub(crate) fn contents_for_node(&self, node_id: NodeId) -> &[u8] {
         let span = self.spans[node_id.0];
         self.contents_for_span(span)
@@ -1,9 +1,13 @@
-use std::{collections::HashMap, fmt};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
 
 use crate::{
-    engine::{ExternalFnRecord, PermanentDefinitions},
-    errors::{ErrorBatch, ScriptError},
-    parser::{AstNode, NodeId, ParseResults},
+    engine::{EnumKind, EnumType, ExternalField, ExternalFnRecord, PermanentDefinitions},
+    errors::{ErrorBatch, ErrorKind, ScriptError},
+    eval::{Callback, RuntimeType},
+    parser::{AstNode, NodeId, ParseResults, Span, Variant},
     Type, Value,
 };
 
@@ -44,54 +48,13 @@ impl Scope {
     }
 }
 
-#[cfg(feature = "async")]
-#[derive(Default)]
-pub enum Function {
-    ExternalFn0(Box<dyn Fn() -> Result<Value, String>>),
-    ExternalFn1(Box<dyn Fn(&mut Value) -> Result<Value, String>>),
-    ExternalFn2(Box<dyn Fn(&mut Value, &mut Value) -> Result<Value, String>>),
-    ExternalFn3(Box<dyn Fn(&mut Value, &mut Value, &mut Value) -> Result<Value, String>>),
-    ExternalFn4(
-        Box<dyn Fn(&mut Value, &mut Value, &mut Value, &mut Value) -> Result<Value, String>>,
-    ),
-    ExternalAsyncFn0(fn() -> futures::future::BoxFuture<'static, Result<Value, String>>),
-    ExternalAsyncFn1(
-        for<'a> fn(&'a mut Value) -> futures::future::BoxFuture<'a, Result<Value, String>>,
-    ),
-    ExternalAsyncFn2(
-        for<'a> fn(
-            &'a mut Value,
-            &'a mut Value,
-        ) -> futures::future::BoxFuture<'a, Result<Value, String>>,
-    ),
-    ExternalAsyncFn3(
-        for<'a> fn(
-            &'a mut Value,
-            &'a mut Value,
-            &'a mut Value,
-        ) -> futures::future::BoxFuture<'a, Result<Value, String>>,
-    ),
-    ExternalAsyncFn4(
-        for<'a> fn(
-            &'a mut Value,
-            &'a mut Value,
-            &'a mut Value,
-            &'a mut Value,
-        ) -> futures::future::BoxFuture<'a, Result<Value, String>>,
-    ),
-    #[default]
-    RemoteFn,
-}
-
-#[cfg(not(feature = "async"))]
+/// A registered function, called with its arguments boxed in a slice
 #[derive(Default)]
 pub enum Function {
-    ExternalFn0(Box<dyn Fn() -> Result<Value, String>>),
-    ExternalFn1(Box<dyn Fn(&mut Value) -> Result<Value, String>>),
-    ExternalFn2(Box<dyn Fn(&mut Value, &mut Value) -> Result<Value, String>>),
-    ExternalFn3(Box<dyn Fn(&mut Value, &mut Value, &mut Value) -> Result<Value, String>>),
-    ExternalFn4(
-        Box<dyn Fn(&mut Value, &mut Value, &mut Value, &mut Value) -> Result<Value, String>>,
+    ExternalFn(Box<dyn Fn(&mut [Value]) -> Result<Value, String> + Send + Sync>),
+    #[cfg(feature = "async")]
+    ExternalAsyncFn(
+        for<'a> fn(&'a mut [Value]) -> futures::future::BoxFuture<'a, Result<Value, String>>,
     ),
     #[default]
     RemoteFn,
@@ -105,10 +68,92 @@ pub struct ExternalFunctionId(pub usize);
 pub struct FunctionId(pub usize);
 
 pub struct Variable {
-    type_id: TypeId,
+    pub type_id: TypeId,
     is_mutable: bool,
 }
 
+/// A variable the host binds before the script runs, like `request` in a script handling one
+///
+/// Globals live in the registers right after the script body's return register, in order. They
+/// can be used anywhere in the script, closures capture them like other variables, and functions
+/// load them from the script body's registers.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "lsp", derive(serde::Serialize, serde::Deserialize))]
+pub struct Global {
+    pub name: String,
+    pub type_id: TypeId,
+    pub is_mutable: bool,
+}
+
+/// Something from outside of a closure that the closure copies when it's created
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capture {
+    // A variable, by its definition
+    Variable(NodeId),
+    // A global, by its index in `TypeChecker::globals`
+    Global(usize),
+}
+
+/// A function defined in the script itself, or the body of a closure
+///
+/// The script body is always `FunctionId(0)`, so the local function at index `idx` is
+/// `FunctionId(idx + 1)`.
+pub struct LocalFunction {
+    pub node_id: NodeId,
+    pub params: Vec<NodeId>,
+    pub param_types: Vec<TypeId>,
+    pub ret: TypeId,
+    pub block: NodeId,
+
+    // The variables and globals a closure copies from outside of it
+    pub captures: Vec<Capture>,
+}
+
+/// A closure whose body is being checked, and the variables from outside of it that it uses
+struct ClosureCaptures {
+    // How many scopes were in the scope stack when the closure started
+    scope_depth: usize,
+    captures: Vec<Capture>,
+}
+
+/// A type that only exists in the script, either a struct it defines or the type of a closure
+///
+/// These get the `TypeId`s right after the ones in `PermanentDefinitions::types`, so the type at
+/// index `idx` is `TypeId(types.len() + idx)`.
+pub enum LocalType {
+    Struct(LocalStruct),
+    Function(FunctionType),
+}
+
+/// A struct type defined in the script itself
+pub struct LocalStruct {
+    pub node_id: NodeId,
+    pub name: Vec<u8>,
+    pub fields: Vec<(Vec<u8>, TypeId)>,
+
+    // The block the struct is defined in, which it can only be used inside of, or None for the
+    // top level of the script
+    pub block: Option<NodeId>,
+}
+
+impl LocalStruct {
+    pub fn field(&self, name: &[u8]) -> Option<(usize, TypeId)> {
+        self.fields
+            .iter()
+            .position(|(field_name, _)| field_name == name)
+            .map(|idx| (idx, self.fields[idx].1))
+    }
+}
+
+/// The type of a closure taking the given parameters, written `fn(i64) -> bool` in scripts
+///
+/// Closures with the same signature share a type, whatever they capture.
+#[derive(PartialEq, Eq)]
+pub struct FunctionType {
+    pub params: Vec<TypeId>,
+    pub ret: TypeId,
+}
+
 pub struct TypeChecker<'permanent> {
     // The globally registered definitions that are available before
     // we start typechecking the current script
@@ -126,13 +171,60 @@ pub struct TypeChecker<'permanent> {
     // Mapping betwen definition node id and the full variable definition
     pub variable_info: HashMap<NodeId, Variable>,
 
+    // The variables bound by the host, which take priority over the script's own
+    pub globals: Vec<Global>,
+
+    // Which global each use of one refers to, based on NodeId
+    pub global_resolution: HashMap<NodeId, usize>,
+
     // List of local functions
-    // note: local functions not yet supported
-    // pub local_functions: Vec<ExternalFnRecord>,
+    pub local_functions: Vec<LocalFunction>,
+
+    // Local functions by name, to allow overloading
+    pub local_function_names: HashMap<Vec<u8>, Vec<FunctionId>>,
+
+    // List of struct types defined in the script, and the types of its closures
+    pub local_types: Vec<LocalType>,
+
+    // Field index of each field access, based on NodeId
+    pub field_resolution: HashMap<NodeId, usize>,
+
+    // Fields of registered types, read and assigned through their getter and setter
+    pub external_field_resolution: HashMap<NodeId, &'permanent ExternalField>,
 
     // Call resolution
     pub call_resolution: HashMap<NodeId, ExternalFunctionId>,
 
+    // Call resolution for calls to local functions
+    pub local_call_resolution: HashMap<NodeId, FunctionId>,
+
+    // Whether an i64 operand may meet an f64 one, by converting it to f64
+    pub numeric_promotion: bool,
+
+    // The i64 operands that get converted to f64, based on NodeId
+    pub promoted_nodes: HashSet<NodeId>,
+
+    // How many loops surround the node being checked, within the current function
+    pub loop_depth: usize,
+
+    // The declared return type of the function being checked, or None in the script body
+    pub return_type: Option<TypeId>,
+
+    // The type the host wants the script's value to have, if it cares
+    pub expected_type: Option<TypeId>,
+
+    // The `return`s in the script body, which have to agree with the script's value
+    pub main_returns: Vec<NodeId>,
+
+    // The `?`s in the script body, which can only return early if the script's value allows it
+    pub main_tries: Vec<NodeId>,
+
+    // The type each `?` returns early with, based on NodeId
+    pub try_return_types: HashMap<NodeId, TypeId>,
+
+    // The closures around the node being checked, innermost last
+    closures: Vec<ClosureCaptures>,
+
     pub errors: ErrorBatch,
     pub scope_stack: Vec<ScopeId>,
     pub scope: Vec<Scope>,
@@ -165,8 +257,29 @@ impl<'permanent> TypeChecker<'permanent> {
             node_types: vec![],
             variable_def_site: HashMap::new(),
             variable_info: HashMap::new(),
+            globals: vec![],
+            global_resolution: HashMap::new(),
+
+            local_functions: vec![],
+            local_function_names: HashMap::new(),
+
+            local_types: vec![],
+            field_resolution: HashMap::new(),
+            external_field_resolution: HashMap::new(),
 
             call_resolution: HashMap::new(),
+            local_call_resolution: HashMap::new(),
+
+            numeric_promotion: false,
+            promoted_nodes: HashSet::new(),
+
+            loop_depth: 0,
+            return_type: None,
+            expected_type: None,
+            main_returns: vec![],
+            main_tries: vec![],
+            try_return_types: HashMap::new(),
+            closures: vec![],
 
             scope: vec![],
             scope_stack: vec![],
@@ -181,6 +294,7 @@ impl<'permanent> TypeChecker<'permanent> {
         self.errors.push(ScriptError {
             message: message.into(),
             span,
+            kind: ErrorKind::Script,
         })
     }
 
@@ -198,40 +312,54 @@ impl<'permanent> TypeChecker<'permanent> {
 
     pub fn typecheck_node(&mut self, node_id: NodeId) {
         match &self.parse_results.ast_nodes[node_id.0] {
-            AstNode::Int => {
-                self.node_types[node_id.0] = I64_TYPE;
-            }
+            AstNode::Int => self.typecheck_int(node_id, false),
             AstNode::Float => {
                 self.node_types[node_id.0] = F64_TYPE;
             }
-            AstNode::String => {
+            AstNode::String | AstNode::Text => {
+                self.node_types[node_id.0] = STRING_TYPE;
+            }
+            AstNode::Interpolation(parts) => {
+                for part in parts.clone() {
+                    self.typecheck_node(part);
+
+                    let part_type = self.node_types[part.0];
+                    if !matches!(part_type, I64_TYPE | F64_TYPE | BOOL_TYPE | STRING_TYPE) {
+                        self.error(
+                            format!(
+                                "values of type {} can't be interpolated into a string",
+                                self.stringify_type(part_type)
+                            ),
+                            part,
+                        )
+                    }
+                }
                 self.node_types[node_id.0] = STRING_TYPE;
             }
             AstNode::BinaryOp { lhs, op, rhs } => {
                 self.typecheck_binop(*lhs, *op, *rhs, node_id);
             }
+            AstNode::UnaryOp { op, operand } => {
+                self.typecheck_unary_op(*op, *operand, node_id);
+            }
+            AstNode::Cast { value, ty } => {
+                self.typecheck_cast(*value, *ty, node_id);
+            }
             AstNode::Statement(node) => {
-                self.typecheck_node(*node);
-                self.node_types[node_id.0] = UNIT_TYPE;
+                let node = *node;
+                self.typecheck_node(node);
+
+                // Nothing after a `return` runs, so `return x;` still gives its block x's type
+                self.node_types[node_id.0] =
+                    if matches!(self.parse_results.ast_nodes[node.0], AstNode::Return(_)) {
+                        self.node_types[node.0]
+                    } else {
+                        UNIT_TYPE
+                    };
             }
             AstNode::Block(nodes) => {
-                if nodes.is_empty() {
-                    self.node_types[node_id.0] = UNIT_TYPE;
-                } else {
-                    // FIXME: clone to get around ownership issue
-                    let nodes = nodes.clone();
-
-                    self.enter_scope(node_id);
-                    // FIXME: grab the last one if it's an expression
-                    let mut type_id = UNIT_TYPE;
-                    for node_id in nodes {
-                        self.typecheck_node(node_id);
-
-                        type_id = self.node_types[node_id.0];
-                    }
-                    self.node_types[node_id.0] = type_id;
-                    self.exit_scope();
-                }
+                // FIXME: clone to get around ownership issue
+                self.typecheck_block(&nodes.clone(), node_id, None)
             }
             AstNode::Type => {
                 let span = self.parse_results.spans[node_id.0];
@@ -243,12 +371,48 @@ impl<'permanent> TypeChecker<'permanent> {
                     b"f64" => self.node_types[node_id.0] = F64_TYPE,
                     b"bool" => self.node_types[node_id.0] = BOOL_TYPE,
                     b"String" => self.node_types[node_id.0] = STRING_TYPE,
+                    _ if self.find_struct(contents).is_some() => {
+                        self.node_types[node_id.0] = self
+                            .find_struct(contents)
+                            .expect("internal error: struct went missing");
+                    }
                     _ => self.error(
                         format!("unknown type: {}", String::from_utf8_lossy(contents)),
                         node_id,
                     ),
                 }
             }
+            AstNode::GenericType { name, params } => {
+                self.typecheck_generic_type(*name, &params.clone(), node_id)
+            }
+            AstNode::FunctionType { params, ret } => {
+                self.typecheck_function_type(&params.clone(), *ret, node_id)
+            }
+            AstNode::ArrayType(element) => {
+                let element = *element;
+                self.typecheck_node(element);
+
+                let element_type = self.node_types[element.0];
+                if let Some(array_type) = self.array_type(element_type) {
+                    self.node_types[node_id.0] = array_type;
+                } else if element_type != UNKNOWN_TYPE {
+                    self.error(
+                        format!(
+                            "arrays of {} are not supported",
+                            self.stringify_type(element_type)
+                        ),
+                        node_id,
+                    )
+                }
+            }
+            AstNode::Array(items) => self.typecheck_array(&items.clone(), node_id),
+            AstNode::Index { target, index } => self.typecheck_index(*target, *index, node_id),
+            // Structs are declared before typechecking starts
+            AstNode::Struct { .. } => self.node_types[node_id.0] = UNIT_TYPE,
+            AstNode::StructLiteral { name, fields } => {
+                self.typecheck_struct_literal(*name, &fields.clone(), node_id)
+            }
+            AstNode::Field { target, field } => self.typecheck_field(*target, *field, node_id),
             AstNode::Let {
                 variable_name,
                 ty,
@@ -260,22 +424,56 @@ impl<'permanent> TypeChecker<'permanent> {
                 condition,
                 then_block,
                 else_expression,
-            } => self.typecheck_if(*condition, *then_block, *else_expression, node_id),
+            } => self.typecheck_if(*condition, *then_block, *else_expression, node_id, None),
+            AstNode::IfLet {
+                pattern,
+                value,
+                then_block,
+                else_expression,
+            } => self.typecheck_if_let(
+                *pattern,
+                *value,
+                *then_block,
+                *else_expression,
+                node_id,
+                None,
+            ),
+            AstNode::Match { target, arms } => {
+                self.typecheck_match(*target, &arms.clone(), node_id, None)
+            }
+            AstNode::Variant { variant, payload } => {
+                self.typecheck_variant(*variant, *payload, node_id, None)
+            }
+            AstNode::Try(value) => self.typecheck_try(*value, node_id),
             AstNode::While { condition, block } => {
                 self.typecheck_while(*condition, *block, node_id)
             }
-            // AstNode::For {
-            //     variable,
-            //     range,
-            //     block,
-            // } => self.typecheck_for(*variable, *range, *block, node_id, parse_results),
+            AstNode::Fn { .. } => self.typecheck_fn(node_id),
+            AstNode::Closure { params, ret, body } => {
+                self.typecheck_closure(*params, *ret, *body, node_id, None)
+            }
+            AstNode::Return(value) => self.typecheck_return(*value, node_id),
+            AstNode::Break | AstNode::Continue => {
+                if self.loop_depth == 0 {
+                    let keyword =
+                        if matches!(self.parse_results.ast_nodes[node_id.0], AstNode::Break) {
+                            "break"
+                        } else {
+                            "continue"
+                        };
+                    self.error(format!("`{keyword}` outside of a loop"), node_id);
+                }
+                self.node_types[node_id.0] = UNIT_TYPE;
+            }
+            AstNode::For {
+                variable,
+                range,
+                block,
+            } => self.typecheck_for(*variable, *range, *block, node_id),
             AstNode::True => self.node_types[node_id.0] = BOOL_TYPE,
             AstNode::False => self.node_types[node_id.0] = BOOL_TYPE,
-            // AstNode::Range { lhs, rhs } => self.typecheck_range(*lhs, *rhs, node_id, parse_results),
-            AstNode::Call { head, args } => {
-                // FIXME: clone to get around ownership issue
-                self.typecheck_call(*head, &args.clone(), node_id)
-            }
+            AstNode::Range { lhs, rhs, .. } => self.typecheck_range(*lhs, *rhs, node_id),
+            AstNode::Call { head, args } => self.typecheck_call(*head, &args.clone(), node_id),
             AstNode::Await(inner_node_id) => {
                 let inner_node_id = *inner_node_id;
 
@@ -293,7 +491,7 @@ impl<'permanent> TypeChecker<'permanent> {
                     self.error(
                         format!(
                             "expected future type for .await, found {}",
-                            &self.permanent_definitions.typenames[inner_type_id.0]
+                            self.stringify_type(inner_type_id)
                         ),
                         node_id,
                     )
@@ -303,6 +501,85 @@ impl<'permanent> TypeChecker<'permanent> {
         }
     }
 
+    /// Typecheck a node where a value of the expected type is wanted, like the initializer of a
+    /// `let` with a declared type
+    ///
+    /// Values like `None` and `[]` can only find their type this way. Getting a different type
+    /// isn't an error here, as it's up to the caller to report a mismatch.
+    pub fn typecheck_expecting(&mut self, node_id: NodeId, expected: TypeId) {
+        let expected = (expected != UNKNOWN_TYPE).then_some(expected);
+
+        match &self.parse_results.ast_nodes[node_id.0] {
+            AstNode::Array(items)
+                if items.is_empty() && expected.and_then(|ty| self.element_type(ty)).is_some() =>
+            {
+                self.node_types[node_id.0] = expected.unwrap_or(UNKNOWN_TYPE);
+            }
+            AstNode::Block(nodes) => {
+                // FIXME: clone to get around ownership issue
+                self.typecheck_block(&nodes.clone(), node_id, expected)
+            }
+            AstNode::If {
+                condition,
+                then_block,
+                else_expression,
+            } => self.typecheck_if(*condition, *then_block, *else_expression, node_id, expected),
+            AstNode::IfLet {
+                pattern,
+                value,
+                then_block,
+                else_expression,
+            } => self.typecheck_if_let(
+                *pattern,
+                *value,
+                *then_block,
+                *else_expression,
+                node_id,
+                expected,
+            ),
+            AstNode::Match { target, arms } => {
+                self.typecheck_match(*target, &arms.clone(), node_id, expected)
+            }
+            AstNode::Variant { variant, payload } => {
+                self.typecheck_variant(*variant, *payload, node_id, expected)
+            }
+            AstNode::Closure { params, ret, body } => {
+                self.typecheck_closure(*params, *ret, *body, node_id, expected)
+            }
+            _ => self.typecheck_node(node_id),
+        }
+    }
+
+    fn typecheck_expecting_maybe(&mut self, node_id: NodeId, expected: Option<TypeId>) {
+        match expected {
+            Some(expected) => self.typecheck_expecting(node_id, expected),
+            None => self.typecheck_node(node_id),
+        }
+    }
+
+    pub fn typecheck_block(&mut self, nodes: &[NodeId], node_id: NodeId, expected: Option<TypeId>) {
+        if nodes.is_empty() {
+            self.node_types[node_id.0] = UNIT_TYPE;
+            return;
+        }
+
+        self.enter_scope(node_id);
+        // A trailing expression is the block's value. Statements, including
+        // expressions followed by `;`, have unit type, so a block ending in one is unit.
+        let mut type_id = UNIT_TYPE;
+        for (idx, node_id) in nodes.iter().enumerate() {
+            if idx == nodes.len() - 1 {
+                self.typecheck_expecting_maybe(*node_id, expected);
+            } else {
+                self.typecheck_node(*node_id);
+            }
+
+            type_id = self.node_types[node_id.0];
+        }
+        self.node_types[node_id.0] = type_id;
+        self.exit_scope();
+    }
+
     pub fn typecheck(&mut self) -> Result<(), ErrorBatch> {
         if !self.parse_results.ast_nodes.is_empty() {
             self.node_types = vec![UNKNOWN_TYPE; self.parse_results.ast_nodes.len()];
@@ -314,7 +591,30 @@ impl<'permanent> TypeChecker<'permanent> {
             self.scope.push(Scope::new(last_node_id));
             self.scope_stack.push(ScopeId(0));
 
-            self.typecheck_node(NodeId(last));
+            // Structs can also be used before they're defined, and function signatures may use
+            // them, so they're declared first
+            self.declare_structs(0..self.parse_results.ast_nodes.len(), last_node_id);
+            for idx in 0..self.local_types.len() {
+                self.resolve_struct_fields(idx);
+            }
+
+            // Functions can be called before they're defined (and can call themselves),
+            // so learn all the signatures before checking any bodies
+            for idx in 0..self.parse_results.ast_nodes.len() {
+                if matches!(self.parse_results.ast_nodes[idx], AstNode::Fn { .. }) {
+                    self.declare_fn(NodeId(idx));
+                }
+            }
+
+            match self.expected_type {
+                Some(expected) => self.typecheck_expecting(NodeId(last), expected),
+                None => self.typecheck_node(NodeId(last)),
+            }
+            self.check_main_returns(self.node_types[last], "the script's value");
+        }
+
+        if let Some(expected) = self.expected_type {
+            self.check_script_type(expected);
         }
 
         if self.errors.is_empty() {
@@ -324,6 +624,99 @@ impl<'permanent> TypeChecker<'permanent> {
         }
     }
 
+    /// Typecheck a snippet that continues the source we've already checked, as when running a
+    /// script one REPL line at a time
+    ///
+    /// The snippet's top-level definitions go in the outermost scope, where later snippets can
+    /// see them. If the snippet has errors, everything it defined is forgotten again. Returns the
+    /// snippet's block.
+    pub fn typecheck_snippet(&mut self, parse_results: ParseResults) -> Result<NodeId, ErrorBatch> {
+        let num_nodes = self.parse_results.ast_nodes.len();
+        let num_types = self.local_types.len();
+        let num_functions = self.local_functions.len();
+        let num_scopes = self.scope.len();
+
+        self.parse_results.append(parse_results);
+        self.node_types
+            .resize(self.parse_results.ast_nodes.len(), UNKNOWN_TYPE);
+
+        // The snippet's block is the last node the parser created
+        let block = NodeId(self.parse_results.ast_nodes.len() - 1);
+
+        if self.scope.is_empty() {
+            self.scope.push(Scope::new(block));
+            self.scope_stack.push(ScopeId(0));
+        }
+        let variables = self.scope[0].variables.clone();
+        let function_names = self.local_function_names.clone();
+
+        self.declare_structs(num_nodes..self.parse_results.ast_nodes.len(), block);
+        for idx in num_types..self.local_types.len() {
+            self.resolve_struct_fields(idx);
+        }
+        for idx in num_nodes..self.parse_results.ast_nodes.len() {
+            if matches!(self.parse_results.ast_nodes[idx], AstNode::Fn { .. }) {
+                self.declare_fn(NodeId(idx));
+            }
+        }
+
+        // Check the statements directly rather than as a block, so they stay in the outermost
+        // scope
+        let AstNode::Block(nodes) = &self.parse_results.ast_nodes[block.0] else {
+            panic!("internal error: snippet should parse to a block")
+        };
+        let mut type_id = UNIT_TYPE;
+        for node_id in nodes.clone() {
+            self.typecheck_node(node_id);
+
+            type_id = self.node_types[node_id.0];
+        }
+        self.node_types[block.0] = type_id;
+        self.check_main_returns(type_id, "the script's value");
+
+        if self.errors.is_empty() {
+            return Ok(block);
+        }
+
+        // Forget the snippet, so later snippets can't see any of its definitions
+        self.parse_results.truncate(num_nodes);
+        self.node_types.truncate(num_nodes);
+        self.local_types.truncate(num_types);
+        self.local_functions.truncate(num_functions);
+        self.local_function_names = function_names;
+        self.scope.truncate(num_scopes.max(1));
+        self.scope_stack.truncate(1);
+        self.scope[0].variables = variables;
+
+        let is_forgotten = |node_id: &NodeId| node_id.0 >= num_nodes;
+        self.variable_def_site
+            .retain(|node_id, def_site| !is_forgotten(node_id) && !is_forgotten(def_site));
+        self.variable_info
+            .retain(|node_id, _| !is_forgotten(node_id));
+        self.global_resolution
+            .retain(|node_id, _| !is_forgotten(node_id));
+        self.field_resolution
+            .retain(|node_id, _| !is_forgotten(node_id));
+        self.external_field_resolution
+            .retain(|node_id, _| !is_forgotten(node_id));
+        self.call_resolution
+            .retain(|node_id, _| !is_forgotten(node_id));
+        self.local_call_resolution
+            .retain(|node_id, _| !is_forgotten(node_id));
+        self.try_return_types
+            .retain(|node_id, _| !is_forgotten(node_id));
+        self.promoted_nodes.retain(|node_id| !is_forgotten(node_id));
+
+        Err(std::mem::replace(&mut self.errors, ErrorBatch::empty()))
+    }
+
+    /// Replace the variables of the outermost scope, to forget the ones a snippet defined
+    pub fn restore_variables(&mut self, variables: HashMap<Vec<u8>, NodeId>) {
+        if let Some(scope) = self.scope.first_mut() {
+            scope.variables = variables;
+        }
+    }
+
     pub fn typecheck_let(
         &mut self,
         variable_name: NodeId,
@@ -332,15 +725,16 @@ impl<'permanent> TypeChecker<'permanent> {
         is_mutable: bool,
         node_id: NodeId,
     ) {
-        self.typecheck_node(initializer);
-
         if let Some(ty) = ty {
             self.typecheck_node(ty);
+            self.typecheck_expecting(initializer, self.node_types[ty.0]);
 
             // TODO make this a compatibility check rather than equality check
             if self.node_types[ty.0] != self.node_types[initializer.0] {
                 self.error("initializer does not match declared type", initializer)
             }
+        } else {
+            self.typecheck_node(initializer);
         }
 
         self.define_variable(
@@ -364,6 +758,7 @@ impl<'permanent> TypeChecker<'permanent> {
         then_block: NodeId,
         else_expression: Option<NodeId>,
         node_id: NodeId,
+        expected: Option<TypeId>,
     ) {
         self.typecheck_node(condition);
         let condition_ty = self.node_types[condition.0];
@@ -372,119 +767,1261 @@ impl<'permanent> TypeChecker<'permanent> {
             self.error("expected bool for if condition", condition);
         }
 
-        self.typecheck_node(then_block);
+        self.typecheck_expecting_maybe(then_block, expected);
+        self.typecheck_else(then_block, else_expression, node_id, expected);
+    }
+
+    /// Typecheck the `else` of an `if` or `if let`, and give the whole expression its type
+    fn typecheck_else(
+        &mut self,
+        then_block: NodeId,
+        else_expression: Option<NodeId>,
+        node_id: NodeId,
+        expected: Option<TypeId>,
+    ) {
         let then_ty = self.node_types[then_block.0];
 
-        if let Some(else_expression) = else_expression {
-            self.typecheck_node(else_expression);
-            let else_ty = self.node_types[else_expression.0];
+        // Without an else there may be no value, so the `if` is unit whatever its block is
+        let Some(else_expression) = else_expression else {
+            self.node_types[node_id.0] = UNIT_TYPE;
+            return;
+        };
+
+        let else_expected = expected.or_else(|| (!self.diverges(then_block)).then_some(then_ty));
+        self.typecheck_expecting_maybe(else_expression, else_expected);
+        let else_ty = self.node_types[else_expression.0];
+
+        // An arm that always leaves, like one ending in `return`, can go with any other arm
+        self.node_types[node_id.0] = if self.diverges(then_block) {
+            else_ty
+        } else if self.diverges(else_expression) || then_ty == else_ty {
+            then_ty
+        } else {
+            if then_ty != UNKNOWN_TYPE && else_ty != UNKNOWN_TYPE {
+                let then_name = self.stringify_type(then_ty);
+                let else_name = self.stringify_type(else_ty);
 
-            if then_ty != else_ty {
-                self.error("then and else output different types", else_expression)
+                self.error(
+                    format!("if branch has type {then_name} but else branch has type {else_name}"),
+                    then_block,
+                );
+                self.error(
+                    format!("else branch has type {else_name} but if branch has type {then_name}"),
+                    else_expression,
+                );
             }
-        }
+            UNKNOWN_TYPE
+        };
+    }
 
-        self.node_types[node_id.0] = then_ty
+    #[allow(clippy::too_many_arguments)]
+    pub fn typecheck_if_let(
+        &mut self,
+        pattern: NodeId,
+        value: NodeId,
+        then_block: NodeId,
+        else_expression: Option<NodeId>,
+        node_id: NodeId,
+        expected: Option<TypeId>,
+    ) {
+        self.typecheck_node(value);
+        let value_ty = self.node_types[value.0];
+
+        // The pattern's variables are only visible in the block that runs when it matches
+        self.enter_scope(then_block);
+        self.typecheck_pattern(pattern, value_ty);
+        self.typecheck_expecting_maybe(then_block, expected);
+        self.exit_scope();
+
+        self.typecheck_else(then_block, else_expression, node_id, expected);
     }
 
-    pub fn typecheck_while(&mut self, condition: NodeId, block: NodeId, node_id: NodeId) {
-        self.typecheck_node(condition);
-        let condition_ty = self.node_types[condition.0];
+    pub fn typecheck_match(
+        &mut self,
+        target: NodeId,
+        arms: &[(NodeId, NodeId)],
+        node_id: NodeId,
+        expected: Option<TypeId>,
+    ) {
+        self.typecheck_node(target);
+        let target_ty = self.node_types[target.0];
+
+        // Like the arms of an `if`, arms that always leave can go with any other arm
+        let mut match_ty = None;
+        for (pattern, body) in arms {
+            self.enter_scope(*body);
+            self.typecheck_pattern(*pattern, target_ty);
+            self.typecheck_expecting_maybe(*body, expected.or(match_ty));
+            self.exit_scope();
+
+            if self.diverges(*body) {
+                continue;
+            }
 
-        if condition_ty != BOOL_TYPE {
-            self.error("expected bool for while condition", condition);
+            let body_ty = self.node_types[body.0];
+            match match_ty {
+                None => match_ty = Some(body_ty),
+                Some(match_ty) if match_ty != body_ty => {
+                    if match_ty != UNKNOWN_TYPE && body_ty != UNKNOWN_TYPE {
+                        self.error(
+                            format!(
+                                "match arm has type {} but the first arm has type {}",
+                                self.stringify_type(body_ty),
+                                self.stringify_type(match_ty)
+                            ),
+                            *body,
+                        )
+                    }
+                }
+                Some(_) => {}
+            }
         }
 
-        self.typecheck_node(block);
+        self.node_types[node_id.0] = match_ty
+            .or_else(|| arms.first().map(|(_, body)| self.node_types[body.0]))
+            .unwrap_or(UNIT_TYPE);
 
-        self.node_types[node_id.0] = UNIT_TYPE;
+        if target_ty != UNKNOWN_TYPE {
+            let patterns: Vec<_> = arms.iter().map(|(pattern, _)| *pattern).collect();
+            if let Some(missing) = self.missing_patterns(&patterns, target_ty) {
+                self.error(format!("match doesn't cover {missing}"), target);
+            }
+        }
     }
 
-    // pub fn typecheck_for(
-    //     &mut self,
-    //     variable_name: NodeId,
-    //     range: NodeId,
-    //     block: NodeId,
-    //     node_id: NodeId,
-    //     parse_results: &ParseResults,
-    // ) {
-    //     self.typecheck_node(range, parse_results);
-    //     let range_ty = self.node_types[range.0];
-
-    //     let range_inner_ty = match &self.types[range_ty.0] {
-    //         Type::Range(range_inner_ty) => *range_inner_ty,
-    //         _ => {
-    //             self.error("expected range value in for loop", range);
-    //             UNKNOWN_TYPE
-    //         }
-    //     };
-
-    //     self.typecheck_node(block, parse_results);
+    /// Describe the values none of the patterns match, if there are any
+    fn missing_patterns(&self, patterns: &[NodeId], type_id: TypeId) -> Option<String> {
+        let is_irrefutable = |pattern: NodeId| {
+            matches!(
+                self.parse_results.ast_nodes[pattern.0],
+                AstNode::Wildcard | AstNode::Variable
+            )
+        };
+        if patterns.iter().any(|pattern| is_irrefutable(*pattern)) {
+            return None;
+        }
 
-    //     self.define_variable(variable_name, parse_results);
+        // A variant is covered by a pattern that matches whatever it holds
+        let covers =
+            |pattern: &NodeId, expected: Variant| match self.parse_results.ast_nodes[pattern.0] {
+                AstNode::Variant { variant, payload } => {
+                    variant == expected && payload.is_none_or(is_irrefutable)
+                }
+                _ => false,
+            };
+
+        if let Some(enum_type) = self.enum_type(type_id) {
+            let variants = match enum_type.kind {
+                EnumKind::Option => [(Variant::Some, "`Some(_)`"), (Variant::None, "`None`")],
+                EnumKind::Result => [(Variant::Ok, "`Ok(_)`"), (Variant::Err, "`Err(_)`")],
+            };
+            let missing: Vec<_> = variants
+                .iter()
+                .filter(|(variant, _)| !patterns.iter().any(|pattern| covers(pattern, *variant)))
+                .map(|(_, name)| *name)
+                .collect();
+
+            return (!missing.is_empty()).then(|| missing.join(" or "));
+        }
 
-    //     self.node_types[variable_name.0] = range_inner_ty;
+        if type_id == BOOL_TYPE {
+            let has = |value: AstNode| {
+                patterns
+                    .iter()
+                    .any(|pattern| self.parse_results.ast_nodes[pattern.0] == value)
+            };
+            if has(AstNode::True) && has(AstNode::False) {
+                return None;
+            }
+        }
 
-    //     self.node_types[node_id.0] = VOID_TYPE;
-    // }
+        Some(format!(
+            "every value of type {}, add a `_` arm",
+            self.stringify_type(type_id)
+        ))
+    }
 
-    pub fn typecheck_binop(
-        &mut self,
-        lhs: NodeId,
-        op: NodeId,
-        rhs: NodeId,
-        node_id: NodeId, // whole expression NodeId
-    ) {
-        self.typecheck_node(lhs);
-        self.typecheck_node(rhs);
+    /// Typecheck a pattern that matches values of the given type, defining the variables it binds
+    pub fn typecheck_pattern(&mut self, pattern: NodeId, type_id: TypeId) {
+        self.node_types[pattern.0] = type_id;
 
-        let lhs_ty = self.node_types[lhs.0];
-        let rhs_ty = self.node_types[rhs.0];
-        let op_ast = &self.parse_results.ast_nodes[op.0];
+        if let AstNode::Variant { variant, payload } = self.parse_results.ast_nodes[pattern.0] {
+            let enum_type = self
+                .enum_type(type_id)
+                .filter(|enum_type| enum_type.kind == variant_kind(variant));
 
-        match op_ast {
-            AstNode::Assignment => {
-                // FIXME: replace with compatibility check rather than an equality check
-                if lhs_ty != rhs_ty {
-                    self.error("mismatched types during assignment", node_id)
-                }
-                let lhs_ast = &self.parse_results.ast_nodes[lhs.0];
-
-                if !matches!(lhs_ast, AstNode::Variable) {
-                    self.error("assignment should use a variable on the left side", node_id)
-                } else if let Some(definition_id) = self.variable_def_site.get(&lhs) {
-                    if let Some(variable) = self.variable_info.get(definition_id) {
-                        if !variable.is_mutable {
-                            self.error("assignment to immutable variable", lhs)
-                        }
-                    } else {
+            let payload_ty = match enum_type {
+                Some(enum_type) if variant == Variant::Err => enum_type.error,
+                Some(enum_type) => enum_type.value,
+                None => {
+                    if type_id != UNKNOWN_TYPE {
                         self.error(
-                            "internal error: resolved variable missing variable information",
-                            node_id,
-                        )
+                            format!(
+                                "`{}` patterns can't match values of type {}",
+                                variant.name(),
+                                self.stringify_type(type_id)
+                            ),
+                            pattern,
+                        );
                     }
-                } else {
-                    self.error(
-                        "internal error: variable not resolved to a variable definition",
-                        node_id,
-                    )
+                    UNKNOWN_TYPE
                 }
-                self.node_types[node_id.0] = UNIT_TYPE;
+            };
+
+            if let Some(payload) = payload {
+                self.typecheck_pattern(payload, payload_ty);
             }
-            AstNode::LessThan
-            | AstNode::LessThanOrEqual
-            | AstNode::GreaterThan
-            | AstNode::GreaterThanOrEqual => {
-                if (lhs_ty == I64_TYPE && rhs_ty == I64_TYPE)
-                    || (lhs_ty == F64_TYPE && rhs_ty == F64_TYPE)
-                {
-                    self.node_types[node_id.0] = BOOL_TYPE;
-                } else {
-                    self.error("mismatch types for operation", node_id)
+            return;
+        }
+
+        match self.parse_results.ast_nodes[pattern.0] {
+            AstNode::Wildcard => {}
+            AstNode::Variable => self.define_variable(pattern, type_id, false),
+            AstNode::Int | AstNode::Float | AstNode::String | AstNode::True | AstNode::False => {
+                self.typecheck_node(pattern);
+
+                let pattern_ty = self.node_types[pattern.0];
+                if pattern_ty != type_id && type_id != UNKNOWN_TYPE {
+                    self.error(
+                        format!(
+                            "pattern has type {} but the matched value has type {}",
+                            self.stringify_type(pattern_ty),
+                            self.stringify_type(type_id)
+                        ),
+                        pattern,
+                    )
                 }
             }
-            AstNode::Equal | AstNode::NotEqual => {
-                self.node_types[node_id.0] = BOOL_TYPE;
+            _ => self.error("unsupported pattern", pattern),
+        }
+    }
+
+    /// Typecheck `Some(x)`, `None`, `Ok(x)` or `Err(x)` building a value
+    ///
+    /// The type comes from the expected type if the payload fits it. Otherwise it's the only
+    /// registered `Option` or `Result` type that can hold the payload.
+    pub fn typecheck_variant(
+        &mut self,
+        variant: Variant,
+        payload: Option<NodeId>,
+        node_id: NodeId,
+        expected: Option<TypeId>,
+    ) {
+        let kind = variant_kind(variant);
+        let expected = expected.and_then(|type_id| {
+            self.enum_type(type_id)
+                .filter(|enum_type| enum_type.kind == kind)
+                .map(|enum_type| (type_id, enum_type))
+        });
+
+        let payload_ty = match payload {
+            Some(payload) => {
+                let payload_expected = expected.map(|(_, enum_type)| {
+                    if variant == Variant::Err {
+                        enum_type.error
+                    } else {
+                        enum_type.value
+                    }
+                });
+                self.typecheck_expecting_maybe(payload, payload_expected);
+                self.node_types[payload.0]
+            }
+            None => UNIT_TYPE,
+        };
+        if payload_ty == UNKNOWN_TYPE {
+            return;
+        }
+
+        let holds = |enum_type: &EnumType| match variant {
+            Variant::Some | Variant::Ok => enum_type.value == payload_ty,
+            Variant::Err => enum_type.error == payload_ty,
+            Variant::None => true,
+        };
+
+        if let Some((type_id, _)) = expected.filter(|(_, enum_type)| holds(enum_type)) {
+            self.node_types[node_id.0] = type_id;
+            return;
+        }
+
+        let candidates: Vec<_> = self
+            .permanent_definitions
+            .enum_types
+            .iter()
+            .filter(|(_, enum_type)| enum_type.kind == kind && holds(enum_type))
+            .map(|(type_id, _)| *type_id)
+            .collect();
+
+        match candidates[..] {
+            [type_id] => self.node_types[node_id.0] = type_id,
+            [] => {
+                let kind_name = match kind {
+                    EnumKind::Option => "Option",
+                    EnumKind::Result => "Result",
+                };
+                let contents = if variant == Variant::Err {
+                    "errors"
+                } else {
+                    "values"
+                };
+                self.error(
+                    format!(
+                        "there's no {kind_name} type holding {contents} of type {}",
+                        self.stringify_type(payload_ty)
+                    ),
+                    node_id,
+                )
+            }
+            _ => {
+                let example = match variant {
+                    Variant::Some | Variant::None => "`let x: Option<i64> = None`",
+                    Variant::Ok | Variant::Err => "`let x: Result<i64, String> = Ok(1)`",
+                };
+                self.error(
+                    format!(
+                        "can't infer the type of `{}`, add a type like {example}",
+                        variant.name()
+                    ),
+                    node_id,
+                )
+            }
+        }
+    }
+
+    pub fn typecheck_try(&mut self, value: NodeId, node_id: NodeId) {
+        self.typecheck_node(value);
+
+        let value_ty = self.node_types[value.0];
+        let Some(enum_type) = self.enum_type(value_ty) else {
+            if value_ty != UNKNOWN_TYPE {
+                self.error(
+                    format!(
+                        "the `?` operator can only be used on an Option or a Result, found {}",
+                        self.stringify_type(value_ty)
+                    ),
+                    node_id,
+                );
+            }
+            return;
+        };
+        self.node_types[node_id.0] = enum_type.value;
+
+        match self.return_type {
+            Some(ret) => self.check_try_return(node_id, ret, "the function's return type"),
+            None => self.main_tries.push(node_id),
+        }
+    }
+
+    /// Check that a `?` can return early with a value of the given type, which is the return type
+    /// of its function or the type of the script's value
+    fn check_try_return(&mut self, node_id: NodeId, ret: TypeId, returning: &str) {
+        let AstNode::Try(value) = self.parse_results.ast_nodes[node_id.0] else {
+            return;
+        };
+        let Some(enum_type) = self.enum_type(self.node_types[value.0]) else {
+            return;
+        };
+
+        let fits = self.enum_type(ret).is_some_and(|ret_type| {
+            ret_type.kind == enum_type.kind
+                && (enum_type.kind == EnumKind::Option || ret_type.error == enum_type.error)
+        });
+
+        if fits {
+            self.try_return_types.insert(node_id, ret);
+        } else if ret != UNKNOWN_TYPE {
+            let needed = match enum_type.kind {
+                EnumKind::Option => String::from("an Option"),
+                EnumKind::Result => format!(
+                    "a Result with errors of type {}",
+                    self.stringify_type(enum_type.error)
+                ),
+            };
+            self.error(
+                format!(
+                    "`?` needs {returning} to be {needed}, found {}",
+                    self.stringify_type(ret)
+                ),
+                node_id,
+            )
+        }
+    }
+
+    /// Whether running the node always jumps away, rather than giving a value
+    fn diverges(&self, node_id: NodeId) -> bool {
+        match &self.parse_results.ast_nodes[node_id.0] {
+            AstNode::Return(_) | AstNode::Break | AstNode::Continue => true,
+            AstNode::Statement(node_id) => self.diverges(*node_id),
+            AstNode::Block(nodes) => nodes.last().is_some_and(|node_id| self.diverges(*node_id)),
+            AstNode::If {
+                then_block,
+                else_expression: Some(else_expression),
+                ..
+            }
+            | AstNode::IfLet {
+                then_block,
+                else_expression: Some(else_expression),
+                ..
+            } => self.diverges(*then_block) && self.diverges(*else_expression),
+            AstNode::Match { arms, .. } => arms.iter().all(|(_, body)| self.diverges(*body)),
+            _ => false,
+        }
+    }
+
+    pub fn declare_fn(&mut self, node_id: NodeId) {
+        let AstNode::Fn {
+            name,
+            params,
+            ret,
+            block,
+        } = self.parse_results.ast_nodes[node_id.0]
+        else {
+            self.error("internal error: expected function definition", node_id);
+            return;
+        };
+
+        let params = match &self.parse_results.ast_nodes[params.0] {
+            AstNode::Params(params) => params.clone(),
+            _ => {
+                self.error("internal error: expected function parameters", params);
+                return;
+            }
+        };
+
+        let mut param_names = vec![];
+        let mut param_types = vec![];
+        for param in params {
+            let AstNode::Param { name, ty } = self.parse_results.ast_nodes[param.0] else {
+                self.error("internal error: expected function parameter", param);
+                continue;
+            };
+
+            let type_id = if let Some(ty) = ty {
+                self.typecheck_node(ty);
+                self.node_types[ty.0]
+            } else {
+                self.error("missing type for parameter", param);
+                UNKNOWN_TYPE
+            };
+
+            self.check_duplicate_param(&param_names, name);
+            param_names.push(name);
+            param_types.push(type_id);
+        }
+
+        let ret = if let Some(ret) = ret {
+            self.typecheck_node(ret);
+            self.node_types[ret.0]
+        } else {
+            UNIT_TYPE
+        };
+
+        let fn_name = self
+            .parse_results
+            .contents_for_span(self.parse_results.spans[name.0])
+            .to_vec();
+
+        if let Some(defs) = self.local_function_names.get(&fn_name) {
+            if defs
+                .iter()
+                .any(|def| self.local_functions[def.0 - 1].param_types == param_types)
+            {
+                self.error(
+                    format!(
+                        "function '{}' is already defined with these parameter types",
+                        String::from_utf8_lossy(&fn_name)
+                    ),
+                    name,
+                );
+            }
+        }
+
+        if let Some(defs) = self.permanent_definitions.external_functions.get(&fn_name) {
+            if defs
+                .iter()
+                .any(|def| self.permanent_definitions.functions[def.0].params == param_types)
+            {
+                self.error(
+                    format!(
+                        "function '{}' conflicts with a registered function with the same parameter types",
+                        String::from_utf8_lossy(&fn_name)
+                    ),
+                    name,
+                );
+            }
+        }
+
+        self.local_functions.push(LocalFunction {
+            node_id,
+            params: param_names,
+            param_types,
+            ret,
+            block,
+            captures: vec![],
+        });
+        let function_id = FunctionId(self.local_functions.len());
+
+        self.local_function_names
+            .entry(fn_name)
+            .or_default()
+            .push(function_id);
+    }
+
+    /// Parameters all live in the same scope, so a repeated name would hide the earlier one
+    fn check_duplicate_param(&mut self, earlier: &[NodeId], name: NodeId) {
+        let contents = self.parse_results.contents_for_node(name);
+        if earlier
+            .iter()
+            .any(|earlier| self.parse_results.contents_for_node(*earlier) == contents)
+        {
+            self.error(
+                format!(
+                    "parameter `{}` is already defined",
+                    String::from_utf8_lossy(contents)
+                ),
+                name,
+            );
+        }
+    }
+
+    /// Declare the structs defined in the blocks among the given nodes. Like variables, a struct
+    /// can only be used inside the block it's defined in, except for the top level block.
+    fn declare_structs(&mut self, nodes: std::ops::Range<usize>, top_level: NodeId) {
+        for idx in nodes {
+            let AstNode::Block(statements) = &self.parse_results.ast_nodes[idx] else {
+                continue;
+            };
+
+            let statements = statements.clone();
+            let block = (NodeId(idx) != top_level).then_some(NodeId(idx));
+            for statement in statements {
+                if matches!(
+                    self.parse_results.ast_nodes[statement.0],
+                    AstNode::Struct { .. }
+                ) {
+                    self.declare_struct(statement, block);
+                }
+            }
+        }
+    }
+
+    pub fn declare_struct(&mut self, node_id: NodeId, block: Option<NodeId>) {
+        let AstNode::Struct { name, .. } = &self.parse_results.ast_nodes[node_id.0] else {
+            self.error("internal error: expected struct definition", node_id);
+            return;
+        };
+
+        let name = self.parse_results.contents_for_node(*name).to_vec();
+
+        let is_defined = self.local_types.iter().any(|local_type| {
+            matches!(local_type, LocalType::Struct(local_struct) if local_struct.name == name && local_struct.block == block)
+        });
+        if is_defined || self.is_builtin_typename(&name) {
+            self.error(
+                format!("type {} is already defined", String::from_utf8_lossy(&name)),
+                node_id,
+            );
+            return;
+        }
+
+        self.local_types.push(LocalType::Struct(LocalStruct {
+            node_id,
+            name,
+            fields: vec![],
+            block,
+        }));
+    }
+
+    fn resolve_struct_fields(&mut self, idx: usize) {
+        let LocalType::Struct(LocalStruct { node_id, .. }) = self.local_types[idx] else {
+            return;
+        };
+        let AstNode::Struct { fields, .. } = &self.parse_results.ast_nodes[node_id.0] else {
+            return;
+        };
+
+        let fields = fields.clone();
+
+        let mut resolved_fields: Vec<(Vec<u8>, TypeId)> = vec![];
+        for (field_name, ty) in fields {
+            self.typecheck_node(ty);
+            let type_id = self.node_types[ty.0];
+
+            // Fields are stored by value, so for now only the basic types can be used
+            if !matches!(
+                type_id,
+                I64_TYPE | F64_TYPE | BOOL_TYPE | STRING_TYPE | UNKNOWN_TYPE
+            ) {
+                self.error(
+                    format!(
+                        "fields of type {} are not supported in structs yet",
+                        self.stringify_type(type_id)
+                    ),
+                    ty,
+                );
+            }
+
+            let field_name_contents = self.parse_results.contents_for_node(field_name).to_vec();
+            if resolved_fields
+                .iter()
+                .any(|(name, _)| *name == field_name_contents)
+            {
+                self.error("field is already defined", field_name);
+            }
+
+            self.node_types[field_name.0] = type_id;
+            resolved_fields.push((field_name_contents, type_id));
+        }
+
+        if let LocalType::Struct(local_struct) = &mut self.local_types[idx] {
+            local_struct.fields = resolved_fields;
+        }
+    }
+
+    pub fn typecheck_struct_literal(
+        &mut self,
+        name: NodeId,
+        fields: &[(NodeId, NodeId)],
+        node_id: NodeId,
+    ) {
+        for (_, value) in fields {
+            self.typecheck_node(*value);
+        }
+
+        let name_contents = self.parse_results.contents_for_node(name);
+        let Some(type_id) = self.find_struct(name_contents) else {
+            self.error(
+                format!("unknown struct: {}", String::from_utf8_lossy(name_contents)),
+                name,
+            );
+            return;
+        };
+        self.node_types[name.0] = type_id;
+
+        let local_struct = self.local_struct(type_id);
+        let mut errors = vec![];
+        let mut seen = vec![];
+        for (field_name, value) in fields {
+            let field_name_contents = self.parse_results.contents_for_node(*field_name);
+            match local_struct.and_then(|local_struct| local_struct.field(field_name_contents)) {
+                Some((idx, field_type)) => {
+                    if seen.contains(&idx) {
+                        errors.push((String::from("field is given more than once"), *field_name));
+                    }
+                    seen.push(idx);
+
+                    if self.node_types[value.0] != field_type {
+                        errors.push((
+                            format!(
+                                "field {} should be {}, found {}",
+                                String::from_utf8_lossy(field_name_contents),
+                                self.stringify_type(field_type),
+                                self.stringify_type(self.node_types[value.0])
+                            ),
+                            *value,
+                        ))
+                    }
+                }
+                None => errors.push((
+                    format!(
+                        "no field {} on type {}",
+                        String::from_utf8_lossy(field_name_contents),
+                        self.stringify_type(type_id)
+                    ),
+                    *field_name,
+                )),
+            }
+        }
+
+        if let Some(local_struct) = local_struct {
+            for (idx, (field_name, _)) in local_struct.fields.iter().enumerate() {
+                if !seen.contains(&idx) {
+                    errors.push((
+                        format!(
+                            "missing field {} in {}",
+                            String::from_utf8_lossy(field_name),
+                            String::from_utf8_lossy(&local_struct.name)
+                        ),
+                        node_id,
+                    ))
+                }
+            }
+        }
+
+        for (error, node_id) in errors {
+            self.error(error, node_id);
+        }
+
+        self.node_types[node_id.0] = type_id;
+    }
+
+    pub fn typecheck_field(&mut self, target: NodeId, field: NodeId, node_id: NodeId) {
+        self.typecheck_node(target);
+
+        let target_type = self.node_types[target.0];
+        if target_type == UNKNOWN_TYPE {
+            return;
+        }
+
+        let field_name = self.parse_results.contents_for_node(field);
+        if let Some((idx, field_type)) = self
+            .local_struct(target_type)
+            .and_then(|local_struct| local_struct.field(field_name))
+        {
+            self.field_resolution.insert(node_id, idx);
+            self.node_types[field.0] = field_type;
+            self.node_types[node_id.0] = field_type;
+            return;
+        }
+
+        let external_field = self
+            .permanent_definitions
+            .fields
+            .get(&target_type)
+            .and_then(|fields| {
+                fields
+                    .iter()
+                    .find(|external_field| external_field.name.as_bytes() == field_name)
+            });
+
+        match external_field {
+            Some(external_field) => {
+                self.external_field_resolution
+                    .insert(node_id, external_field);
+                self.node_types[field.0] = external_field.ty;
+                self.node_types[node_id.0] = external_field.ty;
+            }
+            None => self.error(
+                format!(
+                    "no field {} on type {}",
+                    String::from_utf8_lossy(field_name),
+                    self.stringify_type(target_type)
+                ),
+                field,
+            ),
+        }
+    }
+
+    pub fn typecheck_fn(&mut self, node_id: NodeId) {
+        self.node_types[node_id.0] = UNIT_TYPE;
+
+        let Some(local_function) = self
+            .local_functions
+            .iter()
+            .find(|local_function| local_function.node_id == node_id)
+        else {
+            self.error("internal error: function was not declared", node_id);
+            return;
+        };
+
+        let params: Vec<_> = local_function
+            .params
+            .iter()
+            .copied()
+            .zip(local_function.param_types.iter().copied())
+            .collect();
+        let ret = local_function.ret;
+        let block = local_function.block;
+
+        // Functions can't see the variables of the scopes around them, so start with a fresh
+        // scope stack containing only the function's own scope
+        let outer_scope_stack = std::mem::take(&mut self.scope_stack);
+        let outer_closures = std::mem::take(&mut self.closures);
+        let outer_loop_depth = std::mem::take(&mut self.loop_depth);
+        let outer_return_type = self.return_type.replace(ret);
+        self.enter_scope(node_id);
+
+        for (param, type_id) in params {
+            self.define_variable(param, type_id, false);
+            self.node_types[param.0] = type_id;
+        }
+
+        self.typecheck_expecting(block, ret);
+
+        self.exit_scope();
+        self.scope_stack = outer_scope_stack;
+        self.closures = outer_closures;
+        self.loop_depth = outer_loop_depth;
+        self.return_type = outer_return_type;
+
+        let block_ty = self.node_types[block.0];
+        if block_ty != ret && block_ty != UNKNOWN_TYPE && ret != UNKNOWN_TYPE {
+            self.error(
+                format!(
+                    "function body has type {} but the declared return type is {}",
+                    self.stringify_type(block_ty),
+                    self.stringify_type(ret)
+                ),
+                block,
+            )
+        }
+    }
+
+    /// Typecheck a closure, which becomes a local function taking the closure's parameters
+    ///
+    /// Parameters without a type take theirs from the expected closure type, if there is one. The
+    /// closure's return type is its body's, unless it declares one.
+    pub fn typecheck_closure(
+        &mut self,
+        params: NodeId,
+        ret: Option<NodeId>,
+        body: NodeId,
+        node_id: NodeId,
+        expected: Option<TypeId>,
+    ) {
+        let expected = expected
+            .and_then(|expected| self.function_type(expected))
+            .map(|function_type| (function_type.params.clone(), function_type.ret));
+
+        let params = match &self.parse_results.ast_nodes[params.0] {
+            AstNode::Params(params) => params.clone(),
+            _ => {
+                self.error("internal error: expected closure parameters", params);
+                return;
+            }
+        };
+
+        let mut param_names = vec![];
+        let mut param_types = vec![];
+        for (idx, param) in params.iter().enumerate() {
+            let AstNode::Param { name, ty } = self.parse_results.ast_nodes[param.0] else {
+                self.error("internal error: expected closure parameter", *param);
+                continue;
+            };
+
+            let expected_type = expected
+                .as_ref()
+                .filter(|(expected_params, _)| expected_params.len() == params.len())
+                .map(|(expected_params, _)| expected_params[idx]);
+
+            let type_id = if let Some(ty) = ty {
+                self.typecheck_node(ty);
+                self.node_types[ty.0]
+            } else if let Some(expected_type) = expected_type {
+                expected_type
+            } else {
+                let name = String::from_utf8_lossy(self.parse_results.contents_for_node(name));
+                self.error(
+                    format!("can't infer the type of `{name}`, add a type like `|{name}: i64|`"),
+                    *param,
+                );
+                UNKNOWN_TYPE
+            };
+
+            self.check_duplicate_param(&param_names, name);
+            param_names.push(name);
+            param_types.push(type_id);
+        }
+
+        let declared_ret = ret.map(|ret| {
+            self.typecheck_node(ret);
+            self.node_types[ret.0]
+        });
+        let expected_ret = declared_ret.or(expected.map(|(_, ret)| ret));
+
+        // Closures can see the variables around them, copying the ones they use, but `break`,
+        // `continue` and `return` only reach as far as the closure itself. Without a declared
+        // return type, `return`s are checked against the body afterwards, like in the script body.
+        let outer_loop_depth = std::mem::take(&mut self.loop_depth);
+        let outer_return_type = std::mem::replace(&mut self.return_type, declared_ret);
+        let outer_returns = std::mem::take(&mut self.main_returns);
+        let outer_tries = std::mem::take(&mut self.main_tries);
+        self.closures.push(ClosureCaptures {
+            scope_depth: self.scope_stack.len(),
+            captures: vec![],
+        });
+        self.enter_scope(node_id);
+
+        for (param, type_id) in param_names.iter().zip(param_types.iter()) {
+            self.define_variable(*param, *type_id, false);
+            self.node_types[param.0] = *type_id;
+        }
+
+        self.typecheck_expecting_maybe(body, expected_ret);
+
+        self.exit_scope();
+        let captures = self
+            .closures
+            .pop()
+            .map(|closure| closure.captures)
+            .unwrap_or_default();
+        self.loop_depth = outer_loop_depth;
+        self.return_type = outer_return_type;
+
+        let body_ty = self.node_types[body.0];
+        let ret = match declared_ret {
+            Some(ret) => {
+                if body_ty != ret && body_ty != UNKNOWN_TYPE && ret != UNKNOWN_TYPE {
+                    self.error(
+                        format!(
+                            "closure body has type {} but the declared return type is {}",
+                            self.stringify_type(body_ty),
+                            self.stringify_type(ret)
+                        ),
+                        body,
+                    )
+                }
+                ret
+            }
+            None => {
+                self.check_main_returns(body_ty, "the closure's value");
+                body_ty
+            }
+        };
+        self.main_returns = outer_returns;
+        self.main_tries = outer_tries;
+
+        for capture in &captures {
+            let type_id = self.capture_type(*capture);
+
+            if !self.is_copyable(type_id) {
+                let name = match *capture {
+                    Capture::Variable(variable) => {
+                        String::from_utf8_lossy(self.parse_results.contents_for_node(variable))
+                            .to_string()
+                    }
+                    Capture::Global(idx) => self.globals[idx].name.clone(),
+                };
+
+                self.error(
+                    format!(
+                        "closures can't capture `{name}`, as values of type {} can't be copied",
+                        self.stringify_type(type_id)
+                    ),
+                    node_id,
+                )
+            }
+        }
+
+        self.local_functions.push(LocalFunction {
+            node_id,
+            params: param_names,
+            param_types: param_types.clone(),
+            ret,
+            block: body,
+            captures,
+        });
+
+        self.node_types[node_id.0] = self.find_or_add_function_type(param_types, ret);
+    }
+
+    /// The type of a variable or global that a closure captures
+    pub fn capture_type(&self, capture: Capture) -> TypeId {
+        match capture {
+            Capture::Variable(variable) => self
+                .variable_info
+                .get(&variable)
+                .map_or(UNKNOWN_TYPE, |variable| variable.type_id),
+            Capture::Global(idx) => self.globals[idx].type_id,
+        }
+    }
+
+    /// Whether values of the type can be copied, which a closure does with the variables it
+    /// captures
+    fn is_copyable(&self, type_id: TypeId) -> bool {
+        let is_basic = |type_id| {
+            matches!(
+                type_id,
+                I64_TYPE | F64_TYPE | BOOL_TYPE | STRING_TYPE | UNKNOWN_TYPE
+            )
+        };
+
+        is_basic(type_id)
+            || self.element_type(type_id).is_some()
+            || self.local_type(type_id).is_some()
+            || self.enum_type(type_id).is_some_and(|enum_type| {
+                is_basic(enum_type.value) && matches!(enum_type.error, UNIT_TYPE | STRING_TYPE)
+            })
+    }
+
+    pub fn typecheck_return(&mut self, value: Option<NodeId>, node_id: NodeId) {
+        let value_ty = match value {
+            Some(value) => {
+                self.typecheck_expecting_maybe(value, self.return_type);
+                self.node_types[value.0]
+            }
+            None => UNIT_TYPE,
+        };
+        self.node_types[node_id.0] = value_ty;
+
+        match self.return_type {
+            Some(ret) if value_ty != ret && value_ty != UNKNOWN_TYPE && ret != UNKNOWN_TYPE => self
+                .error(
+                    format!(
+                        "returned value has type {} but the declared return type is {}",
+                        self.stringify_type(value_ty),
+                        self.stringify_type(ret)
+                    ),
+                    node_id,
+                ),
+            Some(_) => {}
+            None => self.main_returns.push(node_id),
+        }
+    }
+
+    /// Check that every `return` in the script body gives a value of the script's type, and that
+    /// every `?` in it can return early with one
+    ///
+    /// Closures without a declared return type are checked the same way, against the type of
+    /// their body.
+    fn check_main_returns(&mut self, script_ty: TypeId, returning: &str) {
+        for node_id in std::mem::take(&mut self.main_tries) {
+            self.check_try_return(node_id, script_ty, returning);
+        }
+
+        for node_id in std::mem::take(&mut self.main_returns) {
+            let value_ty = self.node_types[node_id.0];
+            if value_ty != script_ty && value_ty != UNKNOWN_TYPE && script_ty != UNKNOWN_TYPE {
+                self.error(
+                    format!(
+                        "returned value has type {} but {returning} has type {}",
+                        self.stringify_type(value_ty),
+                        self.stringify_type(script_ty)
+                    ),
+                    node_id,
+                )
+            }
+        }
+    }
+
+    /// Check that the script's value has the type the host expects to take out of it, pointing
+    /// at the script's final expression if it doesn't
+    fn check_script_type(&mut self, expected: TypeId) {
+        let Some(root) = self
+            .parse_results
+            .ast_nodes
+            .len()
+            .checked_sub(1)
+            .map(NodeId)
+        else {
+            if expected != UNIT_TYPE {
+                self.errors.push(ScriptError {
+                    message: format!(
+                        "script has type {} but {} was expected",
+                        self.stringify_type(UNIT_TYPE),
+                        self.stringify_type(expected)
+                    ),
+                    span: Span { start: 0, end: 0 },
+                    kind: ErrorKind::Script,
+                });
+            }
+            return;
+        };
+
+        let script_ty = self.node_types[root.0];
+        if script_ty == expected
+            || script_ty == UNKNOWN_TYPE
+            || self.is_callback_of(expected, script_ty)
+        {
+            return;
+        }
+
+        let last = match &self.parse_results.ast_nodes[root.0] {
+            AstNode::Block(nodes) => nodes.last().copied().unwrap_or(root),
+            _ => root,
+        };
+        self.error(
+            format!(
+                "script has type {} but {} was expected",
+                self.stringify_type(script_ty),
+                self.stringify_type(expected)
+            ),
+            last,
+        );
+    }
+
+    pub fn typecheck_while(&mut self, condition: NodeId, block: NodeId, node_id: NodeId) {
+        self.typecheck_node(condition);
+        let condition_ty = self.node_types[condition.0];
+
+        if condition_ty != BOOL_TYPE {
+            self.error("expected bool for while condition", condition);
+        }
+
+        self.loop_depth += 1;
+        self.typecheck_node(block);
+        self.loop_depth -= 1;
+
+        self.node_types[node_id.0] = UNIT_TYPE;
+    }
+
+    pub fn typecheck_for(
+        &mut self,
+        variable: NodeId,
+        range: NodeId,
+        block: NodeId,
+        node_id: NodeId,
+    ) {
+        self.typecheck_node(range);
+
+        let range_ty = self.node_types[range.0];
+        let variable_ty = if matches!(self.parse_results.ast_nodes[range.0], AstNode::Range { .. })
+        {
+            range_ty
+        } else if let Some(element_type) = self.element_type(range_ty) {
+            element_type
+        } else {
+            if range_ty != UNKNOWN_TYPE {
+                self.error("expected range or array in for loop", range);
+            }
+            UNKNOWN_TYPE
+        };
+
+        // The loop variable lives in its own scope, so it's only visible to the loop body
+        self.enter_scope(node_id);
+        self.define_variable(variable, variable_ty, false);
+        self.node_types[variable.0] = variable_ty;
+
+        self.loop_depth += 1;
+        self.typecheck_node(block);
+        self.loop_depth -= 1;
+        self.exit_scope();
+
+        self.node_types[node_id.0] = UNIT_TYPE;
+    }
+
+    pub fn typecheck_array(&mut self, items: &[NodeId], node_id: NodeId) {
+        let Some(first) = items.first() else {
+            self.error(
+                "can't infer the type of an empty array, add a type like `let x: [i64] = []`",
+                node_id,
+            );
+            return;
+        };
+
+        let mut element_type = UNKNOWN_TYPE;
+        for item in items {
+            self.typecheck_node(*item);
+
+            let item_type = self.node_types[item.0];
+            if item == first {
+                element_type = item_type;
+            } else if item_type != element_type {
+                self.error(
+                    format!(
+                        "array elements should all be {}, found {}",
+                        self.stringify_type(element_type),
+                        self.stringify_type(item_type)
+                    ),
+                    *item,
+                );
+            }
+        }
+
+        if let Some(array_type) = self.array_type(element_type) {
+            self.node_types[node_id.0] = array_type;
+        } else if element_type != UNKNOWN_TYPE {
+            self.error(
+                format!(
+                    "arrays of {} are not supported",
+                    self.stringify_type(element_type)
+                ),
+                node_id,
+            )
+        }
+    }
+
+    pub fn typecheck_index(&mut self, target: NodeId, index: NodeId, node_id: NodeId) {
+        self.typecheck_node(target);
+        self.typecheck_node(index);
+
+        if self.node_types[index.0] != I64_TYPE {
+            self.error("expected i64 for array index", index);
+        }
+
+        let target_ty = self.node_types[target.0];
+        if let Some(element_type) = self.element_type(target_ty) {
+            self.node_types[node_id.0] = element_type;
+        } else if target_ty != UNKNOWN_TYPE {
+            self.error(
+                format!(
+                    "expected array for indexing, found {}",
+                    self.stringify_type(target_ty)
+                ),
+                target,
+            );
+        }
+    }
+
+    pub fn typecheck_binop(
+        &mut self,
+        lhs: NodeId,
+        op: NodeId,
+        rhs: NodeId,
+        node_id: NodeId, // whole expression NodeId
+    ) {
+        self.typecheck_node(lhs);
+
+        // The right side of an assignment or comparison wants a value like the left side
+        if matches!(
+            self.parse_results.ast_nodes[op.0],
+            AstNode::Assignment | AstNode::Equal | AstNode::NotEqual
+        ) {
+            self.typecheck_expecting(rhs, self.node_types[lhs.0]);
+        } else {
+            self.typecheck_node(rhs);
+        }
+
+        let lhs_ty = self.node_types[lhs.0];
+        let rhs_ty = self.node_types[rhs.0];
+        let (lhs_ty, rhs_ty) = self.promote_operands(lhs, lhs_ty, op, rhs, rhs_ty);
+        let op_ast = &self.parse_results.ast_nodes[op.0];
+
+        match op_ast {
+            AstNode::Assignment => {
+                // FIXME: replace with compatibility check rather than an equality check
+                if lhs_ty != rhs_ty {
+                    self.error("mismatched types during assignment", node_id)
+                }
+                self.check_assignable(lhs, node_id);
+                self.node_types[node_id.0] = UNIT_TYPE;
+            }
+            AstNode::AddAssignment
+            | AstNode::MinusAssignment
+            | AstNode::MultiplyAssignment
+            | AstNode::DivideAssignment => {
+                if (lhs_ty == I64_TYPE && rhs_ty == I64_TYPE)
+                    || (lhs_ty == F64_TYPE && rhs_ty == F64_TYPE)
+                {
+                    self.node_types[node_id.0] = lhs_ty;
+                } else {
+                    self.error("mismatch types for operation", node_id)
+                }
+                self.check_assignable(lhs, node_id);
+            }
+            AstNode::LessThan
+            | AstNode::LessThanOrEqual
+            | AstNode::GreaterThan
+            | AstNode::GreaterThanOrEqual => {
+                if (lhs_ty == I64_TYPE && rhs_ty == I64_TYPE)
+                    || (lhs_ty == F64_TYPE && rhs_ty == F64_TYPE)
+                    || (lhs_ty == STRING_TYPE && rhs_ty == STRING_TYPE)
+                {
+                    self.node_types[node_id.0] = BOOL_TYPE;
+                } else {
+                    self.error("mismatch types for operation", node_id)
+                }
+            }
+            AstNode::Equal | AstNode::NotEqual => {
+                if lhs_ty != rhs_ty {
+                    self.error("mismatch types for comparison", node_id)
+                } else if !self.is_comparable(lhs_ty) {
+                    self.error(
+                        format!(
+                            "values of type {} can't be compared",
+                            self.stringify_type(lhs_ty)
+                        ),
+                        node_id,
+                    )
+                } else {
+                    self.node_types[node_id.0] = BOOL_TYPE;
+                }
+            }
+            AstNode::Append => {
+                if lhs_ty == STRING_TYPE && rhs_ty == STRING_TYPE {
+                    self.node_types[node_id.0] = STRING_TYPE;
+                } else {
+                    self.error("mismatch types for operation", node_id)
+                }
             }
             AstNode::And | AstNode::Or => {
                 if lhs_ty == BOOL_TYPE && rhs_ty == BOOL_TYPE {
@@ -493,6 +2030,18 @@ impl<'permanent> TypeChecker<'permanent> {
                     self.error("boolean operator expects boolean types", node_id)
                 }
             }
+            AstNode::Modulo
+            | AstNode::BitAnd
+            | AstNode::BitOr
+            | AstNode::BitXor
+            | AstNode::ShiftLeft
+            | AstNode::ShiftRight => {
+                if lhs_ty == I64_TYPE && rhs_ty == I64_TYPE {
+                    self.node_types[node_id.0] = I64_TYPE;
+                } else {
+                    self.error("integer operator expects i64 types", node_id)
+                }
+            }
             _ => {
                 if lhs_ty == I64_TYPE && rhs_ty == I64_TYPE {
                     self.node_types[node_id.0] = I64_TYPE;
@@ -505,6 +2054,193 @@ impl<'permanent> TypeChecker<'permanent> {
         }
     }
 
+    /// With numeric promotion on, an i64 operand next to an f64 one is converted to f64. The
+    /// target of a compound assignment keeps its type, so only its right side is converted.
+    fn promote_operands(
+        &mut self,
+        lhs: NodeId,
+        lhs_ty: TypeId,
+        op: NodeId,
+        rhs: NodeId,
+        rhs_ty: TypeId,
+    ) -> (TypeId, TypeId) {
+        if !self.numeric_promotion {
+            return (lhs_ty, rhs_ty);
+        }
+
+        let is_assignment = match self.parse_results.ast_nodes[op.0] {
+            AstNode::AddAssignment
+            | AstNode::MinusAssignment
+            | AstNode::MultiplyAssignment
+            | AstNode::DivideAssignment => true,
+            AstNode::Plus
+            | AstNode::Minus
+            | AstNode::Multiply
+            | AstNode::Divide
+            | AstNode::Pow
+            | AstNode::LessThan
+            | AstNode::LessThanOrEqual
+            | AstNode::GreaterThan
+            | AstNode::GreaterThanOrEqual
+            | AstNode::Equal
+            | AstNode::NotEqual => false,
+            _ => return (lhs_ty, rhs_ty),
+        };
+
+        if lhs_ty == F64_TYPE && rhs_ty == I64_TYPE {
+            self.promoted_nodes.insert(rhs);
+            (F64_TYPE, F64_TYPE)
+        } else if lhs_ty == I64_TYPE && rhs_ty == F64_TYPE && !is_assignment {
+            self.promoted_nodes.insert(lhs);
+            (F64_TYPE, F64_TYPE)
+        } else {
+            (lhs_ty, rhs_ty)
+        }
+    }
+
+    pub fn typecheck_cast(&mut self, value: NodeId, ty: NodeId, node_id: NodeId) {
+        self.typecheck_node(value);
+        self.typecheck_node(ty);
+
+        let from = self.node_types[value.0];
+        let to = self.node_types[ty.0];
+
+        if (from == I64_TYPE || from == F64_TYPE) && (to == I64_TYPE || to == F64_TYPE) {
+            self.node_types[node_id.0] = to;
+        } else if from != UNKNOWN_TYPE && to != UNKNOWN_TYPE {
+            self.error(
+                format!(
+                    "can't cast {} to {}",
+                    self.stringify_type(from),
+                    self.stringify_type(to)
+                ),
+                node_id,
+            )
+        }
+    }
+
+    /// Check that an integer literal fits in an i64, once negated if it has a `-` in front of it
+    fn typecheck_int(&mut self, node_id: NodeId, negated: bool) {
+        self.node_types[node_id.0] = I64_TYPE;
+
+        let contents = self.parse_results.contents_for_node(node_id);
+        if !contents.iter().all(u8::is_ascii_digit) {
+            self.error(
+                "invalid integer literal: only decimal digits are supported",
+                node_id,
+            );
+            return;
+        }
+
+        let fits = String::from_utf8_lossy(contents)
+            .parse::<i128>()
+            .is_ok_and(|value| {
+                let value = if negated { -value } else { value };
+                i64::try_from(value).is_ok()
+            });
+        if !fits {
+            self.error("integer literal is out of range for i64", node_id)
+        }
+    }
+
+    pub fn typecheck_unary_op(&mut self, op: NodeId, operand: NodeId, node_id: NodeId) {
+        // `-9223372036854775808` is in range, even though the literal on its own isn't
+        if self.is_negated_int(op, operand) {
+            self.typecheck_int(operand, true);
+        } else {
+            self.typecheck_node(operand);
+        }
+
+        let operand_ty = self.node_types[operand.0];
+
+        match self.parse_results.ast_nodes[op.0] {
+            AstNode::Negate => {
+                if operand_ty == I64_TYPE || operand_ty == F64_TYPE {
+                    self.node_types[node_id.0] = operand_ty;
+                } else {
+                    self.error("negation expects a number", node_id)
+                }
+            }
+            AstNode::Not => {
+                if operand_ty == BOOL_TYPE {
+                    self.node_types[node_id.0] = BOOL_TYPE;
+                } else {
+                    self.error("logical not expects a boolean", node_id)
+                }
+            }
+            _ => self.error("unsupported unary operator", op),
+        }
+    }
+
+    /// Whether a unary operator is a `-` directly in front of an integer literal
+    pub fn is_negated_int(&self, op: NodeId, operand: NodeId) -> bool {
+        matches!(self.parse_results.ast_nodes[op.0], AstNode::Negate)
+            && matches!(self.parse_results.ast_nodes[operand.0], AstNode::Int)
+    }
+
+    /// Whether `==` and `!=` work on values of the given type
+    fn is_comparable(&self, type_id: TypeId) -> bool {
+        matches!(type_id, I64_TYPE | F64_TYPE | BOOL_TYPE | STRING_TYPE)
+            || self
+                .permanent_definitions
+                .eq_functions
+                .contains_key(&type_id)
+            || self.local_struct(type_id).is_some()
+    }
+
+    /// Check that the left side of an assignment is a mutable variable, or a field of one
+    fn check_assignable(&mut self, lhs: NodeId, node_id: NodeId) {
+        let mut variable = lhs;
+        while let AstNode::Field { target, .. } = self.parse_results.ast_nodes[variable.0] {
+            variable = target;
+        }
+
+        if !matches!(self.parse_results.ast_nodes[variable.0], AstNode::Variable) {
+            self.error("assignment should use a variable on the left side", node_id)
+        } else if let Some(idx) = self.global_resolution.get(&variable) {
+            let is_captured = self
+                .closures
+                .last()
+                .is_some_and(|closure| closure.captures.contains(&Capture::Global(*idx)));
+
+            if !self.globals[*idx].is_mutable {
+                self.error("assignment to immutable global", lhs)
+            } else if is_captured {
+                self.error(
+                    "assignment to a captured global, which the closure only has a copy of",
+                    lhs,
+                )
+            }
+        } else if let Some(definition_id) = self.variable_def_site.get(&variable) {
+            let is_captured = self.closures.last().is_some_and(|closure| {
+                closure
+                    .captures
+                    .contains(&Capture::Variable(*definition_id))
+            });
+
+            if let Some(variable_info) = self.variable_info.get(definition_id) {
+                if !variable_info.is_mutable {
+                    self.error("assignment to immutable variable", lhs)
+                } else if is_captured {
+                    self.error(
+                        "assignment to a captured variable, which the closure only has a copy of",
+                        lhs,
+                    )
+                }
+            } else {
+                self.error(
+                    "internal error: resolved variable missing variable information",
+                    node_id,
+                )
+            }
+        } else {
+            self.error(
+                "internal error: variable not resolved to a variable definition",
+                node_id,
+            )
+        }
+    }
+
     pub fn with<F>(&mut self, f: F)
     where
         F: Fn(&mut TypeChecker),
@@ -512,32 +2248,26 @@ impl<'permanent> TypeChecker<'permanent> {
         f(self)
     }
 
-    // pub fn typecheck_range(
-    //     &mut self,
-    //     lhs: NodeId,
-    //     rhs: NodeId,
-    //     node_id: NodeId,
-    //     parse_results: &ParseResults,
-    // ) {
-    //     self.typecheck_node(lhs, parse_results);
-    //     self.typecheck_node(rhs, parse_results);
+    pub fn typecheck_range(&mut self, lhs: NodeId, rhs: NodeId, node_id: NodeId) {
+        self.typecheck_node(lhs);
+        self.typecheck_node(rhs);
 
-    //     let lhs_ty = self.node_types[lhs.0];
-    //     let rhs_ty = self.node_types[rhs.0];
+        let lhs_ty = self.node_types[lhs.0];
+        let rhs_ty = self.node_types[rhs.0];
 
-    //     // For now, require both sides to be i64
-    //     if lhs_ty != I64_TYPE {
-    //         self.error("expected i64 for range", lhs)
-    //     }
+        // For now, require both sides to be i64
+        if lhs_ty != I64_TYPE {
+            self.error("expected i64 for range", lhs)
+        }
 
-    //     if rhs_ty != I64_TYPE {
-    //         self.error("expected i64 for range", rhs)
-    //     }
+        if rhs_ty != I64_TYPE {
+            self.error("expected i64 for range", rhs)
+        }
 
-    //     let type_id = self.create_or_find_type(Type::Range(I64_TYPE));
+        // Ranges only appear in `for` loops, so they take the type of the values they produce
+        self.node_types[node_id.0] = I64_TYPE
+    }
 
-    //     self.node_types[node_id.0] = type_id
-    // }
     // pub fn create_or_find_type(&mut self, ty: std::any::TypeId) -> TypeId {
     //     let mut idx = 0;
     //     while idx < self.permanent_definitions.types.len() {
@@ -552,15 +2282,65 @@ impl<'permanent> TypeChecker<'permanent> {
     // }
 
     pub fn typecheck_call(&mut self, head: NodeId, args: &[NodeId], node_id: NodeId) {
-        for node_id in args {
-            self.typecheck_node(*node_id)
-        }
-
         let call_name = self
             .parse_results
-            .contents_for_span(self.parse_results.spans[head.0]);
+            .contents_for_span(self.parse_results.spans[head.0])
+            .to_vec();
+        let call_name = &call_name[..];
+
+        // A variable holding a closure is called rather than a function with the same name
+        if let Some((depth, variable)) = self.find_variable_in_scopes(call_name) {
+            let type_id = self
+                .variable_info
+                .get(&variable)
+                .map_or(UNKNOWN_TYPE, |variable| variable.type_id);
+
+            if self.function_type(type_id).is_some() {
+                self.capture_variable(depth, variable);
+                self.variable_def_site.insert(head, variable);
+                self.node_types[head.0] = type_id;
+                self.typecheck_closure_call(type_id, args, node_id);
+                return;
+            }
+        }
+
+        // When only one function could be called, its parameter types tell arguments like `None`
+        // what they are
+        let param_types = self.only_candidate_params(call_name, args.len());
+        for (idx, node_id) in args.iter().enumerate() {
+            match param_types.as_ref() {
+                Some(param_types) => self.typecheck_expecting(*node_id, param_types[idx]),
+                None => self.typecheck_node(*node_id),
+            }
+        }
+
+        // Functions defined in the script take priority over registered functions
+        let local_match = self.local_function_names.get(call_name).and_then(|defs| {
+            defs.iter().copied().find(|def| {
+                let param_types = &self.local_functions[def.0 - 1].param_types;
+
+                args.len() == param_types.len()
+                    && args
+                        .iter()
+                        .zip(param_types.iter())
+                        .all(|(arg, param)| self.node_types[arg.0] == *param)
+            })
+        });
+
+        if let Some(def) = local_match {
+            self.node_types[node_id.0] = self.local_functions[def.0 - 1].ret;
+            self.local_call_resolution.insert(head, def);
+            self.local_call_resolution.insert(node_id, def);
+            return;
+        }
+
+        let is_local = self.local_function_names.contains_key(call_name);
+
+        let external_defs = self.permanent_definitions.external_functions.get(call_name);
+
+        if external_defs.is_some() || is_local {
+            let defs = external_defs.map(|defs| &defs[..]).unwrap_or_default();
 
-        if let Some(defs) = self.permanent_definitions.external_functions.get(call_name) {
             'outer: for &def in defs {
                 let ExternalFnRecord { params, ret, .. } =
                     &self.permanent_definitions.functions[def.0];
@@ -575,6 +2355,7 @@ impl<'permanent> TypeChecker<'permanent> {
 
                         if self.node_types[arg.0] != param
                             && !self.reference_of(param, self.node_types[arg.0])
+                            && !self.is_callback_of(param, self.node_types[arg.0])
                         {
                             // Types don't match here, and we don't have a reference we can work with
                             // so we need to continue looking
@@ -612,6 +2393,76 @@ impl<'permanent> TypeChecker<'permanent> {
         }
     }
 
+    /// Typecheck a call to a closure held in a variable
+    fn typecheck_closure_call(&mut self, closure_type: TypeId, args: &[NodeId], node_id: NodeId) {
+        let Some(FunctionType { params, ret }) = self.function_type(closure_type) else {
+            return;
+        };
+        let (params, ret) = (params.clone(), *ret);
+
+        if args.len() != params.len() {
+            for arg in args {
+                self.typecheck_node(*arg);
+            }
+            self.error(
+                format!(
+                    "closure takes {} argument(s) but {} were given",
+                    params.len(),
+                    args.len()
+                ),
+                node_id,
+            );
+        } else {
+            for (arg, param) in args.iter().zip(params) {
+                self.typecheck_expecting(*arg, param);
+
+                let arg_type = self.node_types[arg.0];
+                if arg_type != param && arg_type != UNKNOWN_TYPE && param != UNKNOWN_TYPE {
+                    self.error(
+                        format!(
+                            "argument has type {} but the closure takes {}",
+                            self.stringify_type(arg_type),
+                            self.stringify_type(param)
+                        ),
+                        *arg,
+                    )
+                }
+            }
+        }
+
+        self.node_types[node_id.0] = ret;
+    }
+
+    /// Whether the parameter is a `Callback` of a registered function, which takes any closure
+    fn is_callback_of(&self, param: TypeId, arg: TypeId) -> bool {
+        self.function_type(arg).is_some() && self.get_type::<Callback>() == Some(param)
+    }
+
+    /// The parameter types of the one function with the given name and number of parameters, if
+    /// there's exactly one
+    fn only_candidate_params(&self, name: &[u8], num_args: usize) -> Option<Vec<TypeId>> {
+        let local = self
+            .local_function_names
+            .get(name)
+            .into_iter()
+            .flatten()
+            .map(|def| &self.local_functions[def.0 - 1].param_types);
+        let external = self
+            .permanent_definitions
+            .external_functions
+            .get(name)
+            .into_iter()
+            .flatten()
+            .map(|def| &self.permanent_definitions.functions[def.0].params);
+
+        let mut candidates = local
+            .chain(external)
+            .filter(|params| params.len() == num_args);
+        let params = candidates.next()?;
+
+        candidates.next().is_none().then(|| params.clone())
+    }
+
     pub fn is_custom_type(type_id: TypeId) -> bool {
         type_id > STRING_TYPE && type_id != UNKNOWN_TYPE
     }
@@ -623,6 +2474,16 @@ impl<'permanent> TypeChecker<'permanent> {
         is_mutable: bool,
     ) {
         let span = self.parse_results.spans[variable_name_node_id.0];
+
+        if let Some(idx) = self.find_global(&self.parse_results.contents[span.start..span.end]) {
+            self.error(
+                format!(
+                    "`{}` is already a global, so it can't be used as a variable name",
+                    self.globals[idx].name
+                ),
+                variable_name_node_id,
+            );
+        }
         let variable_name = &self.parse_results.contents[span.start..span.end];
 
         let current_scope_id = self
@@ -652,7 +2513,12 @@ impl<'permanent> TypeChecker<'permanent> {
         let span = self.parse_results.spans[unbound_node_id.0];
         let variable_name = self.parse_results.contents_for_span(span);
 
-        if let Some(node_id) = self.find_variable(variable_name) {
+        if let Some(idx) = self.find_global(variable_name) {
+            self.capture_global(idx);
+            self.global_resolution.insert(unbound_node_id, idx);
+            self.node_types[unbound_node_id.0] = self.globals[idx].type_id;
+        } else if let Some((depth, node_id)) = self.find_variable_in_scopes(variable_name) {
+            self.capture_variable(depth, node_id);
             self.variable_def_site.insert(unbound_node_id, node_id);
             if let Some(variable) = self.variable_info.get(&node_id) {
                 self.node_types[unbound_node_id.0] = variable.type_id;
@@ -668,15 +2534,54 @@ impl<'permanent> TypeChecker<'permanent> {
     }
 
     pub fn find_variable(&self, variable_name: &[u8]) -> Option<NodeId> {
-        for scope in self.scope_stack.iter().rev() {
+        self.find_variable_in_scopes(variable_name)
+            .map(|(_, node_id)| node_id)
+    }
+
+    /// Find a variable along with the depth of the scope it's defined in, counting from the
+    /// outermost scope of the scope stack
+    fn find_variable_in_scopes(&self, variable_name: &[u8]) -> Option<(usize, NodeId)> {
+        for (depth, scope) in self.scope_stack.iter().enumerate().rev() {
             if let Some(result) = self.scope[scope.0].variables.get(variable_name) {
-                return Some(*result);
+                return Some((depth, *result));
             }
         }
 
         None
     }
 
+    /// Have the closures being checked capture a variable, when it's defined outside of them.
+    /// A closure inside another one needs the outer closure to capture it too, so it can be
+    /// copied from there.
+    fn capture_variable(&mut self, depth: usize, variable: NodeId) {
+        for closure in self.closures.iter_mut().rev() {
+            if closure.scope_depth <= depth {
+                break;
+            }
+
+            if !closure.captures.contains(&Capture::Variable(variable)) {
+                closure.captures.push(Capture::Variable(variable));
+            }
+        }
+    }
+
+    /// Have every closure being checked capture a global, as globals are outside of all of them
+    fn capture_global(&mut self, idx: usize) {
+        for closure in &mut self.closures {
+            if !closure.captures.contains(&Capture::Global(idx)) {
+                closure.captures.push(Capture::Global(idx));
+            }
+        }
+    }
+
+    /// The global with the given name, if the host bound one. Later globals replace earlier ones
+    /// with the same name.
+    pub fn find_global(&self, name: &[u8]) -> Option<usize> {
+        self.globals
+            .iter()
+            .rposition(|global| global.name.as_bytes() == name)
+    }
+
     pub fn enter_scope(&mut self, node_id: NodeId) {
         self.scope.push(Scope::new(node_id));
         self.scope_stack.push(ScopeId(self.scope.len() - 1));
@@ -704,14 +2609,240 @@ impl<'permanent> TypeChecker<'permanent> {
         self.permanent_definitions.get_type::<T>()
     }
 
+    /// The element type of the given array type, or `None` if it's not an array
+    pub fn element_type(&self, array_type: TypeId) -> Option<TypeId> {
+        self.permanent_definitions
+            .array_of_map
+            .get(&array_type)
+            .copied()
+    }
+
+    /// The array type holding elements of the given type, if there is one
+    pub fn array_type(&self, element_type: TypeId) -> Option<TypeId> {
+        self.permanent_definitions
+            .array_of_map
+            .iter()
+            .find(|(_, element)| **element == element_type)
+            .map(|(array, _)| *array)
+    }
+
+    /// How to work with values of the given type, if it's an `Option` or a `Result`
+    pub fn enum_type(&self, type_id: TypeId) -> Option<&'permanent EnumType> {
+        self.permanent_definitions.enum_types.get(&type_id)
+    }
+
+    /// The registered `Option` or `Result` type with the given payloads, if there is one. The
+    /// error type of an `Option` is unit.
+    pub fn find_enum_type(&self, kind: EnumKind, value: TypeId, error: TypeId) -> Option<TypeId> {
+        self.permanent_definitions
+            .enum_types
+            .iter()
+            .find(|(_, enum_type)| {
+                enum_type.kind == kind && enum_type.value == value && enum_type.error == error
+            })
+            .map(|(type_id, _)| *type_id)
+    }
+
+    pub fn typecheck_function_type(
+        &mut self,
+        params: &[NodeId],
+        ret: Option<NodeId>,
+        node_id: NodeId,
+    ) {
+        let mut param_types = vec![];
+        for param in params {
+            self.typecheck_node(*param);
+            param_types.push(self.node_types[param.0]);
+        }
+
+        let ret = match ret {
+            Some(ret) => {
+                self.typecheck_node(ret);
+                self.node_types[ret.0]
+            }
+            None => UNIT_TYPE,
+        };
+
+        self.node_types[node_id.0] = self.find_or_add_function_type(param_types, ret);
+    }
+
+    pub fn typecheck_generic_type(&mut self, name: NodeId, params: &[NodeId], node_id: NodeId) {
+        for param in params {
+            self.typecheck_node(*param);
+        }
+        let params: Vec<_> = params
+            .iter()
+            .map(|param| self.node_types[param.0])
+            .collect();
+        if params.contains(&UNKNOWN_TYPE) {
+            return;
+        }
+
+        let (kind, value, error) = match (self.parse_results.contents_for_node(name), &params[..]) {
+            (b"Option", [value]) => (EnumKind::Option, *value, UNIT_TYPE),
+            (b"Result", [value, error]) => (EnumKind::Result, *value, *error),
+            (b"Option", _) => {
+                self.error("Option takes one type parameter", node_id);
+                return;
+            }
+            _ => {
+                self.error("Result takes two type parameters", node_id);
+                return;
+            }
+        };
+
+        match self.find_enum_type(kind, value, error) {
+            Some(type_id) => self.node_types[node_id.0] = type_id,
+            None => self.error(
+                format!(
+                    "{} is not supported",
+                    self.stringify_enum_type(kind, value, error)
+                ),
+                node_id,
+            ),
+        }
+    }
+
+    /// The TypeId of the struct defined in the script with the given name
+    /// The struct with the given name that's visible from the scope being checked. A struct in
+    /// an inner block hides one with the same name further out.
+    pub fn find_struct(&self, name: &[u8]) -> Option<TypeId> {
+        self.local_types
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, local_type)| match local_type {
+                LocalType::Struct(local_struct) if local_struct.name == name => {
+                    Some((idx, self.block_depth(local_struct.block)?))
+                }
+                _ => None,
+            })
+            .max_by_key(|(_, depth)| *depth)
+            .map(|(idx, _)| TypeId(self.permanent_definitions.types.len() + idx))
+    }
+
+    /// How far into the scope stack a block's scope is, if it's there at all. The top level
+    /// counts as the outermost scope, including in functions.
+    fn block_depth(&self, block: Option<NodeId>) -> Option<usize> {
+        match block {
+            None => Some(0),
+            Some(block) => self
+                .scope_stack
+                .iter()
+                .rposition(|scope| self.scope[scope.0].node_id == block)
+                .map(|depth| depth + 1),
+        }
+    }
+
+    /// How values of each type, registered or defined in the script, are held at runtime
+    pub fn runtime_types(&self) -> Vec<RuntimeType> {
+        let registered = self
+            .permanent_definitions
+            .types
+            .iter()
+            .map(|rust_type| RuntimeType::Rust(*rust_type));
+        let local = self.local_types.iter().map(|local_type| match local_type {
+            LocalType::Struct(local_struct) => RuntimeType::Struct(
+                local_struct
+                    .fields
+                    .iter()
+                    .map(|(_, field_type)| *field_type)
+                    .collect(),
+            ),
+            LocalType::Function(function_type) => {
+                RuntimeType::Function(function_type.params.clone(), function_type.ret)
+            }
+        });
+
+        registered.chain(local).collect()
+    }
+
+    /// The struct definition for a TypeId, if it's a struct defined in the script
+    pub fn local_struct(&self, type_id: TypeId) -> Option<&LocalStruct> {
+        match self.local_type(type_id)? {
+            LocalType::Struct(local_struct) => Some(local_struct),
+            LocalType::Function(_) => None,
+        }
+    }
+
+    /// The signature of a closure type
+    pub fn function_type(&self, type_id: TypeId) -> Option<&FunctionType> {
+        match self.local_type(type_id)? {
+            LocalType::Function(function_type) => Some(function_type),
+            LocalType::Struct(_) => None,
+        }
+    }
+
+    fn local_type(&self, type_id: TypeId) -> Option<&LocalType> {
+        type_id
+            .0
+            .checked_sub(self.permanent_definitions.types.len())
+            .and_then(|idx| self.local_types.get(idx))
+    }
+
+    /// The TypeId of the closure type with the given signature, creating it if this is the first
+    /// closure with that signature
+    pub fn find_or_add_function_type(&mut self, params: Vec<TypeId>, ret: TypeId) -> TypeId {
+        let function_type = FunctionType { params, ret };
+
+        let idx = match self.local_types.iter().position(
+            |local_type| matches!(local_type, LocalType::Function(other) if *other == function_type),
+        ) {
+            Some(idx) => idx,
+            None => {
+                self.local_types.push(LocalType::Function(function_type));
+                self.local_types.len() - 1
+            }
+        };
+
+        TypeId(self.permanent_definitions.types.len() + idx)
+    }
+
+    fn is_builtin_typename(&self, name: &[u8]) -> bool {
+        matches!(
+            name,
+            b"i64" | b"f64" | b"bool" | b"String" | b"Option" | b"Result"
+        )
+    }
+
     pub fn stringify_type(&self, type_id: TypeId) -> String {
         if type_id == UNKNOWN_TYPE {
             String::from("<UNKNOWN TYPE>")
+        } else if let Some(local_struct) = self.local_struct(type_id) {
+            String::from_utf8_lossy(&local_struct.name).to_string()
+        } else if let Some(function_type) = self.function_type(type_id) {
+            let params: Vec<_> = function_type
+                .params
+                .iter()
+                .map(|param| self.stringify_type(*param))
+                .collect();
+
+            match function_type.ret {
+                UNIT_TYPE => format!("fn({})", params.join(", ")),
+                ret => format!("fn({}) -> {}", params.join(", "), self.stringify_type(ret)),
+            }
+        } else if let Some(enum_type) = self.enum_type(type_id) {
+            self.stringify_enum_type(enum_type.kind, enum_type.value, enum_type.error)
+        } else if let Some(element_type) = self.element_type(type_id) {
+            // Spelled the way scripts write them, rather than as the Rust type behind them
+            format!("[{}]", self.stringify_type(element_type))
+        } else if type_id == STRING_TYPE {
+            String::from("String")
         } else {
             self.permanent_definitions.typenames[type_id.0].clone()
         }
     }
 
+    fn stringify_enum_type(&self, kind: EnumKind, value: TypeId, error: TypeId) -> String {
+        match kind {
+            EnumKind::Option => format!("Option<{}>", self.stringify_type(value)),
+            EnumKind::Result => format!(
+                "Result<{}, {}>",
+                self.stringify_type(value),
+                self.stringify_type(error)
+            ),
+        }
+    }
+
     pub fn stringify_function_name(&self, name: &[u8], function_id: ExternalFunctionId) -> String {
         let fun_def = &self.permanent_definitions.functions[function_id.0];
 
@@ -749,3 +2880,10 @@ impl<'permanent> TypeChecker<'permanent> {
         fun_signature
     }
 }
+
+fn variant_kind(variant: Variant) -> EnumKind {
+    match variant {
+        Variant::Some | Variant::None => EnumKind::Option,
+        Variant::Ok | Variant::Err => EnumKind::Result,
+    }
+}
@@ -1,5 +1,5 @@
 #![cfg(feature = "lsp")]
-use truffle::{register_fn, Engine, ErrorBatch, FnRegister, ScriptError, Span};
+use truffle::{register_fn, Engine, ErrorBatch, ErrorKind, FnRegister, ScriptError, Span};
 
 // Script Builtins
 #[cfg_attr(any(feature = "async", feature = "lsp"), truffle::export)]
@@ -68,6 +68,7 @@ pub fn serialize_and_test_lsp() -> Result<(), ErrorBatch> {
         Some(ErrorBatch::one(ScriptError {
             message: "incomplete math expression".into(),
             span: Span { start: 11, end: 11 },
+            kind: ErrorKind::Script,
         }))
     );
     Ok(())
@@ -1,11 +1,13 @@
-#![allow(illegal_floating_point_literal_pattern)]
 mod test_eval;
 
 use assert_matches::assert_matches;
 use test_eval::*;
-use truffle::ReturnValue;
 #[cfg(feature = "lsp")]
-use truffle::{export, register_fn, Engine, ErrorBatch, FnRegister, ScriptError, Span};
+use truffle::{export, register_fn};
+use truffle::{
+    ArithmeticMode, Bindings, Callback, CancellationToken, Engine, ErrorBatch, ErrorKind,
+    EvalLimits, FallibleFnRegister, FnRegister, ReturnValue, ScriptError, Session, Span,
+};
 
 #[test]
 fn math() {
@@ -62,66 +64,2224 @@ fn variables() {
         eval_source("let mut x = 1; x = 10; x"),
         Ok(ReturnValue::I64(10))
     );
+
+    // Each variable has its own value, which assigning to another one doesn't change
+    assert_matches!(
+        eval_source("let a = 1; let mut b = a; b = 2; a"),
+        Ok(ReturnValue::I64(1))
+    );
+    assert_matches!(
+        eval_source(r#"let s = "a"; let mut t = s; t = t ++ "b"; s ++ t"#),
+        Ok(ReturnValue::String(s)) if s == "aab"
+    );
+    assert_matches!(
+        eval_source(
+            "let mut total = 0; for n in 0..3 { let mut i = 10; i += n; total += i }; total"
+        ),
+        Ok(ReturnValue::I64(33))
+    );
+}
+
+#[test]
+fn assignment_operations() {
+    assert_matches!(
+        eval_source("let mut x = 1; x += 11; x -= 2; x *= 6; x /= 12"),
+        Ok(ReturnValue::I64(5))
+    );
+}
+
+#[test]
+fn if_expression() {
+    assert_matches!(
+        eval_source("if true { 3 } else { 4 }"),
+        Ok(ReturnValue::I64(3))
+    );
+    assert_matches!(
+        eval_source("if false { 3 } else { 4 }"),
+        Ok(ReturnValue::I64(4))
+    );
+    assert_matches!(
+        eval_source("let x = if false { 3 } else { 4 }; x"),
+        Ok(ReturnValue::I64(4))
+    );
+}
+
+#[test]
+fn block_values() {
+    assert_matches!(
+        eval_source("let x = { let y = 2; y * 3 }; x"),
+        Ok(ReturnValue::I64(6))
+    );
+    assert_matches!(eval_source("{ 1; }"), Ok(ReturnValue::Unit));
+    assert_matches!(eval_source(r#""unused";"#), Ok(ReturnValue::Unit));
+    assert_matches!(eval_source("if true { 5 }"), Ok(ReturnValue::Unit));
+    assert_matches!(
+        eval_source("if false { 1 } else if true { 2 } else { 3 }"),
+        Ok(ReturnValue::I64(2))
+    );
+    assert_matches!(
+        eval_source(
+            "fn double_positive(x: i64) -> i64 { let y = if x > 0 { x } else { return 0 }; y * 2 }\ndouble_positive(4) + double_positive(-4)"
+        ),
+        Ok(ReturnValue::I64(8))
+    );
+    assert_matches!(
+        eval_source("let mut n = 0; while true { let next = if n < 3 { n + 1 } else { break }; n = next }; n"),
+        Ok(ReturnValue::I64(3))
+    );
+
+    eval_source("let x = if true { 5 }; x + 1")
+        .expect_err("an if without else has no value")
+        .assert_contains("mismatch types for operation");
+    let errors = eval_source("if true { 1 } else { false }").expect_err("arms should agree");
+    let errors: Vec<_> = errors.into_iter().collect();
+    assert_eq!(
+        errors,
+        [
+            &ScriptError {
+                message: "if branch has type i64 but else branch has type bool".into(),
+                span: Span { start: 8, end: 13 },
+                kind: ErrorKind::Script,
+            },
+            &ScriptError {
+                message: "else branch has type bool but if branch has type i64".into(),
+                span: Span { start: 19, end: 28 },
+                kind: ErrorKind::Script,
+            },
+        ]
+    );
+}
+
+#[test]
+fn while_loop() {
+    assert_matches!(
+        eval_source("let mut x = 0; while x < 10 { x = x + 1 }; x"),
+        Ok(ReturnValue::I64(10))
+    );
+}
+
+#[test]
+fn for_loop() {
+    assert_matches!(
+        eval_source("let mut x = 0; for i in 0..10 { x += i }; x"),
+        Ok(ReturnValue::I64(45))
+    );
+    assert_matches!(
+        eval_source("let mut x = 0; for i in 1..=10 { x += i }; x"),
+        Ok(ReturnValue::I64(55))
+    );
+    assert_matches!(
+        eval_source("let mut x = 0; for i in 5..5 { x += 1 }; x"),
+        Ok(ReturnValue::I64(0))
+    );
+    assert_matches!(
+        eval_source("let n = 3; let mut x = 0; for i in n..n * 2 { x += i }; x"),
+        Ok(ReturnValue::I64(12))
+    );
+    assert_matches!(
+        eval_source(
+            "let mut x = 0; for i in 9223372036854775806..=9223372036854775807 { x += 1 }; x"
+        ),
+        Ok(ReturnValue::I64(2))
+    );
+    // Changing a bound inside the loop doesn't change how many times it runs
+    assert_matches!(
+        eval_source("let mut n = 3; let mut c = 0; for i in 0..n { n = 10; c = c + 1 }; c"),
+        Ok(ReturnValue::I64(3))
+    );
+    assert_matches!(
+        eval_source("let mut n = 3; let mut c = 0; for i in 1..=n { n = n + 1; c = c + 1 }; c"),
+        Ok(ReturnValue::I64(3))
+    );
+    assert_matches!(
+        eval_source("let mut n = 0; let mut c = 0; for i in n..3 { n = 10; c = c + i }; c"),
+        Ok(ReturnValue::I64(3))
+    );
+
+    eval_source("for i in 0..3 { 1 }; i")
+        .expect_err("loop variable should only be visible inside the loop")
+        .assert_contains("variable not found");
+    eval_source("for i in 0..3 { i = 2 }")
+        .expect_err("loop variable should be immutable")
+        .assert_contains("assignment to immutable variable");
+    eval_source("for i in 0.0..3.0 { 1 }")
+        .expect_err("ranges should be over i64")
+        .assert_contains("expected i64 for range");
+}
+
+#[test]
+fn break_and_continue() {
+    assert_matches!(
+        eval_source("let mut x = 0; while true { x += 1; if x == 5 { break } }; x"),
+        Ok(ReturnValue::I64(5))
+    );
+    assert_matches!(
+        eval_source("let mut x = 0; for i in 0..10 { if i % 2 == 0 { continue }; x += i }; x"),
+        Ok(ReturnValue::I64(25))
+    );
+    assert_matches!(
+        eval_source("let mut x = 0; for i in 1..=10 { if i > 3 { break }; x += i }; x"),
+        Ok(ReturnValue::I64(6))
+    );
+    assert_matches!(
+        eval_source("let mut x = 0; for i in 1..=3 { if i == 3 { continue }; x += i }; x"),
+        Ok(ReturnValue::I64(3))
+    );
+    assert_matches!(
+        eval_source(
+            "let mut x = 0; for n in [1, 2, 3, 4] { if n == 2 { continue }; if n == 4 { break }; x += n }; x"
+        ),
+        Ok(ReturnValue::I64(4))
+    );
+    assert_matches!(
+        eval_source(
+            "let mut x = 0; let mut i = 0; while i < 3 { i += 1; for j in 0..10 { if j == i { break }; x += 1 } }; x"
+        ),
+        Ok(ReturnValue::I64(6))
+    );
+
+    eval_source("break")
+        .expect_err("break needs a loop")
+        .assert_contains("`break` outside of a loop");
+    eval_source("while true { fn f() { continue } }")
+        .expect_err("functions can't continue the loop around them")
+        .assert_contains("`continue` outside of a loop");
+}
+
+#[test]
+fn return_early() {
+    assert_matches!(
+        eval_source("fn sign(x: i64) -> i64 { if x < 0 { return -1 }; if x == 0 { return 0 }; 1 }\nsign(-5) + sign(0) * 10 + sign(3) * 100"),
+        Ok(ReturnValue::I64(99))
+    );
+    assert_matches!(
+        eval_source("fn find(xs: [i64], n: i64) -> i64 { let mut i = 0; for x in xs { if x == n { return i }; i += 1 }; return -1; }\nfind([4, 5, 6], 6) * 10 + find([4], 7)"),
+        Ok(ReturnValue::I64(19))
+    );
+    assert_matches!(
+        eval_source(r#"fn greet(name: String) -> String { if name == "" { return "nobody" }; name }
+    greet("")"#),
+        Ok(ReturnValue::String(s)) if s == "nobody"
+    );
+    assert_matches!(
+        eval_source("let mut x = 0; while true { x += 1; if x == 3 { return x * 10 } }; x"),
+        Ok(ReturnValue::I64(30))
+    );
+    assert_matches!(eval_source("return 4; 5"), Ok(ReturnValue::I64(4)));
+    assert_matches!(
+        eval_source("fn noop() { return; }\nnoop()"),
+        Ok(ReturnValue::Unit)
+    );
+
+    eval_source("fn f() -> i64 { return true }\nf()")
+        .expect_err("returned values should match the function")
+        .assert_contains("returned value has type bool but the declared return type is i64");
+    eval_source("if true { return 1.5 }; 3")
+        .expect_err("returned values should match the script")
+        .assert_contains("returned value has type f64 but the script's value has type i64");
+}
+
+#[test]
+fn options_and_results() {
+    assert_matches!(
+        eval_source("let x = Some(4); match x { Some(n) => n * 2, None => 0 }"),
+        Ok(ReturnValue::I64(8))
+    );
+    assert_matches!(
+        eval_source("let x: Option<i64> = None; match x { Some(n) => n * 2, None => -1 }"),
+        Ok(ReturnValue::I64(-1))
+    );
+    assert_matches!(
+        eval_source(r#"let r: Result<i64, String> = Err("bad"); match r { Ok(n) => "fine", Err(e) => e }"#),
+        Ok(ReturnValue::String(s)) if s == "bad"
+    );
+    assert_matches!(
+        eval_source("let x = Some(1.5); if let Some(f) = x { f } else { 0.0 }"),
+        Ok(ReturnValue::F64(1.5))
+    );
+    assert_matches!(
+        eval_source("let mut n = 0; let x: Option<i64> = None; if let Some(v) = x { n = v }; n"),
+        Ok(ReturnValue::I64(0))
+    );
+    assert_matches!(
+        eval_source("let x = 3; match x { 1 => 10, 3 => 30, _ => 0 }"),
+        Ok(ReturnValue::I64(30))
+    );
+    assert_matches!(
+        eval_source(r#"match "b" { "a" => 1, other => 2 }"#),
+        Ok(ReturnValue::I64(2))
+    );
+    assert_matches!(
+        eval_source("Some(2) == Some(2) && Some(1) != None"),
+        Ok(ReturnValue::Bool(true))
+    );
+    assert_matches!(
+        eval_source(
+            "fn half(x: i64) -> Option<i64> { if x % 2 == 0 { Some(x / 2) } else { None } }
+fn quarter(x: i64) -> Option<i64> { let h = half(x)?; half(h) }
+let a = match quarter(12) { Some(q) => q, None => 0 }
+let b = match quarter(6) { Some(q) => q, None => 0 }
+a * 10 + b"
+        ),
+        Ok(ReturnValue::I64(30))
+    );
+    assert_matches!(
+        eval_source(
+            r#"fn parse(s: String) -> Result<i64, String> { if s == "one" { Ok(1) } else { Err("unknown number " ++ s) } }
+fn sum(a: String, b: String) -> Result<i64, String> { Ok(parse(a)? + parse(b)?) }
+match sum("one", "two") { Ok(n) => "{n}", Err(e) => e }"#
+        ),
+        Ok(ReturnValue::String(s)) if s == "unknown number two"
+    );
+
+    let output = eval_source("let x = Some(3); x").expect("options should be returned");
+    let ReturnValue::Custom(value) = output else {
+        panic!("expected an option, found {output:?}")
+    };
+    assert_eq!(value.downcast_ref::<Option<i64>>(), Some(&Some(3)));
+
+    let output = eval_source(r#"let x: Option<String> = Some("a"); let y = x?; Some(y ++ "b")"#)
+        .expect("`?` should work in the script body");
+    let ReturnValue::Custom(value) = output else {
+        panic!("expected an option, found {output:?}")
+    };
+    assert_eq!(
+        value.downcast_ref::<Option<String>>(),
+        Some(&Some("ab".into()))
+    );
+
+    let output = eval_source("let x: Option<i64> = None; let y = x?; Some(y + 1)")
+        .expect("`?` should return early from the script body");
+    let ReturnValue::Custom(value) = output else {
+        panic!("expected an option, found {output:?}")
+    };
+    assert_eq!(value.downcast_ref::<Option<i64>>(), Some(&None));
+}
+
+#[test]
+fn option_and_result_errors() {
+    eval_source("let x = None")
+        .expect_err("None needs a type")
+        .assert_contains("can't infer the type of `None`");
+    eval_source("let x: Option<i64> = Some(true)")
+        .expect_err("the payload should fit the declared type")
+        .assert_contains("initializer does not match declared type");
+    eval_source("match Some(1) { Some(n) => n }")
+        .expect_err("matches should cover every variant")
+        .assert_contains("match doesn't cover `None`");
+    eval_source("match 1 { 1 => 2 }")
+        .expect_err("matches on numbers need a catch-all arm")
+        .assert_contains("add a `_` arm");
+    eval_source("match Some(1) { Some(n) => n, None => true }")
+        .expect_err("arms should have the same type")
+        .assert_contains("match arm has type bool but the first arm has type i64");
+    eval_source("match 1 { Some(n) => n, _ => 0 }")
+        .expect_err("variant patterns need an Option")
+        .assert_contains("`Some` patterns can't match values of type i64");
+    eval_source("let x = 1; x?")
+        .expect_err("`?` needs an Option or a Result")
+        .assert_contains("the `?` operator can only be used on an Option or a Result");
+    eval_source(
+        "fn f(x: Option<i64>) -> i64 { x? }
+f(None)",
+    )
+    .expect_err("`?` needs a function returning an Option")
+    .assert_contains("`?` needs the function's return type to be an Option, found i64");
+    eval_source("let x: Option<[i64]> = None")
+        .expect_err("only registered options are supported")
+        .assert_contains("is not supported");
+}
+
+#[test]
+fn options_in_registered_functions() {
+    fn find_even(xs: Vec<i64>) -> Option<i64> {
+        xs.into_iter().find(|x| x % 2 == 0)
+    }
+
+    fn or_zero(x: Option<i64>) -> i64 {
+        x.unwrap_or(0)
+    }
+
+    fn parse(s: String) -> Result<i64, String> {
+        s.parse().map_err(|_| format!("not a number: {s}"))
+    }
+
+    let mut engine = test_engine();
+    engine.register_fn("find_even", find_even, None);
+    engine.register_fn("or_zero", or_zero, None);
+    engine.register_fn("parse", parse, None);
+
+    assert_matches!(
+        engine.eval_source(
+            "test",
+            b"match find_even([1, 4, 5]) { Some(x) => x, None => -1 }",
+            false
+        ),
+        Ok(ReturnValue::I64(4))
+    );
+    assert_matches!(
+        engine.eval_source("test", b"or_zero(find_even([1])) + or_zero(None)", false),
+        Ok(ReturnValue::I64(0))
+    );
+    assert_matches!(
+        engine.eval_source(
+            "test",
+            br#"match parse("x1") { Ok(n) => "{n}", Err(e) => e }"#,
+            false
+        ),
+        Ok(ReturnValue::String(s)) if s == "not a number: x1"
+    );
+
+    let output = engine
+        .eval_source("test", br#"parse("12")"#, false)
+        .expect("results should be returned");
+    let ReturnValue::Custom(value) = output else {
+        panic!("expected a result, found {output:?}")
+    };
+    assert_eq!(value.downcast_ref::<Result<i64, String>>(), Some(&Ok(12)));
+}
+
+#[test]
+fn arrays() {
+    assert_matches!(
+        eval_source("let xs = [1, 2, 3]; xs[1]"),
+        Ok(ReturnValue::I64(2))
+    );
+    assert_matches!(
+        eval_source("let xs = [1.5, 2.5]; xs[0] + xs[1]"),
+        Ok(ReturnValue::F64(4.0))
+    );
+    assert_matches!(
+        eval_source("let xs = [true, false]; xs[1]"),
+        Ok(ReturnValue::Bool(false))
+    );
+    assert_matches!(
+        eval_source(r#"let xs = ["a", "b"]; xs[1]"#),
+        Ok(ReturnValue::String(_))
+    );
+    assert_matches!(
+        eval_source("let xs = [1, 2, 3]; xs.len()"),
+        Ok(ReturnValue::I64(3))
+    );
+    assert_matches!(
+        eval_source("let xs: [i64] = []; xs.push(4); xs.push(5); xs.len() + xs[1]"),
+        Ok(ReturnValue::I64(7))
+    );
+    assert_matches!(
+        eval_source("let mut total = 0; for x in [1, 2, 3] { total += x }; total"),
+        Ok(ReturnValue::I64(6))
+    );
+    assert_matches!(
+        eval_source(
+            "let mut xs = [1, 2, 3]; let mut s = 0; for x in xs { xs = [100]; s = s + x }; s"
+        ),
+        Ok(ReturnValue::I64(6))
+    );
+    assert_matches!(
+        eval_source(r#"let mut n = 0; for s in ["a", "b"] { n += 1 }; n"#),
+        Ok(ReturnValue::I64(2))
+    );
+    assert_matches!(
+        eval_source("fn first(xs: [i64]) -> i64 { xs[0] }\nfirst([9, 8])"),
+        Ok(ReturnValue::I64(9))
+    );
+
+    let output = eval_source("[1, 2, 3]").expect("arrays should be returned");
+    let ReturnValue::Custom(value) = output else {
+        panic!("expected an array, found {output:?}")
+    };
+    assert_eq!(value.downcast_ref::<Vec<i64>>(), Some(&vec![1, 2, 3]));
+}
+
+#[test]
+fn array_errors() {
+    assert_eq!(
+        eval_source("let xs = [1, 2, 3]; xs[3]").expect_err("indexing past the end should fail"),
+        ErrorBatch::one(ScriptError {
+            message: "index out of bounds: the len is 3 but the index is 3".into(),
+            span: Span { start: 20, end: 25 },
+            kind: ErrorKind::Script,
+        })
+    );
+
+    eval_source("let xs = [1, 2, 3]; xs[0 - 1]")
+        .expect_err("negative indices should fail")
+        .assert_contains("index out of bounds");
+    eval_source("[1, true]")
+        .expect_err("elements should have the same type")
+        .assert_contains("array elements should all be i64");
+    eval_source("let xs = []")
+        .expect_err("empty arrays need a type")
+        .assert_contains("can't infer the type of an empty array");
+    eval_source("let x = 3; x[0]")
+        .expect_err("only arrays can be indexed")
+        .assert_contains("expected array for indexing");
+    eval_source("let xs = [1]; xs[true]")
+        .expect_err("indices should be i64")
+        .assert_contains("expected i64 for array index");
+    eval_source("for x in 3 { 1 }")
+        .expect_err("for loops need a range or an array")
+        .assert_contains("expected range or array in for loop");
+    eval_source("[[1], [2]]")
+        .expect_err("arrays can't be nested")
+        .assert_contains("arrays of [i64] are not supported");
+    eval_source("let xs: [[String]] = []")
+        .expect_err("arrays can't be nested")
+        .assert_contains("arrays of [String] are not supported");
+}
+
+#[test]
+fn arrays_in_registered_functions() {
+    fn sum(xs: Vec<i64>) -> i64 {
+        xs.iter().sum()
+    }
+
+    fn words() -> Vec<String> {
+        vec!["hello".into(), "world".into()]
+    }
+
+    let mut engine = test_engine();
+    engine.register_fn("sum", sum, None);
+    engine.register_fn("words", words, None);
+
+    assert_matches!(
+        engine.eval_source("test", b"let xs = [1, 2, 3]; xs.push(4); sum(xs)", false),
+        Ok(ReturnValue::I64(10))
+    );
+    assert_matches!(
+        engine.eval_source("test", b"let ws = words(); ws.len()", false),
+        Ok(ReturnValue::I64(2))
+    );
+}
+
+#[test]
+fn structs() {
+    assert_matches!(
+        eval_source("struct Point { x: f64, y: f64 }\nlet p = Point { x: 1.5, y: 2.0 }; p.x + p.y"),
+        Ok(ReturnValue::F64(3.5))
+    );
+    assert_matches!(
+        eval_source(
+            "struct Point { x: i64, y: i64 }\nlet mut p = Point { y: 2, x: 1 }; p.x = 10; p.y += 5; p.x * p.y"
+        ),
+        Ok(ReturnValue::I64(70))
+    );
+    assert_matches!(
+        eval_source(
+            r#"struct Person { name: String, alive: bool }
+let mut person = Person { name: "sam", alive: true }
+person.name = "alex"
+person.name"#
+        ),
+        Ok(ReturnValue::String(_))
+    );
+    assert_matches!(
+        eval_source(
+            "fn area(r: Rect) -> i64 { r.w * r.h }\nstruct Rect { w: i64, h: i64 }\narea(Rect { w: 3, h: 4 })"
+        ),
+        Ok(ReturnValue::I64(12))
+    );
+    assert_matches!(
+        eval_source("struct Empty {}\nlet e = Empty {}; e == Empty { }"),
+        Ok(ReturnValue::Bool(true))
+    );
+    assert_matches!(
+        eval_source("let x = true\nif x {}\nwhile false {}\n3"),
+        Ok(ReturnValue::I64(3))
+    );
+
+    // Structs are scoped to the block they're defined in, like variables
+    assert_matches!(
+        eval_source(
+            "let mut a = 0\nif true { struct Q { a: i64 }\nlet q = Q { a: 4 }; a = q.a }\na"
+        ),
+        Ok(ReturnValue::I64(4))
+    );
+    assert_matches!(
+        eval_source(
+            "struct Q { a: i64 }\nlet mut b = false\nif true { struct Q { b: bool }\nb = Q { b: true }.b }\nQ { a: 1 }.a == 1 && b"
+        ),
+        Ok(ReturnValue::Bool(true))
+    );
+
+    let output = eval_source("struct Point { x: i64, y: i64 }\nPoint { x: 1, y: 2 }")
+        .expect("structs should be returned");
+    let ReturnValue::Custom(value) = output else {
+        panic!("expected a struct, found {output:?}")
+    };
+    let point = value
+        .downcast_ref::<truffle::ScriptStruct>()
+        .expect("value should be a script struct");
+    assert_eq!(point.fields[1].downcast_ref::<i64>(), Some(&2));
+}
+
+#[test]
+fn values_are_shared_between_variables() {
+    assert_matches!(
+        eval_source("let xs = [1, 2]; let mut ys = xs; ys.push(3); xs.len()"),
+        Ok(ReturnValue::I64(3))
+    );
+    assert_matches!(
+        eval_source("fn add_to(xs: [i64]) { xs.push(3) }\nlet xs = [1]; add_to(xs); xs.len()"),
+        Ok(ReturnValue::I64(2))
+    );
+    assert_matches!(
+        eval_source("struct Point { x: i64 }\nlet p = Point { x: 1 }; let mut q = p; q.x = 5; p.x"),
+        Ok(ReturnValue::I64(5))
+    );
+    assert_matches!(
+        eval_source(
+            r#"let env = new_env(); let other = env; other.set_var("x", 3); env.read_var("x")"#
+        ),
+        Ok(ReturnValue::I64(3))
+    );
+
+    // A new value for the variable doesn't touch the one it used to share
+    assert_matches!(
+        eval_source("let xs = [1]; let mut ys = xs; ys = [7]; xs[0]"),
+        Ok(ReturnValue::I64(1))
+    );
+    // Closures copy what they use
+    assert_matches!(
+        eval_source("let xs = [1]; let f = || xs.len(); xs.push(2); f()"),
+        Ok(ReturnValue::I64(1))
+    );
+}
+
+#[test]
+fn struct_errors() {
+    eval_source("struct Point { x: f64, y: f64 }\nPoint { x: 1.0 }")
+        .expect_err("all fields should be given")
+        .assert_contains("missing field y in Point");
+    eval_source("struct Point { x: f64, y: f64 }\nPoint { x: 1.0, y: 2.0, z: 3.0 }")
+        .expect_err("unknown fields should be rejected")
+        .assert_contains("no field z on type Point");
+    eval_source("struct Point { x: f64, y: f64 }\nPoint { x: 1, y: 2.0 }")
+        .expect_err("field types should be checked")
+        .assert_contains("field x should be f64, found i64");
+    eval_source("struct Point { x: f64, y: f64 }\nlet p = Point { x: 1.0, y: 2.0 }; p.z")
+        .expect_err("unknown fields should be rejected")
+        .assert_contains("no field z on type Point");
+    eval_source("struct Point { x: f64, y: f64 }\nlet p = Point { x: 1.0, y: 2.0 }; p.x = 3.0")
+        .expect_err("fields of immutable variables can't be assigned")
+        .assert_contains("assignment to immutable variable");
+    eval_source("if true { struct Q { a: i64 } }\nQ { a: 1 }")
+        .expect_err("structs shouldn't be visible outside their block")
+        .assert_contains("unknown struct: Q");
+    eval_source("if true { struct Q { a: i64 } }\nfn f(q: Q) {}")
+        .expect_err("structs shouldn't be visible outside their block")
+        .assert_contains("unknown type: Q");
+    eval_source("struct Point { x: f64 }\nstruct Point { y: f64 }")
+        .expect_err("struct names should be unique")
+        .assert_contains("type Point is already defined");
+    eval_source("Point { x: 1.0 }")
+        .expect_err("structs should be defined")
+        .assert_contains("unknown struct: Point");
+    eval_source("struct Bag { items: [i64] }")
+        .expect_err("only basic types can be fields")
+        .assert_contains("fields of type [i64] are not supported in structs yet");
+}
+
+#[derive(Clone, truffle::Fields)]
+pub struct Player {
+    pub name: String,
+    pub health: i64,
+    #[truffle(skip)]
+    pub secret: i64,
+    level: i64,
+}
+
+#[test]
+fn registered_fields() {
+    fn new_player() -> Player {
+        Player {
+            name: "sam".into(),
+            health: 100,
+            secret: 42,
+            level: 1,
+        }
+    }
+
+    fn level(player: Player) -> i64 {
+        player.level
+    }
+
+    let mut engine = test_engine();
+    engine.register_fields::<Player>();
+    engine.register_fn("new_player", new_player, None);
+    engine.register_fn("level", level, None);
+
+    assert_matches!(
+        engine.eval_source("test", b"let player = new_player(); player.health", false),
+        Ok(ReturnValue::I64(100))
+    );
+    assert_matches!(
+        engine.eval_source(
+            "test",
+            b"let mut player = new_player(); player.health = 10; player.health -= 3; player.health",
+            false
+        ),
+        Ok(ReturnValue::I64(7))
+    );
+    assert_matches!(
+        engine.eval_source(
+            "test",
+            br#"let mut player = new_player(); player.name = "alex"; player.name"#,
+            false
+        ),
+        Ok(ReturnValue::String(_))
+    );
+    assert_matches!(
+        engine.eval_source("test", b"let player = new_player(); level(player)", false),
+        Ok(ReturnValue::I64(1))
+    );
+
+    engine
+        .eval_source(
+            "test",
+            b"let player = new_player(); player.health = 10",
+            false,
+        )
+        .expect_err("fields of immutable variables can't be assigned")
+        .assert_contains("assignment to immutable variable");
+    engine
+        .eval_source(
+            "test",
+            b"let mut player = new_player(); player.health = 1.5",
+            false,
+        )
+        .expect_err("field types should be checked")
+        .assert_contains("mismatched types during assignment");
+    for field in ["secret", "level"] {
+        engine
+            .eval_source(
+                "test",
+                format!("let player = new_player(); player.{field}").as_bytes(),
+                false,
+            )
+            .expect_err("skipped and private fields should not be exposed")
+            .assert_contains(&format!("no field {field} on type"));
+    }
+}
+
+#[test]
+fn external_call() {
+    assert_matches!(eval_source("add(3, 4)"), Ok(ReturnValue::I64(7)));
+    assert_matches!(eval_source("add(6.0, 3.0)"), Ok(ReturnValue::F64(9.0)));
+}
+
+#[test]
+#[allow(clippy::too_many_arguments)]
+fn many_arguments() {
+    fn sum12(
+        a: i64,
+        b: i64,
+        c: i64,
+        d: i64,
+        e: i64,
+        f: i64,
+        g: i64,
+        h: i64,
+        i: i64,
+        j: i64,
+        k: i64,
+        l: i64,
+    ) -> i64 {
+        a + b + c + d + e + f + g + h + i + j + k + l
+    }
+
+    fn describe(name: String, x: f64, y: f64, z: f64, visible: bool) -> String {
+        format!("{name} at ({x}, {y}, {z}), visible: {visible}")
+    }
+
+    fn set_all(env: &mut Env, a: i64, b: i64, c: i64, d: i64, e: i64) {
+        for (name, value) in ["a", "b", "c", "d", "e"].into_iter().zip([a, b, c, d, e]) {
+            env.set_var(name.into(), value);
+        }
+    }
+
+    fn total(env: &mut Env) -> i64 {
+        ["a", "b", "c", "d", "e"]
+            .into_iter()
+            .map(|name| env.read_var(name.into()))
+            .sum()
+    }
+
+    let mut engine = test_engine();
+    engine.register_fn("sum12", sum12, None);
+    engine.register_fn("total", total, None);
+    engine.register_fn("describe", describe, None);
+    engine.register_fn("set_all", set_all, None);
+
+    assert_matches!(
+        engine.eval_source(
+            "test",
+            b"sum12(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12)",
+            false
+        ),
+        Ok(ReturnValue::I64(78))
+    );
+    assert_matches!(
+        engine.eval_source("test", br#"describe("box", 1.0, 2.0, 3.0, true)"#, false),
+        Ok(ReturnValue::String(_))
+    );
+    assert_matches!(
+        engine.eval_source(
+            "test",
+            b"let env = new_env(); env.set_all(1, 2, 3, 4, 5); env.total()",
+            false
+        ),
+        Ok(ReturnValue::I64(15))
+    );
+    engine
+        .eval_source("test", b"sum12(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11)", false)
+        .expect_err("arity should be checked")
+        .assert_contains("sum12");
+}
+
+#[test]
+#[cfg(feature = "async")]
+fn many_arguments_async() {
+    #[truffle::export]
+    async fn sum6(a: i64, b: i64, c: i64, d: i64, e: i64, f: i64) -> i64 {
+        a + b + c + d + e + f
+    }
+
+    let mut engine = test_engine();
+    truffle::register_fn!(engine, "sum6", sum6);
+
+    assert_matches!(
+        futures::executor::block_on(engine.eval_source_async(
+            "test",
+            b"sum6(1, 2, 3, 4, 5, 6)",
+            false
+        )),
+        Ok(ReturnValue::I64(21))
+    );
+}
+
+#[test]
+fn fallible_functions() {
+    fn checked_div(lhs: i64, rhs: i64) -> Result<i64, String> {
+        lhs.checked_div(rhs)
+            .ok_or_else(|| "attempt to divide by zero".into())
+    }
+
+    let mut engine = test_engine();
+    engine.register_fallible_fn("checked_div", checked_div, None);
+
+    assert_matches!(
+        engine.eval_source("test", b"checked_div(10, 2)", false),
+        Ok(ReturnValue::I64(5))
+    );
+    assert_eq!(
+        engine
+            .eval_source("test", b"let x = 1; checked_div(x, 0)", false)
+            .expect_err("an Err should stop the script"),
+        ErrorBatch::one(ScriptError {
+            message: "attempt to divide by zero".into(),
+            span: Span { start: 11, end: 28 },
+            kind: ErrorKind::Script,
+        })
+    );
+}
+
+#[cfg(feature = "lsp")]
+#[export(fallible)]
+fn checked_sqrt(value: f64) -> Result<f64, String> {
+    if value < 0.0 {
+        Err(format!("can't take the square root of {value}"))
+    } else {
+        Ok(value.sqrt())
+    }
+}
+
+#[test]
+#[cfg(feature = "lsp")]
+fn fallible_exported_functions() {
+    let mut engine = Engine::new();
+    register_fn!(engine, "sqrt", checked_sqrt);
+
+    assert_matches!(
+        engine.eval_source("test", b"sqrt(16.0)", false),
+        Ok(ReturnValue::F64(x)) if x == 4.0
+    );
+    engine
+        .eval_source("test", b"sqrt(0.0 - 1.0)", false)
+        .expect_err("an Err should stop the script")
+        .assert_contains("can't take the square root of -1");
+}
+
+#[cfg(feature = "lsp")]
+#[export]
+fn parse_level(level: String) -> Result<i64, String> {
+    level.parse().map_err(|_| format!("not a level: {level}"))
+}
+
+#[cfg(feature = "lsp")]
+#[export(fallible)]
+fn require_level(level: String) -> Result<i64, String> {
+    parse_level(level)
+}
+
+#[test]
+#[cfg(feature = "lsp")]
+fn exported_results_match_registered_ones() {
+    let source = br#"match parse_level("x") { Ok(n) => "{n}", Err(e) => e }"#;
+
+    // Without `fallible`, the script gets the `Result` either way
+    let mut exported = Engine::new();
+    register_fn!(exported, "parse_level", parse_level);
+    let mut registered = Engine::new();
+    registered.register_fn("parse_level", parse_level, None);
+    for engine in [&exported, &registered] {
+        assert_matches!(
+            engine.eval_source("test", source, false),
+            Ok(ReturnValue::String(s)) if s == "not a level: x"
+        );
+    }
+
+    // With it, an `Err` stops the script either way
+    let mut exported = Engine::new();
+    register_fn!(exported, "require_level", require_level);
+    let mut registered = Engine::new();
+    registered.register_fallible_fn("require_level", require_level, None);
+    for engine in [&exported, &registered] {
+        assert_matches!(
+            engine.eval_source("test", br#"require_level("3") + 1"#, false),
+            Ok(ReturnValue::I64(4))
+        );
+        engine
+            .eval_source("test", br#"require_level("x")"#, false)
+            .expect_err("an Err should stop the script")
+            .assert_contains("not a level: x");
+    }
+}
+
+#[test]
+#[cfg(feature = "async")]
+fn fallible_async_functions() {
+    #[truffle::export(fallible)]
+    async fn lookup(id: i64) -> Result<i64, String> {
+        if id == 1 {
+            Ok(100)
+        } else {
+            Err(format!("no entry with id {id}"))
+        }
+    }
+
+    let mut engine = test_engine();
+    truffle::register_fn!(engine, "lookup", lookup);
+
+    assert_matches!(
+        futures::executor::block_on(engine.eval_source_async("test", b"lookup(1)", false)),
+        Ok(ReturnValue::I64(100))
+    );
+    futures::executor::block_on(engine.eval_source_async("test", b"lookup(2)", false))
+        .expect_err("an Err should stop the script")
+        .assert_contains("no entry with id 2");
+
+    // Without `fallible`, the script gets the `Result` itself
+    #[truffle::export]
+    async fn try_lookup(id: i64) -> Result<i64, String> {
+        lookup(id).await
+    }
+
+    truffle::register_fn!(engine, "try_lookup", try_lookup);
+    assert_matches!(
+        futures::executor::block_on(engine.eval_source_async(
+            "test",
+            b"match try_lookup(2) { Ok(n) => n, Err(e) => -1 }",
+            false
+        )),
+        Ok(ReturnValue::I64(-1))
+    );
+}
+
+#[test]
+fn method_and_mutation() {
+    assert_matches!(
+        eval_source(r#"let env = new_env(); env.set_var("kris", 3); env.read_var("kris")"#),
+        Ok(ReturnValue::I64(3))
+    );
+}
+
+#[test]
+fn unary_operators() {
+    assert_matches!(eval_source("-5"), Ok(ReturnValue::I64(-5)));
+    assert_matches!(eval_source("-1.5"), Ok(ReturnValue::F64(x)) if x == -1.5);
+    assert_matches!(eval_source("let x = 3; -x"), Ok(ReturnValue::I64(-3)));
+    assert_matches!(eval_source("--4"), Ok(ReturnValue::I64(4)));
+    assert_matches!(eval_source("2 - -3"), Ok(ReturnValue::I64(5)));
+    assert_matches!(eval_source("-2 * 3 + 1"), Ok(ReturnValue::I64(-5)));
+    assert_matches!(eval_source("-(2 + 3)"), Ok(ReturnValue::I64(-5)));
+    assert_matches!(eval_source("!true"), Ok(ReturnValue::Bool(false)));
+    assert_matches!(
+        eval_source("let done = false; !done && true"),
+        Ok(ReturnValue::Bool(true))
+    );
+    assert_matches!(eval_source("!(1 < 2)"), Ok(ReturnValue::Bool(false)));
+    assert_matches!(
+        eval_source("let x = [1, 2]; -x[1]"),
+        Ok(ReturnValue::I64(-2))
+    );
+    assert_matches!(eval_source("let x = 3;\n-x"), Ok(ReturnValue::I64(-3)));
+    assert_matches!(
+        eval_source("let x = 3\nlet y = x -\n1\ny"),
+        Ok(ReturnValue::I64(2))
+    );
+}
+
+#[test]
+fn unary_operator_errors() {
+    eval_source("-true")
+        .expect_err("only numbers can be negated")
+        .assert_contains("negation expects a number");
+    eval_source("!1")
+        .expect_err("only booleans can be inverted")
+        .assert_contains("logical not expects a boolean");
+
+    // Without a `;`, a `-` starting a line would subtract from the line before
+    eval_source("let mut y = 1\n-5")
+        .expect_err("a line starting with - shouldn't continue the let before it")
+        .assert_contains("`-` at the start of a line continues the expression before it");
+    eval_source("let x = 3\n-x")
+        .expect_err("a line starting with - shouldn't continue the let before it")
+        .assert_contains("`-` at the start of a line continues the expression before it");
+}
+
+#[test]
+fn integer_operators() {
+    assert_matches!(eval_source("7 % 3"), Ok(ReturnValue::I64(1)));
+    assert_matches!(eval_source("-7 % 3"), Ok(ReturnValue::I64(-1)));
+    assert_matches!(eval_source("2 ** 10"), Ok(ReturnValue::I64(1024)));
+    assert_matches!(eval_source("2.0 ** 0.5"), Ok(ReturnValue::F64(x)) if x == 2f64.sqrt());
+    assert_matches!(eval_source("-2 ** 2"), Ok(ReturnValue::I64(-4)));
+
+    // Chains of `**` group from the right
+    assert_matches!(eval_source("2 ** 3 ** 2"), Ok(ReturnValue::I64(512)));
+    assert_matches!(
+        eval_source("2.0 ** 3.0 ** 2.0"),
+        Ok(ReturnValue::F64(x)) if x == 512.0
+    );
+    assert_matches!(eval_source("-2 ** 3 ** 2"), Ok(ReturnValue::I64(-512)));
+    assert_matches!(
+        eval_source("2 * 2 ** 3 ** 2 + 1"),
+        Ok(ReturnValue::I64(1025))
+    );
+    assert_matches!(eval_source("(2 ** 3) ** 2"), Ok(ReturnValue::I64(64)));
+    assert_matches!(eval_source("12 & 10"), Ok(ReturnValue::I64(8)));
+    assert_matches!(eval_source("12 | 10"), Ok(ReturnValue::I64(14)));
+    assert_matches!(eval_source("12 ^ 10"), Ok(ReturnValue::I64(6)));
+    assert_matches!(eval_source("1 << 4"), Ok(ReturnValue::I64(16)));
+    assert_matches!(eval_source("-16 >> 2"), Ok(ReturnValue::I64(-4)));
+    assert_matches!(eval_source("1 + 1 << 2"), Ok(ReturnValue::I64(8)));
+    assert_matches!(eval_source("6 & 3 == 2"), Ok(ReturnValue::Bool(true)));
+    assert_matches!(eval_source("1 | 2 ^ 3 & 2"), Ok(ReturnValue::I64(1)));
+    assert_matches!(eval_source("10 - 7 % 4 * 2"), Ok(ReturnValue::I64(4)));
+}
+
+#[test]
+fn integer_operator_errors() {
+    eval_source("1.5 % 2.0")
+        .expect_err("modulo is only defined for integers")
+        .assert_contains("integer operator expects i64 types");
+    eval_source("true & false")
+        .expect_err("bitwise operators are only defined for integers")
+        .assert_contains("integer operator expects i64 types");
+    eval_source("5 % 0")
+        .expect_err("it should not be possible to take the remainder of zero")
+        .assert_contains("division by zero");
+    eval_source("2 ** -1")
+        .expect_err("integer powers need a non-negative exponent")
+        .assert_contains("exponent must be a non-negative integer");
+    eval_source("1 << 64")
+        .expect_err("shifting by the bit width is out of range")
+        .assert_contains("shift amount must be between 0 and 63");
+    eval_source("1 >> -1")
+        .expect_err("negative shifts are out of range")
+        .assert_contains("shift amount must be between 0 and 63");
+}
+
+#[test]
+fn integer_overflow() {
+    assert_eq!(
+        eval_source("let big = 9223372036854775807; big + 1")
+            .expect_err("overflowing an add should fail"),
+        ErrorBatch::one(ScriptError {
+            message: "attempt to add with overflow".into(),
+            span: Span { start: 35, end: 36 },
+            kind: ErrorKind::Script,
+        })
+    );
+    eval_source("let small = 0 - 9223372036854775807; small - 2")
+        .expect_err("overflowing a subtract should fail")
+        .assert_contains("attempt to subtract with overflow");
+    eval_source("4611686018427387904 * 2")
+        .expect_err("overflowing a multiply should fail")
+        .assert_contains("attempt to multiply with overflow");
+    eval_source("let min = -9223372036854775807 - 1; min / -1")
+        .expect_err("dividing the minimum by -1 should fail")
+        .assert_contains("attempt to divide with overflow");
+    eval_source("let min = -9223372036854775807 - 1; -min")
+        .expect_err("negating the minimum should fail")
+        .assert_contains("attempt to negate with overflow");
+    eval_source("2 ** 63")
+        .expect_err("overflowing a power should fail")
+        .assert_contains("attempt to raise to a power with overflow");
+
+    // The minimum can be written as a literal, but only with the `-` in front of it
+    assert_matches!(
+        eval_source("-9223372036854775808"),
+        Ok(ReturnValue::I64(i64::MIN))
+    );
+    assert_matches!(
+        eval_source("let min = -9223372036854775808; min == -9223372036854775807 - 1"),
+        Ok(ReturnValue::Bool(true))
+    );
+    assert_eq!(
+        eval_source("9223372036854775808").expect_err("the literal is out of range"),
+        ErrorBatch::one(ScriptError {
+            message: "integer literal is out of range for i64".into(),
+            span: Span { start: 0, end: 19 },
+            kind: ErrorKind::Script,
+        })
+    );
+    eval_source("-9223372036854775809")
+        .expect_err("the literal is out of range")
+        .assert_contains("integer literal is out of range for i64");
+    eval_source("99999999999999999999999999999999999999999")
+        .expect_err("the literal is out of range")
+        .assert_contains("integer literal is out of range for i64");
+
+    // Only digits that make a decimal number are out of range, anything else is malformed
+    assert_eq!(
+        eval_source("0x10").expect_err("only decimal literals are supported"),
+        ErrorBatch::one(ScriptError {
+            message: "invalid integer literal: only decimal digits are supported".into(),
+            span: Span { start: 0, end: 4 },
+            kind: ErrorKind::Script,
+        })
+    );
+    eval_source("-0b11111111111111111111111111111111111111111111111111111111111111111")
+        .expect_err("only decimal literals are supported")
+        .assert_contains("invalid integer literal: only decimal digits are supported");
+}
+
+#[test]
+fn arithmetic_modes() {
+    let source = b"let big = 9223372036854775807; big + 1";
+
+    let mut engine = test_engine();
+    assert_eq!(engine.arithmetic_mode(), ArithmeticMode::Checked);
+
+    engine.set_arithmetic_mode(ArithmeticMode::Wrapping);
+    assert_matches!(
+        engine.eval_source("test", source, false),
+        Ok(ReturnValue::I64(i64::MIN))
+    );
+    assert_matches!(
+        engine.eval_source("test", b"2 ** 64", false),
+        Ok(ReturnValue::I64(0))
+    );
+
+    engine.set_arithmetic_mode(ArithmeticMode::Saturating);
+    assert_matches!(
+        engine.eval_source("test", source, false),
+        Ok(ReturnValue::I64(i64::MAX))
+    );
+    assert_matches!(
+        engine.eval_source("test", b"-2 - 3 ** 41", false),
+        Ok(ReturnValue::I64(i64::MIN))
+    );
+}
+
+#[test]
+fn casts() {
+    assert_matches!(eval_source("3 as f64"), Ok(ReturnValue::F64(x)) if x == 3.0);
+    assert_matches!(eval_source("2.9 as i64"), Ok(ReturnValue::I64(2)));
+    assert_matches!(eval_source("-2.9 as i64"), Ok(ReturnValue::I64(-2)));
+    assert_matches!(
+        eval_source("10000000000000000000000.0 as i64"),
+        Ok(ReturnValue::I64(i64::MAX))
+    );
+    assert_matches!(
+        eval_source("-10000000000000000000000.0 as i64"),
+        Ok(ReturnValue::I64(i64::MIN))
+    );
+    assert_matches!(eval_source("7 as i64"), Ok(ReturnValue::I64(7)));
+    assert_matches!(
+        eval_source("let n = 3; 1.5 * n as f64"),
+        Ok(ReturnValue::F64(x)) if x == 4.5
+    );
+    assert_matches!(
+        eval_source("let x = 2.5; x as i64 as f64"),
+        Ok(ReturnValue::F64(x)) if x == 2.0
+    );
+
+    eval_source("true as i64")
+        .expect_err("only numbers can be cast")
+        .assert_contains("can't cast bool to i64");
+    eval_source("1 + 2.0")
+        .expect_err("numbers aren't promoted by default")
+        .assert_contains("mismatch types for operation");
+}
+
+#[test]
+fn numeric_promotion() {
+    let mut engine = test_engine();
+    assert!(!engine.numeric_promotion());
+    engine.set_numeric_promotion(true);
+
+    assert_matches!(
+        engine.eval_source("test", b"1 + 2.5", false),
+        Ok(ReturnValue::F64(x)) if x == 3.5
+    );
+    assert_matches!(
+        engine.eval_source("test", b"let n = 3; 1.5 * n", false),
+        Ok(ReturnValue::F64(x)) if x == 4.5
+    );
+    assert_matches!(
+        engine.eval_source("test", b"2 < 2.5", false),
+        Ok(ReturnValue::Bool(true))
+    );
+    assert_matches!(
+        engine.eval_source("test", b"let mut x = 1.5; x += 2; x", false),
+        Ok(ReturnValue::F64(x)) if x == 3.5
+    );
+    assert_matches!(
+        engine.eval_source("test", b"7 / 2", false),
+        Ok(ReturnValue::I64(3))
+    );
+
+    engine
+        .eval_source("test", b"let mut x = 1; x += 2.5", false)
+        .expect_err("an i64 variable can't hold an f64")
+        .assert_contains("mismatch types for operation");
+}
+
+#[test]
+fn strings() {
+    assert_matches!(
+        eval_source(r#""sam" ++ " " ++ "alex""#),
+        Ok(ReturnValue::String(s)) if s == "sam alex"
+    );
+    assert_matches!(
+        eval_source(r#""line\n\t\"quoted\" \\ \{braces\}""#),
+        Ok(ReturnValue::String(s)) if s == "line\n\t\"quoted\" \\ {braces}"
+    );
+    assert_matches!(eval_source(r#""abc" < "abd""#), Ok(ReturnValue::Bool(true)));
+    assert_matches!(eval_source(r#""b" >= "a""#), Ok(ReturnValue::Bool(true)));
+    assert_matches!(eval_source(r#""B" > "a""#), Ok(ReturnValue::Bool(false)));
+    assert_matches!(
+        eval_source(r#"let mut s = ""; for x in [1, 2, 3] { s = s ++ "{x}," }; s"#),
+        Ok(ReturnValue::String(s)) if s == "1,2,3,"
+    );
+}
+
+#[test]
+fn string_interpolation() {
+    assert_matches!(
+        eval_source(r#"let name = "sam"; let age = 30; "{name} is {age + 1}""#),
+        Ok(ReturnValue::String(s)) if s == "sam is 31"
+    );
+    assert_matches!(
+        eval_source(r#""{1.5} {true} {add(1, 2)}""#),
+        Ok(ReturnValue::String(s)) if s == "1.5 true 3"
+    );
+    assert_matches!(
+        eval_source(r#"let x = 3; "{x}""#),
+        Ok(ReturnValue::String(s)) if s == "3"
+    );
+}
+
+#[test]
+fn string_errors() {
+    eval_source(r#""abc"#)
+        .expect_err("strings need their closing quote")
+        .assert_contains("unterminated string");
+    eval_source(r#""\q""#)
+        .expect_err("only known escape sequences are allowed")
+        .assert_contains("unknown escape sequence");
+    eval_source(r#""{x""#)
+        .expect_err("interpolations need their closing brace")
+        .assert_contains("unclosed interpolation");
+    eval_source(r#""a" ++ 1"#)
+        .expect_err("only strings can be appended")
+        .assert_contains("mismatch types for operation");
+    eval_source(r#"let env = new_env(); "{env}""#)
+        .expect_err("only basic types can be interpolated")
+        .assert_contains("can't be interpolated into a string");
+    eval_source(r#"let xs = ["a"]; "{xs}""#)
+        .expect_err("only basic types can be interpolated")
+        .assert_contains("values of type [String] can't be interpolated into a string");
+}
+
+#[test]
+fn equality() {
+    assert_matches!(eval_source("1 == 1"), Ok(ReturnValue::Bool(true)));
+    assert_matches!(eval_source("1 != 1"), Ok(ReturnValue::Bool(false)));
+    assert_matches!(eval_source("1.5 == 2.5"), Ok(ReturnValue::Bool(false)));
+    assert_matches!(eval_source("1.5 != 2.5"), Ok(ReturnValue::Bool(true)));
+    assert_matches!(eval_source("true == true"), Ok(ReturnValue::Bool(true)));
+    assert_matches!(eval_source("true != false"), Ok(ReturnValue::Bool(true)));
+    assert_matches!(
+        eval_source(r#"let name = "sam"; name == "sam""#),
+        Ok(ReturnValue::Bool(true))
+    );
+    assert_matches!(
+        eval_source(r#""sam" != "alex""#),
+        Ok(ReturnValue::Bool(true))
+    );
+    assert_matches!(
+        eval_source("[1, 2, 3] == [1, 2, 3]"),
+        Ok(ReturnValue::Bool(true))
+    );
+    assert_matches!(
+        eval_source("let mut x = 0; while x != 5 { x = x + 1 }; x"),
+        Ok(ReturnValue::I64(5))
+    );
+    assert_matches!(
+        eval_source("struct P { a: i64 }\nP { a: 1 } == P { a: 1 }"),
+        Ok(ReturnValue::Bool(true))
+    );
+    assert_matches!(
+        eval_source(
+            r#"struct Person { name: String, age: i64, score: f64 }
+let sam = Person { name: "sam", age: 30, score: 1.5 }
+let mut count = 0
+for age in 29..=31 {
+  if sam == Person { name: "sam", age: age, score: 1.5 } { count += 1 }
+  if sam != Person { name: "alex", age: age, score: 1.5 } { count += 10 }
+}
+count"#
+        ),
+        Ok(ReturnValue::I64(31))
+    );
+}
+
+#[test]
+fn equality_errors() {
+    eval_source("1 == true")
+        .expect_err("values of different types can't be compared")
+        .assert_contains("mismatch types for comparison");
+    eval_source("1 != 1.0")
+        .expect_err("values of different types can't be compared")
+        .assert_contains("mismatch types for comparison");
+    eval_source("let env = new_env(); env == env")
+        .expect_err("registered types need a PartialEq hook to be compared")
+        .assert_contains("can't be compared");
+    eval_source("let f = |x: i64| x; f == f")
+        .expect_err("closures can't be compared")
+        .assert_contains("can't be compared");
+}
+
+#[test]
+fn registered_equality() {
+    #[derive(Clone, PartialEq)]
+    struct Point {
+        x: i64,
+        y: i64,
+    }
+
+    fn point(x: i64, y: i64) -> Point {
+        Point { x, y }
+    }
+
+    fn distance(a: Point, b: Point) -> i64 {
+        (a.x - b.x).abs() + (a.y - b.y).abs()
+    }
+
+    fn shift_by(a: &mut Point, b: Point) {
+        a.x += b.x;
+        a.y += b.y;
+    }
+
+    let mut engine = test_engine();
+    engine.register_eq::<Point>();
+    engine.register_fn("point", point, None);
+    engine.register_fn("distance", distance, None);
+    engine.register_fn("shift_by", shift_by, None);
+
+    assert_matches!(
+        engine.eval_source("test", b"point(1, 2) == point(1, 2)", false),
+        Ok(ReturnValue::Bool(true))
+    );
+    assert_matches!(
+        engine.eval_source("test", b"let p = point(1, 2); p == point(2, 1)", false),
+        Ok(ReturnValue::Bool(false))
+    );
+    assert_matches!(
+        engine.eval_source("test", b"let p = point(1, 2); p != point(2, 1)", false),
+        Ok(ReturnValue::Bool(true))
+    );
+
+    // The same value can be passed more than once
+    assert_matches!(
+        engine.eval_source("test", b"let p = point(1, 2); p == p", false),
+        Ok(ReturnValue::Bool(true))
+    );
+    assert_matches!(
+        engine.eval_source("test", b"let p = point(1, 2); let q = p; p == q", false),
+        Ok(ReturnValue::Bool(true))
+    );
+    assert_matches!(
+        engine.eval_source("test", b"let p = point(1, 2); distance(p, p)", false),
+        Ok(ReturnValue::I64(0))
+    );
+    assert_matches!(
+        engine.eval_source(
+            "test",
+            b"let mut p = point(1, 2); shift_by(p, p); p == point(2, 4)",
+            false
+        ),
+        Ok(ReturnValue::Bool(true))
+    );
+}
+
+#[test]
+fn typecheck_errors() {
+    eval_source("let x = 123; x = 4566")
+        .expect_err("test should fail due to missing mut")
+        .assert_contains("assignment to immutable variable");
+}
+
+#[test]
+fn runtime_errors() {
+    eval_source("3 / 0")
+        .expect_err("it should not be possible to divide by zero")
+        .assert_contains("division by zero");
+}
+
+#[test]
+fn user_defined_functions() {
+    assert_matches!(
+        eval_source("fn double(x: i64) -> i64 { x * 2 }\ndouble(21)"),
+        Ok(ReturnValue::I64(42))
+    );
+    assert_matches!(
+        eval_source("let y = square(3.0)\nfn square(x: f64) -> f64 { x * x }\ny + 1.0"),
+        Ok(ReturnValue::F64(10.0))
+    );
+    assert_matches!(
+        eval_source(
+            "fn fib(n: i64) -> i64 { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }\nfib(15)"
+        ),
+        Ok(ReturnValue::I64(610))
+    );
+    assert_matches!(
+        eval_source(
+            r#"fn greet(name: String) -> String { name }
+greet("hi")"#
+        ),
+        Ok(ReturnValue::String(_))
+    );
+    assert_matches!(
+        eval_source("fn pick(x: bool) -> i64 { 1 }\nfn pick(x: i64) -> i64 { 2 }\npick(3)"),
+        Ok(ReturnValue::I64(2))
+    );
+    assert_matches!(
+        eval_source("fn log(x: i64) { print(x) }\nlog(5)\n3"),
+        Ok(ReturnValue::I64(3))
+    );
+}
+
+#[test]
+fn user_defined_function_errors() {
+    eval_source("fn foo(x) -> i64 { 3 }")
+        .expect_err("parameters need types")
+        .assert_contains("missing type for parameter");
+    eval_source("fn foo(x: i64) -> bool { x }")
+        .expect_err("body should match return type")
+        .assert_contains("declared return type");
+    eval_source("fn foo(x: i64) -> i64 { x }\nfoo(true)")
+        .expect_err("argument types should be checked")
+        .assert_contains("foo");
+    eval_source("fn add(x: i64, y: i64) -> i64 { x }")
+        .expect_err("registered functions should not be shadowed")
+        .assert_contains("add");
+    assert_eq!(
+        eval_source("fn f(x: i64, x: i64) -> i64 { x }")
+            .expect_err("parameter names should be unique"),
+        ErrorBatch::one(ScriptError {
+            message: "parameter `x` is already defined".into(),
+            span: Span { start: 13, end: 14 },
+            kind: ErrorKind::Script,
+        })
+    );
+}
+
+#[test]
+fn closures() {
+    assert_matches!(
+        eval_source("let add_one = |x: i64| x + 1; add_one(41)"),
+        Ok(ReturnValue::I64(42))
+    );
+    assert_matches!(
+        eval_source("let offset = 10; let add = |x: i64, y: i64| x + y + offset; add(1, 2)"),
+        Ok(ReturnValue::I64(13))
+    );
+    assert_matches!(
+        eval_source("let mut n = 1; let get = || n; n = 5; get() + n"),
+        Ok(ReturnValue::I64(6))
+    );
+    assert_matches!(
+        eval_source(
+            r#"fn apply(f: fn(i64) -> i64, x: i64) -> i64 { f(x) }
+let base = 3
+apply(|x| x * base, 7)"#
+        ),
+        Ok(ReturnValue::I64(21))
+    );
+    assert_matches!(
+        eval_source(
+            r#"fn adder(n: i64) -> fn(i64) -> i64 { |x| x + n }
+let add_two = adder(2)
+let add_three = adder(3)
+add_two(add_three(0))"#
+        ),
+        Ok(ReturnValue::I64(5))
+    );
+    assert_matches!(
+        eval_source(
+            r#"let greeting = "hello"
+let greet = |name: String| -> String { if name == "" { return greeting }; "{greeting} {name}" }
+greet("truffle") ++ greet("")"#
+        ),
+        Ok(ReturnValue::String(s)) if s == "hello trufflehello"
+    );
+    assert_matches!(
+        eval_source("let x = 2; let outer = |y: i64| { let inner = || x * y; inner() }; outer(5)"),
+        Ok(ReturnValue::I64(10))
+    );
+    assert_matches!(
+        eval_source("let xs = [1, 2, 3]; let total = || { let mut t = 0; for x in xs { t += x } t }; total()"),
+        Ok(ReturnValue::I64(6))
+    );
+    assert_matches!(
+        eval_source("let mut f = |x: i64| x; f = |x: i64| x * 10; f(2)"),
+        Ok(ReturnValue::I64(20))
+    );
 }
 
 #[test]
-fn assignment_operations() {
+fn closure_errors() {
+    eval_source("let f = |x| x + 1")
+        .expect_err("parameters need a type to come from somewhere")
+        .assert_contains("can't infer the type of `x`");
+    eval_source("let f = |x: i64| x; f(1, 2)")
+        .expect_err("closure calls should check their arity")
+        .assert_contains("closure takes 1 argument(s) but 2 were given");
+    eval_source("let f = |x: i64| x; f(true)")
+        .expect_err("closure calls should check their arguments")
+        .assert_contains("argument has type bool but the closure takes i64");
+    eval_source("let mut n = 0; let f = || { n = 1 }; f()")
+        .expect_err("captured variables are copies")
+        .assert_contains("assignment to a captured variable");
+    eval_source("let f = |x: i64| -> bool { x }")
+        .expect_err("the body should match the declared return type")
+        .assert_contains("closure body has type i64 but the declared return type is bool");
+    eval_source("fn apply(f: fn(i64) -> i64) -> i64 { f(1) }\napply(|x: bool| 1)")
+        .expect_err("closure types should match")
+        .assert_contains("apply");
+    eval_source("let env = new_env(); let f = || env.read_var(\"x\")")
+        .expect_err("registered types can't be copied")
+        .assert_contains("closures can't capture `env`");
+    assert_eq!(
+        eval_source("let f = |x: i64, x: i64| x").expect_err("parameter names should be unique"),
+        ErrorBatch::one(ScriptError {
+            message: "parameter `x` is already defined".into(),
+            span: Span { start: 17, end: 18 },
+            kind: ErrorKind::Script,
+        })
+    );
+}
+
+#[test]
+fn callbacks_in_registered_functions() {
+    use std::sync::{Arc, Mutex};
+    use truffle::Callback;
+
+    let handlers: Arc<Mutex<Vec<Callback>>> = Arc::default();
+
+    let mut engine = test_engine();
+    let registered = handlers.clone();
+    engine.register_fn(
+        "on_event",
+        move |handler: Callback| registered.lock().unwrap().push(handler),
+        None,
+    );
+
+    let script = engine
+        .compile(
+            "test",
+            br#"let prefix = "got"
+on_event(|x: i64| "{prefix} {x}")
+on_event(|x: i64| if x > 1 { "big" } else { "small" })"#,
+        )
+        .expect("script should compile");
+    script.run(&engine).expect("script should run");
+
+    let handlers = std::mem::take(&mut *handlers.lock().unwrap());
+    assert_eq!(handlers.len(), 2);
     assert_matches!(
-        eval_source("let mut x = 1; x += 11; x -= 2; x *= 6; x /= 12"),
-        Ok(ReturnValue::I64(5))
+        script.call(&engine, &handlers[0], vec![Box::new(5_i64)]),
+        Ok(ReturnValue::String(s)) if s == "got 5"
+    );
+    assert_matches!(
+        script.call(&engine, &handlers[1], vec![Box::new(5_i64)]),
+        Ok(ReturnValue::String(s)) if s == "big"
+    );
+    script
+        .call(&engine, &handlers[0], vec![Box::new(true)])
+        .expect_err("arguments should match the closure's parameters")
+        .assert_contains("wrong type");
+    script
+        .call(&engine, &handlers[0], vec![])
+        .expect_err("arguments should match the closure's parameters")
+        .assert_contains("closure takes 1 argument(s) but 0 were given");
+
+    let mut session = Session::new(&engine);
+    eval_snippet(&mut session, "let mut count = 10").unwrap();
+    let Ok(ReturnValue::Custom(callback)) = eval_snippet(&mut session, "|x: i64| x + count") else {
+        panic!("closures should be returned as callbacks")
+    };
+    let callback = callback.downcast_ref::<Callback>().unwrap();
+    assert_matches!(
+        session.call(callback, vec![Box::new(1_i64)]),
+        Ok(ReturnValue::I64(11))
+    );
+    assert_matches!(
+        eval_snippet(&mut session, "count + 1"),
+        Ok(ReturnValue::I64(11))
     );
 }
 
 #[test]
-fn if_expression() {
+fn callback_argument_checks() {
+    use std::sync::{Arc, Mutex};
+    use truffle::{Callback, ScriptStruct};
+
+    let handlers: Arc<Mutex<Vec<Callback>>> = Arc::default();
+
+    let mut engine = test_engine();
+    let registered = handlers.clone();
+    engine.register_fn(
+        "on_event",
+        move |handler: Callback| registered.lock().unwrap().push(handler),
+        None,
+    );
+
+    let script = engine
+        .compile(
+            "test",
+            br#"struct Point { x: i64, y: i64 }
+on_event(|xs: [i64]| xs.len())
+on_event(|p: Point| p.x + p.y)
+on_event(|f: fn(i64) -> i64| f(2))
+on_event(|x: i64| x * 5)"#,
+        )
+        .expect("script should compile");
+    script.run(&engine).expect("script should run");
+    let handlers = std::mem::take(&mut *handlers.lock().unwrap());
+
     assert_matches!(
-        eval_source("if true { 3 } else { 4 }"),
+        script.call(&engine, &handlers[0], vec![Box::new(vec![1_i64, 2])]),
+        Ok(ReturnValue::I64(2))
+    );
+    script
+        .call(&engine, &handlers[0], vec![Box::new(vec![1.0_f64])])
+        .expect_err("arrays should hold the right element type")
+        .assert_contains("argument 1 has the wrong type");
+
+    let point = |fields: Vec<truffle::Value>| Box::new(ScriptStruct { fields });
+    assert_matches!(
+        script.call(
+            &engine,
+            &handlers[1],
+            vec![point(vec![Box::new(1_i64), Box::new(2_i64)])]
+        ),
         Ok(ReturnValue::I64(3))
     );
+    script
+        .call(&engine, &handlers[1], vec![point(vec![Box::new(1_i64)])])
+        .expect_err("structs should have the right fields")
+        .assert_contains("argument 1 has the wrong type");
+    script
+        .call(
+            &engine,
+            &handlers[1],
+            vec![point(vec![Box::new(1_i64), Box::new(true)])],
+        )
+        .expect_err("structs should have the right fields")
+        .assert_contains("argument 1 has the wrong type");
+    script
+        .call(&engine, &handlers[1], vec![Box::new(Env::new_env())])
+        .expect_err("registered types aren't script structs")
+        .assert_contains("argument 1 has the wrong type");
+
     assert_matches!(
-        eval_source("if false { 3 } else { 4 }"),
-        Ok(ReturnValue::I64(4))
+        script.call(&engine, &handlers[2], vec![Box::new(handlers[3].clone())]),
+        Ok(ReturnValue::I64(10))
     );
+    script
+        .call(&engine, &handlers[2], vec![Box::new(handlers[0].clone())])
+        .expect_err("closures should have the right signature")
+        .assert_contains("argument 1 has the wrong type");
+
+    // Closures can only be called on the script that created them
+    let other = engine
+        .compile("test", b"fn double(x: i64) -> i64 { x * 2 }\ndouble(1)")
+        .expect("script should compile");
+    other
+        .call(&engine, &handlers[3], vec![Box::new(5_i64)])
+        .expect_err("the closure belongs to a different script")
+        .assert_contains("callback was created by a different script");
+    let mut session = Session::new(&engine);
+    session
+        .call(&handlers[3], vec![Box::new(5_i64)])
+        .expect_err("the closure belongs to a different script")
+        .assert_contains("callback was created by a different script");
+}
+
+#[test]
+fn engine_globals() {
+    let mut engine = test_engine();
+    engine.set_global("limit", 10_i64);
+    engine.set_global("name", String::from("truffle"));
+
     assert_matches!(
-        eval_source("let x = if false { 3 } else { 4 }; x"),
-        Ok(ReturnValue::I64(4))
+        engine.eval_source("test", b"limit * 2", false),
+        Ok(ReturnValue::I64(20))
+    );
+    assert_matches!(
+        engine.eval_source("test", br#"let greet = || "hello {name}"; greet()"#, false),
+        Ok(ReturnValue::String(s)) if s == "hello truffle"
+    );
+
+    let script = engine
+        .compile("test", b"limit + 1")
+        .expect("script should compile");
+    assert_matches!(script.run(&engine), Ok(ReturnValue::I64(11)));
+
+    let mut session = Session::new(&engine);
+    assert_matches!(
+        eval_snippet(&mut session, "let x = limit + 5"),
+        Ok(ReturnValue::Unit)
+    );
+    assert_matches!(
+        eval_snippet(&mut session, "x + limit"),
+        Ok(ReturnValue::I64(25))
+    );
+    drop(session);
+
+    engine.set_global("limit", 20_i64);
+    assert_matches!(
+        engine.eval_source("test", b"limit * 2", false),
+        Ok(ReturnValue::I64(40))
     );
+    assert_matches!(script.run(&engine), Ok(ReturnValue::I64(21)));
+
+    engine.set_global("limit", 2.5_f64);
+    script
+        .run(&engine)
+        .expect_err("the script was compiled for the old globals")
+        .assert_contains("globals changed");
 }
 
 #[test]
-fn while_loop() {
+fn bindings() {
+    let engine = test_engine();
+
+    let mut env = Env::new_env();
+    env.set_var("x".into(), 3);
+
+    let mut bindings = Bindings::new();
+    bindings.push("env", env);
+    bindings.push_mut("count", 5_i64);
+    bindings.push_mut("log", String::new());
+
     assert_matches!(
-        eval_source("let mut x = 0; while x < 10 { x = x + 1 }; x"),
-        Ok(ReturnValue::I64(10))
+        engine.eval_source_with(
+            "test",
+            br#"env.set_var("y", env.read_var("x") + 1)
+count = count + 1
+log = log ++ "ran"
+env.read_var("y") * count"#,
+            &mut bindings,
+            false
+        ),
+        Ok(ReturnValue::I64(24))
+    );
+
+    assert_eq!(bindings.get::<i64>("count"), Some(&6));
+    assert_eq!(
+        bindings.get::<String>("log").map(String::as_str),
+        Some("ran")
+    );
+    assert_eq!(
+        bindings
+            .get_mut::<Env>("env")
+            .map(|env| env.read_var("y".into())),
+        Some(4)
+    );
+
+    // Bindings keep the script's changes for the next run
+    assert_matches!(
+        engine.eval_source_with("test", b"count", &mut bindings, false),
+        Ok(ReturnValue::I64(6))
     );
 }
 
 #[test]
-fn external_call() {
-    assert_matches!(eval_source("add(3, 4)"), Ok(ReturnValue::I64(7)));
-    assert_matches!(eval_source("add(6.0, 3.0)"), Ok(ReturnValue::F64(9.0)));
+fn globals_in_functions() {
+    use std::sync::{Arc, Mutex};
+    use truffle::Callback;
+
+    let handlers: Arc<Mutex<Vec<Callback>>> = Arc::default();
+
+    let mut engine = test_engine();
+    engine.set_global("limit", 10_i64);
+    let registered = handlers.clone();
+    engine.register_fn(
+        "on_event",
+        move |handler: Callback| registered.lock().unwrap().push(handler),
+        None,
+    );
+
+    assert_matches!(
+        engine.eval_source(
+            "test",
+            b"fn doubled() -> i64 { limit * 2 }\nfn total() -> i64 { doubled() + limit }\ntotal()",
+            false
+        ),
+        Ok(ReturnValue::I64(30))
+    );
+    assert_matches!(
+        engine.eval_source(
+            "test",
+            b"fn scaled() -> fn(i64) -> i64 { |x: i64| x * limit }\nlet f = scaled()\nf(3)",
+            false
+        ),
+        Ok(ReturnValue::I64(30))
+    );
+
+    let mut env = Env::new_env();
+    env.set_var("x".into(), 3);
+    let mut bindings = Bindings::new();
+    bindings.push("env", env);
+    bindings.push_mut("count", 5_i64);
+
+    assert_matches!(
+        engine.eval_source_with(
+            "test",
+            br#"fn handle() -> i64 { env.read_var("x") + count }
+fn bump() {
+  count = count + 1
+  count += 10;
+}
+bump()
+bump()
+handle()"#,
+            &mut bindings,
+            false
+        ),
+        Ok(ReturnValue::I64(30))
+    );
+    assert_eq!(bindings.get::<i64>("count"), Some(&27));
+
+    // Closures called from Rust can call functions that use globals too
+    let script = engine
+        .compile(
+            "test",
+            b"fn limited(x: i64) -> bool { x < limit }\non_event(|x: i64| limited(x))",
+        )
+        .expect("script should compile");
+    script.run(&engine).expect("script should run");
+    let handlers = std::mem::take(&mut *handlers.lock().unwrap());
+    assert_matches!(
+        script.call(&engine, &handlers[0], vec![Box::new(5_i64)]),
+        Ok(ReturnValue::Bool(true))
+    );
+    assert_matches!(
+        script.call(&engine, &handlers[0], vec![Box::new(50_i64)]),
+        Ok(ReturnValue::Bool(false))
+    );
+
+    let mut session = Session::new(&engine);
+    eval_snippet(&mut session, "fn over(x: i64) -> bool { x > limit }").unwrap();
+    assert_matches!(
+        eval_snippet(&mut session, "over(11)"),
+        Ok(ReturnValue::Bool(true))
+    );
 }
 
 #[test]
-fn method_and_mutation() {
+fn global_errors() {
+    let mut engine = test_engine();
+    engine.set_global("limit", 10_i64);
+
+    let mut bindings = Bindings::new();
+    bindings.push("env", Env::new_env());
+    bindings.push("total", 1_i64);
+
+    let mut eval =
+        |source: &str| engine.eval_source_with("test", source.as_bytes(), &mut bindings, false);
+
+    eval("limit = 3")
+        .expect_err("engine globals can't be assigned")
+        .assert_contains("assignment to immutable global");
+    eval("total = 3")
+        .expect_err("bindings added with `push` can't be assigned")
+        .assert_contains("assignment to immutable global");
+    eval("let limit = 3")
+        .expect_err("globals can't be shadowed")
+        .assert_contains("`limit` is already a global");
+    eval("fn f() { limit = 3 }")
+        .expect_err("functions can't assign engine globals either")
+        .assert_contains("assignment to immutable global");
+    eval("let f = || env.read_var(\"x\")")
+        .expect_err("registered types can't be copied into closures")
+        .assert_contains("closures can't capture `env`");
+
+    struct Unregistered;
+    let mut bindings = Bindings::new();
+    bindings.push("thing", Unregistered);
+    engine
+        .eval_source_with("test", b"1", &mut bindings, false)
+        .expect_err("binding types need to be registered")
+        .assert_contains("needs to be registered");
+}
+
+#[test]
+fn registered_values_are_dropped_once() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    struct Tracked(Arc<AtomicUsize>);
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let drops = Arc::new(AtomicUsize::new(0));
+    let mut engine = test_engine();
+    let counter = drops.clone();
+    engine.register_fn("track", move || Tracked(counter.clone()), None);
+    engine.register_fn("touch", |_: &mut Tracked, n: i64| n, None);
+
+    let output = engine.eval_source(
+        "test",
+        br#"let a = track()
+let mut b = a
+b = track()
+let mut total = 0
+for n in 0..3 { let t = track(); total += t.touch(n) }
+a.touch(b.touch(total))"#,
+        false,
+    );
+    assert_matches!(output, Ok(ReturnValue::I64(3)));
+    assert_eq!(drops.load(Ordering::SeqCst), 5);
+
+    // A value handed back to the host is dropped by the host
+    let output = engine.eval_source("test", b"let a = track(); a", false);
+    assert_eq!(drops.load(Ordering::SeqCst), 5);
+    drop(output);
+    assert_eq!(drops.load(Ordering::SeqCst), 6);
+
+    let mut session = Session::new(&engine);
+    eval_snippet(&mut session, "let mut a = track()").expect("snippet should run");
+    eval_snippet(&mut session, "a = track()").expect("snippet should run");
+    assert!(drops.load(Ordering::SeqCst) <= 7);
+    drop(session);
+    assert_eq!(drops.load(Ordering::SeqCst), 8);
+}
+
+#[test]
+fn typed_eval() {
+    let engine = test_engine();
+
+    assert_eq!(engine.eval::<i64>("test", b"1 + 2"), Ok(3));
+    assert_eq!(engine.eval::<f64>("test", b"1.5 * 2.0"), Ok(3.0));
+    assert_eq!(engine.eval::<bool>("test", b"2 < 3"), Ok(true));
+    assert_eq!(
+        engine.eval::<String>("test", br#"let s = "a"; s ++ "b""#),
+        Ok("ab".to_string())
+    );
+    assert_eq!(engine.eval::<()>("test", b"let x = 3"), Ok(()));
+    assert_eq!(engine.eval::<Option<i64>>("test", b"Some(3)"), Ok(Some(3)));
+
+    assert_eq!(
+        engine.eval::<Vec<i64>>("test", b"[1, 2, 3]"),
+        Ok(vec![1, 2, 3])
+    );
+    let mut env = engine
+        .eval::<Env>(
+            "test",
+            br#"let env = new_env(); env.set_var("x", 3); new_env()"#,
+        )
+        .expect("registered types can be taken out");
+    env.set_var("x".into(), 1);
+    assert_eq!(env.read_var("x".into()), 1);
+    engine
+        .eval::<Callback>("test", b"let y = 2; |x: i64| x * y")
+        .expect("closures can be taken out as callbacks");
+
     assert_matches!(
-        eval_source(r#"let env = new_env(); env.set_var("kris", 3); env.read_var("kris")"#),
-        Ok(ReturnValue::I64(3))
+        ReturnValue::I64(3).downcast::<bool>(),
+        Err(ReturnValue::I64(3))
     );
+    assert_matches!(ReturnValue::String("a".into()).downcast::<String>(), Ok(s) if s == "a");
 }
 
 #[test]
-fn typecheck_errors() {
-    eval_source("let x = 123; x = 4566")
-        .expect_err("test should fail due to missing mut")
-        .assert_contains("assignment to immutable variable");
+fn typed_eval_errors() {
+    let engine = test_engine();
+
+    let err = engine
+        .eval::<i64>("test", b"let x = 3\nx == 3")
+        .expect_err("a bool isn't an i64");
+    err.assert_contains("script has type bool but i64 was expected");
+    assert_eq!(
+        err.into_iter().next().map(|err| err.span),
+        Some(Span { start: 10, end: 16 })
+    );
+
+    engine
+        .eval::<String>("test", b"")
+        .expect_err("an empty script is unit")
+        .assert_contains("script has type void");
+
+    #[derive(Debug)]
+    struct Unregistered;
+    engine
+        .eval::<Unregistered>("test", b"1")
+        .expect_err("the type needs to be registered")
+        .assert_contains("needs to be registered");
 }
 
 #[test]
-fn runtime_errors() {
-    eval_source("3 / 0")
-        .expect_err("it should not be possible to divide by zero")
-        .assert_contains("division by zero");
+fn compile_expecting() {
+    let engine = test_engine();
+
+    let filter = engine
+        .compile_expecting::<bool>("test", b"let x = 3\nx > 2")
+        .expect("a bool script should compile");
+    assert_matches!(filter.run(&engine), Ok(ReturnValue::Bool(true)));
+
+    // The expected type lets values like `None` and `[]` find their type
+    assert_eq!(engine.eval::<Option<i64>>("test", b"None"), Ok(None));
+    assert_eq!(engine.eval::<Vec<String>>("test", b"[]"), Ok(vec![]));
+
+    let err = engine
+        .compile_expecting::<bool>("test", b"let x = 3\nx + 1")
+        .err()
+        .expect("an i64 script isn't a filter");
+    err.assert_contains("script has type i64 but bool was expected");
+    assert_eq!(
+        err.into_iter().next().map(|err| err.span),
+        Some(Span { start: 10, end: 15 })
+    );
+
+    engine
+        .compile_expecting::<bool>("test", b"if true { return 1 }\nfalse")
+        .err()
+        .expect("early returns have to agree with the script's value")
+        .assert_contains("returned value has type i64 but the script's value has type bool");
+}
+
+#[test]
+fn compile_once_run_many() {
+    let engine = test_engine();
+    let script = engine
+        .compile(
+            "test",
+            br#"let env = new_env(); env.set_var("x", 3); env.read_var("x") + 4"#,
+        )
+        .expect("script should compile");
+
+    for _ in 0..3 {
+        assert_matches!(script.run(&engine), Ok(ReturnValue::I64(7)));
+    }
+
+    // Every run starts from the compiled state, so nothing one run changes carries into the next
+    let script = engine
+        .compile(
+            "test",
+            br#"let mut total = 1
+let mut xs = [total]
+for i in 1..=3 {
+  total = total + i
+  xs.push(total)
+}
+"{total} {xs.len()} {xs[3]}""#,
+        )
+        .expect("script should compile");
+
+    for _ in 0..3 {
+        assert_matches!(script.run(&engine), Ok(ReturnValue::String(s)) if s == "7 4 7");
+    }
+
+    let script = engine
+        .compile("test", br#""hello""#)
+        .expect("script should compile");
+
+    for _ in 0..3 {
+        assert_matches!(script.run(&engine), Ok(ReturnValue::String(s)) if s == "hello");
+    }
+}
+
+#[test]
+#[cfg(feature = "async")]
+fn compile_once_run_many_async() {
+    use futures::executor::block_on;
+
+    let engine = test_engine();
+    let script = engine
+        .compile(
+            "test",
+            br#"let mut x = modify_this(1)
+x = x + 1
+"{x}""#,
+        )
+        .expect("script should compile");
+
+    for _ in 0..3 {
+        assert_matches!(
+            block_on(script.run_async(&engine)),
+            Ok(ReturnValue::String(s)) if s == "102"
+        );
+    }
+}
+
+#[test]
+fn compile_once_call_many() {
+    use std::sync::{Arc, Mutex};
+    use truffle::Callback;
+
+    let handlers: Arc<Mutex<Vec<Callback>>> = Arc::default();
+
+    let mut engine = test_engine();
+    let registered = handlers.clone();
+    engine.register_fn(
+        "on_event",
+        move |handler: Callback| registered.lock().unwrap().push(handler),
+        None,
+    );
+
+    let script = engine
+        .compile(
+            "test",
+            br#"let base = 10
+on_event(|x: i64| {
+  let mut total = base
+  total = total + x
+  total
+})"#,
+        )
+        .expect("script should compile");
+
+    for _ in 0..2 {
+        script.run(&engine).expect("script should run");
+    }
+
+    let handlers = std::mem::take(&mut *handlers.lock().unwrap());
+    assert_eq!(handlers.len(), 2);
+    for handler in &handlers {
+        for _ in 0..3 {
+            assert_matches!(
+                script.call(&engine, handler, vec![Box::new(5_i64)]),
+                Ok(ReturnValue::I64(15))
+            );
+        }
+    }
+}
+
+#[test]
+fn compiled_script_on_another_engine() {
+    let engine = test_engine();
+    let script = engine
+        .compile(
+            "test",
+            br#"let env = new_env(); env.set_var("x", 3); env.read_var("x")"#,
+        )
+        .expect("script should compile");
+
+    script
+        .run(&Engine::new())
+        .expect_err("a script can't run with functions it wasn't compiled against")
+        .assert_contains("the script was compiled with a different engine");
+    script
+        .run(&test_engine())
+        .expect_err("engines with the same functions are still different engines")
+        .assert_contains("the script was compiled with a different engine");
+    assert_matches!(script.run(&engine), Ok(ReturnValue::I64(3)));
+}
+
+#[test]
+fn engine_is_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Engine>();
+    assert_send_sync::<truffle::CompiledScript>();
+}
+
+#[test]
+fn engine_shared_across_threads() {
+    use std::sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    };
+
+    let calls = Arc::new(AtomicI64::new(0));
+    let mut engine = test_engine();
+    let counter = calls.clone();
+    engine.register_fn(
+        "count",
+        move |x: i64| counter.fetch_add(x, Ordering::SeqCst) + x,
+        None,
+    );
+    let engine = Arc::new(engine);
+    let script = Arc::new(
+        engine
+            .compile(
+                "test",
+                br#"let env = new_env()
+let mut total = 0
+for i in 0..10 { total += i; env.set_var("x", total) }
+count(1)
+env.read_var("x")"#,
+            )
+            .expect("script should compile"),
+    );
+
+    let threads: Vec<_> = (0..8)
+        .map(|i: i64| {
+            let engine = engine.clone();
+            let script = script.clone();
+            std::thread::spawn(move || {
+                for j in 0..20 {
+                    // Each run starts from scratch, whatever the other threads are running
+                    assert_matches!(script.run(&engine), Ok(ReturnValue::I64(45)));
+
+                    let source = format!(r#"let s = "n" ++ "{j}"; {i} * {j}"#);
+                    assert_matches!(
+                        engine.eval_source("test", source.as_bytes(), false),
+                        Ok(ReturnValue::I64(n)) if n == i * j
+                    );
+                }
+            })
+        })
+        .collect();
+
+    for thread in threads {
+        thread
+            .join()
+            .expect("thread should finish without panicking");
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 8 * 20);
 }
 
 #[test]
@@ -175,6 +2335,230 @@ fn lsp_goto_definition() {
     )
 }
 
+#[test]
+fn instruction_limit() {
+    let mut engine = test_engine();
+    engine.set_limits(EvalLimits {
+        max_instructions: Some(1000),
+        ..Default::default()
+    });
+
+    assert_eq!(
+        engine
+            .eval_source("test", b"while true {}", false)
+            .expect_err("the loop should hit the limit"),
+        ErrorBatch::one(ScriptError {
+            message: "script ran more than 1000 instructions".into(),
+            span: Span { start: 11, end: 13 },
+            kind: ErrorKind::InstructionLimit,
+        })
+    );
+    assert_eq!(
+        engine
+            .eval_source("test", b"fn forever() { forever() } forever()", false)
+            .expect_err("the recursion should hit the limit"),
+        ErrorBatch::one(ScriptError {
+            message: "script ran more than 1000 instructions".into(),
+            span: Span { start: 15, end: 24 },
+            kind: ErrorKind::InstructionLimit,
+        })
+    );
+
+    assert_matches!(
+        engine.eval_source(
+            "test",
+            b"let mut x = 0; while x < 10 { x = x + 1 }; x",
+            false
+        ),
+        Ok(ReturnValue::I64(10))
+    );
+
+    // Only the instructions that actually run count, not the ones in branches that are skipped
+    assert_matches!(
+        engine.eval_source(
+            "test",
+            b"let mut x = 0
+while x < 100 {
+    if x < 0 { x = x * 2 + x * 3 + x * 4 + x * 5 + x * 6 + x * 7 + x * 8 + x * 9 + x * 10 }
+    x = x + 1
+}
+x",
+            false
+        ),
+        Ok(ReturnValue::I64(100))
+    );
+    engine
+        .eval_source(
+            "test",
+            b"let mut x = 0; while x < 200 { x = x + 1 }; x",
+            false,
+        )
+        .expect_err("the loop should hit the limit")
+        .assert_contains("script ran more than 1000 instructions");
+}
+
+#[test]
+fn time_limit() {
+    let mut engine = test_engine();
+    engine.set_limits(EvalLimits {
+        max_duration: Some(std::time::Duration::from_millis(10)),
+        ..Default::default()
+    });
+
+    assert_eq!(
+        engine
+            .eval_source("test", b"while true {}", false)
+            .expect_err("the loop should hit the limit"),
+        ErrorBatch::one(ScriptError {
+            message: "script ran for longer than 10ms".into(),
+            span: Span { start: 11, end: 13 },
+            kind: ErrorKind::TimeLimit,
+        })
+    );
+}
+
+#[test]
+fn cancellation() {
+    let cancellation = CancellationToken::new();
+
+    let mut engine = test_engine();
+    engine.set_limits(EvalLimits {
+        cancellation: Some(cancellation.clone()),
+        ..Default::default()
+    });
+
+    let errors = std::thread::scope(|scope| {
+        scope.spawn(|| {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            cancellation.cancel();
+        });
+
+        engine
+            .eval_source("test", b"while true {}", false)
+            .unwrap_err()
+    });
+    assert_eq!(
+        errors,
+        ErrorBatch::one(ScriptError {
+            message: "script was cancelled".into(),
+            span: Span { start: 11, end: 13 },
+            kind: ErrorKind::Cancelled,
+        })
+    );
+}
+
+#[test]
+fn session_keeps_definitions() {
+    let engine = test_engine();
+    let mut session = Session::new(&engine);
+
+    assert_matches!(
+        eval_snippet(&mut session, "let x = 1"),
+        Ok(ReturnValue::Unit)
+    );
+    assert_matches!(eval_snippet(&mut session, "x + 1"), Ok(ReturnValue::I64(2)));
+    assert_matches!(
+        eval_snippet(&mut session, "fn double(a: i64) -> i64 { a * 2 }"),
+        Ok(ReturnValue::Unit)
+    );
+    assert_matches!(
+        eval_snippet(&mut session, "struct Point { x: i64, y: i64 }"),
+        Ok(ReturnValue::Unit)
+    );
+    assert_matches!(
+        eval_snippet(&mut session, "let mut p = Point { x: 3, y: 4 }"),
+        Ok(ReturnValue::Unit)
+    );
+    assert_matches!(
+        eval_snippet(&mut session, "p.x = double(x)"),
+        Ok(ReturnValue::Unit)
+    );
+    assert_matches!(
+        eval_snippet(&mut session, "p.x + p.y"),
+        Ok(ReturnValue::I64(6))
+    );
+    assert_matches!(eval_snippet(&mut session, "p"), Ok(ReturnValue::Custom(_)));
+    assert_matches!(eval_snippet(&mut session, "p.y"), Ok(ReturnValue::I64(4)));
+}
+
+#[test]
+fn session_keeps_strings() {
+    let engine = test_engine();
+    let mut session = Session::new(&engine);
+
+    assert_matches!(
+        eval_snippet(&mut session, "let s = \"hi\""),
+        Ok(ReturnValue::Unit)
+    );
+    assert_matches!(eval_snippet(&mut session, "s"), Ok(ReturnValue::String(s)) if s == "hi");
+    assert_matches!(eval_snippet(&mut session, "s"), Ok(ReturnValue::String(s)) if s == "hi");
+}
+
+#[test]
+fn session_keeps_registered_values() {
+    let engine = test_engine();
+    let mut session = Session::new(&engine);
+
+    assert_matches!(
+        eval_snippet(&mut session, r#"let e = new_env(); e.set_var("x", 5)"#),
+        Ok(ReturnValue::Unit)
+    );
+
+    // The variable still holds the value, which can't be copied out
+    eval_snippet(&mut session, "e")
+        .expect_err("the value is still in use")
+        .assert_contains("the script's value is still in use");
+    assert_matches!(
+        eval_snippet(&mut session, r#"e.read_var("x")"#),
+        Ok(ReturnValue::I64(5))
+    );
+
+    // Arrays and script structs are copied instead
+    assert_matches!(
+        eval_snippet(&mut session, "let xs = [1, 2]"),
+        Ok(ReturnValue::Unit)
+    );
+    let Ok(ReturnValue::Custom(xs)) = eval_snippet(&mut session, "xs") else {
+        panic!("arrays should be copied out")
+    };
+    assert_eq!(xs.downcast_ref::<Vec<i64>>(), Some(&vec![1, 2]));
+    assert_matches!(
+        eval_snippet(&mut session, "xs.len()"),
+        Ok(ReturnValue::I64(2))
+    );
+}
+
+#[test]
+fn session_recovers_from_errors() {
+    let engine = test_engine();
+    let mut session = Session::new(&engine);
+
+    assert_matches!(
+        eval_snippet(&mut session, "let x = 10"),
+        Ok(ReturnValue::Unit)
+    );
+    assert_matches!(eval_snippet(&mut session, "let y = 1 + true"), Err(_));
+    assert_matches!(eval_snippet(&mut session, "y"), Err(_));
+    assert_matches!(
+        eval_snippet(&mut session, "fn broken() -> i64 { true }"),
+        Err(_)
+    );
+    assert_matches!(eval_snippet(&mut session, "broken()"), Err(_));
+
+    // Error spans point into the whole session's source
+    let errors = eval_snippet(&mut session, "x + false").unwrap_err();
+    for error in &errors {
+        assert_eq!(
+            &session.source()[error.span.start..error.span.end],
+            b"x + false"
+        );
+    }
+
+    assert_matches!(
+        eval_snippet(&mut session, "x * 2"),
+        Ok(ReturnValue::I64(20))
+    );
+}
 #[test]
 #[cfg(feature = "lsp")]
 fn lsp_find_all_references() {
@@ -211,7 +2595,8 @@ fn lsp_check_script() {
         result,
         Some(ErrorBatch::one(ScriptError {
             message: "incomplete math expression".into(),
-            span: Span { start: 11, end: 11 }
+            span: Span { start: 11, end: 11 },
+            kind: ErrorKind::Script,
         }))
     )
 }
@@ -229,7 +2614,8 @@ fn lsp_check_script_call() {
         result,
         Some(ErrorBatch::one(ScriptError {
             message: "could not find compatible function for greeter(i64)".into(),
-            span: Span { start: 0, end: 10 }
+            span: Span { start: 0, end: 10 },
+            kind: ErrorKind::Script,
         }))
     )
 }
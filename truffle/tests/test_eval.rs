@@ -1,11 +1,9 @@
 use std::collections::HashMap;
 
-use truffle::{register_fn, Engine, ErrorBatch, FnRegister, ReturnValue};
+use truffle::{register_fn, Engine, ErrorBatch, FnRegister, ReturnValue, Session};
 
 #[cfg(feature = "async")]
-pub fn eval_source(source: &str) -> Result<ReturnValue, ErrorBatch> {
-    use futures::executor::block_on;
-
+pub fn test_engine() -> Engine {
     #[truffle::export]
     async fn modify_this(this: i64) -> i64 {
         this + 100
@@ -29,11 +27,11 @@ pub fn eval_source(source: &str) -> Result<ReturnValue, ErrorBatch> {
     register_fn!(engine, "set_var", Env::set_var);
     register_fn!(engine, "read_var", Env::read_var);
 
-    block_on(engine.eval_source_async("test", source.as_bytes(), false))
+    engine
 }
 
 #[cfg(not(feature = "async"))]
-pub fn eval_source(source: &str) -> Result<ReturnValue, ErrorBatch> {
+pub fn test_engine() -> Engine {
     let mut engine = Engine::new();
     register_fn!(engine, "print", print::<i64>);
     register_fn!(engine, "print", print::<f64>);
@@ -45,9 +43,37 @@ pub fn eval_source(source: &str) -> Result<ReturnValue, ErrorBatch> {
     register_fn!(engine, "set_var", Env::set_var);
     register_fn!(engine, "read_var", Env::read_var);
 
+    engine
+}
+
+#[cfg(feature = "async")]
+pub fn eval_source(source: &str) -> Result<ReturnValue, ErrorBatch> {
+    use futures::executor::block_on;
+
+    let engine = test_engine();
+
+    block_on(engine.eval_source_async("test", source.as_bytes(), false))
+}
+
+#[cfg(not(feature = "async"))]
+pub fn eval_source(source: &str) -> Result<ReturnValue, ErrorBatch> {
+    let engine = test_engine();
+
     engine.eval_source("test", source.as_bytes(), false)
 }
 
+#[allow(unused)]
+#[cfg(feature = "async")]
+pub fn eval_snippet(session: &mut Session, source: &str) -> Result<ReturnValue, ErrorBatch> {
+    futures::executor::block_on(session.eval_async(source.as_bytes(), false))
+}
+
+#[allow(unused)]
+#[cfg(not(feature = "async"))]
+pub fn eval_snippet(session: &mut Session, source: &str) -> Result<ReturnValue, ErrorBatch> {
+    session.eval(source.as_bytes(), false)
+}
+
 // Script Builtins
 #[cfg_attr(any(feature = "async", feature = "lsp"), truffle::export)]
 pub fn add<T: std::ops::Add>(lhs: T, rhs: T) -> T::Output {